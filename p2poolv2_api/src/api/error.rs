@@ -26,12 +26,18 @@ use std::fmt;
 #[derive(Debug)]
 pub enum ApiError {
     ServerError(String),
+    BadRequest(String),
+    Conflict(String),
+    Forbidden(String),
 }
 
 impl fmt::Display for ApiError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ApiError::ServerError(msg) => write!(f, "axum server error: {msg}"),
+            ApiError::BadRequest(msg) => write!(f, "bad request: {msg}"),
+            ApiError::Conflict(msg) => write!(f, "conflict: {msg}"),
+            ApiError::Forbidden(msg) => write!(f, "forbidden: {msg}"),
         }
     }
 }
@@ -40,11 +46,13 @@ impl Error for ApiError {}
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        match self {
-            ApiError::ServerError(msg) => {
-                let body = Json(json!({ "error": msg }));
-                (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
-            }
-        }
+        let (status, msg) = match self {
+            ApiError::ServerError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            ApiError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            ApiError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+        };
+        let body = Json(json!({ "error": msg }));
+        (status, body).into_response()
     }
 }
@@ -18,18 +18,22 @@ use crate::api::error::ApiError;
 use axum::{
     Json, Router,
     extract::{Query, State},
+    http::StatusCode,
     middleware::{self},
-    routing::get,
+    routing::{get, post},
 };
 use chrono::DateTime;
 use p2poolv2_lib::{
     accounting::{simple_pplns::SimplePplnsShare, stats::metrics::MetricsHandle},
     config::ApiConfig,
+    node::reachability::{Reachability, ReachabilityHandle},
+    node::sync::{SyncStatus, SyncStatusHandle},
     shares::chain::chain_store::ChainStore,
+    stratum::drain::DrainRequest,
 };
 use serde::Deserialize;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::oneshot;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::sync::{mpsc, oneshot};
 use tracing::info;
 
 use crate::api::auth::auth_middleware;
@@ -38,6 +42,9 @@ use crate::api::auth::auth_middleware;
 pub(crate) struct AppState {
     pub(crate) chain_store: Arc<ChainStore>,
     pub(crate) metrics_handle: MetricsHandle,
+    pub(crate) sync_status: SyncStatusHandle,
+    pub(crate) reachability: ReachabilityHandle,
+    pub(crate) drain_tx: mpsc::Sender<DrainRequest>,
     pub(crate) auth_user: Option<String>,
     pub(crate) auth_token: Option<String>,
 }
@@ -54,10 +61,16 @@ pub async fn start_api_server(
     config: ApiConfig,
     chain_store: Arc<ChainStore>,
     metrics_handle: MetricsHandle,
+    sync_status: SyncStatusHandle,
+    reachability: ReachabilityHandle,
+    drain_tx: mpsc::Sender<DrainRequest>,
 ) -> Result<oneshot::Sender<()>, std::io::Error> {
     let app_state = Arc::new(AppState {
         chain_store,
         metrics_handle,
+        sync_status,
+        reachability,
+        drain_tx,
         auth_user: config.auth_user.clone(),
         auth_token: config.auth_token.clone(),
     });
@@ -71,6 +84,11 @@ pub async fn start_api_server(
         .route("/health", get(health_check))
         .route("/metrics", get(metrics))
         .route("/pplns_shares", get(pplns_shares))
+        .route("/ledger_balances", get(ledger_balances))
+        .route("/share_balances", get(share_balances))
+        .route("/sync_status", get(get_sync_status))
+        .route("/reachability", get(get_reachability))
+        .route("/drain", post(drain))
         .layer(middleware::from_fn_with_state(
             app_state.clone(),
             auth_middleware,
@@ -148,3 +166,61 @@ async fn pplns_shares(
 
     Ok(Json(shares))
 }
+
+/// Outstanding payout ledger balances in satoshis, keyed by btcaddress.
+/// Negative balances were paid ahead of the address's entitlement.
+async fn ledger_balances(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<HashMap<String, i64>>, ApiError> {
+    let balances = state
+        .chain_store
+        .get_ledger_balances()
+        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+
+    Ok(Json(balances))
+}
+
+/// Share chain ledger balances at the chain tip in satoshis, keyed by
+/// btcaddress. Every node on the share chain computes the same balances.
+async fn share_balances(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<HashMap<String, i64>>, ApiError> {
+    let balances = state
+        .chain_store
+        .get_tip_share_balances()
+        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+
+    Ok(Json(balances))
+}
+
+/// Share chain sync state of the node
+async fn get_sync_status(State(state): State<Arc<AppState>>) -> Json<SyncStatus> {
+    Json(state.sync_status.get())
+}
+
+/// Whether peers can reach the node, and the addresses it listens on
+async fn get_reachability(State(state): State<Arc<AppState>>) -> Json<Reachability> {
+    Json(state.reachability.get())
+}
+
+/// Start draining stratum connections and shut the node down once done.
+/// Miners are sent client.reconnect with the optional host, port and wait
+/// from the request body.
+///
+/// Draining stops the node, so it is refused unless API authentication
+/// is configured.
+async fn drain(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<DrainRequest>,
+) -> Result<StatusCode, ApiError> {
+    if state.auth_user.is_none() || state.auth_token.is_none() {
+        return Err(ApiError::Forbidden(
+            "Draining needs API authentication to be configured".into(),
+        ));
+    }
+    request.validate().map_err(ApiError::BadRequest)?;
+    match state.drain_tx.try_send(request) {
+        Ok(()) => Ok(StatusCode::ACCEPTED),
+        Err(_) => Err(ApiError::Conflict("Node is already draining".into())),
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use p2poolv2_lib::config::ApiConfig;
+use p2poolv2_lib::stratum::drain::DrainRequest;
+use std::error::Error;
+
+/// Execute the drain command.
+///
+/// Asks the node's API to send client.reconnect to all stratum clients and
+/// shut the node down once they have disconnected. The node only drains
+/// when API authentication is configured, the password is prompted for
+/// unless provided.
+pub fn execute(
+    api_config: &ApiConfig,
+    request: &DrainRequest,
+    password: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    request.validate()?;
+
+    let url = format!("http://{}:{}/drain", api_config.hostname, api_config.port);
+    let mut http_request = reqwest::blocking::Client::new().post(&url).json(request);
+    if let Some(username) = &api_config.auth_user {
+        let password = match password {
+            None => rpassword::prompt_password("Enter API password: ")?,
+            Some(ref p) if p == "-" => rpassword::prompt_password("Enter API password: ")?,
+            Some(p) => p,
+        };
+        http_request = http_request.basic_auth(username, Some(password));
+    }
+
+    let response = http_request.send()?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().unwrap_or_default();
+        return Err(format!("Drain request failed with {status}: {body}").into());
+    }
+
+    println!("Node is draining stratum connections and will shut down when done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Accept a single HTTP request and answer with the given status line.
+    /// Returns the port to connect to and a handle yielding the raw request.
+    fn serve_once(status: &'static str) -> (u16, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            // Read until the end of the JSON body
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\n\r\n").as_bytes())
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (port, handle)
+    }
+
+    fn api_config(port: u16, auth_user: Option<String>) -> ApiConfig {
+        ApiConfig {
+            hostname: "127.0.0.1".into(),
+            port,
+            auth_user,
+            auth_token: None,
+        }
+    }
+
+    #[test]
+    fn test_drain_posts_request_with_basic_auth() {
+        let (port, server) = serve_once("202 Accepted");
+        let request = DrainRequest {
+            host: Some("pool.example.com".into()),
+            port: Some(3333),
+            ..Default::default()
+        };
+
+        execute(
+            &api_config(port, Some("admin".into())),
+            &request,
+            Some("secret".into()),
+        )
+        .unwrap();
+
+        let received = server.join().unwrap();
+        assert!(received.starts_with("POST /drain HTTP/1.1"));
+        // base64 of admin:secret
+        assert!(received.contains("authorization: Basic YWRtaW46c2VjcmV0"));
+        assert!(received.contains(r#""host":"pool.example.com","port":3333"#));
+    }
+
+    #[test]
+    fn test_drain_reports_rejected_request() {
+        let (port, server) = serve_once("409 Conflict");
+
+        let result = execute(&api_config(port, None), &DrainRequest::default(), None);
+        server.join().unwrap();
+        assert!(result.unwrap_err().to_string().contains("409"));
+    }
+
+    #[test]
+    fn test_drain_rejects_invalid_request() {
+        let request = DrainRequest {
+            port: Some(3333),
+            ..Default::default()
+        };
+        assert!(execute(&api_config(1, None), &request, None).is_err());
+    }
+}
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+pub mod drain;
 pub mod gen_auth;
 
 use clap::{Parser, Subcommand};
@@ -21,6 +22,7 @@ use p2poolv2_lib::cli_commands;
 use p2poolv2_lib::config::Config;
 use p2poolv2_lib::shares::chain::chain_store::ChainStore;
 use p2poolv2_lib::shares::share_block::ShareBlock;
+use p2poolv2_lib::stratum::drain::DrainRequest;
 use std::error::Error;
 use std::sync::Arc;
 
@@ -53,6 +55,10 @@ pub enum Commands {
         #[arg(short, long)]
         end_time: Option<u64>,
     },
+    /// List outstanding payout ledger balances carried forward for miners
+    LedgerBalances,
+    /// List share chain ledger balances at the chain tip
+    ShareBalances,
     /// Generate API authentication credentials (salt, password, HMAC)
     GenAuth {
         /// Username for API authentication
@@ -60,6 +66,30 @@ pub enum Commands {
         /// Password (leave empty to auto-generate, or use "-" to prompt)
         password: Option<String>,
     },
+    /// Ask a running node to move stratum clients away and shut down
+    Drain {
+        /// Host miners should reconnect to, defaults to this node
+        #[arg(long)]
+        host: Option<String>,
+        /// Port miners should reconnect to, requires host
+        #[arg(long)]
+        port: Option<u16>,
+        /// Seconds miners should wait before reconnecting, requires port
+        #[arg(long)]
+        wait: Option<u64>,
+        /// Number of clients sent client.reconnect at a time
+        #[arg(long)]
+        batch_size: Option<usize>,
+        /// Milliseconds between batches
+        #[arg(long)]
+        batch_interval_ms: Option<u64>,
+        /// Seconds to wait for clients to disconnect before closing them
+        #[arg(long)]
+        timeout_secs: Option<u64>,
+        /// API password (prompted for if the API requires auth, use "-" to prompt)
+        #[arg(long)]
+        password: Option<String>,
+    },
 }
 
 pub fn run() -> Result<(), Box<dyn Error>> {
@@ -74,7 +104,35 @@ pub fn run() -> Result<(), Box<dyn Error>> {
             // gen-auth doesn't need config or store
             crate::commands::gen_auth::execute(username.clone(), password.clone())?;
         }
-        Some(Commands::Info) | Some(Commands::PplnsShares { .. }) => {
+        Some(Commands::Drain {
+            host,
+            port,
+            wait,
+            batch_size,
+            batch_interval_ms,
+            timeout_secs,
+            password,
+        }) => {
+            // drain talks to the running node's API instead of the store
+            let config_path = cli
+                .config
+                .as_ref()
+                .ok_or("Config file required for this command. Use --config")?;
+            let config = Config::load(config_path)?;
+            let request = DrainRequest {
+                host: host.clone(),
+                port: *port,
+                wait: *wait,
+                batch_size: *batch_size,
+                batch_interval_ms: *batch_interval_ms,
+                timeout_secs: *timeout_secs,
+            };
+            crate::commands::drain::execute(&config.api, &request, password.clone())?;
+        }
+        Some(Commands::Info)
+        | Some(Commands::PplnsShares { .. })
+        | Some(Commands::LedgerBalances)
+        | Some(Commands::ShareBalances) => {
             // These commands require config and store
             let config_path = cli
                 .config
@@ -106,6 +164,12 @@ pub fn run() -> Result<(), Box<dyn Error>> {
                 }) => {
                     cli_commands::pplns_shares::execute(chain, *limit, *start_time, *end_time)?;
                 }
+                Some(Commands::LedgerBalances) => {
+                    cli_commands::ledger_balances::execute(chain)?;
+                }
+                Some(Commands::ShareBalances) => {
+                    cli_commands::share_balances::execute(chain)?;
+                }
                 _ => unreachable!(),
             }
         }
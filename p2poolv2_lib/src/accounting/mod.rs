@@ -24,3 +24,13 @@ pub struct OutputPair {
     pub address: bitcoin::Address,
     pub amount: bitcoin::Amount,
 }
+
+/// Change to an address balance in the payout ledger, in satoshis.
+///
+/// Positive deltas carry forward entitlement that was not paid in the
+/// coinbase, negative deltas settle previously carried balance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerUpdate {
+    pub address: String,
+    pub delta: i64,
+}
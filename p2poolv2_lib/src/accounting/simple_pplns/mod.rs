@@ -18,6 +18,7 @@ use bitcoin::consensus::{Decodable, Encodable};
 use serde::{Deserialize, Serialize};
 
 pub mod payout;
+pub mod share_chain_payout;
 
 /// PPLNS share representation
 ///
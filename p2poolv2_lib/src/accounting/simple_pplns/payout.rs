@@ -14,8 +14,8 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::accounting::OutputPair;
 use crate::accounting::simple_pplns::SimplePplnsShare;
+use crate::accounting::{LedgerUpdate, OutputPair};
 use crate::config::StratumConfig;
 #[cfg(test)]
 #[mockall_double::double]
@@ -23,11 +23,130 @@ use crate::shares::chain::chain_store::ChainStore;
 #[cfg(not(test))]
 use crate::shares::chain::chain_store::ChainStore;
 use bitcoin::{Address, Amount};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Basis points in a whole, used for cuts and uncle weights
+const BASIS_POINT_FACTOR: u64 = 10_000; // 100 * 100
+
+/// Weight of uncle shares in PPLNS payouts, in basis points of their work.
+///
+/// This is a protocol constant, all nodes must credit uncles the same
+/// way for share chain payouts to validate across the network.
+pub const UNCLE_WEIGHT: u16 = 5_000;
+
+/// Work credited to a share in PPLNS payouts
+///
+/// Shares on the main chain are credited with their full work and
+/// uncles with UNCLE_WEIGHT basis points of their work.
+pub fn weighted_work(work: u128, is_uncle: bool) -> u128 {
+    if is_uncle {
+        work.saturating_mul(UNCLE_WEIGHT as u128) / BASIS_POINT_FACTOR as u128
+    } else {
+        work
+    }
+}
+
+/// Pay the top max_outputs keys owed at least min_output, carrying the
+/// rest in the ledger.
+///
+/// A key is owed its entitlement plus its ledger balance. Keys that
+/// are not paid carry their entitlement forward as a positive balance.
+/// When the paid keys are owed more than the amount distributed, they
+/// are settled highest owed first until the amount runs out. When they
+/// are owed less, the carried entitlements are advanced to them in
+/// proportion to what they are owed, leaving negative balances that
+/// are taken from their later entitlements. The balance changes always
+/// add up to zero, so carried amounts are only ever paid to miners.
+///
+/// Returns the payouts, highest owed first, and the non zero balance
+/// changes to apply if the coinbase makes it into a block.
+pub(crate) fn settle<K: Ord + Clone>(
+    entitlements: &BTreeMap<K, Amount>,
+    balances: &BTreeMap<K, i64>,
+    max_outputs: usize,
+    min_output: Amount,
+) -> (Vec<(K, Amount)>, BTreeMap<K, i64>) {
+    let total: u64 = entitlements.values().map(|amount| amount.to_sat()).sum();
+    let mut owed: BTreeMap<&K, i128> = BTreeMap::new();
+    for (key, amount) in entitlements {
+        *owed.entry(key).or_insert(0) += amount.to_sat() as i128;
+    }
+    for (key, balance) in balances {
+        *owed.entry(key).or_insert(0) += *balance as i128;
+    }
+
+    // Highest owed first, ties broken by key so every node ranks alike
+    let mut ranked: Vec<(&K, i128)> = owed.into_iter().collect();
+    ranked.sort_by(|(a_key, a_owed), (b_key, b_owed)| {
+        b_owed.cmp(a_owed).then_with(|| a_key.cmp(b_key))
+    });
+    let mut payees: Vec<(&K, u128)> = ranked
+        .iter()
+        .filter(|(_, owed)| *owed >= min_output.to_sat() as i128)
+        .take(max_outputs)
+        .map(|(key, owed)| (*key, *owed as u128))
+        .collect();
+    if payees.is_empty() && total > 0 {
+        // Nobody is owed enough, rather than leave the amount unpaid
+        // advance it to the highest owed
+        payees.push((ranked[0].0, ranked[0].1.max(0) as u128));
+    }
+
+    let owed_total: u128 = payees.iter().map(|(_, owed)| owed).sum();
+    let mut paid: Vec<(K, u64)> = Vec::with_capacity(payees.len());
+    if owed_total >= total as u128 {
+        let mut remaining = total;
+        for (key, owed) in payees {
+            let amount = owed.min(remaining as u128) as u64;
+            if amount == 0 {
+                break;
+            }
+            remaining -= amount;
+            paid.push((key.clone(), amount));
+        }
+        // The last payee may only get a part of what it is owed
+        if paid.len() > 1
+            && paid
+                .last()
+                .is_some_and(|(_, amount)| *amount < min_output.to_sat())
+        {
+            let (_, amount) = paid.pop().unwrap();
+            paid[0].1 += amount;
+        }
+    } else {
+        let mut distributed = 0u64;
+        let last = payees.len() - 1;
+        for (i, (key, owed)) in payees.into_iter().enumerate() {
+            let amount = if i == last {
+                total - distributed
+            } else {
+                (owed * total as u128).checked_div(owed_total).unwrap_or(0) as u64
+            };
+            distributed += amount;
+            paid.push((key.clone(), amount));
+        }
+    }
+    paid.retain(|(_, amount)| *amount > 0);
+
+    let mut deltas: BTreeMap<K, i64> = entitlements
+        .iter()
+        .map(|(key, amount)| (key.clone(), amount.to_sat() as i64))
+        .collect();
+    for (key, amount) in &paid {
+        *deltas.entry(key.clone()).or_insert(0) -= *amount as i64;
+    }
+    deltas.retain(|_, delta| *delta != 0);
+
+    let paid = paid
+        .into_iter()
+        .map(|(key, amount)| (key, Amount::from_sat(amount)))
+        .collect();
+    (paid, deltas)
+}
+
 pub struct Payout {
     /// Step size in seconds for batch querying shares from storage.
     /// This determines how far back in time to query in each batch.
@@ -114,23 +233,29 @@ impl Payout {
     /// * `total_amount` - Total bitcoin amount to distribute among contributors
     ///
     /// # Returns
-    /// Vector of OutputPair containing addresses and their proportional amounts
+    /// Vector of OutputPair containing addresses and their proportional amounts,
+    /// along with the ledger updates to apply if the coinbase makes it into a block.
+    /// Only the top max_coinbase_outputs miners owed at least min_coinbase_output
+    /// are paid in the coinbase, see settle_outputs.
     pub async fn get_output_distribution(
         &self,
         store: &Arc<ChainStore>,
         total_difficulty: f64,
         total_amount: bitcoin::Amount,
         config: &StratumConfig<crate::config::Parsed>,
-    ) -> Result<Vec<OutputPair>, Box<dyn Error + Send + Sync>> {
+    ) -> Result<(Vec<OutputPair>, Vec<LedgerUpdate>), Box<dyn Error + Send + Sync>> {
         let shares = self
             .get_shares_for_difficulty(store, total_difficulty)
             .await?;
 
         if shares.is_empty() {
-            return Ok(vec![OutputPair {
-                address: config.bootstrap_address().clone(),
-                amount: total_amount,
-            }]);
+            return Ok((
+                vec![OutputPair {
+                    address: config.bootstrap_address().clone(),
+                    amount: total_amount,
+                }],
+                Vec::new(),
+            ));
         }
 
         // Extra two places for potential cuts
@@ -149,13 +274,66 @@ impl Payout {
         );
 
         let address_difficulty_map = Self::group_shares_by_address(&shares);
+        let mut miner_outputs = Vec::with_capacity(address_difficulty_map.len());
         Self::append_proportional_distribution(
             address_difficulty_map,
             remaining_total_amount,
-            &mut distribution,
+            &mut miner_outputs,
         )?;
 
-        Ok(distribution)
+        let balances = store.get_ledger_balances()?;
+        let (miner_outputs, ledger_updates) = Self::settle_outputs(
+            miner_outputs,
+            &balances,
+            config.max_coinbase_outputs,
+            Amount::from_sat(config.min_coinbase_output),
+        )?;
+        distribution.extend(miner_outputs);
+
+        Ok((distribution, ledger_updates))
+    }
+
+    /// Limit miner outputs to the top max_outputs addresses owed at least
+    /// min_output, see [settle]. Ledger balances are keyed by address
+    /// string, so addresses only in the ledger are parsed to be paid.
+    fn settle_outputs(
+        miner_outputs: Vec<OutputPair>,
+        balances: &HashMap<String, i64>,
+        max_outputs: usize,
+        min_output: Amount,
+    ) -> Result<(Vec<OutputPair>, Vec<LedgerUpdate>), Box<dyn Error + Send + Sync>> {
+        let mut addresses: HashMap<String, Address> = HashMap::with_capacity(miner_outputs.len());
+        let mut entitlements: BTreeMap<String, Amount> = BTreeMap::new();
+        for output in miner_outputs {
+            let address_str = output.address.to_string();
+            *entitlements
+                .entry(address_str.clone())
+                .or_insert(Amount::ZERO) += output.amount;
+            addresses.insert(address_str, output.address);
+        }
+        let balances: BTreeMap<String, i64> = balances
+            .iter()
+            .map(|(address_str, balance)| (address_str.clone(), *balance))
+            .collect();
+
+        let (paid, deltas) = settle(&entitlements, &balances, max_outputs, min_output);
+
+        let mut distribution = Vec::with_capacity(paid.len());
+        for (address_str, amount) in paid {
+            let address = match addresses.remove(&address_str) {
+                Some(address) => address,
+                None => address_str
+                    .parse::<bitcoin::Address<_>>()
+                    .map_err(|e| format!("Invalid bitcoin address '{address_str}': {e}"))?
+                    .assume_checked(),
+            };
+            distribution.push(OutputPair { address, amount });
+        }
+        let ledger_updates = deltas
+            .into_iter()
+            .map(|(address, delta)| LedgerUpdate { address, delta })
+            .collect();
+        Ok((distribution, ledger_updates))
     }
 
     /// Appends new output pair to distribution and returns remaining amount
@@ -165,7 +343,6 @@ impl Payout {
         address: &Option<Address>,
         cut: Option<u16>, // in basis points
     ) -> Amount {
-        const BASIS_POINT_FACTOR: u64 = 10_000; // 100 * 100
         if let (Some(addr), Some(cut_bp)) = (address.as_ref(), cut.filter(|c| *c > 0)) {
             if let Some(amount) = total_amount.checked_mul(cut_bp.into()) {
                 if let Some(div_amount) = amount.checked_div(BASIS_POINT_FACTOR) {
@@ -580,6 +757,13 @@ mod tests {
         assert_eq!(total, 600);
     }
 
+    #[test]
+    fn test_weighted_work() {
+        assert_eq!(weighted_work(1000, false), 1000);
+        assert_eq!(weighted_work(1000, true), 500);
+        assert_eq!(weighted_work(u128::MAX, true), u128::MAX / 10_000);
+    }
+
     #[tokio::test]
     async fn test_payout_constructors() {
         let payout1 = Payout::new(3600);
@@ -610,12 +794,15 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let total_amount = bitcoin::Amount::from_sat(50_000_000); // 0.5 BTC
 
         let stratum_config = StratumConfig::new_for_test_default().parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -664,12 +851,15 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let total_amount = bitcoin::Amount::from_sat(100_000_000); // 1.0 BTC
 
         let stratum_config = StratumConfig::new_for_test_default().parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -745,12 +935,15 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let total_amount = bitcoin::Amount::from_sat(100_000_000); // 1.0 BTC
 
         let stratum_config = StratumConfig::new_for_test_default().parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -781,7 +974,7 @@ mod tests {
 
         let stratum_config = StratumConfig::new_for_test_default().parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -896,6 +1089,9 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let total_amount = bitcoin::Amount::from_sat(100_000_000); // 1.0 BTC
 
@@ -906,7 +1102,7 @@ mod tests {
         stratum_config.donation = Some(500); // 5%
         let stratum_config = stratum_config.parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -985,6 +1181,9 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let total_amount = bitcoin::Amount::from_sat(100_000_000); // 1.0 BTC
 
@@ -995,7 +1194,7 @@ mod tests {
         stratum_config.fee = Some(200); // 2%
         let stratum_config = stratum_config.parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -1077,6 +1276,9 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let total_amount = bitcoin::Amount::from_sat(100_000_000); // 1.0 BTC
 
@@ -1090,7 +1292,7 @@ mod tests {
         stratum_config.fee = Some(200); // 2%
         let stratum_config = stratum_config.parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -1167,7 +1369,7 @@ mod tests {
         stratum_config.donation = Some(500); // 5%
         let stratum_config = stratum_config.parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -1214,6 +1416,9 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let total_amount = bitcoin::Amount::from_sat(100_000_000); // 1.0 BTC
 
@@ -1224,7 +1429,7 @@ mod tests {
         stratum_config.donation = Some(0); // 0% - should not create output
         let stratum_config = stratum_config.parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -1300,6 +1505,9 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let total_amount = bitcoin::Amount::from_sat(100_000_000); // 1.0 BTC
 
@@ -1310,7 +1518,7 @@ mod tests {
         stratum_config.fee = Some(0); // 0% - should not create output
         let stratum_config = stratum_config.parse().unwrap();
 
-        let result = payout
+        let (result, _) = payout
             .get_output_distribution(&Arc::new(store), 1000.0, total_amount, &stratum_config)
             .await
             .unwrap();
@@ -1326,4 +1534,178 @@ mod tests {
         let total_distributed: bitcoin::Amount = result.iter().map(|op| op.amount).sum();
         assert_eq!(total_distributed, total_amount);
     }
+
+    fn output(address: &str, sats: u64) -> OutputPair {
+        OutputPair {
+            address: address
+                .parse::<bitcoin::Address<_>>()
+                .unwrap()
+                .assume_checked(),
+            amount: Amount::from_sat(sats),
+        }
+    }
+
+    fn amount_for(distribution: &[OutputPair], address: &str) -> Option<u64> {
+        distribution
+            .iter()
+            .find(|op| op.address.to_string() == address)
+            .map(|op| op.amount.to_sat())
+    }
+
+    #[test]
+    fn test_settle_outputs_limits_outputs() {
+        let miner_outputs = vec![
+            output("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", 60_000),
+            output("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", 30_000),
+            output("bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh", 10_000),
+        ];
+
+        let (distribution, ledger_updates) =
+            Payout::settle_outputs(miner_outputs, &HashMap::new(), 2, Amount::from_sat(546))
+                .unwrap();
+
+        // Top two are paid ahead with the third miner's entitlement, which is carried
+        assert_eq!(distribution.len(), 2);
+        assert_eq!(
+            amount_for(&distribution, "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"),
+            Some(66_666)
+        );
+        assert_eq!(
+            amount_for(&distribution, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            Some(33_334)
+        );
+        assert_eq!(
+            ledger_updates,
+            vec![
+                LedgerUpdate {
+                    address: "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq".to_string(),
+                    delta: -6_666,
+                },
+                LedgerUpdate {
+                    address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+                    delta: -3_334,
+                },
+                LedgerUpdate {
+                    address: "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh".to_string(),
+                    delta: 10_000,
+                },
+            ]
+        );
+        assert_eq!(
+            ledger_updates
+                .iter()
+                .map(|update| update.delta)
+                .sum::<i64>(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_settle_outputs_settles_balances() {
+        let miner_outputs = vec![
+            output("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", 60_000),
+            output("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4", 30_000),
+            output("bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh", 200),
+        ];
+        let balances = HashMap::from([
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+                5_000,
+            ),
+            ("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy".to_string(), 1_000),
+        ]);
+
+        let (distribution, ledger_updates) =
+            Payout::settle_outputs(miner_outputs, &balances, 2, Amount::from_sat(546)).unwrap();
+
+        // Dust output is carried and settles part of the second miner's balance
+        assert_eq!(distribution.len(), 2);
+        assert_eq!(
+            amount_for(&distribution, "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"),
+            Some(60_000)
+        );
+        assert_eq!(
+            amount_for(&distribution, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            Some(30_200)
+        );
+        assert_eq!(
+            ledger_updates,
+            vec![
+                LedgerUpdate {
+                    address: "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string(),
+                    delta: -200,
+                },
+                LedgerUpdate {
+                    address: "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh".to_string(),
+                    delta: 200,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settle_outputs_takes_advances_from_entitlement() {
+        let miner_outputs = vec![
+            output("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", 60_000),
+            output("bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh", 40_000),
+        ];
+        let balances = HashMap::from([
+            (
+                "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq".to_string(),
+                -6_666,
+            ),
+            (
+                "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh".to_string(),
+                6_666,
+            ),
+        ]);
+
+        let (distribution, ledger_updates) =
+            Payout::settle_outputs(miner_outputs, &balances, 2, Amount::from_sat(546)).unwrap();
+
+        // The miner paid ahead earlier gets less, settling the carried balance
+        assert_eq!(distribution.len(), 2);
+        assert_eq!(
+            amount_for(&distribution, "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"),
+            Some(53_334)
+        );
+        assert_eq!(
+            amount_for(&distribution, "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh"),
+            Some(46_666)
+        );
+        assert_eq!(
+            ledger_updates,
+            vec![
+                LedgerUpdate {
+                    address: "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq".to_string(),
+                    delta: 6_666,
+                },
+                LedgerUpdate {
+                    address: "bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh".to_string(),
+                    delta: -6_666,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settle_outputs_pays_only_miner() {
+        let miner_outputs = vec![output("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", 100)];
+        let balances = HashMap::from([(
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq".to_string(),
+            500,
+        )]);
+
+        let (distribution, ledger_updates) =
+            Payout::settle_outputs(miner_outputs, &balances, 1, Amount::from_sat(546)).unwrap();
+
+        // Owed more than dust, but only the entitlement is available to pay
+        assert_eq!(distribution.len(), 1);
+        assert_eq!(
+            distribution[0].address.to_string(),
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq"
+        );
+        assert_eq!(distribution[0].amount, Amount::from_sat(100));
+        assert!(ledger_updates.is_empty());
+    }
 }
@@ -0,0 +1,703 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! PPLNS payout computed from the share chain.
+//!
+//! Every node holding the same share chain computes the same output
+//! distribution, so the coinbase of a share received from a peer can
+//! be checked against it. Miners that do not fit in the coinbase have
+//! their entitlement carried in a ledger that is also derived from the
+//! share chain, see [get_balances]. Ledger balances are stored for each
+//! share when it is added to the chain, see [store_balances].
+
+use crate::accounting::OutputPair;
+use crate::accounting::simple_pplns::payout::{settle, weighted_work};
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::chain::retarget::{RETARGET_WINDOW, TARGET_SHARE_SPACING_SECS, work_to_u128};
+use crate::shares::share_block::{ShareBlock, ShareHeader};
+use crate::shares::share_commitment::CoinbaseCut;
+use bitcoin::hashes::Hash;
+use bitcoin::{Address, Amount, BlockHash, CompactTarget, CompressedPublicKey, Network, Target};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Weights are scaled down to this many bits before computing amounts,
+/// so that multiplying by the total amount can not overflow u128.
+const MAX_WEIGHT_BITS: u32 = 64;
+
+/// Maximum number of miner outputs in a share chain coinbase, the rest
+/// is carried in the share chain ledger
+pub const MAX_COINBASE_OUTPUTS: usize = 100;
+
+/// Minimum miner output in a share chain coinbase, smaller amounts are
+/// carried in the share chain ledger
+pub const MIN_COINBASE_OUTPUT: Amount = Amount::from_sat(546);
+
+/// Share chain ledger balances in satoshis, keyed by miner pubkey.
+/// Positive balances are owed to the miner, negative balances were
+/// paid ahead and are taken from later entitlements.
+pub type MinerBalances = BTreeMap<CompressedPublicKey, i64>;
+
+/// Payouts for a share and the ledger balance changes they make
+type Settlement = (Vec<(CompressedPublicKey, Amount)>, MinerBalances);
+
+/// Number of shares at the share target the share chain PPLNS window
+/// covers, a day of shares at TARGET_SHARE_SPACING_SECS. This is a
+/// protocol constant, unlike the stratum difficulty_multiplier, so that
+/// every node computes the same window.
+pub const WINDOW_SHARES: u128 = (24 * 60 * 60 / TARGET_SHARE_SPACING_SECS) as u128;
+
+/// Work window PPLNS pays over, given the share bits of the share
+/// being paid for.
+///
+/// Share bits are checked against the share chain target at the parent
+/// when a share is validated. The bitcoin bits in the share's bitcoin
+/// header are set by the miner and not checked, so they are not used.
+pub fn window_work(share_bits: CompactTarget) -> u128 {
+    work_to_u128(Target::from_compact(share_bits).to_work()).saturating_mul(WINDOW_SHARES)
+}
+
+/// Credit work to miners walking back from a share, nearest first.
+///
+/// Each share on the chain credits its miner with its work and each
+/// uncle it includes credits the uncle's miner with the UNCLE_WEIGHT
+/// share of the uncle's work. Shares are included whole until the credited
+/// work reaches window_work or genesis is reached.
+///
+/// Miners are keyed by pubkey in a BTreeMap so the iteration order is
+/// the same on every node.
+pub fn get_miner_weights(
+    store: &Arc<ChainStore>,
+    tip: &BlockHash,
+    window_work: u128,
+) -> Result<BTreeMap<CompressedPublicKey, u128>, Box<dyn Error + Send + Sync>> {
+    let mut weights = BTreeMap::new();
+    let mut accumulated_work = 0u128;
+    let mut current = *tip;
+    while accumulated_work < window_work && current != BlockHash::all_zeros() {
+        let header = store
+            .get_share_header(&current)
+            .ok_or_else(|| format!("Share {current} not found for payout"))?;
+        let work = weighted_work(work_to_u128(header.get_work()), false);
+        *weights.entry(header.miner_pubkey).or_insert(0u128) += work;
+        accumulated_work = accumulated_work.saturating_add(work);
+
+        for uncle in &header.uncles {
+            let uncle_header = store
+                .get_share_header(uncle)
+                .ok_or_else(|| format!("Uncle {uncle} not found for payout"))?;
+            let work = weighted_work(work_to_u128(uncle_header.get_work()), true);
+            *weights.entry(uncle_header.miner_pubkey).or_insert(0u128) += work;
+            accumulated_work = accumulated_work.saturating_add(work);
+        }
+        current = header.prev_share_blockhash;
+    }
+    Ok(weights)
+}
+
+/// Generate the output distribution for a share built on top of tip.
+///
+/// The donation and fee cuts committed in the share are paid first, as
+/// in the local PPLNS payout, see [take_cuts]. The rest is split between
+/// miners in proportion to the work credited to them. Only the top
+/// MAX_COINBASE_OUTPUTS miners owed at least MIN_COINBASE_OUTPUT are
+/// paid, taking the share chain ledger balances at tip into account,
+/// see [settle].
+///
+/// # Arguments
+/// * `store` - Handle to the chain store to walk share headers from
+/// * `tip` - The share the new share builds on
+/// * `miner_pubkey` - Miner of the new share, entitled to everything when no work is credited
+/// * `window_work` - Work to accumulate walking back from tip, see [window_work]
+/// * `total_amount` - Total bitcoin amount to distribute
+/// * `cuts` - Coinbase cuts committed in the share, donation first
+/// * `network` - Bitcoin network to build output addresses for
+pub fn get_output_distribution(
+    store: &Arc<ChainStore>,
+    tip: &BlockHash,
+    miner_pubkey: &CompressedPublicKey,
+    window_work: u128,
+    total_amount: Amount,
+    cuts: &[&CoinbaseCut],
+    network: Network,
+) -> Result<Vec<OutputPair>, Box<dyn Error + Send + Sync>> {
+    let (cut_amounts, miners_amount) = take_cuts(cuts, total_amount);
+    let mut distribution = Vec::with_capacity(cuts.len());
+    for (cut, amount) in cuts.iter().zip(cut_amounts) {
+        if amount == Amount::ZERO {
+            continue;
+        }
+        let address = Address::from_script(&cut.script_pubkey, network)
+            .map_err(|e| format!("Coinbase cut script is not an address: {e}"))?;
+        distribution.push(OutputPair { address, amount });
+    }
+
+    let balances = get_balances(store, tip)?;
+    let (paid, _) = settle_share(
+        store,
+        tip,
+        miner_pubkey,
+        window_work,
+        miners_amount,
+        &balances,
+    )?;
+    distribution.extend(paid.into_iter().map(|(pubkey, amount)| OutputPair {
+        address: Address::p2pkh(pubkey, network),
+        amount,
+    }));
+    Ok(distribution)
+}
+
+/// Take the coinbase cuts from total in order, each from the amount
+/// left by the earlier cuts, and return the cut amounts and the amount
+/// left for miners.
+pub fn take_cuts(cuts: &[&CoinbaseCut], total_amount: Amount) -> (Vec<Amount>, Amount) {
+    let mut remaining = total_amount;
+    let amounts = cuts
+        .iter()
+        .map(|cut| {
+            let amount = cut.amount(remaining);
+            remaining -= amount;
+            amount
+        })
+        .collect();
+    (amounts, remaining)
+}
+
+/// Number of shares ending at a share's parent whose bitcoin targets
+/// the share's bitcoin target is checked against, see [found_bitcoin_block].
+pub const BITCOIN_TARGET_WINDOW: usize = RETARGET_WINDOW;
+
+/// Bitcoin difficulty changes by at most this factor at a retarget
+const BITCOIN_RETARGET_STEP: u128 = 4;
+
+/// Check if a share found a bitcoin block.
+///
+/// The bitcoin bits in a share are set by its miner and are not
+/// checked, so the share's bitcoin target is only trusted up to the
+/// bitcoin retarget step from the median bitcoin target of the
+/// BITCOIN_TARGET_WINDOW shares ending at the parent. Faking a found
+/// block with easy bits then takes at least a quarter of the work of a
+/// real block, and moving the median takes most of the recent shares.
+/// Only share headers are read.
+pub fn found_bitcoin_block(store: &Arc<ChainStore>, header: &ShareHeader) -> bool {
+    let target = header.bitcoin_header.target();
+    if header.bitcoin_header.validate_pow(target).is_err() {
+        return false;
+    }
+    let mut works: Vec<u128> = store
+        .get_ancestor_headers(&header.prev_share_blockhash, BITCOIN_TARGET_WINDOW)
+        .iter()
+        .map(|ancestor| work_to_u128(ancestor.bitcoin_header.work()))
+        .collect();
+    if works.is_empty() {
+        return false;
+    }
+    works.sort_unstable();
+    work_to_u128(target.to_work()).saturating_mul(BITCOIN_RETARGET_STEP) >= works[works.len() / 2]
+}
+
+/// Share chain ledger balances after the shares up to and including tip.
+///
+/// Balances only change with shares that find a bitcoin block, as only
+/// their coinbase pays out. Balances are stored for each share as it is
+/// added, so they are usually read for tip directly. Otherwise the walk
+/// back from tip stops at the nearest share with stored balances, and
+/// the shares on the way that found a block are settled oldest first.
+/// Nothing is stored, so this is safe to call while validating.
+/// Uncles that find a block are not on the chain and do not change
+/// balances.
+pub fn get_balances(
+    store: &Arc<ChainStore>,
+    tip: &BlockHash,
+) -> Result<MinerBalances, Box<dyn Error + Send + Sync>> {
+    let mut balances = MinerBalances::new();
+    let mut found_blocks = Vec::new();
+    let mut current = *tip;
+    while current != BlockHash::all_zeros() {
+        if let Some(stored) = store.get_share_balances(&current)? {
+            balances = stored;
+            break;
+        }
+        let header = store
+            .get_share_header(&current)
+            .ok_or_else(|| format!("Share {current} not found for ledger"))?;
+        if found_bitcoin_block(store, &header) {
+            found_blocks.push(current);
+        }
+        current = header.prev_share_blockhash;
+    }
+
+    // Settling needs the coinbase value, so only these need the full share
+    for blockhash in found_blocks.into_iter().rev() {
+        let share = store
+            .get_share(&blockhash)
+            .ok_or_else(|| format!("Share {blockhash} not found for ledger"))?;
+        settle_found_block(store, &share, &mut balances)?;
+    }
+    Ok(balances)
+}
+
+/// Compute and store the ledger balances after a share that has been
+/// added to the chain.
+///
+/// Called when a share is added, never during validation, so shares
+/// that fail validation do not change stored balances.
+pub fn store_balances(
+    store: &Arc<ChainStore>,
+    share: &ShareBlock,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut balances = get_balances(store, &share.header.prev_share_blockhash)?;
+    if found_bitcoin_block(store, &share.header) {
+        settle_found_block(store, share, &mut balances)?;
+    }
+    store.put_share_balances(&share.block_hash(), &balances)
+}
+
+/// Apply the ledger balance changes made by the coinbase of a share
+/// that found a bitcoin block.
+fn settle_found_block(
+    store: &Arc<ChainStore>,
+    share: &ShareBlock,
+    balances: &mut MinerBalances,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let total_amount = share
+        .bitcoin_transactions
+        .first()
+        .map(|coinbase| coinbase.output.iter().map(|output| output.value).sum())
+        .unwrap_or(Amount::ZERO);
+    let (_, miners_amount) = take_cuts(&share.header.coinbase_cuts(), total_amount);
+    let (_, deltas) = settle_share(
+        store,
+        &share.header.prev_share_blockhash,
+        &share.header.miner_pubkey,
+        window_work(share.header.bits),
+        miners_amount,
+        balances,
+    )?;
+    for (pubkey, delta) in deltas {
+        let balance = balances.entry(pubkey).or_insert(0);
+        *balance += delta;
+        if *balance == 0 {
+            balances.remove(&pubkey);
+        }
+    }
+    Ok(())
+}
+
+/// Split the amount for a share built on tip between miners by work
+/// and settle it against the ledger balances, returning the payouts
+/// and balance changes.
+///
+/// Amounts are computed with integer arithmetic and the last miner in
+/// pubkey order gets the rounding remainder.
+fn settle_share(
+    store: &Arc<ChainStore>,
+    tip: &BlockHash,
+    miner_pubkey: &CompressedPublicKey,
+    window_work: u128,
+    total_amount: Amount,
+    balances: &MinerBalances,
+) -> Result<Settlement, Box<dyn Error + Send + Sync>> {
+    let weights = get_miner_weights(store, tip, window_work)?;
+    let total_weight: u128 = weights.values().sum();
+    let mut entitlements = BTreeMap::new();
+    if total_weight == 0 {
+        entitlements.insert(*miner_pubkey, total_amount);
+    } else {
+        let shift = (u128::BITS - total_weight.leading_zeros()).saturating_sub(MAX_WEIGHT_BITS);
+        let scaled_total = total_weight >> shift;
+        let mut distributed_amount = Amount::ZERO;
+        for (i, (pubkey, weight)) in weights.iter().enumerate() {
+            let amount = if i == weights.len() - 1 {
+                total_amount - distributed_amount
+            } else {
+                let sats = total_amount.to_sat() as u128 * (weight >> shift) / scaled_total;
+                Amount::from_sat(sats as u64)
+            };
+            distributed_amount += amount;
+            entitlements.insert(*pubkey, amount);
+        }
+    }
+    Ok(settle(
+        &entitlements,
+        balances,
+        MAX_COINBASE_OUTPUTS,
+        MIN_COINBASE_OUTPUT,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shares::share_block::{ShareBlock, ShareHeader};
+    use crate::test_utils::TestShareBlockBuilder;
+    use std::collections::HashMap;
+
+    const MINER_A: &str = "020202020202020202020202020202020202020202020202020202020202020202";
+    const MINER_B: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn share(prev: BlockHash, miner: &str, uncles: Vec<BlockHash>, nonce: u32) -> ShareBlock {
+        TestShareBlockBuilder::new()
+            .prev_share_blockhash(prev.to_string())
+            .miner_pubkey(miner)
+            .uncles(uncles)
+            .nonce(nonce)
+            .build()
+    }
+
+    /// Mock store serving the headers, with no stored ledger balances
+    fn mock_store_with(headers: Vec<ShareHeader>) -> ChainStore {
+        let headers: HashMap<BlockHash, ShareHeader> = headers
+            .into_iter()
+            .map(|header| (header.block_hash(), header))
+            .collect();
+        let ancestors = headers.clone();
+        let mut store = ChainStore::default();
+        store
+            .expect_get_share_header()
+            .returning(move |hash| headers.get(hash).cloned());
+        store
+            .expect_get_ancestor_headers()
+            .returning(move |hash, count| {
+                let mut found = Vec::new();
+                let mut current = *hash;
+                while found.len() < count {
+                    let Some(header) = ancestors.get(&current) else {
+                        break;
+                    };
+                    current = header.prev_share_blockhash;
+                    found.push(header.clone());
+                }
+                found
+            });
+        store
+    }
+
+    fn store_with(headers: Vec<ShareHeader>) -> Arc<ChainStore> {
+        let mut store = mock_store_with(headers);
+        store.expect_get_share_balances().returning(|_| Ok(None));
+        Arc::new(store)
+    }
+
+    /// Roll the bitcoin header nonce until it meets its own bitcoin bits
+    fn mine_bitcoin_header(share: &mut ShareBlock, bits: u32) {
+        share.header.bitcoin_header.bits = CompactTarget::from_consensus(bits);
+        while share
+            .header
+            .bitcoin_header
+            .validate_pow(share.header.bitcoin_header.target())
+            .is_err()
+        {
+            share.header.bitcoin_header.nonce += 1;
+        }
+    }
+
+    fn pubkey(miner: &str) -> CompressedPublicKey {
+        miner.parse().unwrap()
+    }
+
+    #[test]
+    fn test_get_miner_weights_with_uncle() {
+        let s1 = share(BlockHash::all_zeros(), MINER_A, vec![], 1);
+        let uncle = share(s1.block_hash(), MINER_B, vec![], 2);
+        let s2 = share(s1.block_hash(), MINER_A, vec![], 3);
+        let s3 = share(s2.block_hash(), MINER_A, vec![uncle.block_hash()], 4);
+        let work = work_to_u128(s1.header.get_work());
+        let store = store_with(vec![
+            s1.header.clone(),
+            s2.header.clone(),
+            s3.header.clone(),
+            uncle.header.clone(),
+        ]);
+
+        let weights = get_miner_weights(&store, &s3.block_hash(), u128::MAX).unwrap();
+        assert_eq!(weights.len(), 2);
+        assert_eq!(weights[&pubkey(MINER_A)], 3 * work);
+        assert_eq!(weights[&pubkey(MINER_B)], work / 2);
+
+        // The window stops the walk once enough work is credited
+        let weights = get_miner_weights(&store, &s3.block_hash(), work).unwrap();
+        assert_eq!(weights.len(), 2);
+        assert_eq!(weights[&pubkey(MINER_A)], work);
+    }
+
+    #[test]
+    fn test_get_miner_weights_missing_share() {
+        let s1 = share(BlockHash::all_zeros(), MINER_A, vec![], 1);
+        let s2 = share(s1.block_hash(), MINER_A, vec![], 2);
+        let store = store_with(vec![s2.header.clone()]);
+
+        assert!(get_miner_weights(&store, &s2.block_hash(), u128::MAX).is_err());
+    }
+
+    #[test]
+    fn test_get_output_distribution() {
+        let s1 = share(BlockHash::all_zeros(), MINER_A, vec![], 1);
+        let s2 = share(s1.block_hash(), MINER_B, vec![], 2);
+        let s3 = share(s2.block_hash(), MINER_A, vec![], 3);
+        let store = store_with(vec![
+            s1.header.clone(),
+            s2.header.clone(),
+            s3.header.clone(),
+        ]);
+
+        let distribution = get_output_distribution(
+            &store,
+            &s3.block_hash(),
+            &pubkey(MINER_B),
+            u128::MAX,
+            Amount::from_sat(3_000_001),
+            &[],
+            Network::Signet,
+        )
+        .unwrap();
+
+        assert_eq!(distribution.len(), 2);
+        assert_eq!(
+            distribution[0].address,
+            Address::p2pkh(pubkey(MINER_A), Network::Signet)
+        );
+        assert_eq!(distribution[0].amount, Amount::from_sat(2_000_000));
+        assert_eq!(
+            distribution[1].address,
+            Address::p2pkh(pubkey(MINER_B), Network::Signet)
+        );
+        assert_eq!(distribution[1].amount, Amount::from_sat(1_000_001));
+
+        // Walking the same chain again gives the same distribution
+        let again = get_output_distribution(
+            &store,
+            &s3.block_hash(),
+            &pubkey(MINER_B),
+            u128::MAX,
+            Amount::from_sat(3_000_001),
+            &[],
+            Network::Signet,
+        )
+        .unwrap();
+        assert_eq!(
+            distribution
+                .iter()
+                .map(|output| (output.address.clone(), output.amount))
+                .collect::<Vec<_>>(),
+            again
+                .iter()
+                .map(|output| (output.address.clone(), output.amount))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_get_output_distribution_without_work_pays_share_miner() {
+        let store = store_with(vec![]);
+
+        let distribution = get_output_distribution(
+            &store,
+            &BlockHash::all_zeros(),
+            &pubkey(MINER_B),
+            u128::MAX,
+            Amount::from_sat(1_000_000),
+            &[],
+            Network::Signet,
+        )
+        .unwrap();
+
+        assert_eq!(distribution.len(), 1);
+        assert_eq!(
+            distribution[0].address,
+            Address::p2pkh(pubkey(MINER_B), Network::Signet)
+        );
+        assert_eq!(distribution[0].amount, Amount::from_sat(1_000_000));
+    }
+
+    #[test]
+    fn test_get_output_distribution_takes_cuts_first() {
+        let store = store_with(vec![]);
+        let donation_address = Address::p2pkh(pubkey(MINER_A), Network::Signet);
+        let donation = CoinbaseCut {
+            script_pubkey: donation_address.script_pubkey(),
+            basis_points: 100,
+        };
+        let fee = CoinbaseCut {
+            script_pubkey: donation_address.script_pubkey(),
+            basis_points: 5_000,
+        };
+
+        let distribution = get_output_distribution(
+            &store,
+            &BlockHash::all_zeros(),
+            &pubkey(MINER_B),
+            u128::MAX,
+            Amount::from_sat(1_000_000),
+            &[&donation, &fee],
+            Network::Signet,
+        )
+        .unwrap();
+
+        // The fee is taken from what is left after the donation
+        assert_eq!(distribution.len(), 3);
+        assert_eq!(distribution[0].address, donation_address);
+        assert_eq!(distribution[0].amount, Amount::from_sat(10_000));
+        assert_eq!(distribution[1].amount, Amount::from_sat(495_000));
+        assert_eq!(
+            distribution[2].address,
+            Address::p2pkh(pubkey(MINER_B), Network::Signet)
+        );
+        assert_eq!(distribution[2].amount, Amount::from_sat(495_000));
+    }
+
+    #[test]
+    fn test_get_output_distribution_carries_small_entitlements() {
+        let s1 = share(BlockHash::all_zeros(), MINER_A, vec![], 1);
+        let s2 = share(s1.block_hash(), MINER_B, vec![], 2);
+        let s3 = share(s2.block_hash(), MINER_A, vec![], 3);
+        let store = store_with(vec![
+            s1.header.clone(),
+            s2.header.clone(),
+            s3.header.clone(),
+        ]);
+
+        let distribution = get_output_distribution(
+            &store,
+            &s3.block_hash(),
+            &pubkey(MINER_A),
+            u128::MAX,
+            Amount::from_sat(1_000),
+            &[],
+            Network::Signet,
+        )
+        .unwrap();
+
+        // MINER_B is owed less than MIN_COINBASE_OUTPUT and is carried
+        assert_eq!(distribution.len(), 1);
+        assert_eq!(
+            distribution[0].address,
+            Address::p2pkh(pubkey(MINER_A), Network::Signet)
+        );
+        assert_eq!(distribution[0].amount, Amount::from_sat(1_000));
+    }
+
+    #[test]
+    fn test_get_balances_from_found_block() {
+        let easy_bits = CompactTarget::from_consensus(0x207fffff);
+        let mut s1 = share(BlockHash::all_zeros(), MINER_A, vec![], 1);
+        s1.header.bitcoin_header.bits = easy_bits;
+        let mut s2 = share(s1.block_hash(), MINER_B, vec![], 2);
+        s2.header.bitcoin_header.bits = easy_bits;
+        let mut s3 = share(s2.block_hash(), MINER_A, vec![], 3);
+        // Harder than the bitcoin target of the shares before it
+        mine_bitcoin_header(&mut s3, 0x2000ffff);
+        s3.bitcoin_transactions[0].output[0].value = Amount::from_sat(1_000);
+        s3.bitcoin_transactions[0].output.truncate(1);
+
+        let found = s3.clone();
+        let stored = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let mut store = mock_store_with(vec![
+            s1.header.clone(),
+            s2.header.clone(),
+            s3.header.clone(),
+        ]);
+        store
+            .expect_get_share()
+            .returning(move |hash| (*hash == found.block_hash()).then(|| found.clone()));
+        let get_stored = stored.clone();
+        store
+            .expect_get_share_balances()
+            .returning(move |hash| Ok(get_stored.lock().unwrap().get(hash).cloned()));
+        let put_stored = stored.clone();
+        store
+            .expect_put_share_balances()
+            .times(1)
+            .returning(move |hash, balances| {
+                put_stored.lock().unwrap().insert(*hash, balances.clone());
+                Ok(())
+            });
+        let store = Arc::new(store);
+        assert!(found_bitcoin_block(&store, &s3.header));
+
+        // Both miners are owed 500 in s3's coinbase, so it was all advanced to MINER_A
+        let expected = MinerBalances::from([(pubkey(MINER_A), -500), (pubkey(MINER_B), 500)]);
+        assert_eq!(get_balances(&store, &s3.block_hash()).unwrap(), expected);
+        // Reading balances stores nothing, that is left to adding the share
+        assert!(stored.lock().unwrap().is_empty());
+
+        store_balances(&store, &s3).unwrap();
+        assert_eq!(
+            stored.lock().unwrap().get(&s3.block_hash()),
+            Some(&expected)
+        );
+
+        // The next coinbase settles the carried balance, balances are read from store
+        let distribution = get_output_distribution(
+            &store,
+            &s3.block_hash(),
+            &pubkey(MINER_A),
+            u128::MAX,
+            Amount::from_sat(3_000),
+            &[],
+            Network::Signet,
+        )
+        .unwrap();
+        assert_eq!(distribution.len(), 2);
+        assert_eq!(
+            distribution[0].address,
+            Address::p2pkh(pubkey(MINER_A), Network::Signet)
+        );
+        assert_eq!(distribution[0].amount, Amount::from_sat(1_500));
+        assert_eq!(
+            distribution[1].address,
+            Address::p2pkh(pubkey(MINER_B), Network::Signet)
+        );
+        assert_eq!(distribution[1].amount, Amount::from_sat(1_500));
+    }
+
+    #[test]
+    fn test_found_bitcoin_block_rejects_easy_bits() {
+        let mut s1 = share(BlockHash::all_zeros(), MINER_A, vec![], 1);
+        s1.header.bitcoin_header.bits = CompactTarget::from_consensus(0x1f00ffff);
+        let mut s2 = share(s1.block_hash(), MINER_B, vec![], 2);
+        s2.header.bitcoin_header.bits = CompactTarget::from_consensus(0x1f00ffff);
+        let store = store_with(vec![s1.header.clone(), s2.header.clone()]);
+
+        // A share that meets its own bits, far easier than the bits of its ancestors
+        let mut fake = share(s2.block_hash(), MINER_A, vec![], 3);
+        mine_bitcoin_header(&mut fake, 0x207fffff);
+        assert!(!found_bitcoin_block(&store, &fake.header));
+
+        // Within the retarget step of the ancestors' bits
+        let mut real = share(s2.block_hash(), MINER_A, vec![], 4);
+        mine_bitcoin_header(&mut real, 0x1f03ff00);
+        assert!(found_bitcoin_block(&store, &real.header));
+
+        // Not found without the work for the claimed bits
+        real.header.bitcoin_header.bits = CompactTarget::from_consensus(0x03000001);
+        assert!(!found_bitcoin_block(&store, &real.header));
+    }
+
+    #[test]
+    fn test_window_work() {
+        let bits = CompactTarget::from_consensus(0x1d00ffff);
+        let work = work_to_u128(Target::from_compact(bits).to_work());
+        assert_eq!(window_work(bits), work * WINDOW_SHARES);
+        assert_eq!(WINDOW_SHARES, 8640);
+    }
+}
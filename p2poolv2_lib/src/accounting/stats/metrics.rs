@@ -18,6 +18,7 @@ use crate::accounting::stats::pool_local_stats::load_pool_local_stats;
 use crate::accounting::stats::user::User;
 use crate::accounting::stats::worker::Worker;
 use crate::accounting::{simple_pplns::SimplePplnsShare, stats::pool_local_stats};
+use crate::stratum::messages::RejectReason;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::SystemTime;
@@ -39,6 +40,9 @@ pub struct PoolMetrics {
     pub accepted_total: u64,
     /// Total rejected shares
     pub rejected_total: u64,
+    /// Rejected shares by reason
+    #[serde(default)]
+    pub rejections: HashMap<RejectReason, u64>,
     /// Highest difficulty share on this start
     pub best_share: u64,
     /// Highest difficulty share across restarts
@@ -55,6 +59,7 @@ impl Default for PoolMetrics {
             lastupdate: None,
             accepted_total: 0,
             rejected_total: 0,
+            rejections: HashMap::new(),
             start_time: std::time::SystemTime::now()
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap()
@@ -74,6 +79,7 @@ impl PoolMetrics {
         Ok(PoolMetrics {
             accepted_total: pool_stats.accepted_total,
             rejected_total: pool_stats.rejected_total,
+            rejections: pool_stats.rejections,
             users: pool_stats.users,
             ..Default::default()
         })
@@ -91,6 +97,7 @@ pub enum MetricsMessage {
         response: oneshot::Sender<()>,
     },
     RecordShareRejected {
+        reason: RejectReason,
         response: oneshot::Sender<()>,
     },
     IncrementWorkerCount {
@@ -158,8 +165,8 @@ impl MetricsActor {
                 self.record_share_accepted(btcaddress, workername, difficulty, truediff);
                 let _ = response.send(());
             }
-            MetricsMessage::RecordShareRejected { response } => {
-                self.record_share_rejected();
+            MetricsMessage::RecordShareRejected { reason, response } => {
+                self.record_share_rejected(reason);
                 let _ = response.send(());
             }
             MetricsMessage::IncrementWorkerCount {
@@ -217,8 +224,9 @@ impl MetricsActor {
     }
 
     /// Update metrics from rejected share
-    fn record_share_rejected(&mut self) {
+    fn record_share_rejected(&mut self, reason: RejectReason) {
         self.metrics.rejected_total += 1;
+        *self.metrics.rejections.entry(reason).or_default() += 1;
     }
 
     /// Increment worker counts - called after worker has authorised successfully.
@@ -293,10 +301,12 @@ impl MetricsHandle {
     /// We don't difficulty or user info for rejected shares as the could be rejected for any reason
     pub async fn record_share_rejected(
         &self,
+        reason: RejectReason,
     ) -> Result<(), tokio::sync::oneshot::error::RecvError> {
         let (response_tx, response_rx) = oneshot::channel();
         self.sender
             .send(MetricsMessage::RecordShareRejected {
+                reason,
                 response: response_tx,
             })
             .await
@@ -488,9 +498,15 @@ mod tests {
         let handle = start_metrics(log_dir.path().to_str().unwrap().to_string())
             .await
             .unwrap();
-        let _ = handle.record_share_rejected().await;
+        let _ = handle
+            .record_share_rejected(RejectReason::LowDifficulty)
+            .await;
 
-        let _ = handle.record_share_rejected().await;
+        let _ = handle.record_share_rejected(RejectReason::Duplicate).await;
+        let metrics = handle.get_metrics().await;
+        assert_eq!(metrics.rejected_total, 2);
+        assert_eq!(metrics.rejections[&RejectReason::LowDifficulty], 1);
+        assert_eq!(metrics.rejections[&RejectReason::Duplicate], 1);
     }
 
     #[test_log::test(tokio::test)]
@@ -534,7 +550,9 @@ mod tests {
                 2200,
             )
             .await;
-        let _ = handle.record_share_rejected().await;
+        let _ = handle
+            .record_share_rejected(RejectReason::LowDifficulty)
+            .await;
 
         let current_unix_timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -575,7 +593,9 @@ mod tests {
                 134,
             )
             .await;
-        let _ = handle.record_share_rejected().await;
+        let _ = handle
+            .record_share_rejected(RejectReason::LowDifficulty)
+            .await;
         let _ = handle
             .increment_worker_count("user4".to_string(), "workerD".to_string())
             .await;
@@ -122,6 +122,7 @@ mod tests {
             lastupdate: Some(1234567890),
             accepted_total: 0,
             rejected_total: 0,
+            rejections: HashMap::new(),
             best_share: 0,
             best_share_ever: 0,
             users: HashMap::with_capacity(100),
@@ -166,6 +167,7 @@ mod tests {
             lastupdate: Some(1234567890),
             accepted_total: 0,
             rejected_total: 0,
+            rejections: HashMap::new(),
             best_share: 0,
             best_share_ever: 0,
             users: HashMap::with_capacity(100),
@@ -35,6 +35,19 @@ impl PoolMetrics {
         output.push_str(&format!("shares_rejected_total {}\n", self.rejected_total));
         output.push('\n');
 
+        output.push_str("# HELP shares_rejected Rejected shares by reason\n");
+        output.push_str("# TYPE shares_rejected counter\n");
+        let mut rejections: Vec<_> = self.rejections.iter().collect();
+        rejections.sort_by_key(|(reason, _)| reason.as_str());
+        for (reason, count) in rejections {
+            output.push_str(&format!(
+                "shares_rejected{{reason=\"{}\"}} {}\n",
+                reason.as_str(),
+                count
+            ));
+        }
+        output.push('\n');
+
         output.push_str("# HELP best_share Highest difficulty share\n");
         output.push_str("# TYPE best_share gauge\n");
         output.push_str(&format!("best_share {}\n", self.best_share));
@@ -154,6 +167,7 @@ impl PoolMetrics {
 mod tests {
     use super::*;
     use crate::accounting::stats::user::User;
+    use crate::stratum::messages::RejectReason;
     use std::collections::HashMap;
 
     #[test]
@@ -161,6 +175,7 @@ mod tests {
         let metrics = PoolMetrics {
             accepted_total: 100,
             rejected_total: 5,
+            rejections: HashMap::from([(RejectReason::Duplicate, 3)]),
             best_share: 500,
             best_share_ever: 500,
             pool_difficulty: 1000,
@@ -174,6 +189,7 @@ mod tests {
         // Check that it contains the expected metrics
         assert!(exposition.contains(&format!("shares_accepted_total {}", 100 * TWO32)));
         assert!(exposition.contains("shares_rejected_total 5"));
+        assert!(exposition.contains("shares_rejected{reason=\"duplicate\"} 3"));
         assert!(exposition.contains("best_share 500"));
         assert!(exposition.contains("best_share_ever 500"));
         assert!(exposition.contains("difficulty 1000"));
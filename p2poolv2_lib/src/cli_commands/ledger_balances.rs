@@ -0,0 +1,87 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::shares::chain::chain_store::ChainStore;
+use serde::Serialize;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Structure to hold an outstanding ledger balance for JSON output
+#[derive(Serialize)]
+struct LedgerBalanceInfo {
+    btcaddress: String,
+    balance: i64,
+}
+
+/// Implementation of the ledger-balances command
+pub fn execute(chain_store: Arc<ChainStore>) -> Result<(), Box<dyn Error>> {
+    let balances = chain_store
+        .get_ledger_balances()
+        .map_err(|e| e.to_string())?;
+
+    // Largest balances first
+    let mut balance_infos: Vec<LedgerBalanceInfo> = balances
+        .into_iter()
+        .map(|(btcaddress, balance)| LedgerBalanceInfo {
+            btcaddress,
+            balance,
+        })
+        .collect();
+    balance_infos.sort_by(|a, b| {
+        b.balance
+            .cmp(&a.balance)
+            .then_with(|| a.btcaddress.cmp(&b.btcaddress))
+    });
+
+    // Serialize to JSON and print
+    println!("{}", serde_json::to_string_pretty(&balance_infos)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::execute;
+    use crate::accounting::LedgerUpdate;
+    use crate::shares::chain::chain_store::ChainStore;
+    use crate::shares::share_block::ShareBlock;
+    use crate::store::Store;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_execute_with_balances() {
+        let temp_dir = tempdir().unwrap();
+        let store =
+            Arc::new(Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap());
+        store
+            .apply_ledger_updates(&[LedgerUpdate {
+                address: "addr1".to_string(),
+                delta: 1000,
+            }])
+            .unwrap();
+
+        let chain = Arc::new(ChainStore::new(
+            store,
+            ShareBlock::build_genesis_for_network(bitcoin::Network::Signet),
+            bitcoin::Network::Signet,
+        ));
+
+        let result = execute(chain);
+
+        assert!(result.is_ok(), "Execute should not return an error");
+    }
+}
@@ -15,7 +15,9 @@
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
 pub mod chain_info;
+pub mod ledger_balances;
 pub mod pplns_shares;
+pub mod share_balances;
 
 // Re-export the shared store functionality
 pub mod store {
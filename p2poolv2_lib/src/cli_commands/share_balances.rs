@@ -0,0 +1,95 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::shares::chain::chain_store::ChainStore;
+use serde::Serialize;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Structure to hold a share chain ledger balance for JSON output
+#[derive(Serialize)]
+struct ShareBalanceInfo {
+    btcaddress: String,
+    balance: i64,
+}
+
+/// Implementation of the share-balances command
+pub fn execute(chain_store: Arc<ChainStore>) -> Result<(), Box<dyn Error>> {
+    let balances = chain_store
+        .get_tip_share_balances()
+        .map_err(|e| e.to_string())?;
+
+    // Largest balances first
+    let mut balance_infos: Vec<ShareBalanceInfo> = balances
+        .into_iter()
+        .map(|(btcaddress, balance)| ShareBalanceInfo {
+            btcaddress,
+            balance,
+        })
+        .collect();
+    balance_infos.sort_by(|a, b| {
+        b.balance
+            .cmp(&a.balance)
+            .then_with(|| a.btcaddress.cmp(&b.btcaddress))
+    });
+
+    // Serialize to JSON and print
+    println!("{}", serde_json::to_string_pretty(&balance_infos)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::execute;
+    use crate::shares::chain::chain_store::ChainStore;
+    use crate::shares::share_block::ShareBlock;
+    use crate::store::Store;
+    use bitcoin::{Address, CompressedPublicKey};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_execute_with_share_balances() {
+        let temp_dir = tempdir().unwrap();
+        let store =
+            Arc::new(Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap());
+        let genesis = ShareBlock::build_genesis_for_network(bitcoin::Network::Signet);
+        let genesis_hash = genesis.header.block_hash();
+        let chain = Arc::new(ChainStore::new(
+            store.clone(),
+            genesis,
+            bitcoin::Network::Signet,
+        ));
+
+        let miner: CompressedPublicKey =
+            "020202020202020202020202020202020202020202020202020202020202020202"
+                .parse()
+                .unwrap();
+        store
+            .put_share_balances(&genesis_hash, &BTreeMap::from([(miner, 1000)]))
+            .unwrap();
+
+        let balances = chain.get_tip_share_balances().unwrap();
+        let address = Address::p2pkh(miner, bitcoin::Network::Signet).to_string();
+        assert_eq!(balances.get(&address), Some(&1000));
+
+        let result = execute(chain);
+
+        assert!(result.is_ok(), "Execute should not return an error");
+    }
+}
@@ -43,10 +43,27 @@ pub enum Command {
         Message,
         oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>,
     ),
+    /// Command to broadcast a share block or transaction over gossipsub
+    SendGossip(
+        Message,
+        oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>,
+    ),
     /// Command to get a list of connected peers
     GetPeers(oneshot::Sender<Vec<libp2p::PeerId>>),
     /// Command to shutdown node
     Shutdown(oneshot::Sender<()>),
     /// Get PPLNS shares from the node with optional filtering
     GetPplnsShares(GetPplnsShareQuery, oneshot::Sender<Vec<SimplePplnsShare>>),
+    /// Ban a peer for the configured ban duration and disconnect it
+    BanPeer(
+        libp2p::PeerId,
+        oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>,
+    ),
+    /// Lift the ban on a peer
+    UnbanPeer(
+        libp2p::PeerId,
+        oneshot::Sender<Result<(), Box<dyn Error + Send + Sync>>>,
+    ),
+    /// Get banned peers with the unix timestamp their ban ends
+    GetBannedPeers(oneshot::Sender<Vec<(libp2p::PeerId, u64)>>),
 }
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::stratum::sv2::noise::AuthorityKeys;
 use crate::stratum::work::coinbase::parse_address;
 use crate::stratum::work::error::WorkError;
 use bitcoin::address::NetworkChecked;
@@ -21,6 +22,7 @@ use bitcoin::{Address, CompressedPublicKey};
 use bitcoindrpc::BitcoinRpcConfig;
 use serde::Deserialize;
 use std::marker::PhantomData;
+use std::time::Duration;
 
 /// Max length for pool signature P2Poolv2 + 8 more bytes for users to add
 const MAX_POOL_SIGNATURE_LENGTH: usize = 16;
@@ -70,6 +72,23 @@ pub struct StratumConfig<State = Raw> {
     pub difficulty_multiplier: f64,
     /// Optional pool signature to include in coinbase
     pub pool_signature: Option<String>,
+    /// Maximum number of miner outputs in the coinbase, the rest is carried in the payout ledger.
+    /// Only used when not mining on the share chain, which has a fixed limit for all nodes.
+    #[serde(default = "default_max_coinbase_outputs")]
+    pub max_coinbase_outputs: usize,
+    /// Minimum coinbase output value in satoshis, smaller entitlements are carried in the payout ledger
+    #[serde(default = "default_min_coinbase_output")]
+    pub min_coinbase_output: u64,
+    /// Port for the Stratum V2 mining endpoint, disabled when not set
+    pub sv2_port: Option<u16>,
+    /// Hex encoded secp256k1 secret key signing the SV2 noise certificates, required with sv2_port
+    pub sv2_authority_secret_key: Option<String>,
+    /// Validity of the SV2 noise certificates in seconds
+    #[serde(default = "default_sv2_cert_validity_secs")]
+    pub sv2_cert_validity_secs: u64,
+    /// Additional stratum listeners, e.g. a high difficulty port for ASICs or a TLS port
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
 
     // Parsed addresses - only available when State = Parsed
     #[serde(skip)]
@@ -78,21 +97,53 @@ pub struct StratumConfig<State = Raw> {
     pub(crate) donation_address_parsed: Option<Address<NetworkChecked>>,
     #[serde(skip)]
     pub(crate) fee_address_parsed: Option<Address<NetworkChecked>>,
+    #[serde(skip)]
+    pub(crate) sv2_authority_keys_parsed: Option<AuthorityKeys>,
 
     #[serde(skip)]
     #[serde(default)]
     _state: PhantomData<State>,
 }
 
+/// A stratum listener, all listeners share the job pipeline, connections and metrics
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ListenerConfig {
+    /// The hostname to bind to
+    pub hostname: String,
+    /// The port to bind to
+    pub port: u16,
+    /// The start difficulty for miners connecting to this listener
+    pub start_difficulty: u64,
+    /// The minimum difficulty for miners connecting to this listener
+    pub minimum_difficulty: u64,
+    /// The maximum difficulty for miners connecting to this listener, not enforced if None
+    pub maximum_difficulty: Option<u64>,
+    /// The version mask to use for version-rolling
+    #[serde(deserialize_with = "deserialize_version_mask")]
+    pub version_mask: i32,
+    /// Path to the PEM certificate chain, serves the listener over TLS when set with tls_key
+    pub tls_cert: Option<String>,
+    /// Path to the PEM private key for tls_cert
+    pub tls_key: Option<String>,
+}
+
 impl StratumConfig<Raw> {
     /// Parse and validate addresses, converting from Raw to Parsed state
     pub fn parse(self) -> Result<StratumConfig<Parsed>, WorkError> {
+        if self.max_coinbase_outputs == 0 {
+            return Err(WorkError {
+                message: "At least one coinbase output is required for miners".to_string(),
+            });
+        }
+
         if self.pool_signature.clone().unwrap_or("".to_string()).len() > MAX_POOL_SIGNATURE_LENGTH {
             return Err(WorkError {
                 message: format!("Pool signature length is limited to {MAX_POOL_SIGNATURE_LENGTH}"),
             });
         }
 
+        self.validate_listeners()?;
+
         let bootstrap_address_parsed = parse_address(&self.bootstrap_address, self.network)?;
 
         let donation_address_parsed = self
@@ -107,6 +158,24 @@ impl StratumConfig<Raw> {
             .map(|addr| parse_address(addr, self.network))
             .transpose()?;
 
+        let sv2_authority_keys_parsed = match (self.sv2_port, &self.sv2_authority_secret_key) {
+            (None, _) => None,
+            (Some(_), None) => {
+                return Err(WorkError {
+                    message: "sv2_authority_secret_key is required with sv2_port".to_string(),
+                });
+            }
+            (Some(_), Some(secret)) => Some(
+                AuthorityKeys::from_secret_hex(
+                    secret,
+                    Duration::from_secs(self.sv2_cert_validity_secs),
+                )
+                .map_err(|e| WorkError {
+                    message: e.to_string(),
+                })?,
+            ),
+        };
+
         Ok(StratumConfig {
             hostname: self.hostname,
             port: self.port,
@@ -124,12 +193,68 @@ impl StratumConfig<Raw> {
             version_mask: self.version_mask,
             difficulty_multiplier: self.difficulty_multiplier,
             pool_signature: self.pool_signature,
+            max_coinbase_outputs: self.max_coinbase_outputs,
+            min_coinbase_output: self.min_coinbase_output,
+            sv2_port: self.sv2_port,
+            sv2_authority_secret_key: self.sv2_authority_secret_key,
+            sv2_cert_validity_secs: self.sv2_cert_validity_secs,
+            listeners: self.listeners,
             bootstrap_address_parsed: Some(bootstrap_address_parsed),
             donation_address_parsed,
             fee_address_parsed,
+            sv2_authority_keys_parsed,
             _state: PhantomData,
         })
     }
+
+    /// Listeners need both or neither of the TLS files, a start
+    /// difficulty above their minimum and an address of their own
+    fn validate_listeners(&self) -> Result<(), WorkError> {
+        let mut addresses = vec![(self.hostname.as_str(), self.port)];
+        if let Some(sv2_port) = self.sv2_port {
+            addresses.push((self.hostname.as_str(), sv2_port));
+        }
+        for listener in &self.listeners {
+            if listener.tls_cert.is_some() != listener.tls_key.is_some() {
+                return Err(WorkError {
+                    message: format!(
+                        "Listener on port {} needs both tls_cert and tls_key",
+                        listener.port
+                    ),
+                });
+            }
+            if listener.start_difficulty < listener.minimum_difficulty {
+                return Err(WorkError {
+                    message: format!(
+                        "Listener on port {} has start_difficulty below minimum_difficulty",
+                        listener.port
+                    ),
+                });
+            }
+            if listener
+                .maximum_difficulty
+                .is_some_and(|maximum| maximum < listener.minimum_difficulty)
+            {
+                return Err(WorkError {
+                    message: format!(
+                        "Listener on port {} has maximum_difficulty below minimum_difficulty",
+                        listener.port
+                    ),
+                });
+            }
+            let address = (listener.hostname.as_str(), listener.port);
+            if addresses.contains(&address) {
+                return Err(WorkError {
+                    message: format!(
+                        "Listener address {}:{} is used more than once",
+                        listener.hostname, listener.port
+                    ),
+                });
+            }
+            addresses.push(address);
+        }
+        Ok(())
+    }
 }
 
 impl StratumConfig<Parsed> {
@@ -149,6 +274,27 @@ impl StratumConfig<Parsed> {
     pub fn fee_address(&self) -> Option<&Address<NetworkChecked>> {
         self.fee_address_parsed.as_ref()
     }
+
+    /// All stratum listeners, the one from the top level settings first
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        let mut listeners = vec![ListenerConfig {
+            hostname: self.hostname.clone(),
+            port: self.port,
+            start_difficulty: self.start_difficulty,
+            minimum_difficulty: self.minimum_difficulty,
+            maximum_difficulty: self.maximum_difficulty,
+            version_mask: self.version_mask,
+            tls_cert: None,
+            tls_key: None,
+        }];
+        listeners.extend(self.listeners.iter().cloned());
+        listeners
+    }
+
+    /// Get the SV2 authority keys, set when the SV2 endpoint is enabled
+    pub fn sv2_authority_keys(&self) -> Option<&AuthorityKeys> {
+        self.sv2_authority_keys_parsed.as_ref()
+    }
 }
 
 #[cfg(any(test, feature = "test-utils"))]
@@ -173,14 +319,36 @@ impl StratumConfig<Raw> {
             version_mask: 0x1fffe000,
             difficulty_multiplier: 1.0,
             pool_signature: None,
+            max_coinbase_outputs: default_max_coinbase_outputs(),
+            min_coinbase_output: default_min_coinbase_output(),
+            sv2_port: None,
+            sv2_authority_secret_key: None,
+            sv2_cert_validity_secs: default_sv2_cert_validity_secs(),
+            listeners: Vec::new(),
             bootstrap_address_parsed: None,
             donation_address_parsed: None,
             fee_address_parsed: None,
+            sv2_authority_keys_parsed: None,
             _state: PhantomData,
         }
     }
 }
 
+/// Keep the coinbase to a reasonable size by default
+fn default_max_coinbase_outputs() -> usize {
+    100
+}
+
+/// Standard dust limit for P2PKH outputs
+fn default_min_coinbase_output() -> u64 {
+    546
+}
+
+/// SV2 certificates are valid for an hour by default
+fn default_sv2_cert_validity_secs() -> u64 {
+    3600
+}
+
 /// helper function to deserialize the network from the config file, which is provided as a string like Core
 /// Possible values are: main, test, testnet4, signet, regtest
 fn deserialize_network<'de, D>(deserializer: D) -> Result<bitcoin::Network, D::Error>
@@ -217,6 +385,49 @@ pub struct NetworkConfig {
     pub max_requests_per_second: u64,
     pub peer_inactivity_timeout_secs: u64,
     pub dial_timeout_secs: u64,
+    /// Path to the node key file, the key is kept in the store if not set
+    #[serde(default)]
+    pub node_key_path: Option<String>,
+    /// Number of peers from the address book to redial on startup
+    #[serde(default = "default_max_redial_peers")]
+    pub max_redial_peers: usize,
+    /// Misbehaviour score at which a peer is banned
+    #[serde(default = "default_ban_threshold")]
+    pub ban_threshold: u32,
+    /// How long a misbehaving peer stays banned
+    #[serde(default = "default_ban_duration_secs")]
+    pub ban_duration_secs: u64,
+    /// Peer multiaddrs to seed discovery from
+    #[serde(default)]
+    pub bootstrap_peers: Vec<String>,
+    /// DNS seed names, resolved through their dnsaddr TXT records
+    #[serde(default)]
+    pub dns_seeds: Vec<String>,
+    /// Number of outbound peers discovery tries to keep connected
+    #[serde(default = "default_target_outbound_peers")]
+    pub target_outbound_peers: usize,
+    /// How often to run a Kademlia random walk and dial new peers
+    #[serde(default = "default_discovery_interval_secs")]
+    pub discovery_interval_secs: u64,
+    /// Limit on outbound peers from the same /16 (IPv4) or /32 (IPv6)
+    #[serde(default = "default_max_peers_per_subnet")]
+    pub max_peers_per_subnet: usize,
+    /// Also listen and dial over QUIC, on the UDP port matching the listen address
+    #[serde(default)]
+    pub enable_quic: bool,
+    /// Probe whether peers can reach us with AutoNAT
+    #[serde(default)]
+    pub enable_autonat: bool,
+    /// Accept connections through relays when we are not publicly
+    /// reachable, and upgrade them to direct connections with DCUtR
+    #[serde(default)]
+    pub enable_relay_client: bool,
+    /// Relay multiaddrs, including the relay's peer id, to listen through
+    #[serde(default)]
+    pub relay_servers: Vec<String>,
+    /// Relay connections for peers behind NAT
+    #[serde(default)]
+    pub enable_relay_server: bool,
 }
 
 impl Default for NetworkConfig {
@@ -238,10 +449,48 @@ impl Default for NetworkConfig {
             max_requests_per_second: 1,
             peer_inactivity_timeout_secs: 60,
             dial_timeout_secs: 30,
+            node_key_path: None,
+            max_redial_peers: default_max_redial_peers(),
+            ban_threshold: default_ban_threshold(),
+            ban_duration_secs: default_ban_duration_secs(),
+            bootstrap_peers: vec![],
+            dns_seeds: vec![],
+            target_outbound_peers: default_target_outbound_peers(),
+            discovery_interval_secs: default_discovery_interval_secs(),
+            max_peers_per_subnet: default_max_peers_per_subnet(),
+            enable_quic: false,
+            enable_autonat: false,
+            enable_relay_client: false,
+            relay_servers: vec![],
+            enable_relay_server: false,
         }
     }
 }
 
+fn default_max_redial_peers() -> usize {
+    8
+}
+
+fn default_ban_threshold() -> u32 {
+    100
+}
+
+fn default_ban_duration_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_target_outbound_peers() -> usize {
+    8
+}
+
+fn default_discovery_interval_secs() -> u64 {
+    60
+}
+
+fn default_max_peers_per_subnet() -> usize {
+    2
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct StoreConfig {
     pub path: String,
@@ -635,4 +884,121 @@ mod tests {
         config_with_sig.pool_signature = Some("MyPool/1.0 and some more bytes....".to_string());
         assert_err!(config_with_sig.parse());
     }
+
+    #[test]
+    fn test_sv2_authority_key_option() {
+        let config = StratumConfig::<Raw>::new_for_test_default();
+        assert!(config.parse().unwrap().sv2_authority_keys().is_none());
+
+        // The key is required when the endpoint is enabled
+        let mut config = StratumConfig::<Raw>::new_for_test_default();
+        config.sv2_port = Some(3334);
+        assert_err!(config.clone().parse());
+
+        config.sv2_authority_secret_key = Some("not a key".to_string());
+        assert_err!(config.clone().parse());
+
+        config.sv2_authority_secret_key =
+            Some("0101010101010101010101010101010101010101010101010101010101010101".to_string());
+        let parsed = config.parse().unwrap();
+        assert_eq!(
+            parsed.sv2_authority_keys().unwrap().public_key_hex().len(),
+            64
+        );
+    }
+
+    fn test_listener(port: u16) -> ListenerConfig {
+        ListenerConfig {
+            hostname: "127.0.0.1".to_string(),
+            port,
+            start_difficulty: 100_000,
+            minimum_difficulty: 10_000,
+            maximum_difficulty: None,
+            version_mask: 0x1fffe000,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+
+    #[test]
+    fn test_listeners() {
+        let mut config = StratumConfig::<Raw>::new_for_test_default();
+        config.listeners = vec![test_listener(3340)];
+        let listeners = config.parse().unwrap().listeners();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].port, 3333);
+        assert_eq!(listeners[0].start_difficulty, 1);
+        assert_eq!(listeners[1], test_listener(3340));
+
+        // TLS needs both the certificate and the key
+        let mut config = StratumConfig::<Raw>::new_for_test_default();
+        let mut listener = test_listener(3340);
+        listener.tls_cert = Some("cert.pem".to_string());
+        config.listeners = vec![listener.clone()];
+        assert_err!(config.clone().parse());
+        listener.tls_key = Some("key.pem".to_string());
+        config.listeners = vec![listener];
+        assert!(config.parse().is_ok());
+
+        // Listeners can't share an address with each other or the top level listener
+        let mut config = StratumConfig::<Raw>::new_for_test_default();
+        config.listeners = vec![test_listener(3333)];
+        assert_err!(config.parse());
+        let mut config = StratumConfig::<Raw>::new_for_test_default();
+        config.listeners = vec![test_listener(3340), test_listener(3340)];
+        assert_err!(config.parse());
+
+        // Start difficulty can't be below the minimum
+        let mut config = StratumConfig::<Raw>::new_for_test_default();
+        let mut listener = test_listener(3340);
+        listener.start_difficulty = 1;
+        config.listeners = vec![listener];
+        assert_err!(config.parse());
+
+        // Maximum difficulty can't be below the minimum
+        let mut config = StratumConfig::<Raw>::new_for_test_default();
+        let mut listener = test_listener(3340);
+        listener.maximum_difficulty = Some(1_000);
+        config.listeners = vec![listener.clone()];
+        assert_err!(config.clone().parse());
+        listener.maximum_difficulty = Some(10_000);
+        config.listeners = vec![listener];
+        assert!(config.parse().is_ok());
+    }
+
+    #[test]
+    fn test_listeners_from_toml() {
+        let toml = r#"
+            hostname = "0.0.0.0"
+            port = 3333
+            start_difficulty = 1
+            minimum_difficulty = 1
+            zmqpubhashblock = "tcp://127.0.0.1:28332"
+            bootstrap_address = "tb1qyazxde6558qj6z3d9np5e6msmrspwpf6k0qggk"
+            network = "signet"
+            version_mask = "1fffe000"
+            difficulty_multiplier = 1.0
+
+            [[listeners]]
+            hostname = "0.0.0.0"
+            port = 3340
+            start_difficulty = 100000
+            minimum_difficulty = 10000
+            version_mask = "1fffe000"
+            tls_cert = "/etc/p2pool/cert.pem"
+            tls_key = "/etc/p2pool/key.pem"
+            "#;
+        let config: StratumConfig = config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(config.listeners[0].version_mask, 0x1fffe000);
+        assert_eq!(
+            config.listeners[0].tls_cert.as_deref(),
+            Some("/etc/p2pool/cert.pem")
+        );
+    }
 }
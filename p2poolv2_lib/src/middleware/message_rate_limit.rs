@@ -0,0 +1,316 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Per peer, per message kind rate limiting.
+//!
+//! Requests are counted in fixed windows of `rate_limit_window_secs`
+//! for each peer and message kind. Requests over the configured limit
+//! are dropped with a `RateLimitExceeded` error, which the peer score
+//! middleware penalises.
+
+use crate::config::NetworkConfig;
+use crate::node::messages::Message;
+use crate::service::p2p_service::RequestContext;
+use crate::utils::time_provider::TimeProvider;
+use futures::future::{self, BoxFuture, FutureExt};
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::warn;
+
+/// Forget windows of idle peers once we track this many entries
+const MAX_TRACKED_WINDOWS: usize = 1024;
+
+/// Start of the current window and requests counted in it, for each
+/// peer and message kind
+type Windows = Arc<Mutex<HashMap<(PeerId, MessageKind), (u64, u64)>>>;
+
+/// Kinds of messages that are rate limited separately.
+///
+/// The p2p protocol has no workbase messages, so the workbase limits
+/// in NetworkConfig are not used here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    /// Shares and share headers
+    MiningShare,
+    /// Inventory announcements and requests for data
+    Inventory,
+    /// Bitcoin transactions
+    Transaction,
+}
+
+impl MessageKind {
+    pub fn of(message: &Message) -> Self {
+        match message {
+            Message::ShareBlock(_)
+            | Message::CompactShareBlock(_)
+            | Message::ShareBlockTxns(_)
+            | Message::ShareHeaders(_) => MessageKind::MiningShare,
+            Message::Inventory(_)
+            | Message::NotFound(_)
+            | Message::GetShareHeaders(_, _)
+            | Message::GetShareBlocks(_, _)
+            | Message::GetData(_)
+            | Message::GetShareBlockTxns(_)
+            | Message::Version(_)
+            | Message::VerAck(_)
+            | Message::Tip(_) => MessageKind::Inventory,
+            Message::Transaction(_) => MessageKind::Transaction,
+        }
+    }
+}
+
+/// Error returned for requests over a peer's rate limit
+#[derive(Debug, thiserror::Error)]
+#[error("Peer {peer} exceeded the {kind:?} rate limit")]
+pub struct RateLimitExceeded {
+    pub peer: PeerId,
+    pub kind: MessageKind,
+}
+
+/// Requests allowed per window for each message kind, zero is unlimited
+#[derive(Debug, Clone)]
+struct MessageLimits {
+    mining_share: u64,
+    inventory: u64,
+    transaction: u64,
+    window_secs: u64,
+}
+
+impl MessageLimits {
+    fn new(config: &NetworkConfig) -> Self {
+        let window_secs = config.rate_limit_window_secs.max(1);
+        Self {
+            mining_share: config.max_miningshare_per_second as u64 * window_secs,
+            inventory: config.max_inventory_per_second as u64 * window_secs,
+            transaction: config.max_transaction_per_second as u64 * window_secs,
+            window_secs,
+        }
+    }
+
+    fn limit(&self, kind: MessageKind) -> u64 {
+        match kind {
+            MessageKind::MiningShare => self.mining_share,
+            MessageKind::Inventory => self.inventory,
+            MessageKind::Transaction => self.transaction,
+        }
+    }
+}
+
+/// Layer that injects the MessageRateLimitService middleware into the stack.
+pub struct MessageRateLimitLayer {
+    limits: MessageLimits,
+}
+
+impl MessageRateLimitLayer {
+    pub fn new(config: &NetworkConfig) -> Self {
+        Self {
+            limits: MessageLimits::new(config),
+        }
+    }
+}
+
+impl<S> Layer<S> for MessageRateLimitLayer {
+    type Service = MessageRateLimitService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        MessageRateLimitService {
+            service,
+            limits: self.limits.clone(),
+            windows: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+/// Middleware that drops requests over the per peer, per message kind limits.
+pub struct MessageRateLimitService<S> {
+    service: S,
+    limits: MessageLimits,
+    windows: Windows,
+}
+
+impl<S> MessageRateLimitService<S> {
+    /// Count a request, returning false if it is over the limit
+    fn allow(&self, peer: PeerId, kind: MessageKind, now: u64) -> bool {
+        let limit = self.limits.limit(kind);
+        if limit == 0 {
+            return true;
+        }
+        let window_secs = self.limits.window_secs;
+        let window_start = now - now % window_secs;
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= MAX_TRACKED_WINDOWS {
+            windows.retain(|_, (start, _)| *start == window_start);
+        }
+        let (start, count) = windows.entry((peer, kind)).or_insert((window_start, 0));
+        if *start != window_start {
+            *start = window_start;
+            *count = 0;
+        }
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+impl<S, C, T> Service<RequestContext<C, T>> for MessageRateLimitService<S>
+where
+    S: Service<
+            RequestContext<C, T>,
+            Response = (),
+            Error = Box<dyn std::error::Error + Send + Sync>,
+        > + Send
+        + 'static,
+    S::Future: Send + 'static,
+    C: Send + Sync + 'static,
+    T: TimeProvider + Send + Sync + 'static,
+{
+    type Response = ();
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = BoxFuture<'static, Result<(), Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: RequestContext<C, T>) -> Self::Future {
+        let peer = req.peer;
+        let kind = MessageKind::of(&req.request);
+        if !self.allow(peer, kind, req.time_provider.seconds_since_epoch()) {
+            warn!(
+                "Dropping {} request from peer {peer}, rate limit exceeded",
+                req.request
+            );
+            return future::ready(Err(RateLimitExceeded { peer, kind }.into())).boxed();
+        }
+        self.service.call(req).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StratumConfig;
+    #[mockall_double::double]
+    use crate::shares::chain::chain_store::ChainStore;
+    use crate::utils::time_provider::TestTimeProvider;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::sync::{mpsc, oneshot};
+
+    #[derive(Clone)]
+    struct MockService;
+
+    impl<C, T> Service<RequestContext<C, T>> for MockService {
+        type Response = ();
+        type Error = Box<dyn std::error::Error + Send + Sync>;
+        type Future = future::Ready<Result<(), Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: RequestContext<C, T>) -> Self::Future {
+            future::ready(Ok(()))
+        }
+    }
+
+    fn request(
+        peer: PeerId,
+        message: Message,
+        time_provider: &TestTimeProvider,
+    ) -> RequestContext<oneshot::Sender<Message>, TestTimeProvider> {
+        let (response_channel, _) = oneshot::channel();
+        let (swarm_tx, _) = mpsc::channel(1);
+        RequestContext {
+            peer,
+            request: message,
+            store: Arc::new(ChainStore::default()),
+            response_channel,
+            swarm_tx,
+            time_provider: time_provider.clone(),
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_per_peer_and_message_kind() {
+        let config = NetworkConfig {
+            max_inventory_per_second: 1,
+            max_transaction_per_second: 0,
+            rate_limit_window_secs: 2,
+            ..Default::default()
+        };
+        let mut service = MessageRateLimitLayer::new(&config).layer(MockService);
+        let mut time_provider = TestTimeProvider::new(UNIX_EPOCH + Duration::from_secs(1000));
+        let peer = PeerId::random();
+        let other_peer = PeerId::random();
+
+        // Two inventory requests are allowed in the two second window
+        for _ in 0..2 {
+            assert!(
+                service
+                    .call(request(peer, Message::NotFound(()), &time_provider))
+                    .await
+                    .is_ok()
+            );
+        }
+        let error = service
+            .call(request(peer, Message::NotFound(()), &time_provider))
+            .await
+            .unwrap_err();
+        let error = error.downcast_ref::<RateLimitExceeded>().unwrap();
+        assert_eq!(error.kind, MessageKind::Inventory);
+
+        // Other peers and unlimited message kinds are not affected
+        assert!(
+            service
+                .call(request(other_peer, Message::NotFound(()), &time_provider))
+                .await
+                .is_ok()
+        );
+        for _ in 0..5 {
+            let transaction = bitcoin::Transaction {
+                version: bitcoin::transaction::Version::TWO,
+                lock_time: bitcoin::absolute::LockTime::ZERO,
+                input: vec![],
+                output: vec![],
+            };
+            assert!(
+                service
+                    .call(request(
+                        peer,
+                        Message::Transaction(transaction),
+                        &time_provider
+                    ))
+                    .await
+                    .is_ok()
+            );
+        }
+
+        // The limit resets in the next window
+        time_provider.set_since_epoch(1002);
+        assert!(
+            service
+                .call(request(peer, Message::NotFound(()), &time_provider))
+                .await
+                .is_ok()
+        );
+    }
+}
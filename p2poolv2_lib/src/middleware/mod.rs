@@ -15,3 +15,5 @@
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
 pub mod inactivity;
+pub mod message_rate_limit;
+pub mod peer_score;
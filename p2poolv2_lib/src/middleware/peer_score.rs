@@ -0,0 +1,579 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Peer reputation middleware.
+//!
+//! Errors from handling a peer's requests are classified into offences,
+//! each adding a penalty to the peer's misbehaviour score. Once a
+//! peer's score reaches the configured threshold the peer is banned
+//! for the configured duration and disconnected. Scores decay over
+//! time, so occasional offences by honest peers do not add up to a ban.
+//!
+//! Bans are persisted in the store so they survive restarts.
+
+use crate::config::NetworkConfig;
+use crate::middleware::message_rate_limit::RateLimitExceeded;
+use crate::node::SwarmSend;
+use crate::node::behaviour::request_response::CodecError;
+use crate::node::p2p_message_handlers::UnsolicitedMessage;
+use crate::service::p2p_service::RequestContext;
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::validation::ValidationError;
+use crate::utils::time_provider::TimeProvider;
+use futures::Future;
+use libp2p::PeerId;
+use std::collections::HashMap;
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::Sender;
+use tower::{Layer, Service};
+use tracing::{error, info, warn};
+
+/// Scores decay by one point for every SCORE_DECAY_SECS without offences
+const SCORE_DECAY_SECS: u64 = 60;
+
+/// Misbehaviour we penalise peers for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// Share does not meet its target or has unexpected bits
+    InvalidPow,
+    /// Share coinbase or merkle root does not commit to the share
+    BadCommitment,
+    /// Share breaks the uncle inclusion rules
+    InvalidUncles,
+    /// Share coinbase does not pay the expected PPLNS distribution
+    WrongPayout,
+    /// Transaction fails basic validity checks
+    InvalidTransaction,
+    /// Message could not be decoded
+    MalformedMessage,
+    /// Message checksum does not match its payload
+    ChecksumMismatch,
+    /// Message magic is for another network
+    WrongNetwork,
+    /// Message exceeds the size limit for its type
+    OversizedMessage,
+    /// Peer sent data we did not ask for
+    UnsolicitedData,
+    /// Peer sent more messages than its rate limit allows
+    RateLimitExceeded,
+    /// Sync peer did not deliver the chain work it announced
+    UndeliveredChainWork,
+    /// Peer sent requests before its version handshake
+    MissingHandshake,
+}
+
+impl Offence {
+    /// Score added to a peer for the offence
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Offence::InvalidPow => 50,
+            Offence::BadCommitment => 50,
+            Offence::InvalidUncles => 20,
+            Offence::WrongPayout => 25,
+            Offence::InvalidTransaction => 20,
+            Offence::MalformedMessage => 20,
+            Offence::ChecksumMismatch => 10,
+            Offence::WrongNetwork => 100,
+            Offence::OversizedMessage => 50,
+            Offence::UnsolicitedData => 10,
+            Offence::RateLimitExceeded => 5,
+            Offence::UndeliveredChainWork => 20,
+            Offence::MissingHandshake => 20,
+        }
+    }
+
+    /// Classify an error from handling a peer's message.
+    ///
+    /// Returns None for errors honest peers can run into, e.g. a share
+    /// whose parent we have not received yet.
+    pub fn from_error(error: &(dyn Error + Send + Sync + 'static)) -> Option<Self> {
+        if let Some(error) = error.downcast_ref::<ValidationError>() {
+            return match error {
+                ValidationError::InsufficientWork(_) | ValidationError::UnexpectedBits { .. } => {
+                    Some(Offence::InvalidPow)
+                }
+                ValidationError::MissingCoinbase
+                | ValidationError::CommitmentMismatch
+                | ValidationError::MerkleRootMismatch => Some(Offence::BadCommitment),
+                ValidationError::TooManyUncles
+                | ValidationError::DuplicateUncle(_)
+                | ValidationError::UncleOnChain(_)
+                | ValidationError::UncleTooDeep(_)
+                | ValidationError::UncleAlreadyIncluded(_) => Some(Offence::InvalidUncles),
+                ValidationError::PayoutMismatch { .. }
+                | ValidationError::CoinbaseCutsTooLarge(_) => Some(Offence::WrongPayout),
+                ValidationError::InvalidTransaction { .. } => Some(Offence::InvalidTransaction),
+                ValidationError::InvalidTimestamp { .. }
+                | ValidationError::PrevShareNotFound(_)
+                | ValidationError::UncleNotFound(_)
+                | ValidationError::ChainState(_) => None,
+            };
+        }
+        if error.downcast_ref::<UnsolicitedMessage>().is_some() {
+            return Some(Offence::UnsolicitedData);
+        }
+        if error.downcast_ref::<RateLimitExceeded>().is_some() {
+            return Some(Offence::RateLimitExceeded);
+        }
+        error
+            .downcast_ref::<std::io::Error>()
+            .and_then(Self::from_io_error)
+    }
+
+    /// Classify an io error from reading a peer's message
+    pub fn from_io_error(error: &std::io::Error) -> Option<Self> {
+        match CodecError::from_io_error(error)? {
+            CodecError::ChecksumMismatch => Some(Offence::ChecksumMismatch),
+            CodecError::Malformed(_) | CodecError::UnknownMessage(_) => {
+                Some(Offence::MalformedMessage)
+            }
+            CodecError::WrongMagic(_) => Some(Offence::WrongNetwork),
+            CodecError::TooLarge { .. } => Some(Offence::OversizedMessage),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PeerScoresState {
+    /// Score and the time it was last updated, for peers with offences
+    scores: HashMap<PeerId, (u32, u64)>,
+    /// Banned peers and the time their ban ends
+    banned: HashMap<PeerId, u64>,
+}
+
+/// Misbehaviour scores and bans, shared between the request service
+/// and the node. Times are in seconds since epoch.
+#[derive(Clone)]
+pub struct PeerScores {
+    state: Arc<Mutex<PeerScoresState>>,
+    store: Arc<ChainStore>,
+    ban_threshold: u32,
+    ban_duration_secs: u64,
+}
+
+impl PeerScores {
+    /// Create peer scores, loading bans that have not expired from the store
+    pub fn new(config: &NetworkConfig, store: Arc<ChainStore>, now: u64) -> Self {
+        let mut state = PeerScoresState::default();
+        match store.get_banned_peers() {
+            Ok(banned) => {
+                for (peer_id, until) in banned {
+                    match peer_id.parse::<PeerId>() {
+                        Ok(peer_id) if until > now => {
+                            state.banned.insert(peer_id, until);
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("Invalid banned peer id {}: {}", peer_id, e),
+                    }
+                }
+            }
+            Err(e) => error!("Failed to load banned peers: {}", e),
+        }
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            store,
+            ban_threshold: config.ban_threshold,
+            ban_duration_secs: config.ban_duration_secs,
+        }
+    }
+
+    /// Check if a peer is banned at `now`
+    pub fn is_banned(&self, peer: &PeerId, now: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        state.banned.get(peer).is_some_and(|until| *until > now)
+    }
+
+    /// Get the current misbehaviour score for a peer
+    pub fn score(&self, peer: &PeerId, now: u64) -> u32 {
+        let state = self.state.lock().unwrap();
+        state
+            .scores
+            .get(peer)
+            .map(|(score, updated)| decayed(*score, *updated, now))
+            .unwrap_or(0)
+    }
+
+    /// Add the penalty for an offence to the peer's score.
+    /// Returns true if the peer is banned as a result.
+    pub fn record_offence(&self, peer: PeerId, offence: Offence, now: u64) -> bool {
+        let score = {
+            let mut state = self.state.lock().unwrap();
+            let entry = state.scores.entry(peer).or_insert((0, now));
+            let score = decayed(entry.0, entry.1, now).saturating_add(offence.penalty());
+            *entry = (score, now);
+            score
+        };
+        info!("Peer {peer} penalised for {offence:?}, score {score}");
+        if score < self.ban_threshold {
+            return false;
+        }
+        if let Err(e) = self.ban(peer, now) {
+            error!("Failed to persist ban for peer {}: {}", peer, e);
+        }
+        true
+    }
+
+    /// Ban a peer for the configured ban duration
+    pub fn ban(&self, peer: PeerId, now: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let until = now.saturating_add(self.ban_duration_secs);
+        {
+            let mut state = self.state.lock().unwrap();
+            state.scores.remove(&peer);
+            state.banned.insert(peer, until);
+        }
+        info!("Banned peer {peer} until {until}");
+        self.store.ban_peer(&peer.to_string(), until)
+    }
+
+    /// Lift the ban on a peer and reset its score
+    pub fn unban(&self, peer: &PeerId) -> Result<(), Box<dyn Error + Send + Sync>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.scores.remove(peer);
+            state.banned.remove(peer);
+        }
+        info!("Unbanned peer {peer}");
+        self.store.unban_peer(&peer.to_string())
+    }
+
+    /// Peers banned at `now`, with the time their ban ends
+    pub fn banned_peers(&self, now: u64) -> Vec<(PeerId, u64)> {
+        let state = self.state.lock().unwrap();
+        state
+            .banned
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(peer, until)| (*peer, *until))
+            .collect()
+    }
+}
+
+fn decayed(score: u32, updated: u64, now: u64) -> u32 {
+    let decay = now.saturating_sub(updated) / SCORE_DECAY_SECS;
+    score.saturating_sub(decay.try_into().unwrap_or(u32::MAX))
+}
+
+/// Record an offence for the error from handling a peer's message, and
+/// disconnect the peer if it gets banned.
+pub async fn penalise_peer<C: Send + Sync + 'static>(
+    scores: &PeerScores,
+    peer: PeerId,
+    error: &(dyn Error + Send + Sync + 'static),
+    now: u64,
+    swarm_tx: &Sender<SwarmSend<C>>,
+) {
+    let Some(offence) = Offence::from_error(error) else {
+        return;
+    };
+    if scores.record_offence(peer, offence, now)
+        && let Err(e) = swarm_tx.send(SwarmSend::Disconnect(peer)).await
+    {
+        error!("Failed to send disconnect command for peer {peer}: {e}");
+    }
+}
+
+/// Layer that injects the PeerScoreService middleware into the stack.
+pub struct PeerScoreLayer<C> {
+    scores: PeerScores,
+    swarm_tx: Sender<SwarmSend<C>>,
+}
+
+impl<C> PeerScoreLayer<C> {
+    pub fn new(scores: PeerScores, swarm_tx: Sender<SwarmSend<C>>) -> Self {
+        Self { scores, swarm_tx }
+    }
+}
+
+impl<S, C> Layer<S> for PeerScoreLayer<C> {
+    type Service = PeerScoreService<S, C>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        PeerScoreService {
+            service,
+            scores: self.scores.clone(),
+            swarm_tx: self.swarm_tx.clone(),
+        }
+    }
+}
+
+/// Middleware that penalises peers for failed requests and drops
+/// requests from banned peers.
+pub struct PeerScoreService<S, C> {
+    service: S,
+    scores: PeerScores,
+    swarm_tx: Sender<SwarmSend<C>>,
+}
+
+impl<S, C, T> Service<RequestContext<C, T>> for PeerScoreService<S, C>
+where
+    S: Service<
+            RequestContext<C, T>,
+            Response = (),
+            Error = Box<dyn std::error::Error + Send + Sync>,
+        > + Send
+        + 'static,
+    S::Future: Send + 'static,
+    C: Send + Sync + 'static,
+    T: TimeProvider + Send + Sync + 'static,
+{
+    type Response = ();
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<(), Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: RequestContext<C, T>) -> Self::Future {
+        let peer = req.peer;
+        let now = req.time_provider.seconds_since_epoch();
+        let scores = self.scores.clone();
+        let swarm_tx = self.swarm_tx.clone();
+
+        if scores.is_banned(&peer, now) {
+            return Box::pin(async move {
+                let _ = swarm_tx.send(SwarmSend::Disconnect(peer)).await;
+                Err(format!("Ignoring request from banned peer {peer}").into())
+            });
+        }
+
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let result = future.await;
+            if let Err(e) = &result {
+                penalise_peer(&scores, peer, e.as_ref(), now, &swarm_tx).await;
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StratumConfig;
+    use crate::middleware::message_rate_limit::MessageKind;
+    use crate::node::messages::Message;
+    use crate::utils::time_provider::TestTimeProvider;
+    use bitcoin::BlockHash;
+    use bitcoin::hashes::Hash;
+    use mockall::predicate::*;
+    use std::time::SystemTime;
+    use tokio::sync::{mpsc, oneshot};
+
+    fn scores_with_store(store: ChainStore) -> PeerScores {
+        PeerScores::new(&NetworkConfig::default(), Arc::new(store), 1000)
+    }
+
+    #[test]
+    fn test_offence_from_error() {
+        let error: Box<dyn Error + Send + Sync> =
+            ValidationError::InsufficientWork(BlockHash::all_zeros()).into();
+        assert_eq!(
+            Offence::from_error(error.as_ref()),
+            Some(Offence::InvalidPow)
+        );
+
+        let error: Box<dyn Error + Send + Sync> = ValidationError::CommitmentMismatch.into();
+        assert_eq!(
+            Offence::from_error(error.as_ref()),
+            Some(Offence::BadCommitment)
+        );
+
+        let error: Box<dyn Error + Send + Sync> =
+            UnsolicitedMessage("ShareBlockTxns".to_string()).into();
+        assert_eq!(
+            Offence::from_error(error.as_ref()),
+            Some(Offence::UnsolicitedData)
+        );
+
+        let error: Box<dyn Error + Send + Sync> = RateLimitExceeded {
+            peer: PeerId::random(),
+            kind: MessageKind::Inventory,
+        }
+        .into();
+        assert_eq!(
+            Offence::from_error(error.as_ref()),
+            Some(Offence::RateLimitExceeded)
+        );
+
+        let error = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            CodecError::ChecksumMismatch,
+        );
+        assert_eq!(
+            Offence::from_io_error(&error),
+            Some(Offence::ChecksumMismatch)
+        );
+        let error = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            CodecError::Malformed("bad".to_string()),
+        );
+        assert_eq!(
+            Offence::from_io_error(&error),
+            Some(Offence::MalformedMessage)
+        );
+        let error = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            CodecError::WrongMagic([0; 4]),
+        );
+        assert_eq!(Offence::from_io_error(&error), Some(Offence::WrongNetwork));
+        let error = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            CodecError::TooLarge {
+                discriminant: None,
+                len: 10,
+                max: 1,
+            },
+        );
+        assert_eq!(
+            Offence::from_io_error(&error),
+            Some(Offence::OversizedMessage)
+        );
+
+        // Honest peers can send shares we can't connect yet
+        let error: Box<dyn Error + Send + Sync> =
+            ValidationError::PrevShareNotFound(BlockHash::all_zeros()).into();
+        assert_eq!(Offence::from_error(error.as_ref()), None);
+        let error: Box<dyn Error + Send + Sync> = "some failure".into();
+        assert_eq!(Offence::from_error(error.as_ref()), None);
+        let error = std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout");
+        assert_eq!(Offence::from_io_error(&error), None);
+    }
+
+    #[test]
+    fn test_scores_ban_over_threshold_and_decay() {
+        let peer = PeerId::random();
+        let mut store = ChainStore::default();
+        store.expect_get_banned_peers().returning(|| Ok(Vec::new()));
+        store
+            .expect_ban_peer()
+            .with(eq(peer.to_string()), eq(1120 + 24 * 60 * 60))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let scores = scores_with_store(store);
+
+        assert!(!scores.record_offence(peer, Offence::InvalidPow, 1000));
+        assert_eq!(scores.score(&peer, 1000), 50);
+
+        // Two minutes later the score has decayed by two points
+        assert_eq!(scores.score(&peer, 1120), 48);
+        assert!(!scores.record_offence(peer, Offence::ChecksumMismatch, 1120));
+        assert_eq!(scores.score(&peer, 1120), 58);
+
+        assert!(scores.record_offence(peer, Offence::BadCommitment, 1120));
+        assert!(scores.is_banned(&peer, 1120));
+        assert!(!scores.is_banned(&peer, 1120 + 24 * 60 * 60));
+        assert_eq!(scores.banned_peers(1120), vec![(peer, 1120 + 24 * 60 * 60)]);
+    }
+
+    #[test]
+    fn test_scores_load_and_unban() {
+        let banned = PeerId::random();
+        let expired = PeerId::random();
+        let mut store = ChainStore::default();
+        store.expect_get_banned_peers().returning(move || {
+            Ok(vec![
+                (banned.to_string(), 2000),
+                (expired.to_string(), 500),
+                ("not a peer".to_string(), 2000),
+            ])
+        });
+        store
+            .expect_unban_peer()
+            .with(eq(banned.to_string()))
+            .times(1)
+            .returning(|_| Ok(()));
+        let scores = scores_with_store(store);
+
+        assert!(scores.is_banned(&banned, 1000));
+        assert!(!scores.is_banned(&expired, 1000));
+
+        scores.unban(&banned).unwrap();
+        assert!(!scores.is_banned(&banned, 1000));
+    }
+
+    #[tokio::test]
+    async fn test_service_penalises_and_drops_banned_peer() {
+        let peer = PeerId::random();
+        let mut store = ChainStore::default();
+        store.expect_get_banned_peers().returning(|| Ok(Vec::new()));
+        store.expect_ban_peer().returning(|_, _| Ok(()));
+        let scores = scores_with_store(store);
+
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<oneshot::Sender<Message>>>(8);
+        let mut service =
+            PeerScoreLayer::new(scores.clone(), swarm_tx.clone()).layer(tower::service_fn(
+                |_req: RequestContext<oneshot::Sender<Message>, TestTimeProvider>| async {
+                    Err::<(), Box<dyn Error + Send + Sync>>(
+                        ValidationError::PayoutMismatch {
+                            address: StratumConfig::new_for_test_default()
+                                .parse()
+                                .unwrap()
+                                .bootstrap_address()
+                                .clone(),
+                            expected: bitcoin::Amount::from_sat(1000),
+                            actual: bitcoin::Amount::from_sat(10),
+                        }
+                        .into(),
+                    )
+                },
+            ));
+
+        let request = |peer| {
+            let (response_channel, _) = oneshot::channel::<Message>();
+            RequestContext {
+                peer,
+                request: Message::NotFound(()),
+                store: Arc::new(ChainStore::default()),
+                response_channel,
+                swarm_tx: swarm_tx.clone(),
+                time_provider: TestTimeProvider::new(SystemTime::now()),
+                stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
+            }
+        };
+
+        let now = || {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+
+        // A single wrong payout is penalised without a ban
+        assert!(service.call(request(peer)).await.is_err());
+        assert!(swarm_rx.try_recv().is_err());
+        assert!(!scores.is_banned(&peer, now()));
+
+        // Repeated wrong payouts get the peer banned and disconnected
+        for _ in 0..3 {
+            assert!(service.call(request(peer)).await.is_err());
+        }
+        assert!(matches!(swarm_rx.try_recv(), Ok(SwarmSend::Disconnect(p)) if p == peer));
+        assert!(scores.is_banned(&peer, now()));
+
+        // Further requests from the banned peer are dropped
+        assert!(service.call(request(peer)).await.is_err());
+        assert!(matches!(swarm_rx.try_recv(), Ok(SwarmSend::Disconnect(p)) if p == peer));
+    }
+}
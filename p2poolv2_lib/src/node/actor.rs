@@ -20,6 +20,9 @@ use crate::command::Command;
 use crate::config::Config;
 use crate::node::Node;
 use crate::node::SwarmSend;
+use crate::node::messages::{GetData, Message};
+use crate::node::reachability::ReachabilityHandle;
+use crate::node::sync::SyncStatusHandle;
 #[cfg(test)]
 #[mockall_double::double]
 use crate::shares::chain::chain_store::ChainStore;
@@ -30,15 +33,23 @@ use crate::stratum::emission::EmissionReceiver;
 use libp2p::futures::StreamExt;
 use std::error::Error;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info};
 
+/// How often the node announces tip changes and checks if it is behind its peers
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
 /// NodeHandle provides an interface to interact with a Node running in a separate task
 #[derive(Clone)]
 #[allow(dead_code)]
 pub struct NodeHandle {
     // The channel to send commands to the Node Actor
     command_tx: mpsc::Sender<Command>,
+    // Sync status updated by the Node Actor
+    sync_status: SyncStatusHandle,
+    // Reachability updated by the Node Actor
+    reachability: ReachabilityHandle,
 }
 
 #[allow(dead_code)]
@@ -52,6 +63,9 @@ impl NodeHandle {
     ) -> Result<(Self, oneshot::Receiver<()>), Box<dyn Error + Send + Sync>> {
         let (command_tx, command_rx) = mpsc::channel::<Command>(32);
         let (node_actor, stopping_rx) = NodeActor::new(config, store.clone(), command_rx).unwrap();
+        let swarm_tx = node_actor.node.swarm_tx.clone();
+        let sync_status = node_actor.node.sync.status();
+        let reachability = node_actor.node.reachability();
 
         tokio::spawn(async move {
             node_actor.run().await;
@@ -60,10 +74,27 @@ impl NodeHandle {
         let store_clone = store.clone();
         let metrics_clone = metrics.clone();
         tokio::spawn(async move {
-            handle_stratum_shares(emissions_rx, store_clone, metrics_clone).await;
+            handle_stratum_shares(emissions_rx, store_clone, metrics_clone, swarm_tx).await;
         });
 
-        Ok((Self { command_tx }, stopping_rx))
+        Ok((
+            Self {
+                command_tx,
+                sync_status,
+                reachability,
+            },
+            stopping_rx,
+        ))
+    }
+
+    /// Handle to read the node's share chain sync status
+    pub fn sync_status(&self) -> SyncStatusHandle {
+        self.sync_status.clone()
+    }
+
+    /// Handle to read whether peers can reach the node
+    pub fn reachability(&self) -> ReachabilityHandle {
+        self.reachability.clone()
     }
 
     /// Get a list of connected peers
@@ -76,6 +107,15 @@ impl NodeHandle {
         }
     }
 
+    /// Broadcast a share block or transaction to the network over gossipsub
+    pub async fn send_gossip(&self, message: Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::SendGossip(message, tx))
+            .await?;
+        rx.await?
+    }
+
     /// Shutdown the node
     pub async fn shutdown(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
         let (tx, rx) = oneshot::channel();
@@ -98,14 +138,42 @@ impl NodeHandle {
             .await;
         rx.await.unwrap_or_default()
     }
+
+    /// Ban a peer and disconnect it
+    pub async fn ban_peer(
+        &self,
+        peer_id: libp2p::PeerId,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx.send(Command::BanPeer(peer_id, tx)).await?;
+        rx.await?
+    }
+
+    /// Lift the ban on a peer
+    pub async fn unban_peer(
+        &self,
+        peer_id: libp2p::PeerId,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx
+            .send(Command::UnbanPeer(peer_id, tx))
+            .await?;
+        rx.await?
+    }
+
+    /// Get banned peers with the unix timestamp their ban ends
+    pub async fn get_banned_peers(
+        &self,
+    ) -> Result<Vec<(libp2p::PeerId, u64)>, Box<dyn Error + Send + Sync>> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx.send(Command::GetBannedPeers(tx)).await?;
+        Ok(rx.await?)
+    }
 }
 
 #[cfg(test)]
 use mockall::mock;
 
-#[cfg(test)]
-use crate::node::messages::Message;
-
 #[cfg(test)]
 mock! {
     pub NodeHandle {
@@ -129,6 +197,8 @@ struct NodeActor {
     node: Node,
     command_rx: mpsc::Receiver<Command>,
     stopping_tx: oneshot::Sender<()>,
+    /// How often to look for new peers
+    discovery_interval: Duration,
 }
 
 impl NodeActor {
@@ -144,27 +214,52 @@ impl NodeActor {
                 node,
                 command_rx,
                 stopping_tx,
+                discovery_interval: Duration::from_secs(config.network.discovery_interval_secs),
             },
             stopping_rx,
         ))
     }
 
     async fn run(mut self) {
+        let mut sync_interval = tokio::time::interval(SYNC_INTERVAL);
+        let mut discovery_interval = tokio::time::interval(self.discovery_interval);
         loop {
             tokio::select! {
+                _ = sync_interval.tick() => {
+                    self.node.sync_tick().await;
+                },
+                _ = discovery_interval.tick() => {
+                    self.node.discovery_tick();
+                },
                 buf = self.node.swarm_rx.recv() => {
                     match buf {
                         Some(SwarmSend::Request(peer_id, msg)) => {
+                            let msg = self.node.handshakes.gate_message(&peer_id, msg);
+                            let requested_txid = match &msg {
+                                Message::GetData(GetData::Txid(txid)) => Some(*txid),
+                                _ => None,
+                            };
                             let request_id =    self.node.swarm.behaviour_mut().request_response.send_request(&peer_id, msg);
+                            if let Some(txid) = requested_txid {
+                                self.node.transaction_requests.insert(request_id, txid);
+                            }
                             debug!("Sent message to peer: {peer_id}, request_id: {request_id}");
                         }
                         Some(SwarmSend::Response(response_channel, msg)) => {
                             let request_id = self.node.swarm.behaviour_mut().request_response.send_response(response_channel, msg);
                             debug!("Sent message to response channel: {:?}", request_id);
                         }
-                        Some(SwarmSend::Inv(_share_block)) => {
-                            // Handle inventory message (optional logging or processing)
-                            tracing::info!("Received SwarmSend::Inv message");
+                        Some(SwarmSend::Inv(share_block)) => {
+                            let blockhash = share_block.block_hash();
+                            self.node.broadcast_share(share_block);
+                            self.node.process_orphans(blockhash).await;
+                            self.node.sync_tick().await;
+                        }
+                        Some(SwarmSend::Orphan(peer_id, share_block, missing)) => {
+                            self.node.add_orphan(peer_id, share_block, missing).await;
+                        }
+                        Some(SwarmSend::DownloadShares(blockhashes)) => {
+                            self.node.queue_share_downloads(blockhashes);
                         }
                         Some(SwarmSend::Disconnect(peer_id)) => {
                             if let Err(_e) = self.node.swarm.disconnect_peer_id(peer_id) {
@@ -191,6 +286,9 @@ impl NodeActor {
                             let peers = self.node.swarm.connected_peers().cloned().collect::<Vec<_>>();
                             tx.send(peers).unwrap();
                         },
+                        Some(Command::SendGossip(message, tx)) => {
+                            let _ = tx.send(self.node.publish(message));
+                        },
                         Some(Command::SendToPeer(peer_id, message, tx)) => {
                             match self.node.send_to_peer(peer_id, message) {
                                 Ok(_) => tx.send(Ok(())).unwrap(),
@@ -210,6 +308,15 @@ impl NodeActor {
                             let result = self.node.handle_get_pplns_shares(query);
                             let _ = tx.send(result);
                         },
+                        Some(Command::BanPeer(peer_id, tx)) => {
+                            let _ = tx.send(self.node.ban_peer(peer_id));
+                        },
+                        Some(Command::UnbanPeer(peer_id, tx)) => {
+                            let _ = tx.send(self.node.unban_peer(&peer_id));
+                        },
+                        Some(Command::GetBannedPeers(tx)) => {
+                            let _ = tx.send(self.node.banned_peers());
+                        },
                         None => {
                             info!("Stopping node actor on channel close");
                             self.stopping_tx.send(()).unwrap();
@@ -232,7 +339,11 @@ mod tests {
     #[tokio::test]
     async fn test_node_handle_get_pplns_shares_sends_correct_command() {
         let (command_tx, mut command_rx) = mpsc::channel(32);
-        let node_handle = NodeHandle { command_tx };
+        let node_handle = NodeHandle {
+            command_tx,
+            sync_status: SyncStatusHandle::default(),
+            reachability: ReachabilityHandle::default(),
+        };
 
         let query = GetPplnsShareQuery {
             limit: 42,
@@ -278,7 +389,11 @@ mod tests {
         let (command_tx, command_rx) = mpsc::channel(1);
         drop(command_rx); // Close the receiver to cause send error
 
-        let node_handle = NodeHandle { command_tx };
+        let node_handle = NodeHandle {
+            command_tx,
+            sync_status: SyncStatusHandle::default(),
+            reachability: ReachabilityHandle::default(),
+        };
 
         let query = GetPplnsShareQuery {
             limit: 10,
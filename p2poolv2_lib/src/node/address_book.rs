@@ -0,0 +1,111 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::store::peer_book::PeerRecord;
+use libp2p::{Multiaddr, PeerId};
+
+/// Pick peers from the address book to redial on startup.
+///
+/// Peers with the best connection record come first, ties broken by
+/// the most recently seen. Entries that don't parse or have no usable
+/// addresses are skipped, as is our own peer id.
+pub fn peers_to_redial(
+    mut records: Vec<(String, PeerRecord)>,
+    local_peer_id: &PeerId,
+    max_peers: usize,
+) -> Vec<(PeerId, Vec<Multiaddr>)> {
+    records.sort_by(|(_, a), (_, b)| {
+        b.score()
+            .cmp(&a.score())
+            .then_with(|| b.last_seen.cmp(&a.last_seen))
+    });
+    records
+        .into_iter()
+        .filter_map(|(peer_id, record)| {
+            let peer_id = peer_id.parse::<PeerId>().ok()?;
+            if peer_id == *local_peer_id {
+                return None;
+            }
+            let addresses: Vec<Multiaddr> = record
+                .addresses
+                .iter()
+                .filter_map(|address| address.parse().ok())
+                .collect();
+            if addresses.is_empty() {
+                None
+            } else {
+                Some((peer_id, addresses))
+            }
+        })
+        .take(max_peers)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(addresses: &[&str], last_seen: u64, successes: u32, failures: u32) -> PeerRecord {
+        PeerRecord {
+            addresses: addresses.iter().map(|a| a.to_string()).collect(),
+            last_seen,
+            successes,
+            failures,
+        }
+    }
+
+    #[test]
+    fn test_peers_to_redial() {
+        let local = PeerId::random();
+        let reliable = PeerId::random();
+        let recent = PeerId::random();
+        let stale = PeerId::random();
+        let unreachable = PeerId::random();
+
+        let records = vec![
+            (
+                stale.to_string(),
+                record(&["/ip4/10.0.0.3/tcp/6884"], 100, 1, 0),
+            ),
+            (
+                reliable.to_string(),
+                record(&["/ip4/10.0.0.1/tcp/6884", "not an address"], 100, 5, 1),
+            ),
+            (
+                recent.to_string(),
+                record(&["/ip4/10.0.0.2/tcp/6884"], 200, 1, 0),
+            ),
+            (unreachable.to_string(), record(&[], 300, 9, 0)),
+            (
+                local.to_string(),
+                record(&["/ip4/127.0.0.1/tcp/6884"], 300, 9, 0),
+            ),
+            (
+                "not a peer id".to_string(),
+                record(&["/ip4/10.0.0.4/tcp/6884"], 300, 9, 0),
+            ),
+        ];
+
+        let peers = peers_to_redial(records.clone(), &local, 8);
+        let peer_ids: Vec<PeerId> = peers.iter().map(|(peer_id, _)| *peer_id).collect();
+        assert_eq!(peer_ids, vec![reliable, recent, stale]);
+        assert_eq!(peers[0].1, vec!["/ip4/10.0.0.1/tcp/6884".parse().unwrap()]);
+
+        let peers = peers_to_redial(records, &local, 1);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].0, reliable);
+    }
+}
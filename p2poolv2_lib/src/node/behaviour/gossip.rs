@@ -0,0 +1,148 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Gossipsub broadcast of new shares and transactions.
+//!
+//! Each network has its own topics, named after its network magic, so
+//! nodes on different networks never exchange shares. Gossip payloads
+//! are RawMessages, the same encoding we use for request-response.
+//! Shares are gossiped as compact share blocks, receivers fetch any
+//! transactions they are missing from the peer that forwarded the share.
+//!
+//! Message ids are the share blockhash or txid, so the same share
+//! received from several peers is only processed once. Messages are
+//! only forwarded after the node has validated them and reported the
+//! result to gossipsub.
+
+use crate::node::messages::{Message, RawMessage};
+use bitcoin::consensus::encode;
+use bitcoin::hashes::{Hash, sha256};
+use libp2p::gossipsub::{self, IdentTopic, MessageAuthenticity, MessageId, ValidationMode};
+use libp2p::identity::Keypair;
+use std::error::Error;
+use std::time::Duration;
+
+/// Largest gossip message we send or accept
+pub const MAX_GOSSIP_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Topic for new share blocks on the network with `magic`
+pub fn shares_topic(magic: [u8; 4]) -> IdentTopic {
+    IdentTopic::new(format!("/p2pool/{}/shares", hex::encode(magic)))
+}
+
+/// Topic for transactions on the network with `magic`
+pub fn transactions_topic(magic: [u8; 4]) -> IdentTopic {
+    IdentTopic::new(format!("/p2pool/{}/transactions", hex::encode(magic)))
+}
+
+/// Derive the message id from the share blockhash or txid of the
+/// gossiped message, falling back to a hash of the payload for
+/// messages we can't decode.
+fn message_id(message: &gossipsub::Message) -> MessageId {
+    match decode_gossip(&message.data).map(RawMessage::into_payload) {
+        Ok(Message::CompactShareBlock(compact)) => {
+            MessageId::from(compact.block_hash().to_byte_array().to_vec())
+        }
+        Ok(Message::Transaction(transaction)) => {
+            MessageId::from(transaction.compute_txid().to_byte_array().to_vec())
+        }
+        _ => MessageId::from(sha256::Hash::hash(&message.data).to_byte_array().to_vec()),
+    }
+}
+
+/// Encode a message for gossip
+pub fn encode_gossip(magic: [u8; 4], message: Message) -> Vec<u8> {
+    encode::serialize(&RawMessage::new(magic, message))
+}
+
+/// Decode a gossip payload, verifying its checksum
+pub fn decode_gossip(data: &[u8]) -> Result<RawMessage, encode::Error> {
+    encode::deserialize(data)
+}
+
+/// Build the gossipsub behaviour and subscribe to the topics for the network
+pub fn build_gossipsub(
+    local_key: &Keypair,
+    magic: [u8; 4],
+) -> Result<gossipsub::Behaviour, Box<dyn Error>> {
+    let config = gossipsub::ConfigBuilder::default()
+        .heartbeat_interval(Duration::from_secs(1))
+        .validation_mode(ValidationMode::Strict)
+        // Hold messages until the node validates them
+        .validate_messages()
+        .message_id_fn(message_id)
+        .max_transmit_size(MAX_GOSSIP_MESSAGE_SIZE)
+        .build()?;
+    let mut gossipsub =
+        gossipsub::Behaviour::new(MessageAuthenticity::Signed(local_key.clone()), config)?;
+    gossipsub.subscribe(&shares_topic(magic))?;
+    gossipsub.subscribe(&transactions_topic(magic))?;
+    Ok(gossipsub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::messages::network_magic;
+    use crate::shares::compact_block::CompactShareBlock;
+    use crate::test_utils::TestShareBlockBuilder;
+
+    fn gossip_message(data: Vec<u8>) -> gossipsub::Message {
+        gossipsub::Message {
+            source: None,
+            data,
+            sequence_number: None,
+            topic: shares_topic(network_magic::REGTEST).hash(),
+        }
+    }
+
+    #[test]
+    fn test_topics_are_per_network() {
+        assert_ne!(
+            shares_topic(network_magic::MAINNET).hash(),
+            shares_topic(network_magic::TESTNET).hash()
+        );
+        assert_ne!(
+            shares_topic(network_magic::REGTEST).hash(),
+            transactions_topic(network_magic::REGTEST).hash()
+        );
+    }
+
+    #[test]
+    fn test_message_id_from_blockhash() {
+        let share_block = TestShareBlockBuilder::new().build();
+        let compact = CompactShareBlock::from_share_block(&share_block).unwrap();
+        let data = encode_gossip(
+            network_magic::REGTEST,
+            Message::CompactShareBlock(compact.clone()),
+        );
+
+        assert_eq!(
+            message_id(&gossip_message(data.clone())),
+            MessageId::from(share_block.block_hash().to_byte_array().to_vec())
+        );
+
+        let decoded = decode_gossip(&data).unwrap();
+        assert_eq!(decoded.magic(), &network_magic::REGTEST);
+        assert_eq!(decoded.into_payload(), Message::CompactShareBlock(compact));
+
+        // Undecodable payloads still get an id
+        assert_eq!(
+            message_id(&gossip_message(vec![1, 2, 3])),
+            message_id(&gossip_message(vec![1, 2, 3]))
+        );
+    }
+}
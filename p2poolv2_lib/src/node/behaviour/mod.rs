@@ -14,15 +14,19 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+pub mod gossip;
 pub mod request_response;
 use crate::config::Config;
 use crate::node::messages::network_magic;
 use libp2p::connection_limits;
+use libp2p::gossipsub;
 use libp2p::request_response::ProtocolSupport;
+use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::{
-    Multiaddr, PeerId, identify,
+    Multiaddr, PeerId, autonat, dcutr, identify,
     identity::Keypair,
     kad::{self, store::MemoryStore},
+    relay,
     swarm::NetworkBehaviour,
 };
 use request_response::{ConsensusCodec, P2PoolRequestResponseProtocol};
@@ -37,7 +41,12 @@ pub struct P2PoolBehaviour {
     pub kademlia: kad::Behaviour<MemoryStore>,
     pub identify: identify::Behaviour,
     pub request_response: RequestResponseBehaviour,
+    pub gossipsub: gossipsub::Behaviour,
     pub limits: connection_limits::Behaviour,
+    pub autonat: Toggle<autonat::Behaviour>,
+    pub relay_client: relay::client::Behaviour,
+    pub relay_server: Toggle<relay::Behaviour>,
+    pub dcutr: Toggle<dcutr::Behaviour>,
 }
 
 /// The interval at which the node will send heartbeat messages to peers
@@ -51,16 +60,26 @@ pub enum P2PoolBehaviourEvent {
     Kademlia(kad::Event),
     Identify(identify::Event),
     RequestResponse(RequestResponseEvent),
+    Gossipsub(gossipsub::Event),
+    Autonat(autonat::Event),
+    RelayClient(relay::client::Event),
+    RelayServer(relay::Event),
+    Dcutr(dcutr::Event),
 }
 
 #[allow(dead_code)]
 impl P2PoolBehaviour {
-    pub fn new(local_key: &Keypair, config: &Config) -> Result<Self, Box<dyn Error>> {
+    pub fn new(
+        local_key: &Keypair,
+        config: &Config,
+        relay_client: relay::client::Behaviour,
+    ) -> Result<Self, Box<dyn Error>> {
+        let local_peer_id = local_key.public().to_peer_id();
+
         // Initialize Kademlia
-        let store = MemoryStore::new(local_key.public().to_peer_id());
-        let mut kad_config = kad::Config::default();
+        let store = MemoryStore::new(local_peer_id);
+        let mut kad_config = kad::Config::new(libp2p::StreamProtocol::new("/p2pool/kad/1.0.0"));
         kad_config.set_query_timeout(tokio::time::Duration::from_secs(60));
-        kad_config.set_protocol_names(vec![libp2p::StreamProtocol::new("/p2pool/kad/1.0.0")]);
 
         let kademlia_behaviour =
             kad::Behaviour::with_config(local_key.public().to_peer_id(), store, kad_config);
@@ -79,13 +98,8 @@ impl P2PoolBehaviour {
         let limits = connection_limits::Behaviour::new(limits_config);
 
         // Select the appropriate network magic based on the bitcoin network
-        let magic = match config.stratum.network {
-            bitcoin::Network::Bitcoin => network_magic::MAINNET,
-            bitcoin::Network::Testnet => network_magic::TESTNET,
-            bitcoin::Network::Signet => network_magic::SIGNET,
-            bitcoin::Network::Regtest => network_magic::REGTEST,
-            _ => network_magic::REGTEST, // Default to regtest for unknown networks
-        };
+        let magic = network_magic::for_network(config.stratum.network)
+            .ok_or_else(|| format!("No network magic for {}", config.stratum.network))?;
 
         let codec = ConsensusCodec::new(magic);
 
@@ -97,7 +111,24 @@ impl P2PoolBehaviour {
                 std::iter::once((P2PoolRequestResponseProtocol::new(), ProtocolSupport::Full)),
                 libp2p::request_response::Config::default(),
             ),
+            gossipsub: gossip::build_gossipsub(local_key, magic)?,
             limits,
+            autonat: config
+                .network
+                .enable_autonat
+                .then(|| autonat::Behaviour::new(local_peer_id, autonat::Config::default()))
+                .into(),
+            relay_client,
+            relay_server: config
+                .network
+                .enable_relay_server
+                .then(|| relay::Behaviour::new(local_peer_id, relay::Config::default()))
+                .into(),
+            dcutr: config
+                .network
+                .enable_relay_client
+                .then(|| dcutr::Behaviour::new(local_peer_id))
+                .into(),
         };
 
         Ok(behaviour)
@@ -127,6 +158,36 @@ impl From<identify::Event> for P2PoolBehaviourEvent {
     }
 }
 
+impl From<gossipsub::Event> for P2PoolBehaviourEvent {
+    fn from(event: gossipsub::Event) -> Self {
+        P2PoolBehaviourEvent::Gossipsub(event)
+    }
+}
+
+impl From<autonat::Event> for P2PoolBehaviourEvent {
+    fn from(event: autonat::Event) -> Self {
+        P2PoolBehaviourEvent::Autonat(event)
+    }
+}
+
+impl From<relay::client::Event> for P2PoolBehaviourEvent {
+    fn from(event: relay::client::Event) -> Self {
+        P2PoolBehaviourEvent::RelayClient(event)
+    }
+}
+
+impl From<relay::Event> for P2PoolBehaviourEvent {
+    fn from(event: relay::Event) -> Self {
+        P2PoolBehaviourEvent::RelayServer(event)
+    }
+}
+
+impl From<dcutr::Event> for P2PoolBehaviourEvent {
+    fn from(event: dcutr::Event) -> Self {
+        P2PoolBehaviourEvent::Dcutr(event)
+    }
+}
+
 impl From<RequestResponseEvent> for P2PoolBehaviourEvent {
     fn from(event: RequestResponseEvent) -> Self {
         P2PoolBehaviourEvent::RequestResponse(event)
@@ -16,11 +16,12 @@
 
 use async_trait::async_trait;
 use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::{Hash, sha256d};
 use libp2p::futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use libp2p::request_response::{Codec, OutboundFailure};
 use std::io;
 
-use crate::node::messages::{Message, RawMessage, network_magic};
+use crate::node::messages::{MAX_PAYLOAD_LEN, Message, RawMessage, max_payload_len, network_magic};
 
 // Protocol name for our request-response protocol
 #[derive(Debug, Clone)]
@@ -73,20 +74,63 @@ impl ConsensusCodec {
         let mut header_bytes = [0u8; 12];
         io.read_exact(&mut header_bytes).await?;
 
+        let magic = [
+            header_bytes[0],
+            header_bytes[1],
+            header_bytes[2],
+            header_bytes[3],
+        ];
+        if magic != self.magic {
+            return Err(invalid_data(CodecError::WrongMagic(magic)));
+        }
+
         // Parse payload length from header
         let payload_len = u32::from_le_bytes([
             header_bytes[4],
             header_bytes[5],
             header_bytes[6],
             header_bytes[7],
-        ]);
+        ]) as usize;
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(invalid_data(CodecError::TooLarge {
+                discriminant: None,
+                len: payload_len,
+                max: MAX_PAYLOAD_LEN,
+            }));
+        }
+        if payload_len == 0 {
+            return Err(invalid_data(CodecError::Malformed(
+                "Empty payload".to_string(),
+            )));
+        }
 
-        // Read exactly payload_len bytes
-        let mut payload_bytes = vec![0u8; payload_len as usize];
+        // The first payload byte is the message discriminant, check the
+        // length against the limit for the message before reading the rest
+        let mut payload_bytes = vec![0u8; 1];
         io.read_exact(&mut payload_bytes).await?;
+        let discriminant = payload_bytes[0];
+        let max = max_payload_len(discriminant)
+            .ok_or(invalid_data(CodecError::UnknownMessage(discriminant)))?;
+        if payload_len > max {
+            return Err(invalid_data(CodecError::TooLarge {
+                discriminant: Some(discriminant),
+                len: payload_len,
+                max,
+            }));
+        }
+
+        // Read the remaining payload_len bytes
+        payload_bytes.resize(payload_len, 0);
+        io.read_exact(&mut payload_bytes[1..]).await?;
+
+        // Verify checksum: first 4 bytes of SHA256d(payload)
+        let hash = sha256d::Hash::hash(&payload_bytes);
+        if hash[..4] != header_bytes[8..12] {
+            return Err(invalid_data(CodecError::ChecksumMismatch));
+        }
 
         let message = Message::consensus_decode(&mut &payload_bytes[..])
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            .map_err(|e| invalid_data(CodecError::Malformed(e.to_string())))?;
 
         Ok(message)
     }
@@ -157,6 +201,38 @@ impl Codec for ConsensusCodec {
 pub type RequestResponseBehaviour = libp2p::request_response::Behaviour<ConsensusCodec>;
 pub type RequestResponseEvent = libp2p::request_response::Event<Message, Message>;
 
+/// Errors reading a message from a peer, carried inside the io::Error
+/// returned by the codec so the node can tell misbehaving peers apart
+/// from network failures.
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("Message for another network, magic {0:02x?}")]
+    WrongMagic([u8; 4]),
+    #[error("Message of {len} bytes exceeds the limit of {max} bytes for message {discriminant:?}")]
+    TooLarge {
+        discriminant: Option<u8>,
+        len: usize,
+        max: usize,
+    },
+    #[error("Unknown message {0}")]
+    UnknownMessage(u8),
+    #[error("Checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Malformed message: {0}")]
+    Malformed(String),
+}
+
+fn invalid_data(error: CodecError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+impl CodecError {
+    /// Find the codec error inside an io error, if there is one
+    pub fn from_io_error(error: &io::Error) -> Option<&CodecError> {
+        error.get_ref()?.downcast_ref::<CodecError>()
+    }
+}
+
 // Error type for request-response failures
 #[derive(Debug, thiserror::Error)]
 pub enum RequestResponseError {
@@ -166,3 +242,109 @@ pub enum RequestResponseError {
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::futures::io::Cursor;
+
+    #[tokio::test]
+    async fn test_read_message_checks_checksum() {
+        let codec = ConsensusCodec::default();
+        let mut bytes = Vec::new();
+        RawMessage::new(network_magic::REGTEST, Message::NotFound(()))
+            .consensus_encode(&mut bytes)
+            .unwrap();
+
+        let message = codec
+            .read_message(&mut Cursor::new(bytes.clone()))
+            .await
+            .unwrap();
+        assert_eq!(message, Message::NotFound(()));
+
+        // Corrupt the checksum
+        bytes[8] ^= 0xff;
+        let error = codec
+            .read_message(&mut Cursor::new(bytes))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            CodecError::from_io_error(&error),
+            Some(CodecError::ChecksumMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_message_rejects_wrong_magic() {
+        let codec = ConsensusCodec::new(network_magic::MAINNET);
+        let mut bytes = Vec::new();
+        RawMessage::new(network_magic::TESTNET, Message::NotFound(()))
+            .consensus_encode(&mut bytes)
+            .unwrap();
+
+        let error = codec
+            .read_message(&mut Cursor::new(bytes))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            CodecError::from_io_error(&error),
+            Some(CodecError::WrongMagic(magic)) if *magic == network_magic::TESTNET
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_message_enforces_size_limits() {
+        let codec = ConsensusCodec::default();
+
+        // Header claiming a huge payload is rejected without reading it
+        let mut bytes = network_magic::REGTEST.to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 4]);
+        let error = codec
+            .read_message(&mut Cursor::new(bytes))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            CodecError::from_io_error(&error),
+            Some(CodecError::TooLarge {
+                discriminant: None,
+                ..
+            })
+        ));
+
+        // A VerAck with a payload after its discriminant is over the VerAck limit
+        let mut verack = Vec::new();
+        Message::VerAck(()).consensus_encode(&mut verack).unwrap();
+        let mut bytes = network_magic::REGTEST.to_vec();
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 4]);
+        bytes.extend_from_slice(&verack);
+        bytes.push(0);
+        let error = codec
+            .read_message(&mut Cursor::new(bytes))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            CodecError::from_io_error(&error),
+            Some(CodecError::TooLarge {
+                discriminant: Some(_),
+                len: 2,
+                max: 1,
+            })
+        ));
+
+        // Unknown messages are rejected
+        let mut bytes = network_magic::REGTEST.to_vec();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&[0u8; 4]);
+        bytes.push(200);
+        let error = codec
+            .read_message(&mut Cursor::new(bytes))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            CodecError::from_io_error(&error),
+            Some(CodecError::UnknownMessage(200))
+        ));
+    }
+}
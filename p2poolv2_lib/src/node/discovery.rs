@@ -0,0 +1,271 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Peer discovery.
+//!
+//! Peers are found from the configured bootstrap addresses and DNS
+//! seeds, and from Kademlia random walks. The node dials discovered
+//! peers until it has the target number of outbound connections.
+//!
+//! To make it hard for an attacker to eclipse the node with many
+//! peers from address space it controls, outbound peers are spread
+//! across network groups (/16 for IPv4, /32 for IPv6), with a limit
+//! on outbound peers from the same group.
+
+use libp2p::multiaddr::Protocol;
+use libp2p::{Multiaddr, PeerId};
+use std::collections::{HashMap, HashSet};
+
+/// Network group of an address, peers in the same group are likely
+/// run by the same operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetGroup {
+    V4([u8; 2]),
+    V6([u8; 4]),
+}
+
+impl NetGroup {
+    /// Network group of the first IP address in a multiaddr. DNS
+    /// names have no group until they are resolved.
+    pub fn of(address: &Multiaddr) -> Option<Self> {
+        address.iter().find_map(|protocol| match protocol {
+            Protocol::Ip4(ip) => {
+                let octets = ip.octets();
+                Some(NetGroup::V4([octets[0], octets[1]]))
+            }
+            Protocol::Ip6(ip) => {
+                let octets = ip.octets();
+                Some(NetGroup::V6([octets[0], octets[1], octets[2], octets[3]]))
+            }
+            _ => None,
+        })
+    }
+}
+
+/// Multiaddr to dial for a DNS seed name. The dns transport resolves
+/// the seed's dnsaddr TXT records to peer addresses.
+pub fn dns_seed_address(seed: &str) -> Option<Multiaddr> {
+    format!("/dnsaddr/{seed}").parse().ok()
+}
+
+/// Tracks discovered peers and our outbound connections
+#[derive(Debug)]
+pub struct Discovery {
+    target_outbound: usize,
+    max_per_group: usize,
+    /// Peers we have learned addresses for
+    candidates: HashMap<PeerId, Vec<Multiaddr>>,
+    /// Outbound peers and the network group we connected to
+    outbound: HashMap<PeerId, Option<NetGroup>>,
+    /// Peers we are dialing and their network group
+    dialing: HashMap<PeerId, Option<NetGroup>>,
+}
+
+impl Discovery {
+    pub fn new(target_outbound: usize, max_per_group: usize) -> Self {
+        Self {
+            target_outbound,
+            max_per_group,
+            candidates: HashMap::new(),
+            outbound: HashMap::new(),
+            dialing: HashMap::new(),
+        }
+    }
+
+    /// Remember addresses learned for a peer
+    pub fn add_candidate(&mut self, peer: PeerId, addresses: impl IntoIterator<Item = Multiaddr>) {
+        let known = self.candidates.entry(peer).or_default();
+        for address in addresses {
+            if !known.contains(&address) {
+                known.push(address);
+            }
+        }
+    }
+
+    /// Record an established outbound connection
+    pub fn outbound_connected(&mut self, peer: PeerId, address: &Multiaddr) {
+        self.dialing.remove(&peer);
+        self.outbound.insert(peer, NetGroup::of(address));
+    }
+
+    /// Record a failed dial. The peer stays a candidate, so we can try
+    /// it again once we've run out of other peers.
+    pub fn dial_failed(&mut self, peer: &PeerId) {
+        self.dialing.remove(peer);
+    }
+
+    /// Forget a disconnected peer's outbound connection
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.dialing.remove(peer);
+        self.outbound.remove(peer);
+    }
+
+    /// Number of outbound peers
+    pub fn outbound_count(&self) -> usize {
+        self.outbound.len()
+    }
+
+    /// Pick candidates to dial to reach the target number of outbound
+    /// peers, preferring network groups we have the fewest peers in.
+    ///
+    /// Peers in `exclude`, e.g. connected or banned peers, are skipped.
+    pub fn peers_to_dial(&mut self, exclude: &HashSet<PeerId>) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        let needed = self
+            .target_outbound
+            .saturating_sub(self.outbound.len() + self.dialing.len());
+        if needed == 0 {
+            return Vec::new();
+        }
+
+        let mut group_counts: HashMap<NetGroup, usize> = HashMap::new();
+        for group in self
+            .outbound
+            .values()
+            .chain(self.dialing.values())
+            .flatten()
+        {
+            *group_counts.entry(*group).or_default() += 1;
+        }
+
+        let mut candidates: Vec<(PeerId, Option<NetGroup>)> = self
+            .candidates
+            .iter()
+            .filter(|(peer, _)| {
+                !exclude.contains(peer)
+                    && !self.outbound.contains_key(peer)
+                    && !self.dialing.contains_key(peer)
+            })
+            .map(|(peer, addresses)| (*peer, addresses.iter().find_map(NetGroup::of)))
+            .collect();
+        // Stable order so peers in the same group are picked consistently
+        candidates.sort_by_key(|(peer, _)| *peer);
+
+        let mut selected = Vec::new();
+        while selected.len() < needed {
+            let next = candidates
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, group))| {
+                    group.is_none_or(|group| {
+                        group_counts.get(&group).copied().unwrap_or_default() < self.max_per_group
+                    })
+                })
+                .min_by_key(|(_, (_, group))| {
+                    group
+                        .and_then(|group| group_counts.get(&group).copied())
+                        .unwrap_or_default()
+                })
+                .map(|(index, _)| index);
+            let Some(index) = next else {
+                break;
+            };
+            let (peer, group) = candidates.remove(index);
+            if let Some(group) = group {
+                *group_counts.entry(group).or_default() += 1;
+            }
+            self.dialing.insert(peer, group);
+            selected.push((peer, self.candidates[&peer].clone()));
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(ip: &str) -> Multiaddr {
+        format!("/ip4/{ip}/tcp/6884").parse().unwrap()
+    }
+
+    #[test]
+    fn test_net_group() {
+        assert_eq!(
+            NetGroup::of(&address("10.1.2.3")),
+            Some(NetGroup::V4([10, 1]))
+        );
+        assert_eq!(
+            NetGroup::of(&"/ip6/2001:db8::1/tcp/6884".parse().unwrap()),
+            Some(NetGroup::V6([0x20, 0x01, 0x0d, 0xb8]))
+        );
+        assert_eq!(
+            NetGroup::of(&"/dns4/seed.example.com/tcp/6884".parse().unwrap()),
+            None
+        );
+        assert_eq!(
+            dns_seed_address("seed.example.com"),
+            Some("/dnsaddr/seed.example.com".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_peers_to_dial_spreads_across_groups() {
+        let mut discovery = Discovery::new(3, 1);
+        let same_group = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for (i, peer) in same_group.iter().enumerate() {
+            discovery.add_candidate(*peer, [address(&format!("10.1.0.{i}"))]);
+        }
+        let other_group = PeerId::random();
+        discovery.add_candidate(other_group, [address("192.168.0.1")]);
+
+        // Only one peer per /16 group is selected
+        let selected: Vec<PeerId> = discovery
+            .peers_to_dial(&HashSet::new())
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect();
+        assert_eq!(selected.len(), 2);
+        assert!(selected.contains(&other_group));
+        assert_eq!(
+            selected
+                .iter()
+                .filter(|peer| same_group.contains(peer))
+                .count(),
+            1
+        );
+
+        // Nothing more to dial while those dials are in flight
+        assert!(discovery.peers_to_dial(&HashSet::new()).is_empty());
+    }
+
+    #[test]
+    fn test_peers_to_dial_reaches_target() {
+        let mut discovery = Discovery::new(2, 2);
+        let connected = PeerId::random();
+        discovery.add_candidate(connected, [address("10.1.0.1")]);
+        discovery.outbound_connected(connected, &address("10.1.0.1"));
+
+        let banned = PeerId::random();
+        discovery.add_candidate(banned, [address("10.2.0.1")]);
+        let candidate = PeerId::random();
+        discovery.add_candidate(candidate, [address("10.3.0.1")]);
+
+        let exclude = HashSet::from([banned]);
+        let selected = discovery.peers_to_dial(&exclude);
+        assert_eq!(selected, vec![(candidate, vec![address("10.3.0.1")])]);
+
+        // A failed dial lets us try again
+        discovery.dial_failed(&candidate);
+        assert_eq!(discovery.peers_to_dial(&exclude).len(), 1);
+
+        discovery.outbound_connected(candidate, &address("10.3.0.1"));
+        assert_eq!(discovery.outbound_count(), 2);
+        assert!(discovery.peers_to_dial(&exclude).is_empty());
+
+        discovery.disconnected(&connected);
+        assert_eq!(discovery.outbound_count(), 1);
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use bitcoin::BlockHash;
+use libp2p::PeerId;
+use std::collections::{HashMap, VecDeque};
+
+/// The maximum number of share blocks requested from a single peer at a time
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 16;
+
+/// Queue of share blocks to download during headers-first sync.
+///
+/// Blockhashes are queued in the order received, which is ordered by
+/// height, and assigned to peers in a round robin, with a limit on
+/// the number of requests in flight to each peer.
+#[derive(Debug)]
+pub struct DownloadQueue {
+    queue: VecDeque<BlockHash>,
+    in_flight: HashMap<BlockHash, PeerId>,
+    max_in_flight_per_peer: usize,
+}
+
+impl Default for DownloadQueue {
+    fn default() -> Self {
+        Self::new(MAX_IN_FLIGHT_PER_PEER)
+    }
+}
+
+impl DownloadQueue {
+    pub fn new(max_in_flight_per_peer: usize) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            max_in_flight_per_peer,
+        }
+    }
+
+    /// Add blockhashes to the back of the queue, skipping blockhashes
+    /// already queued or in flight.
+    pub fn enqueue(&mut self, blockhashes: Vec<BlockHash>) {
+        for blockhash in blockhashes {
+            if !self.in_flight.contains_key(&blockhash) && !self.queue.contains(&blockhash) {
+                self.queue.push_back(blockhash);
+            }
+        }
+    }
+
+    /// Assign queued blockhashes to peers with free capacity.
+    ///
+    /// Returns the peer and blockhash pairs to send requests for. The
+    /// assigned blockhashes are tracked as in flight till they are
+    /// received or the peer is removed.
+    pub fn next_requests(&mut self, peers: &[PeerId]) -> Vec<(PeerId, BlockHash)> {
+        let mut requests = Vec::new();
+        let mut in_flight_counts: HashMap<PeerId, usize> =
+            peers.iter().map(|peer| (*peer, 0)).collect();
+        for peer in self.in_flight.values() {
+            if let Some(count) = in_flight_counts.get_mut(peer) {
+                *count += 1;
+            }
+        }
+
+        loop {
+            let mut assigned = false;
+            for peer in peers {
+                let count = in_flight_counts.get_mut(peer).unwrap();
+                if *count >= self.max_in_flight_per_peer {
+                    continue;
+                }
+                match self.queue.pop_front() {
+                    Some(blockhash) => {
+                        self.in_flight.insert(blockhash, *peer);
+                        requests.push((*peer, blockhash));
+                        *count += 1;
+                        assigned = true;
+                    }
+                    None => return requests,
+                }
+            }
+            if !assigned {
+                return requests;
+            }
+        }
+    }
+
+    /// Mark a blockhash as no longer in flight, returns the peer it was
+    /// requested from.
+    pub fn received(&mut self, blockhash: &BlockHash) -> Option<PeerId> {
+        self.in_flight.remove(blockhash)
+    }
+
+    /// Remove a disconnected peer, moving its in flight blockhashes
+    /// back to the front of the queue.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        let mut requeue: Vec<BlockHash> = self
+            .in_flight
+            .iter()
+            .filter(|(_, peer)| *peer == peer_id)
+            .map(|(blockhash, _)| *blockhash)
+            .collect();
+        for blockhash in &requeue {
+            self.in_flight.remove(blockhash);
+        }
+        // Keep a deterministic order, the caller only cares that these are retried first
+        requeue.sort();
+        for blockhash in requeue.into_iter().rev() {
+            self.queue.push_front(blockhash);
+        }
+    }
+
+    /// Number of blockhashes waiting to be requested
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Number of blockhashes requested and not yet received
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    fn blockhashes(count: u8) -> Vec<BlockHash> {
+        (1..=count)
+            .map(|i| BlockHash::from_byte_array([i; 32]))
+            .collect()
+    }
+
+    #[test]
+    fn test_enqueue_skips_duplicates() {
+        let mut queue = DownloadQueue::new(2);
+        let hashes = blockhashes(3);
+        queue.enqueue(hashes.clone());
+        queue.enqueue(hashes.clone());
+        assert_eq!(queue.queued(), 3);
+
+        let peer = PeerId::random();
+        let requests = queue.next_requests(&[peer]);
+        assert_eq!(requests.len(), 2);
+
+        // In flight blockhashes are not queued again
+        queue.enqueue(hashes);
+        assert_eq!(queue.queued(), 1);
+        assert_eq!(queue.in_flight(), 2);
+    }
+
+    #[test]
+    fn test_next_requests_spreads_across_peers() {
+        let mut queue = DownloadQueue::new(2);
+        let hashes = blockhashes(5);
+        queue.enqueue(hashes.clone());
+
+        let peer1 = PeerId::random();
+        let peer2 = PeerId::random();
+        let requests = queue.next_requests(&[peer1, peer2]);
+
+        assert_eq!(
+            requests,
+            vec![
+                (peer1, hashes[0]),
+                (peer2, hashes[1]),
+                (peer1, hashes[2]),
+                (peer2, hashes[3]),
+            ]
+        );
+        assert_eq!(queue.queued(), 1);
+
+        // Both peers are at capacity
+        assert!(queue.next_requests(&[peer1, peer2]).is_empty());
+
+        // Receiving a block frees capacity for the peer it came from
+        assert_eq!(queue.received(&hashes[1]), Some(peer2));
+        assert_eq!(
+            queue.next_requests(&[peer1, peer2]),
+            vec![(peer2, hashes[4])]
+        );
+        assert_eq!(queue.queued(), 0);
+    }
+
+    #[test]
+    fn test_next_requests_without_peers() {
+        let mut queue = DownloadQueue::default();
+        queue.enqueue(blockhashes(2));
+        assert!(queue.next_requests(&[]).is_empty());
+        assert_eq!(queue.queued(), 2);
+    }
+
+    #[test]
+    fn test_remove_peer_requeues_in_flight() {
+        let mut queue = DownloadQueue::new(1);
+        let hashes = blockhashes(3);
+        queue.enqueue(hashes.clone());
+
+        let peer1 = PeerId::random();
+        let peer2 = PeerId::random();
+        let requests = queue.next_requests(&[peer1, peer2]);
+        assert_eq!(requests, vec![(peer1, hashes[0]), (peer2, hashes[1])]);
+
+        queue.remove_peer(&peer1);
+        assert_eq!(queue.in_flight(), 1);
+        assert_eq!(queue.queued(), 2);
+
+        // Requeued blockhash is requested before the rest of the queue
+        queue.received(&hashes[1]);
+        assert_eq!(queue.next_requests(&[peer2]), vec![(peer2, hashes[0])]);
+    }
+}
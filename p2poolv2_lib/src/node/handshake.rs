@@ -0,0 +1,302 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Version handshake between peers.
+//!
+//! Both sides send a Version request as soon as a connection is
+//! established and answer the peer's Version with a VerAck once they
+//! accept its protocol version. The handshake with a peer is complete
+//! when we have accepted its Version and it has acknowledged ours.
+//!
+//! Optional messages are only sent to peers that advertise the
+//! matching service bit in their Version.
+
+use crate::node::messages::{GetData, Message, VersionMessage};
+use crate::node::sync::local_tip;
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+/// P2P protocol version implemented by this node
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest P2P protocol version we accept from peers
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Service bits advertised in the Version message
+pub mod services {
+    /// Peer serves and accepts compact share blocks
+    pub const COMPACT_SHARES: u64 = 1 << 0;
+}
+
+/// Services supported by this node
+pub const LOCAL_SERVICES: u64 = services::COMPACT_SHARES;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[error("Unsupported protocol version {0}, minimum is {MIN_PROTOCOL_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("Duplicate version message")]
+    DuplicateVersion,
+}
+
+/// Build the Version message describing this node and its chain tip
+pub fn local_version(store: &ChainStore) -> VersionMessage {
+    let tip = local_tip(store);
+    VersionMessage {
+        version: PROTOCOL_VERSION,
+        services: LOCAL_SERVICES,
+        best_share_height: tip.height,
+        chain_work: tip.chain_work,
+        user_agent: format!("/p2poolv2:{}/", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+/// Handshake progress with a connected peer
+#[derive(Debug, Default)]
+struct PeerHandshake {
+    /// Version received from the peer, once accepted
+    version: Option<VersionMessage>,
+    /// Peer acknowledged our Version
+    acked: bool,
+    /// We dialed the peer
+    outbound: bool,
+}
+
+impl PeerHandshake {
+    fn is_complete(&self) -> bool {
+        self.version.is_some() && self.acked
+    }
+}
+
+/// Handshake state for all connected peers
+#[derive(Debug, Default)]
+pub struct Handshakes {
+    peers: HashMap<PeerId, PeerHandshake>,
+}
+
+impl Handshakes {
+    /// Start tracking the handshake with a newly connected peer
+    pub fn connected(&mut self, peer: PeerId, outbound: bool) {
+        self.peers.insert(
+            peer,
+            PeerHandshake {
+                outbound,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Forget a disconnected peer
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Accept a Version from a peer.
+    ///
+    /// Returns true if this completes the handshake.
+    pub fn received_version(
+        &mut self,
+        peer: PeerId,
+        version: VersionMessage,
+    ) -> Result<bool, HandshakeError> {
+        if version.version < MIN_PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion(version.version));
+        }
+        let handshake = self.peers.entry(peer).or_default();
+        if handshake.version.is_some() {
+            return Err(HandshakeError::DuplicateVersion);
+        }
+        handshake.version = Some(version);
+        Ok(handshake.is_complete())
+    }
+
+    /// Record the peer's VerAck for our Version.
+    ///
+    /// Returns true if this completes the handshake.
+    pub fn received_verack(&mut self, peer: PeerId) -> bool {
+        let handshake = self.peers.entry(peer).or_default();
+        let was_complete = handshake.is_complete();
+        handshake.acked = true;
+        !was_complete && handshake.is_complete()
+    }
+
+    /// Check if the handshake with a peer is complete
+    pub fn is_complete(&self, peer: &PeerId) -> bool {
+        self.peers.get(peer).is_some_and(PeerHandshake::is_complete)
+    }
+
+    /// Check if we dialed the peer
+    pub fn is_outbound(&self, peer: &PeerId) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|handshake| handshake.outbound)
+    }
+
+    /// Version the peer sent us, if we accepted one
+    pub fn version(&self, peer: &PeerId) -> Option<&VersionMessage> {
+        self.peers
+            .get(peer)
+            .and_then(|handshake| handshake.version.as_ref())
+    }
+
+    /// Check if both we and the peer support a service
+    pub fn supports(&self, peer: &PeerId, service: u64) -> bool {
+        LOCAL_SERVICES & service != 0
+            && self
+                .version(peer)
+                .is_some_and(|version| version.services & service != 0)
+    }
+
+    /// Check if we handle a request from the peer.
+    ///
+    /// Only Version and Tip are handled before we have accepted the
+    /// peer's Version. The peer's VerAck for our Version can arrive
+    /// after its first requests, so the handshake need not be complete.
+    pub fn accepts_request(&self, peer: &PeerId, request: &Message) -> bool {
+        matches!(request, Message::Version(_) | Message::Tip(_)) || self.version(peer).is_some()
+    }
+
+    /// Replace optional messages the peer has not negotiated with
+    /// their baseline equivalent.
+    pub fn gate_message(&self, peer: &PeerId, message: Message) -> Message {
+        match message {
+            Message::GetData(GetData::CompactBlock(blockhash))
+                if !self.supports(peer, services::COMPACT_SHARES) =>
+            {
+                Message::GetData(GetData::Block(blockhash))
+            }
+            message => message,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{BlockHash, Work};
+
+    fn version(version: u32, services: u64) -> VersionMessage {
+        VersionMessage {
+            version,
+            services,
+            best_share_height: 10,
+            chain_work: Work::from_le_bytes([0; 32]),
+            user_agent: "/test/".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_handshake_completes_after_version_and_verack() {
+        let mut handshakes = Handshakes::default();
+        let peer = PeerId::random();
+        handshakes.connected(peer, true);
+
+        assert_eq!(
+            handshakes.received_version(peer, version(PROTOCOL_VERSION, 0)),
+            Ok(false)
+        );
+        assert!(!handshakes.is_complete(&peer));
+        assert!(handshakes.received_verack(peer));
+        assert!(handshakes.is_complete(&peer));
+        assert!(handshakes.is_outbound(&peer));
+
+        // A repeated VerAck does not complete the handshake again
+        assert!(!handshakes.received_verack(peer));
+        assert_eq!(
+            handshakes.received_version(peer, version(PROTOCOL_VERSION, 0)),
+            Err(HandshakeError::DuplicateVersion)
+        );
+
+        handshakes.disconnected(&peer);
+        assert!(!handshakes.is_complete(&peer));
+    }
+
+    #[test]
+    fn test_handshake_rejects_old_versions() {
+        let mut handshakes = Handshakes::default();
+        let peer = PeerId::random();
+        handshakes.connected(peer, false);
+
+        assert_eq!(
+            handshakes.received_version(peer, version(MIN_PROTOCOL_VERSION - 1, 0)),
+            Err(HandshakeError::UnsupportedVersion(MIN_PROTOCOL_VERSION - 1))
+        );
+        assert!(handshakes.version(&peer).is_none());
+    }
+
+    #[test]
+    fn test_accepts_only_handshake_requests_before_version() {
+        let mut handshakes = Handshakes::default();
+        let peer = PeerId::random();
+        handshakes.connected(peer, false);
+
+        let request = Message::GetData(GetData::Block(BlockHash::all_zeros()));
+        assert!(!handshakes.accepts_request(&peer, &request));
+        assert!(handshakes.accepts_request(&peer, &Message::Version(version(PROTOCOL_VERSION, 0))));
+
+        handshakes
+            .received_version(peer, version(PROTOCOL_VERSION, 0))
+            .unwrap();
+        assert!(handshakes.accepts_request(&peer, &request));
+    }
+
+    #[test]
+    fn test_gate_compact_block_requests_on_services() {
+        let mut handshakes = Handshakes::default();
+        let legacy_peer = PeerId::random();
+        let compact_peer = PeerId::random();
+        handshakes
+            .received_version(legacy_peer, version(PROTOCOL_VERSION, 0))
+            .unwrap();
+        handshakes
+            .received_version(
+                compact_peer,
+                version(PROTOCOL_VERSION, services::COMPACT_SHARES),
+            )
+            .unwrap();
+
+        let request = Message::GetData(GetData::CompactBlock(BlockHash::all_zeros()));
+        assert_eq!(
+            handshakes.gate_message(&legacy_peer, request.clone()),
+            Message::GetData(GetData::Block(BlockHash::all_zeros()))
+        );
+        assert_eq!(
+            handshakes.gate_message(&compact_peer, request.clone()),
+            request
+        );
+    }
+
+    #[test]
+    fn test_local_version() {
+        let mut store = ChainStore::default();
+        store.expect_get_tip_height().returning(|| Ok(Some(7)));
+        store
+            .expect_get_total_work()
+            .returning(|| Ok(Work::from_hex("0x100").unwrap()));
+
+        let version = local_version(&store);
+        assert_eq!(version.version, PROTOCOL_VERSION);
+        assert_eq!(version.services, LOCAL_SERVICES);
+        assert_eq!(version.best_share_height, 7);
+        assert_eq!(version.chain_work, Work::from_hex("0x100").unwrap());
+    }
+}
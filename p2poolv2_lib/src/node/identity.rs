@@ -0,0 +1,131 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::config::NetworkConfig;
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use libp2p::identity::Keypair;
+use std::error::Error;
+use std::path::Path;
+use tracing::info;
+
+/// Metadata key for the node keypair when no key file is configured
+const NODE_KEYPAIR_METADATA_KEY: &str = "node_keypair";
+
+/// Load the node keypair, generating and persisting one on first start.
+///
+/// The keypair is read from network.node_key_path if configured,
+/// otherwise from the store metadata. Keeping the keypair stable
+/// keeps our PeerId stable across restarts.
+pub fn load_or_create_keypair(
+    config: &NetworkConfig,
+    store: &ChainStore,
+) -> Result<Keypair, Box<dyn Error + Send + Sync>> {
+    let existing = match &config.node_key_path {
+        Some(path) if Path::new(path).exists() => Some(std::fs::read(path)?),
+        Some(_) => None,
+        None => store.get_metadata(NODE_KEYPAIR_METADATA_KEY)?,
+    };
+
+    if let Some(bytes) = existing {
+        let keypair = Keypair::from_protobuf_encoding(&bytes)
+            .map_err(|e| format!("Invalid node keypair: {e}"))?;
+        info!("Loaded node identity {}", keypair.public().to_peer_id());
+        return Ok(keypair);
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    let bytes = keypair.to_protobuf_encoding()?;
+    match &config.node_key_path {
+        Some(path) => write_key_file(Path::new(path), &bytes)?,
+        None => store.put_metadata(NODE_KEYPAIR_METADATA_KEY, &bytes)?,
+    }
+    info!("Generated node identity {}", keypair.public().to_peer_id());
+    Ok(keypair)
+}
+
+/// Write the key file readable by the owner only
+fn write_key_file(path: &Path, bytes: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    std::io::Write::write_all(&mut file, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockall::predicate::eq;
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_keypair_persisted_in_store() {
+        let config = NetworkConfig::default();
+        let mut store = ChainStore::default();
+        let saved: Arc<Mutex<Option<Vec<u8>>>> = Arc::new(Mutex::new(None));
+
+        let saved_get = saved.clone();
+        store
+            .expect_get_metadata()
+            .with(eq(NODE_KEYPAIR_METADATA_KEY))
+            .returning(move |_| Ok(saved_get.lock().unwrap().clone()));
+        let saved_put = saved.clone();
+        store
+            .expect_put_metadata()
+            .times(1)
+            .returning(move |_, value| {
+                *saved_put.lock().unwrap() = Some(value.to_vec());
+                Ok(())
+            });
+
+        let first = load_or_create_keypair(&config, &store).unwrap();
+        let second = load_or_create_keypair(&config, &store).unwrap();
+        assert_eq!(first.public().to_peer_id(), second.public().to_peer_id());
+    }
+
+    #[test]
+    fn test_keypair_persisted_in_file() {
+        let temp_dir = tempdir().unwrap();
+        let key_path = temp_dir.path().join("keys").join("node.key");
+        let config = NetworkConfig {
+            node_key_path: Some(key_path.to_str().unwrap().to_string()),
+            ..NetworkConfig::default()
+        };
+        // The store is not used when a key file is configured
+        let store = ChainStore::default();
+
+        let first = load_or_create_keypair(&config, &store).unwrap();
+        assert!(key_path.exists());
+        let second = load_or_create_keypair(&config, &store).unwrap();
+        assert_eq!(first.public().to_peer_id(), second.public().to_peer_id());
+
+        std::fs::write(&key_path, b"not a key").unwrap();
+        assert!(load_or_create_keypair(&config, &store).is_err());
+    }
+}
@@ -14,11 +14,13 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::shares::compact_block::CompactShareBlock;
 use crate::shares::share_block::{ShareBlock, ShareHeader, Txids};
+use bitcoin::bip152::{BlockTransactions, BlockTransactionsRequest};
 use bitcoin::consensus::{Decodable, Encodable, encode};
 use bitcoin::hashes::{Hash, sha256d};
 use bitcoin::io::{Read, Write};
-use bitcoin::{BlockHash, Txid, VarInt};
+use bitcoin::{BlockHash, Txid, VarInt, Work};
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
@@ -33,6 +35,34 @@ mod message_discriminants {
     pub const SHARE_BLOCK: u8 = 5;
     pub const GET_DATA: u8 = 6;
     pub const TRANSACTION: u8 = 7;
+    pub const COMPACT_SHARE_BLOCK: u8 = 8;
+    pub const GET_SHARE_BLOCK_TXNS: u8 = 9;
+    pub const SHARE_BLOCK_TXNS: u8 = 10;
+    pub const VERSION: u8 = 11;
+    pub const VERACK: u8 = 12;
+    pub const TIP: u8 = 13;
+}
+
+/// Largest payload we accept for any message
+pub const MAX_PAYLOAD_LEN: usize = 8 * 1024 * 1024;
+
+/// Largest payload we accept for a message, by its discriminant, the
+/// first byte of the payload. Returns None for unknown messages.
+pub fn max_payload_len(discriminant: u8) -> Option<usize> {
+    use message_discriminants::*;
+    match discriminant {
+        NOT_FOUND | VERACK => Some(1),
+        VERSION | TIP | GET_DATA => Some(1024),
+        // Block locators
+        GET_SHARE_HEADERS | GET_SHARE_BLOCKS => Some(64 * 1024),
+        // Up to MAX_HEADERS share headers with their uncles
+        SHARE_HEADERS => Some(2 * 1024 * 1024),
+        INVENTORY | GET_SHARE_BLOCK_TXNS => Some(2 * 1024 * 1024),
+        TRANSACTION => Some(4 * 1024 * 1024),
+        // Share blocks carry the bitcoin block's transactions
+        SHARE_BLOCK | COMPACT_SHARE_BLOCK | SHARE_BLOCK_TXNS => Some(MAX_PAYLOAD_LEN),
+        _ => None,
+    }
 }
 
 /// InventoryMessage discriminants to determine the type of inventory message
@@ -45,6 +75,7 @@ mod inventory_discriminants {
 mod getdata_discriminants {
     pub const BLOCK: u8 = 0;
     pub const TXID: u8 = 1;
+    pub const COMPACT_BLOCK: u8 = 2;
 }
 
 /// Network magic bytes for different P2Poolv2 networks
@@ -53,10 +84,27 @@ pub mod network_magic {
     pub const MAINNET: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
     /// Testnet P2Poolv2
     pub const TESTNET: [u8; 4] = [0x0b, 0x11, 0x09, 0x07];
+    /// Testnet4 P2Poolv2
+    pub const TESTNET4: [u8; 4] = [0x1c, 0x16, 0x3f, 0x28];
     /// Signet P2Poolv2
     pub const SIGNET: [u8; 4] = [0x0a, 0x03, 0xcf, 0x40];
     /// Regtest P2Poolv2
     pub const REGTEST: [u8; 4] = [0xfa, 0xbf, 0xb5, 0xda];
+
+    /// Network magic for the bitcoin network.
+    ///
+    /// Returns None for networks added to rust-bitcoin that have no
+    /// magic yet, so that such a node does not join another network.
+    pub fn for_network(network: bitcoin::Network) -> Option<[u8; 4]> {
+        match network {
+            bitcoin::Network::Bitcoin => Some(MAINNET),
+            bitcoin::Network::Testnet => Some(TESTNET),
+            bitcoin::Network::Testnet4 => Some(TESTNET4),
+            bitcoin::Network::Signet => Some(SIGNET),
+            bitcoin::Network::Regtest => Some(REGTEST),
+            _ => None,
+        }
+    }
 }
 
 /// P2P network messages, encoded using bitcoin consensus_encode
@@ -70,6 +118,19 @@ pub enum Message {
     ShareBlock(ShareBlock),
     GetData(GetData),
     Transaction(bitcoin::Transaction),
+    /// Share block with short ids for the bitcoin transactions
+    CompactShareBlock(CompactShareBlock),
+    /// Request bitcoin transactions missing from a compact share block, by index
+    GetShareBlockTxns(BlockTransactionsRequest),
+    /// Bitcoin transactions requested with GetShareBlockTxns
+    ShareBlockTxns(BlockTransactions),
+    /// First message sent on a new connection, announcing our protocol
+    /// version, services and chain tip
+    Version(VersionMessage),
+    /// Response accepting a peer's Version or Tip
+    VerAck(()),
+    /// Announce a change of the sender's chain tip, acknowledged with a VerAck
+    Tip(TipMessage),
 }
 
 /// A complete P2P network message with protocol framing
@@ -141,6 +202,12 @@ impl Display for Message {
             Message::ShareBlock(_) => write!(f, "ShareBlock"),
             Message::GetData(_) => write!(f, "GetData"),
             Message::Transaction(_) => write!(f, "Transaction"),
+            Message::CompactShareBlock(_) => write!(f, "CompactShareBlock"),
+            Message::GetShareBlockTxns(_) => write!(f, "GetShareBlockTxns"),
+            Message::ShareBlockTxns(_) => write!(f, "ShareBlockTxns"),
+            Message::Version(_) => write!(f, "Version"),
+            Message::VerAck(_) => write!(f, "VerAck"),
+            Message::Tip(_) => write!(f, "Tip"),
         }
     }
 }
@@ -232,6 +299,32 @@ impl Encodable for Message {
                 len += tx.consensus_encode(w)?;
                 Ok(len)
             }
+            Message::CompactShareBlock(block) => {
+                let mut len = COMPACT_SHARE_BLOCK.consensus_encode(w)?;
+                len += block.consensus_encode(w)?;
+                Ok(len)
+            }
+            Message::GetShareBlockTxns(request) => {
+                let mut len = GET_SHARE_BLOCK_TXNS.consensus_encode(w)?;
+                len += request.consensus_encode(w)?;
+                Ok(len)
+            }
+            Message::ShareBlockTxns(transactions) => {
+                let mut len = SHARE_BLOCK_TXNS.consensus_encode(w)?;
+                len += transactions.consensus_encode(w)?;
+                Ok(len)
+            }
+            Message::Version(version) => {
+                let mut len = VERSION.consensus_encode(w)?;
+                len += version.consensus_encode(w)?;
+                Ok(len)
+            }
+            Message::VerAck(_) => VERACK.consensus_encode(w),
+            Message::Tip(tip) => {
+                let mut len = TIP.consensus_encode(w)?;
+                len += tip.consensus_encode(w)?;
+                Ok(len)
+            }
         }
     }
 }
@@ -259,6 +352,18 @@ impl Decodable for Message {
             TRANSACTION => Ok(Message::Transaction(
                 bitcoin::Transaction::consensus_decode(r)?,
             )),
+            COMPACT_SHARE_BLOCK => Ok(Message::CompactShareBlock(
+                CompactShareBlock::consensus_decode(r)?,
+            )),
+            GET_SHARE_BLOCK_TXNS => Ok(Message::GetShareBlockTxns(
+                BlockTransactionsRequest::consensus_decode(r)?,
+            )),
+            SHARE_BLOCK_TXNS => Ok(Message::ShareBlockTxns(
+                BlockTransactions::consensus_decode(r)?,
+            )),
+            VERSION => Ok(Message::Version(VersionMessage::consensus_decode(r)?)),
+            VERACK => Ok(Message::VerAck(())),
+            TIP => Ok(Message::Tip(TipMessage::consensus_decode(r)?)),
             _ => Err(encode::Error::ParseFailed("Invalid Message discriminant")),
         }
     }
@@ -281,6 +386,9 @@ impl Decodable for RawMessage {
         let magic: [u8; 4] = Decodable::consensus_decode(r)?;
         let payload_len: u32 = Decodable::consensus_decode(r)?;
         let expected_checksum: [u8; 4] = Decodable::consensus_decode(r)?;
+        if payload_len as usize > MAX_PAYLOAD_LEN {
+            return Err(encode::Error::ParseFailed("Message too large"));
+        }
 
         // Read payload into buffer
         let mut payload_bytes = vec![0u8; payload_len as usize];
@@ -353,6 +461,8 @@ impl Decodable for InventoryMessage {
 pub enum GetData {
     Block(BlockHash),
     Txid(Txid),
+    /// Request a share block as a CompactShareBlock
+    CompactBlock(BlockHash),
 }
 
 impl Encodable for GetData {
@@ -369,6 +479,11 @@ impl Encodable for GetData {
                 len += txid.consensus_encode(w)?;
                 Ok(len)
             }
+            GetData::CompactBlock(hash) => {
+                let mut len = COMPACT_BLOCK.consensus_encode(w)?;
+                len += hash.consensus_encode(w)?;
+                Ok(len)
+            }
         }
     }
 }
@@ -380,11 +495,76 @@ impl Decodable for GetData {
         match disc {
             BLOCK => Ok(GetData::Block(BlockHash::consensus_decode(r)?)),
             TXID => Ok(GetData::Txid(Txid::consensus_decode(r)?)),
+            COMPACT_BLOCK => Ok(GetData::CompactBlock(BlockHash::consensus_decode(r)?)),
             _ => Err(encode::Error::ParseFailed("Invalid GetData discriminant")),
         }
     }
 }
 
+/// Version message exchanged when peers connect
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionMessage {
+    /// P2P protocol version of the sender
+    pub version: u32,
+    /// Bitfield of optional services the sender supports
+    pub services: u64,
+    /// Height of the sender's best share
+    pub best_share_height: u32,
+    /// Total work on the sender's share chain
+    pub chain_work: Work,
+    /// Software name and version of the sender
+    pub user_agent: String,
+}
+
+impl Encodable for VersionMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, bitcoin::io::Error> {
+        let mut len = self.version.consensus_encode(w)?;
+        len += self.services.consensus_encode(w)?;
+        len += self.best_share_height.consensus_encode(w)?;
+        len += self.chain_work.to_le_bytes().consensus_encode(w)?;
+        len += self.user_agent.consensus_encode(w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for VersionMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, encode::Error> {
+        Ok(VersionMessage {
+            version: u32::consensus_decode(r)?,
+            services: u64::consensus_decode(r)?,
+            best_share_height: u32::consensus_decode(r)?,
+            chain_work: Work::from_le_bytes(<[u8; 32]>::consensus_decode(r)?),
+            user_agent: String::consensus_decode(r)?,
+        })
+    }
+}
+
+/// Chain tip announced to peers when it changes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TipMessage {
+    /// Height of the sender's best share
+    pub best_share_height: u32,
+    /// Total work on the sender's share chain
+    pub chain_work: Work,
+}
+
+impl Encodable for TipMessage {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, bitcoin::io::Error> {
+        let mut len = self.best_share_height.consensus_encode(w)?;
+        len += self.chain_work.to_le_bytes().consensus_encode(w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for TipMessage {
+    fn consensus_decode<R: Read + ?Sized>(r: &mut R) -> Result<Self, encode::Error> {
+        Ok(TipMessage {
+            best_share_height: u32::consensus_decode(r)?,
+            chain_work: Work::from_le_bytes(<[u8; 32]>::consensus_decode(r)?),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -444,6 +624,28 @@ mod tests {
         assert_eq!(raw_regtest.magic, network_magic::REGTEST);
     }
 
+    #[test]
+    fn test_network_magic_for_network() {
+        let networks = [
+            bitcoin::Network::Bitcoin,
+            bitcoin::Network::Testnet,
+            bitcoin::Network::Testnet4,
+            bitcoin::Network::Signet,
+            bitcoin::Network::Regtest,
+        ];
+        let magics: std::collections::HashSet<[u8; 4]> = networks
+            .iter()
+            .map(|network| network_magic::for_network(*network).unwrap())
+            .collect();
+
+        // Every network has its own magic
+        assert_eq!(magics.len(), networks.len());
+        assert_eq!(
+            network_magic::for_network(bitcoin::Network::Testnet4),
+            Some(network_magic::TESTNET4)
+        );
+    }
+
     #[test]
     fn test_message_not_found_roundtrip() {
         let msg = Message::NotFound(());
@@ -552,6 +754,84 @@ mod tests {
         assert_eq!(decoded, get_data);
     }
 
+    #[test]
+    fn test_message_compact_share_block_roundtrip() {
+        let share_block = crate::test_utils::TestShareBlockBuilder::new().build();
+        let compact = CompactShareBlock::from_share_block(&share_block).unwrap();
+
+        let msg = Message::CompactShareBlock(compact);
+        let mut encoded = Vec::new();
+        msg.consensus_encode(&mut encoded).unwrap();
+
+        let decoded = Message::consensus_decode(&mut &encoded[..]).unwrap();
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_message_share_block_txns_roundtrip() {
+        let block_hash =
+            BlockHash::from_str("0000000086704a35f17580d06f76d4c02d2b1f68774800675fb45f0411205bb5")
+                .unwrap();
+
+        let request = Message::GetShareBlockTxns(BlockTransactionsRequest {
+            block_hash,
+            indexes: vec![1, 2, 5],
+        });
+        let mut encoded = Vec::new();
+        request.consensus_encode(&mut encoded).unwrap();
+        assert_eq!(
+            Message::consensus_decode(&mut &encoded[..]).unwrap(),
+            request
+        );
+
+        let response = Message::ShareBlockTxns(BlockTransactions {
+            block_hash,
+            transactions: vec![crate::test_utils::test_coinbase_transaction()],
+        });
+        let mut encoded = Vec::new();
+        response.consensus_encode(&mut encoded).unwrap();
+        assert_eq!(
+            Message::consensus_decode(&mut &encoded[..]).unwrap(),
+            response
+        );
+    }
+
+    #[test]
+    fn test_get_data_compact_block_roundtrip() {
+        let get_data = GetData::CompactBlock(BlockHash::all_zeros());
+        let mut encoded = Vec::new();
+        get_data.consensus_encode(&mut encoded).unwrap();
+
+        let decoded = GetData::consensus_decode(&mut &encoded[..]).unwrap();
+        assert_eq!(decoded, get_data);
+    }
+
+    #[test]
+    fn test_message_version_roundtrip() {
+        let msg = Message::Version(VersionMessage {
+            version: 1,
+            services: 1,
+            best_share_height: 42,
+            chain_work: Work::from_hex("0x1000").unwrap(),
+            user_agent: "/p2poolv2:0.1.0/".to_string(),
+        });
+        let encoded = encode::serialize(&msg);
+        let decoded: Message = encode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, msg);
+
+        let encoded = encode::serialize(&Message::VerAck(()));
+        let decoded: Message = encode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, Message::VerAck(()));
+
+        let msg = Message::Tip(TipMessage {
+            best_share_height: 43,
+            chain_work: Work::from_hex("0x2000").unwrap(),
+        });
+        let encoded = encode::serialize(&msg);
+        let decoded: Message = encode::deserialize(&encoded).unwrap();
+        assert_eq!(decoded, msg);
+    }
+
     #[test]
     fn test_message_discriminants_unique() {
         use message_discriminants::*;
@@ -564,6 +844,12 @@ mod tests {
             SHARE_BLOCK,
             GET_DATA,
             TRANSACTION,
+            COMPACT_SHARE_BLOCK,
+            GET_SHARE_BLOCK_TXNS,
+            SHARE_BLOCK_TXNS,
+            VERSION,
+            VERACK,
+            TIP,
         ];
 
         // Check all discriminants are unique
@@ -18,13 +18,41 @@ pub mod behaviour;
 pub mod request_response_handler;
 pub use crate::config::Config;
 pub mod actor;
+pub mod address_book;
+pub mod discovery;
+pub mod download_queue;
+pub mod handshake;
+pub mod identity;
 pub mod messages;
+pub mod orphan_pool;
 pub mod p2p_message_handlers;
+pub mod reachability;
+pub mod sync;
+pub mod transport;
 
 use crate::accounting::simple_pplns::SimplePplnsShare;
+use crate::config::{Parsed, StratumConfig};
+use crate::middleware::peer_score::{Offence, PeerScores, penalise_peer};
+use crate::node::behaviour::gossip;
 use crate::node::behaviour::request_response::RequestResponseEvent;
-use crate::node::messages::Message;
+use crate::node::discovery::Discovery;
+use crate::node::download_queue::DownloadQueue;
+use crate::node::handshake::Handshakes;
+use crate::node::messages::{
+    GetData, InventoryMessage, Message, TipMessage, VersionMessage, network_magic,
+};
+use crate::node::orphan_pool::OrphanPool;
+use crate::node::p2p_message_handlers::receivers::getheaders::MAX_HEADERS;
+use crate::node::p2p_message_handlers::receivers::{
+    handle_share_block, handle_share_block_txns, handle_transaction,
+    reconstruct_compact_share_block,
+};
 use crate::node::p2p_message_handlers::senders::{send_blocks_inventory, send_getheaders};
+use crate::node::p2p_message_handlers::{
+    UnsolicitedMessage, handle_and_relay_share_block, handle_response,
+};
+use crate::node::reachability::ReachabilityHandle;
+use crate::node::sync::{ChainTip, SyncManager};
 use crate::service::build_service;
 use crate::service::p2p_service::RequestContext;
 #[cfg(test)]
@@ -32,20 +60,30 @@ use crate::service::p2p_service::RequestContext;
 use crate::shares::chain::chain_store::ChainStore;
 #[cfg(not(test))]
 use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::compact_block::{CompactShareBlock, PartialShareBlock};
 use crate::shares::share_block::ShareBlock;
-use crate::utils::time_provider::SystemTimeProvider;
+use crate::shares::validation::{ValidationMode, validate_transaction};
+use crate::utils::time_provider::{SystemTimeProvider, TimeProvider};
 use behaviour::{P2PoolBehaviour, P2PoolBehaviourEvent};
+use bitcoin::bip152::BlockTransactionsRequest;
+use bitcoin::{BlockHash, Txid};
 use libp2p::PeerId;
 use libp2p::SwarmBuilder;
-use libp2p::core::transport::Transport;
+use libp2p::autonat::{self, NatStatus};
+use libp2p::core::transport::ListenerId;
+use libp2p::gossipsub::{self, MessageAcceptance};
 use libp2p::identify;
-use libp2p::request_response::ResponseChannel;
-use libp2p::tcp::Config as TcpConfig;
+use libp2p::request_response::{
+    InboundFailure, OutboundFailure, OutboundRequestId, ResponseChannel,
+};
+use libp2p::swarm::dial_opts::DialOpts;
 use libp2p::{
     Multiaddr, Swarm,
     kad::{Event as KademliaEvent, QueryResult},
     swarm::SwarmEvent,
 };
+use libp2p::{dcutr, relay};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::time::Duration;
 use tokio::sync::mpsc;
@@ -79,6 +117,11 @@ pub enum SwarmSend<C> {
     Response(C, Message),
     Inv(ShareBlock),
     Disconnect(PeerId),
+    /// Queue share blocks to download from peers during headers-first sync
+    DownloadShares(Vec<BlockHash>),
+    /// Hold a share from a peer until the missing share, its parent or
+    /// an uncle, is added to the chain
+    Orphan(PeerId, ShareBlock, BlockHash),
 }
 
 /// Node is the main struct that represents the node
@@ -92,6 +135,38 @@ struct Node {
         (),
         Box<dyn Error + Send + Sync>,
     >,
+    /// Share blocks to download from peers during headers-first sync
+    download_queue: DownloadQueue,
+    /// Outstanding GetData requests for share blocks in the download queue
+    download_requests: HashMap<OutboundRequestId, BlockHash>,
+    /// Compact share blocks waiting for a response to GetShareBlockTxns
+    pending_compact_blocks: HashMap<OutboundRequestId, PartialShareBlock>,
+    /// Outstanding GetData requests for transactions, only requested
+    /// transactions are stored
+    transaction_requests: HashMap<OutboundRequestId, Txid>,
+    /// Stratum config used to verify the payouts of received shares
+    stratum_config: std::sync::Arc<StratumConfig<Parsed>>,
+    /// Misbehaviour scores and bans for peers
+    peer_scores: PeerScores,
+    /// Version handshake state for connected peers
+    handshakes: Handshakes,
+    /// Tracks peer chain tips to keep our share chain caught up
+    sync: SyncManager,
+    /// Chain tip we last announced to peers
+    announced_tip: Option<ChainTip>,
+    /// Network magic for gossip messages
+    magic: [u8; 4],
+    /// Discovered peers and outbound peer selection
+    discovery: Discovery,
+    /// Shares waiting for their parent or uncles
+    orphans: OrphanPool,
+    /// Relays to listen through when we are not publicly reachable,
+    /// empty unless the relay client is enabled
+    relay_servers: Vec<Multiaddr>,
+    /// Listeners on relayed addresses
+    relay_listeners: Vec<ListenerId>,
+    /// Reachability status shared with the API
+    reachability: ReachabilityHandle,
 }
 
 impl Node {
@@ -99,9 +174,14 @@ impl Node {
         config: &Config,
         store: std::sync::Arc<ChainStore>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let id_keys = libp2p::identity::Keypair::generate_ed25519();
+        let id_keys =
+            identity::load_or_create_keypair(&config.network, &store).map_err(|e| e.to_string())?;
+        let now = SystemTimeProvider.seconds_since_epoch();
+        let peer_scores = PeerScores::new(&config.network, store.clone(), now);
 
-        let behavior = match P2PoolBehaviour::new(&id_keys, config) {
+        let (relay_transport, relay_client) =
+            libp2p::relay::client::new(id_keys.public().to_peer_id());
+        let behavior = match P2PoolBehaviour::new(&id_keys, config, relay_client) {
             Ok(behavior) => behavior,
             Err(err) => {
                 error!("Failed to create P2PoolBehaviour: {}", err);
@@ -109,23 +189,8 @@ impl Node {
             }
         };
 
-        let dial_timeout_secs = config.network.dial_timeout_secs;
-
-        let tcp_config = TcpConfig::default().nodelay(true);
-        let noise_config = match libp2p::noise::Config::new(&id_keys) {
-            Ok(cfg) => cfg,
-            Err(err) => {
-                error!("Failed to create Noise config: {}", err);
-                return Err(Box::new(err));
-            }
-        };
-
-        let transport = libp2p::tcp::Transport::<libp2p::tcp::tokio::Tcp>::new(tcp_config.clone())
-            .upgrade(libp2p::core::upgrade::Version::V1)
-            .authenticate(noise_config)
-            .multiplex(libp2p::yamux::Config::default())
-            .timeout(Duration::from_secs(dial_timeout_secs))
-            .boxed();
+        let transport = transport::build_transport(&id_keys, &config.network, relay_transport)
+            .inspect_err(|e| error!("Failed to create transport: {}", e))?;
 
         let mut swarm = SwarmBuilder::with_existing_identity(id_keys)
             .with_tokio()
@@ -137,10 +202,16 @@ impl Node {
             .build();
 
         if !config.network.listen_address.is_empty() {
-            match config.network.listen_address.parse() {
-                Ok(addr) => match swarm.listen_on(addr) {
+            match config.network.listen_address.parse::<Multiaddr>() {
+                Ok(addr) => match swarm.listen_on(addr.clone()) {
                     Ok(_) => {
                         info!("Node listening on {}", config.network.listen_address);
+                        if config.network.enable_quic
+                            && let Some(quic_addr) = transport::quic_listen_address(&addr)
+                            && let Err(e) = swarm.listen_on(quic_addr.clone())
+                        {
+                            warn!("Failed to listen on {}: {}", quic_addr, e);
+                        }
                     }
                     Err(e) => {
                         error!(
@@ -179,21 +250,126 @@ impl Node {
                     Err(e) => debug!("Invalid multiaddr {}: {}", peer_addr, e),
                 }
             }
+
+            match store.get_peer_records() {
+                Ok(records) => {
+                    let local_peer_id = *swarm.local_peer_id();
+                    let records = records
+                        .into_iter()
+                        .filter(|(peer_id, _)| {
+                            peer_id
+                                .parse::<PeerId>()
+                                .is_ok_and(|peer_id| !peer_scores.is_banned(&peer_id, now))
+                        })
+                        .collect();
+                    for (peer_id, addresses) in address_book::peers_to_redial(
+                        records,
+                        &local_peer_id,
+                        config.network.max_redial_peers,
+                    ) {
+                        let opts = DialOpts::peer_id(peer_id).addresses(addresses).build();
+                        if let Err(e) = swarm.dial(opts) {
+                            debug!("Failed to redial known peer {}: {}", peer_id, e);
+                        } else {
+                            info!("Redialed known peer {}", peer_id);
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to load peer address book: {}", e),
+            }
+
+            for peer_addr in &config.network.bootstrap_peers {
+                match peer_addr.parse::<Multiaddr>() {
+                    Ok(remote) => {
+                        // Bootstrap peers with a known peer id seed the Kademlia routing table
+                        if let Some(libp2p::multiaddr::Protocol::P2p(peer_id)) =
+                            remote.iter().last()
+                        {
+                            swarm
+                                .behaviour_mut()
+                                .kademlia
+                                .add_address(&peer_id, remote.clone());
+                        }
+                        if let Err(e) = swarm.dial(remote) {
+                            debug!("Failed to dial bootstrap peer {}: {}", peer_addr, e);
+                        } else {
+                            info!("Dialed bootstrap peer {}", peer_addr);
+                        }
+                    }
+                    Err(e) => debug!("Invalid bootstrap multiaddr {}: {}", peer_addr, e),
+                }
+            }
+
+            for seed in &config.network.dns_seeds {
+                match discovery::dns_seed_address(seed) {
+                    Some(remote) => {
+                        if let Err(e) = swarm.dial(remote) {
+                            debug!("Failed to dial DNS seed {}: {}", seed, e);
+                        } else {
+                            info!("Dialed DNS seed {}", seed);
+                        }
+                    }
+                    None => debug!("Invalid DNS seed {}", seed),
+                }
+            }
         }
 
+        let stratum_config = std::sync::Arc::new(config.stratum.clone().parse()?);
+        let magic = network_magic::for_network(config.stratum.network)
+            .ok_or_else(|| format!("No network magic for {}", config.stratum.network))?;
+
         let (swarm_tx, swarm_rx) = mpsc::channel(100);
 
         // Initialize the service field before constructing the Node
-        let service =
-            build_service::<ResponseChannel<Message>, _>(config.network.clone(), swarm_tx.clone());
+        let service = build_service::<ResponseChannel<Message>, _>(
+            config.network.clone(),
+            swarm_tx.clone(),
+            peer_scores.clone(),
+        );
+
+        let relay_servers = config
+            .network
+            .relay_servers
+            .iter()
+            .filter_map(|relay| {
+                relay
+                    .parse::<Multiaddr>()
+                    .inspect_err(|e| warn!("Invalid relay multiaddr {}: {}", relay, e))
+                    .ok()
+            })
+            .collect();
 
-        Ok(Self {
+        let mut node = Self {
             swarm,
             swarm_tx,
             swarm_rx,
             store,
             service,
-        })
+            download_queue: DownloadQueue::default(),
+            download_requests: HashMap::new(),
+            pending_compact_blocks: HashMap::new(),
+            transaction_requests: HashMap::new(),
+            stratum_config,
+            peer_scores,
+            handshakes: Handshakes::default(),
+            sync: SyncManager::default(),
+            announced_tip: None,
+            magic,
+            discovery: Discovery::new(
+                config.network.target_outbound_peers,
+                config.network.max_peers_per_subnet,
+            ),
+            orphans: OrphanPool::default(),
+            relay_servers,
+            relay_listeners: Vec::new(),
+            reachability: ReachabilityHandle::default(),
+        };
+        // Without AutoNAT we can't tell if we are reachable, so listen
+        // through relays from the start
+        if config.network.enable_relay_client && !config.network.enable_autonat {
+            node.listen_via_relays();
+        }
+        Ok(node)
     }
 
     /// Returns a Vec of peer IDs that are currently connected to this node
@@ -217,6 +393,7 @@ impl Node {
         message: Message,
     ) -> Result<(), Box<dyn Error>> {
         info!("Sending message to peer: {peer_id}, message: {message:?}");
+        let message = self.handshakes.gate_message(&peer_id, message);
         self.swarm
             .behaviour_mut()
             .request_response
@@ -224,6 +401,242 @@ impl Node {
         Ok(())
     }
 
+    /// Announce a new share blockhash to all connected peers
+    pub fn send_inventory_to_peers(&mut self, blockhash: BlockHash) {
+        let peers = self.swarm.connected_peers().cloned().collect::<Vec<_>>();
+        self.send_inventory(peers, blockhash);
+    }
+
+    /// Announce a new share blockhash to the given peers
+    fn send_inventory(&mut self, peers: Vec<PeerId>, blockhash: BlockHash) {
+        info!(
+            "Sending inventory for share {blockhash} to {} peers",
+            peers.len()
+        );
+        for peer_id in peers {
+            self.swarm.behaviour_mut().request_response.send_request(
+                &peer_id,
+                Message::Inventory(InventoryMessage::BlockHashes(vec![blockhash])),
+            );
+        }
+    }
+
+    /// Broadcast a new share block to the network over gossipsub as a
+    /// compact share block.
+    ///
+    /// The shares topic only carries compact share blocks, so peers that
+    /// have not negotiated COMPACT_SHARES are sent inventory instead and
+    /// request the full share block. If gossipsub has no peers for the
+    /// shares topic yet, we fall back to inventory for all peers.
+    pub fn broadcast_share(&mut self, share_block: ShareBlock) {
+        let blockhash = share_block.block_hash();
+        let result = CompactShareBlock::from_share_block(&share_block)
+            .map_err(|e| e.into())
+            .and_then(|compact| self.publish(Message::CompactShareBlock(compact)));
+        if let Err(e) = result {
+            debug!("Failed to gossip share {blockhash}: {e}, sending inventory instead");
+            self.send_inventory_to_peers(blockhash);
+            return;
+        }
+        let full_block_peers = self
+            .swarm
+            .connected_peers()
+            .filter(|peer| {
+                !self
+                    .handshakes
+                    .supports(peer, handshake::services::COMPACT_SHARES)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        if !full_block_peers.is_empty() {
+            self.send_inventory(full_block_peers, blockhash);
+        }
+    }
+
+    /// Publish a compact share block or transaction on its gossipsub topic
+    pub fn publish(&mut self, message: Message) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let topic = match message {
+            Message::CompactShareBlock(_) => gossip::shares_topic(self.magic),
+            Message::Transaction(_) => gossip::transactions_topic(self.magic),
+            message => return Err(format!("{message} messages are not gossiped").into()),
+        };
+        let data = gossip::encode_gossip(self.magic, message);
+        self.swarm.behaviour_mut().gossipsub.publish(topic, data)?;
+        Ok(())
+    }
+
+    /// Validate gossip messages and tell gossipsub whether to forward them
+    async fn handle_gossip_event(&mut self, event: gossipsub::Event) {
+        let gossipsub::Event::Message {
+            propagation_source,
+            message_id,
+            message,
+        } = event
+        else {
+            return;
+        };
+        let acceptance = match gossip::decode_gossip(&message.data) {
+            Ok(raw) if raw.magic() == &self.magic => {
+                self.handle_gossip_message(propagation_source, raw.into_payload())
+                    .await
+            }
+            Ok(raw) => {
+                debug!(
+                    "Gossip from peer {propagation_source} for network {:?}",
+                    raw.magic()
+                );
+                MessageAcceptance::Reject
+            }
+            Err(e) => {
+                debug!("Malformed gossip from peer {propagation_source}: {e}");
+                self.peer_scores.record_offence(
+                    propagation_source,
+                    Offence::MalformedMessage,
+                    SystemTimeProvider.seconds_since_epoch(),
+                );
+                MessageAcceptance::Reject
+            }
+        };
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(&message_id, &propagation_source, acceptance)
+        {
+            debug!("Failed to report gossip validation result: {e}");
+        }
+    }
+
+    /// Validate a gossiped compact share block or transaction.
+    ///
+    /// Messages that fail validation are rejected and their sender
+    /// penalised. Messages we can't validate yet, e.g. shares whose
+    /// parent or transactions we don't have, are ignored and not
+    /// forwarded. We relay those shares ourselves once they are complete
+    /// and added to the chain.
+    ///
+    /// Gossiped transactions are validated but not stored, only
+    /// transactions we requested are added to the store.
+    async fn handle_gossip_message(&mut self, peer: PeerId, message: Message) -> MessageAcceptance {
+        let result = match message {
+            Message::CompactShareBlock(compact) => self.handle_gossip_share(peer, compact).await,
+            Message::Transaction(transaction) => validate_transaction(&transaction)
+                .map(|_| MessageAcceptance::Accept)
+                .map_err(|e| e.into()),
+            message => {
+                debug!("Unexpected {message} gossip from peer {peer}");
+                return MessageAcceptance::Reject;
+            }
+        };
+        match result {
+            Ok(acceptance) => acceptance,
+            Err(e) => {
+                debug!("Failed to handle gossip from peer {peer}: {e}");
+                let invalid = Offence::from_error(e.as_ref()).is_some();
+                penalise_peer(
+                    &self.peer_scores,
+                    peer,
+                    e.as_ref(),
+                    SystemTimeProvider.seconds_since_epoch(),
+                    &self.swarm_tx,
+                )
+                .await;
+                if invalid {
+                    MessageAcceptance::Reject
+                } else {
+                    MessageAcceptance::Ignore
+                }
+            }
+        }
+    }
+
+    /// Reconstruct a gossiped compact share block and add it to the chain.
+    ///
+    /// Shares missing transactions are completed with a GetShareBlockTxns
+    /// request to the peer, shares missing their parent or uncles go to
+    /// the orphan pool. Both are ignored by gossipsub.
+    async fn handle_gossip_share(
+        &mut self,
+        peer: PeerId,
+        compact: CompactShareBlock,
+    ) -> Result<MessageAcceptance, Box<dyn Error + Send + Sync>> {
+        let blockhash = compact.block_hash();
+        if self.store.get_share(&blockhash).is_some() {
+            return Ok(MessageAcceptance::Ignore);
+        }
+        let partial = reconstruct_compact_share_block(compact, &self.store)?;
+        if !partial.is_complete() {
+            self.request_missing_transactions(peer, partial);
+            return Ok(MessageAcceptance::Ignore);
+        }
+        let share_block = partial.into_share_block()?;
+        match handle_share_block(
+            share_block.clone(),
+            self.store.clone(),
+            &self.stratum_config,
+            ValidationMode::Relay,
+            &SystemTimeProvider,
+        )
+        .await
+        {
+            Ok(()) => {
+                self.process_orphans(blockhash).await;
+                Ok(MessageAcceptance::Accept)
+            }
+            Err(e) => match orphan_pool::missing_share(e.as_ref()) {
+                Some(missing) => {
+                    self.add_orphan(peer, share_block, missing).await;
+                    Ok(MessageAcceptance::Ignore)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Add share blocks to the download queue and request them from peers
+    pub fn queue_share_downloads(&mut self, blockhashes: Vec<BlockHash>) {
+        self.download_queue.enqueue(blockhashes);
+        self.request_share_downloads();
+    }
+
+    /// Send GetData requests for queued share blocks, spread across
+    /// connected peers.
+    fn request_share_downloads(&mut self) {
+        let peers = self.swarm.connected_peers().cloned().collect::<Vec<_>>();
+        for (peer_id, blockhash) in self.download_queue.next_requests(&peers) {
+            let request_id = self
+                .swarm
+                .behaviour_mut()
+                .request_response
+                .send_request(&peer_id, Message::GetData(GetData::Block(blockhash)));
+            self.download_requests.insert(request_id, blockhash);
+        }
+        debug!(
+            "Share downloads queued: {}, in flight: {}",
+            self.download_queue.queued(),
+            self.download_queue.in_flight()
+        );
+    }
+
+    /// Ban a peer for the configured ban duration and disconnect it
+    pub fn ban_peer(&mut self, peer_id: PeerId) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.peer_scores
+            .ban(peer_id, SystemTimeProvider.seconds_since_epoch())?;
+        let _ = self.swarm.disconnect_peer_id(peer_id);
+        Ok(())
+    }
+
+    /// Lift the ban on a peer
+    pub fn unban_peer(&mut self, peer_id: &PeerId) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.peer_scores.unban(peer_id)
+    }
+
+    /// Peers currently banned, with the time their ban ends
+    pub fn banned_peers(&self) -> Vec<(PeerId, u64)> {
+        self.peer_scores
+            .banned_peers(SystemTimeProvider.seconds_since_epoch())
+    }
+
     /// Handle the command to get pplns shares from store
     pub fn handle_get_pplns_shares(
         &self,
@@ -241,34 +654,68 @@ impl Node {
         match event {
             SwarmEvent::NewListenAddr { address, .. } => {
                 info!("Listening on {address:?}");
+                self.reachability.add_listen_address(&address);
+                Ok(())
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                info!("No longer listening on {address:?}");
+                self.reachability.remove_listen_address(&address);
                 Ok(())
             }
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
+                if self
+                    .peer_scores
+                    .is_banned(&peer_id, SystemTimeProvider.seconds_since_epoch())
+                {
+                    info!("Disconnecting banned peer {peer_id}");
+                    let _ = self.swarm.disconnect_peer_id(peer_id);
+                    return Ok(());
+                }
+                // Further connections to a peer, e.g. after a hole punch,
+                // share the handshake of the first connection
+                let first_connection = num_established.get() == 1;
+                if first_connection {
+                    self.handshakes.connected(peer_id, endpoint.is_dialer());
+                }
                 match endpoint {
-                    libp2p::core::ConnectedPoint::Dialer { .. } => {
-                        if let Err(e) =
-                            send_getheaders(peer_id, self.store.clone(), self.swarm_tx.clone())
-                                .await
-                        {
-                            error!(
-                                "Failed to handle outbound connection to peer {}: {}",
-                                peer_id, e
-                            );
-                        } else {
-                            info!("Outbound connection established to peer: {}", peer_id);
-                        }
+                    libp2p::core::ConnectedPoint::Dialer { address, .. } => {
+                        self.discovery.outbound_connected(peer_id, &address);
+                        self.add_to_address_book(&peer_id, &[address]);
+                        self.record_peer_connection(&peer_id, true);
+                        info!("Outbound connection established to peer: {}", peer_id);
                     }
                     libp2p::core::ConnectedPoint::Listener { .. } => {
                         info!("Inbound connection established from peer: {peer_id}");
                     }
                 }
+                if first_connection {
+                    let version = handshake::local_version(&self.store);
+                    self.swarm
+                        .behaviour_mut()
+                        .request_response
+                        .send_request(&peer_id, Message::Version(version));
+                }
                 Ok(())
             }
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                ..
+            } => {
                 info!("Disconnected from peer: {peer_id}");
+                if num_established == 0 {
+                    self.handshakes.disconnected(&peer_id);
+                    self.sync.peer_disconnected(&peer_id);
+                    self.discovery.disconnected(&peer_id);
+                }
                 self.swarm.behaviour_mut().remove_peer(&peer_id);
+                self.download_queue.remove_peer(&peer_id);
+                self.request_share_downloads();
                 Ok(())
             }
             SwarmEvent::OutgoingConnectionError {
@@ -276,6 +723,10 @@ impl Node {
                 error,
                 connection_id,
             } => {
+                if let Some(peer_id) = peer_id {
+                    self.discovery.dial_failed(&peer_id);
+                    self.record_peer_connection(&peer_id, false);
+                }
                 error!(
                     "Failed to connect to peer: {peer_id:?}, error: {error}, connection_id: {connection_id}"
                 );
@@ -297,6 +748,37 @@ impl Node {
                     self.handle_request_response_event(request_response_event)
                         .await
                 }
+                P2PoolBehaviourEvent::Gossipsub(gossip_event) => {
+                    self.handle_gossip_event(gossip_event).await;
+                    Ok(())
+                }
+                P2PoolBehaviourEvent::Autonat(autonat_event) => {
+                    self.handle_autonat_event(autonat_event);
+                    Ok(())
+                }
+                P2PoolBehaviourEvent::RelayClient(relay_event) => {
+                    match relay_event {
+                        relay::client::Event::ReservationReqAccepted { relay_peer_id, .. } => {
+                            info!("Relay {relay_peer_id} accepted our reservation")
+                        }
+                        event => debug!("Relay client event: {event:?}"),
+                    }
+                    Ok(())
+                }
+                P2PoolBehaviourEvent::RelayServer(relay_event) => {
+                    debug!("Relay server event: {relay_event:?}");
+                    Ok(())
+                }
+                P2PoolBehaviourEvent::Dcutr(dcutr::Event {
+                    remote_peer_id,
+                    result,
+                }) => {
+                    match result {
+                        Ok(_) => info!("Upgraded relayed connection to {remote_peer_id} to direct"),
+                        Err(e) => debug!("Hole punching to {remote_peer_id} failed: {e}"),
+                    }
+                    Ok(())
+                }
             },
             _ => Ok(()),
         }
@@ -305,11 +787,14 @@ impl Node {
     /// Handle identify events, these are events that are generated by the identify protocol
     fn handle_identify_event(&mut self, event: identify::Event) {
         match event {
-            identify::Event::Received { peer_id, info } => {
+            identify::Event::Received { peer_id, info, .. } => {
                 info!(
                     "Identified Peer {} with protocol version {}",
                     peer_id, info.protocol_version
                 );
+                self.add_to_address_book(&peer_id, &info.listen_addrs);
+                self.discovery
+                    .add_candidate(peer_id, info.listen_addrs.iter().cloned());
                 // Add the peer's advertised addresses to Kademlia
                 for addr in info.listen_addrs {
                     self.swarm
@@ -332,6 +817,51 @@ impl Node {
         }
     }
 
+    /// Handle AutoNAT events, listening through relays while peers can't dial us
+    fn handle_autonat_event(&mut self, event: autonat::Event) {
+        match event {
+            autonat::Event::StatusChanged { old, new } => {
+                info!("Reachability changed from {old:?} to {new:?}");
+                self.reachability.set_nat_status(&new);
+                match new {
+                    NatStatus::Private => self.listen_via_relays(),
+                    NatStatus::Public(_) => self.stop_listening_via_relays(),
+                    NatStatus::Unknown => {}
+                }
+            }
+            event => debug!("AutoNAT event: {event:?}"),
+        }
+    }
+
+    /// Listen on circuit addresses through the configured relays, so
+    /// peers can connect to us through them
+    fn listen_via_relays(&mut self) {
+        if !self.relay_listeners.is_empty() {
+            return;
+        }
+        for relay in self.relay_servers.clone() {
+            let circuit = relay.with(libp2p::multiaddr::Protocol::P2pCircuit);
+            match self.swarm.listen_on(circuit.clone()) {
+                Ok(listener) => {
+                    info!("Listening through relay {circuit}");
+                    self.relay_listeners.push(listener);
+                }
+                Err(e) => warn!("Failed to listen through relay {}: {}", circuit, e),
+            }
+        }
+    }
+
+    fn stop_listening_via_relays(&mut self) {
+        for listener in self.relay_listeners.drain(..) {
+            self.swarm.remove_listener(listener);
+        }
+    }
+
+    /// Handle to read the node's reachability from other tasks
+    pub fn reachability(&self) -> ReachabilityHandle {
+        self.reachability.clone()
+    }
+
     /// Handle kademlia events, these are events that are generated by the kademlia protocol
     fn handle_kademlia_event(&mut self, event: KademliaEvent) {
         match event {
@@ -345,10 +875,14 @@ impl Node {
                 info!(
                     "Routing updated for peer: {peer}, is_new_peer: {is_new_peer}, addresses: {addresses:?}, bucket_range: {bucket_range:?}, old_peer: {old_peer:?}"
                 );
+                let addresses = addresses.into_vec();
+                self.add_to_address_book(&peer, &addresses);
+                self.discovery.add_candidate(peer, addresses);
             }
             KademliaEvent::OutboundQueryProgressed { result, .. } => match result {
                 QueryResult::GetClosestPeers(Ok(ok)) => {
                     debug!("Got closest peers: {:?}", ok.peers);
+                    self.dial_discovered_peers();
                 }
                 QueryResult::GetClosestPeers(Err(err)) => {
                     error!("Failed to get closest peers: {err}");
@@ -359,6 +893,63 @@ impl Node {
         }
     }
 
+    /// Walk the DHT from a random key to discover new peers, and dial
+    /// peers until we reach the target number of outbound peers.
+    pub fn discovery_tick(&mut self) {
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        if let Err(e) = kademlia.bootstrap() {
+            debug!("Failed to bootstrap Kademlia: {}", e);
+        }
+        kademlia.get_closest_peers(PeerId::random());
+        self.dial_discovered_peers();
+    }
+
+    /// Dial discovered peers, spread across network groups, if we are
+    /// below the target number of outbound peers
+    fn dial_discovered_peers(&mut self) {
+        let now = SystemTimeProvider.seconds_since_epoch();
+        let mut exclude: HashSet<PeerId> = self.swarm.connected_peers().cloned().collect();
+        exclude.insert(*self.swarm.local_peer_id());
+        exclude.extend(
+            self.peer_scores
+                .banned_peers(now)
+                .into_iter()
+                .map(|(peer_id, _)| peer_id),
+        );
+        for (peer_id, addresses) in self.discovery.peers_to_dial(&exclude) {
+            let opts = DialOpts::peer_id(peer_id).addresses(addresses).build();
+            if let Err(e) = self.swarm.dial(opts) {
+                debug!("Failed to dial discovered peer {}: {}", peer_id, e);
+                self.discovery.dial_failed(&peer_id);
+            } else {
+                info!("Dialed discovered peer {}", peer_id);
+            }
+        }
+    }
+
+    /// Record addresses learned for a peer in the address book, so we can redial it after a restart
+    fn add_to_address_book(&self, peer_id: &PeerId, addresses: &[Multiaddr]) {
+        let addresses: Vec<String> = addresses.iter().map(|addr| addr.to_string()).collect();
+        if let Err(e) = self.store.add_peer_addresses(
+            &peer_id.to_string(),
+            &addresses,
+            SystemTimeProvider.seconds_since_epoch(),
+        ) {
+            warn!("Failed to update address book for peer {}: {}", peer_id, e);
+        }
+    }
+
+    /// Record the outcome of a dial in the address book
+    fn record_peer_connection(&self, peer_id: &PeerId, success: bool) {
+        if let Err(e) = self.store.record_peer_connection(
+            &peer_id.to_string(),
+            success,
+            SystemTimeProvider.seconds_since_epoch(),
+        ) {
+            warn!("Failed to update address book for peer {}: {}", peer_id, e);
+        }
+    }
+
     /// Handle connection established events, these are events that are generated when a connection is established
     async fn handle_connection_established(&mut self, peer_id: libp2p::PeerId) {
         info!("Connection established with peer: {peer_id}");
@@ -375,54 +966,552 @@ impl Node {
         &mut self,
         request_response_event: RequestResponseEvent,
     ) -> Result<(), Box<dyn Error>> {
-        if let RequestResponseEvent::Message {
+        match request_response_event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    libp2p::request_response::Message::Request {
+                        request_id: _,
+                        request,
+                        channel,
+                    },
+            } => self.handle_request(peer, request, channel).await,
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    libp2p::request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                self.handle_response(peer, request_id, response).await;
+                Ok(())
+            }
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                debug!(
+                    "Outbound failure to peer: {peer}, request_id: {request_id}, error: {error:?}"
+                );
+                if let OutboundFailure::Io(e) = &error {
+                    self.penalise_codec_failure(peer, e);
+                }
+                // Retry failed share downloads, possibly from another peer
+                if let Some(blockhash) = self.download_requests.remove(&request_id) {
+                    self.download_queue.received(&blockhash);
+                    self.queue_share_downloads(vec![blockhash]);
+                }
+                self.pending_compact_blocks.remove(&request_id);
+                self.transaction_requests.remove(&request_id);
+                Ok(())
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                debug!("Inbound failure from peer: {peer}, error: {error:?}");
+                if let InboundFailure::Io(e) = &error {
+                    self.penalise_codec_failure(peer, e);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Accept or refuse the Version sent by a peer.
+    ///
+    /// Peers with incompatible versions are disconnected, otherwise we
+    /// answer with a VerAck.
+    async fn handle_version(
+        &mut self,
+        peer: PeerId,
+        version: VersionMessage,
+        channel: ResponseChannel<Message>,
+    ) -> Result<(), Box<dyn Error>> {
+        info!(
+            "Received version {} from peer {peer}, user agent {}, best share height {}",
+            version.version, version.user_agent, version.best_share_height
+        );
+        let tip = ChainTip {
+            height: version.best_share_height,
+            chain_work: version.chain_work,
+        };
+        match self.handshakes.received_version(peer, version) {
+            Ok(complete) => {
+                self.sync.peer_tip(peer, tip);
+                if self
+                    .swarm
+                    .behaviour_mut()
+                    .request_response
+                    .send_response(channel, Message::VerAck(()))
+                    .is_err()
+                {
+                    debug!("Failed to send verack to peer {peer}");
+                }
+                if complete {
+                    self.handshake_complete(peer).await;
+                }
+            }
+            Err(e) => {
+                warn!("Disconnecting peer {peer}: {e}");
+                let _ = self.swarm.disconnect_peer_id(peer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start syncing with a peer once the handshake is complete
+    async fn handshake_complete(&mut self, peer: PeerId) {
+        info!("Handshake complete with peer {peer}");
+        self.request_share_downloads();
+        self.update_sync().await;
+    }
+
+    /// Record a chain tip announced by a peer
+    async fn handle_tip(
+        &mut self,
+        peer: PeerId,
+        tip: TipMessage,
+        channel: ResponseChannel<Message>,
+    ) {
+        debug!(
+            "Peer {peer} announced tip at height {}",
+            tip.best_share_height
+        );
+        self.sync.peer_tip(
             peer,
-            message:
-                libp2p::request_response::Message::Request {
-                    request_id: _,
-                    request,
-                    channel,
-                },
-        } = request_response_event
+            ChainTip {
+                height: tip.best_share_height,
+                chain_work: tip.chain_work,
+            },
+        );
+        // Acknowledge so the request does not fail on the sender
+        if self
+            .swarm
+            .behaviour_mut()
+            .request_response
+            .send_response(channel, Message::VerAck(()))
+            .is_err()
         {
-            // Create the RequestContext
-            let ctx = RequestContext::<ResponseChannel<Message>, _> {
+            debug!("Failed to acknowledge tip from peer {peer}");
+        }
+        self.update_sync().await;
+    }
+
+    /// Announce our chain tip to peers if it changed, and request
+    /// headers from peers that are ahead of us.
+    pub async fn sync_tick(&mut self) {
+        let tip = sync::local_tip(&self.store);
+        if self.announced_tip != Some(tip) {
+            self.announced_tip = Some(tip);
+            let peers = self
+                .swarm
+                .connected_peers()
+                .filter(|peer| self.handshakes.is_complete(peer))
+                .cloned()
+                .collect::<Vec<_>>();
+            debug!(
+                "Announcing tip at height {} to {} peers",
+                tip.height,
+                peers.len()
+            );
+            for peer in peers {
+                self.swarm.behaviour_mut().request_response.send_request(
+                    &peer,
+                    Message::Tip(TipMessage {
+                        best_share_height: tip.height,
+                        chain_work: tip.chain_work,
+                    }),
+                );
+            }
+        }
+        self.update_sync().await;
+    }
+
+    /// Advance the sync state machine, requesting headers if a peer
+    /// has more work than our chain.
+    async fn update_sync(&mut self) {
+        let downloads_pending =
+            self.download_queue.queued() > 0 || self.download_queue.in_flight() > 0;
+        if let Some(peer) = self.sync.update(
+            sync::local_tip(&self.store),
+            downloads_pending,
+            SystemTimeProvider.seconds_since_epoch(),
+        ) {
+            info!("Requesting headers from peer {peer} with more work");
+            if let Err(e) = send_getheaders(peer, self.store.clone(), self.swarm_tx.clone()).await {
+                error!("Failed to request headers from peer {}: {}", peer, e);
+            }
+        }
+        for peer in self.sync.take_failed_peers() {
+            if self.peer_scores.record_offence(
                 peer,
-                request: request.clone(),
-                store: self.store.clone(),
-                response_channel: channel,
-                swarm_tx: self.swarm_tx.clone(),
-                time_provider: SystemTimeProvider,
-            };
-
-            // Check readiness with a timeout
-            match tokio::time::timeout(Duration::from_secs(1), self.service.ready()).await {
-                Ok(Ok(_)) => {
-                    // Service is ready, call it
-                    if let Err(err) = self.service.call(ctx).await {
-                        error!("Service call failed for peer {}: {}", peer, err);
-                    }
+                Offence::UndeliveredChainWork,
+                SystemTimeProvider.seconds_since_epoch(),
+            ) {
+                let _ = self.swarm.disconnect_peer_id(peer);
+            }
+        }
+    }
+
+    /// Penalise a peer that sent a message we could not decode
+    fn penalise_codec_failure(&mut self, peer: PeerId, error: &std::io::Error) {
+        if let Some(offence) = Offence::from_io_error(error)
+            && self.peer_scores.record_offence(
+                peer,
+                offence,
+                SystemTimeProvider.seconds_since_epoch(),
+            )
+        {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+    }
+
+    /// Handle a response to a request sent by this node.
+    ///
+    /// Share blocks we are downloading during sync are released from
+    /// the download queue and added to the chain without relaying them
+    /// to peers. If the parent for a downloaded share block has not
+    /// arrived yet, the share block is held in the orphan pool till the
+    /// parent is added.
+    ///
+    /// Compact share blocks are reconstructed, fetching any missing
+    /// transactions from the peer before the share is added.
+    async fn handle_response(
+        &mut self,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        response: Message,
+    ) {
+        if let Message::VerAck(_) = response {
+            if self.handshakes.received_verack(peer) {
+                self.handshake_complete(peer).await;
+            }
+            return;
+        }
+        let headers_batch = match &response {
+            Message::ShareHeaders(headers) => Some(headers.len() >= MAX_HEADERS),
+            _ => None,
+        };
+        let result = if let Some(partial) = self.pending_compact_blocks.remove(&request_id) {
+            self.complete_compact_share_block(peer, partial, response)
+                .await
+        } else if let Some(txid) = self.transaction_requests.remove(&request_id) {
+            match response {
+                Message::Transaction(transaction) if transaction.compute_txid() == txid => {
+                    handle_transaction(transaction, self.store.clone()).await
                 }
-                Ok(Err(err)) => {
-                    // Service failed permanently
-                    error!("Service not ready for peer {}: {}", peer, err);
-                    if let Err(send_err) = self.swarm_tx.send(SwarmSend::Disconnect(peer)).await {
-                        error!(
-                            "Failed to send disconnect command for peer {}: {:?}",
-                            peer, send_err
-                        );
+                Message::NotFound(_) => {
+                    debug!("Transaction {txid} not found by peer {peer}");
+                    Ok(())
+                }
+                response => Err(UnsolicitedMessage(response.to_string()).into()),
+            }
+        } else {
+            match self.download_requests.remove(&request_id) {
+                Some(blockhash) => {
+                    self.download_queue.received(&blockhash);
+                    match response {
+                        Message::ShareBlock(share_block) => {
+                            let parent = share_block.header.prev_share_blockhash;
+                            if self.store.get_share(&parent).is_none() {
+                                debug!(
+                                    "Parent for share {blockhash} not received yet, holding as orphan"
+                                );
+                                self.add_orphan(peer, share_block, parent).await;
+                                Ok(())
+                            } else {
+                                let result = handle_share_block(
+                                    share_block,
+                                    self.store.clone(),
+                                    &self.stratum_config,
+                                    ValidationMode::Sync,
+                                    &SystemTimeProvider,
+                                )
+                                .await;
+                                if result.is_ok() {
+                                    self.process_orphans(blockhash).await;
+                                }
+                                result
+                            }
+                        }
+                        _ => {
+                            debug!("Share {blockhash} not received from peer {peer}");
+                            Ok(())
+                        }
                     }
                 }
+                None => match response {
+                    Message::CompactShareBlock(compact) => {
+                        self.handle_compact_share_block(peer, compact).await
+                    }
+                    response => {
+                        handle_response(
+                            peer,
+                            response,
+                            self.store.clone(),
+                            &self.stratum_config,
+                            self.swarm_tx.clone(),
+                            &SystemTimeProvider,
+                        )
+                        .await
+                    }
+                },
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to handle response from peer {}: {}", peer, e);
+            penalise_peer(
+                &self.peer_scores,
+                peer,
+                e.as_ref(),
+                SystemTimeProvider.seconds_since_epoch(),
+                &self.swarm_tx,
+            )
+            .await;
+        }
+        // Checked once the headers are in store, against the work they add up to
+        if let Some(more_requested) = headers_batch {
+            match self.store.get_best_header_work() {
+                Ok(header_work) => self.sync.headers_received(
+                    &peer,
+                    more_requested,
+                    header_work,
+                    SystemTimeProvider.seconds_since_epoch(),
+                ),
+                Err(e) => error!("Failed to get best header chain work: {}", e),
+            }
+        }
+        self.request_share_downloads();
+        self.update_sync().await;
+    }
 
-                Err(_) => {
-                    // Timeout due to rate limit or other delay
-                    error!("Service readiness timed out for peer {}", peer);
-                    if let Err(send_err) = self.swarm_tx.send(SwarmSend::Disconnect(peer)).await {
-                        error!(
-                            "Failed to send disconnect command for peer {}: {:?}",
-                            peer, send_err
-                        );
+    /// Hold a share that is missing its parent or an uncle, and request
+    /// the missing share from the peer that sent it
+    pub async fn add_orphan(&mut self, peer: PeerId, share_block: ShareBlock, missing: BlockHash) {
+        self.hold_orphan(peer, share_block, missing);
+        // The missing share may have been added while the orphan was on its way to us
+        if self.store.get_share(&missing).is_some() {
+            self.process_orphans(missing).await;
+        }
+        self.update_orphan_status();
+    }
+
+    fn hold_orphan(&mut self, peer: PeerId, share_block: ShareBlock, missing: BlockHash) {
+        debug!(
+            "Holding orphan share {} waiting for share {missing}",
+            share_block.block_hash()
+        );
+        let now = SystemTimeProvider.seconds_since_epoch();
+        if self.orphans.add(share_block, peer, missing, now) {
+            debug!("Requesting missing share {missing} from peer {peer}");
+            self.swarm
+                .behaviour_mut()
+                .request_response
+                .send_request(&peer, Message::GetData(GetData::Block(missing)));
+        }
+    }
+
+    /// Process the orphans waiting for a share that was added to the
+    /// chain. Orphans that connect are relayed, and the orphans waiting
+    /// for them are processed in turn.
+    ///
+    /// Orphans wait up to ORPHAN_EXPIRY_SECS for their parent, so they
+    /// are validated in sync mode, see [ValidationMode].
+    pub async fn process_orphans(&mut self, blockhash: BlockHash) {
+        let mut added = vec![blockhash];
+        while let Some(blockhash) = added.pop() {
+            for (peer, share_block) in self.orphans.take_waiting_for(&blockhash) {
+                let orphan = share_block.block_hash();
+                match handle_share_block(
+                    share_block.clone(),
+                    self.store.clone(),
+                    &self.stratum_config,
+                    ValidationMode::Sync,
+                    &SystemTimeProvider,
+                )
+                .await
+                {
+                    Ok(()) => {
+                        info!("Added orphan share {orphan} after share {blockhash}");
+                        self.broadcast_share(share_block);
+                        added.push(orphan);
                     }
+                    Err(e) => match orphan_pool::missing_share(e.as_ref()) {
+                        Some(missing) => self.hold_orphan(peer, share_block, missing),
+                        None => {
+                            debug!("Dropping invalid orphan share {orphan}: {e}");
+                            penalise_peer(
+                                &self.peer_scores,
+                                peer,
+                                e.as_ref(),
+                                SystemTimeProvider.seconds_since_epoch(),
+                                &self.swarm_tx,
+                            )
+                            .await;
+                        }
+                    },
+                }
+            }
+        }
+        self.update_orphan_status();
+    }
+
+    /// Expire old orphans and publish the orphan counts
+    fn update_orphan_status(&mut self) {
+        self.orphans
+            .expire(SystemTimeProvider.seconds_since_epoch());
+        self.sync
+            .status()
+            .set_orphans(self.orphans.len(), self.orphans.evicted());
+    }
+
+    /// Reconstruct a compact share block from our template mempool.
+    ///
+    /// Complete share blocks are added to the chain and relayed. For
+    /// incomplete share blocks the missing transactions are requested
+    /// from the peer that sent the compact share block.
+    async fn handle_compact_share_block(
+        &mut self,
+        peer: PeerId,
+        compact: CompactShareBlock,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let partial = reconstruct_compact_share_block(compact, &self.store)?;
+        if partial.is_complete() {
+            return handle_and_relay_share_block(
+                peer,
+                partial.into_share_block()?,
+                self.store.clone(),
+                &self.stratum_config,
+                ValidationMode::Relay,
+                self.swarm_tx.clone(),
+                &SystemTimeProvider,
+            )
+            .await;
+        }
+        self.request_missing_transactions(peer, partial);
+        Ok(())
+    }
+
+    /// Request the transactions missing from a partial share block from
+    /// the peer that sent the compact share block.
+    fn request_missing_transactions(&mut self, peer: PeerId, partial: PartialShareBlock) {
+        let request = BlockTransactionsRequest {
+            block_hash: partial.block_hash(),
+            indexes: partial.missing_indexes(),
+        };
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .request_response
+            .send_request(&peer, Message::GetShareBlockTxns(request));
+        self.pending_compact_blocks.insert(request_id, partial);
+    }
+
+    /// Fill the missing transactions of a compact share block from the
+    /// peer's ShareBlockTxns response, then add and relay the share.
+    ///
+    /// If the peer could not provide the transactions, we fall back to
+    /// requesting the full share block.
+    async fn complete_compact_share_block(
+        &mut self,
+        peer: PeerId,
+        partial: PartialShareBlock,
+        response: Message,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match response {
+            Message::ShareBlockTxns(block_transactions) => {
+                let share_block = handle_share_block_txns(partial, block_transactions)?;
+                handle_and_relay_share_block(
+                    peer,
+                    share_block,
+                    self.store.clone(),
+                    &self.stratum_config,
+                    ValidationMode::Relay,
+                    self.swarm_tx.clone(),
+                    &SystemTimeProvider,
+                )
+                .await
+            }
+            _ => {
+                debug!(
+                    "Transactions for share {} not received from peer {peer}, requesting full share",
+                    partial.block_hash()
+                );
+                self.swarm.behaviour_mut().request_response.send_request(
+                    &peer,
+                    Message::GetData(GetData::Block(partial.block_hash())),
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle a request from a peer using the request service
+    async fn handle_request(
+        &mut self,
+        peer: PeerId,
+        request: Message,
+        channel: ResponseChannel<Message>,
+    ) -> Result<(), Box<dyn Error>> {
+        // The handshake state lives in the node, outside the request service
+        if !self.handshakes.accepts_request(&peer, &request) {
+            warn!("Disconnecting peer {peer}: {request} request before version handshake");
+            self.peer_scores.record_offence(
+                peer,
+                Offence::MissingHandshake,
+                SystemTimeProvider.seconds_since_epoch(),
+            );
+            let _ = self.swarm.disconnect_peer_id(peer);
+            return Ok(());
+        }
+        match request {
+            Message::Version(version) => return self.handle_version(peer, version, channel).await,
+            Message::Tip(tip) => {
+                self.handle_tip(peer, tip, channel).await;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // Create the RequestContext
+        let ctx = RequestContext::<ResponseChannel<Message>, _> {
+            peer,
+            request: request.clone(),
+            store: self.store.clone(),
+            response_channel: channel,
+            swarm_tx: self.swarm_tx.clone(),
+            time_provider: SystemTimeProvider,
+            stratum_config: self.stratum_config.clone(),
+        };
+
+        // Check readiness with a timeout
+        match tokio::time::timeout(Duration::from_secs(1), self.service.ready()).await {
+            Ok(Ok(_)) => {
+                // Service is ready, call it
+                if let Err(err) = self.service.call(ctx).await {
+                    error!("Service call failed for peer {}: {}", peer, err);
+                }
+            }
+            Ok(Err(err)) => {
+                // Service failed permanently
+                error!("Service not ready for peer {}: {}", peer, err);
+                if let Err(send_err) = self.swarm_tx.send(SwarmSend::Disconnect(peer)).await {
+                    error!(
+                        "Failed to send disconnect command for peer {}: {:?}",
+                        peer, send_err
+                    );
+                }
+            }
+
+            Err(_) => {
+                // Timeout due to rate limit or other delay
+                error!("Service readiness timed out for peer {}", peer);
+                if let Err(send_err) = self.swarm_tx.send(SwarmSend::Disconnect(peer)).await {
+                    error!(
+                        "Failed to send disconnect command for peer {}: {:?}",
+                        peer, send_err
+                    );
                 }
             }
         }
@@ -467,14 +1556,8 @@ mod tests {
     use futures::StreamExt;
     use std::time::{Duration, Instant};
 
-    #[tokio::test]
-    async fn test_node_dial_timeout_does_not_hang() {
-        use libp2p::swarm::SwarmEvent;
-
-        // Use a local address that will refuse connections immediately
-        let unreachable_peer = "/ip4/127.0.0.1/tcp/65535".to_string(); // Fast fail
-
-        let mut network_config = NetworkConfig {
+    fn test_config() -> Config {
+        let network_config = NetworkConfig {
             listen_address: "/ip4/127.0.0.1/tcp/0".to_string(),
             dial_peers: vec![],
             max_pending_incoming: 10,
@@ -491,12 +1574,23 @@ mod tests {
             max_requests_per_second: 1,
             peer_inactivity_timeout_secs: 30,
             dial_timeout_secs: 2,
+            node_key_path: None,
+            max_redial_peers: 8,
+            ban_threshold: 100,
+            ban_duration_secs: 86400,
+            bootstrap_peers: vec![],
+            dns_seeds: vec![],
+            target_outbound_peers: 8,
+            discovery_interval_secs: 60,
+            max_peers_per_subnet: 2,
+            enable_quic: false,
+            enable_autonat: false,
+            enable_relay_client: false,
+            relay_servers: vec![],
+            enable_relay_server: false,
         };
-        network_config.dial_peers = vec![unreachable_peer];
-        network_config.dial_timeout_secs = 2;
-
-        let mut config = Config {
-            network: network_config.clone(),
+        Config {
+            network: network_config,
             bitcoinrpc: BitcoinRpcConfig {
                 url: "http://localhost:8332".to_string(),
                 username: "testuser".to_string(),
@@ -525,10 +1619,34 @@ mod tests {
                 auth_user: None,
                 auth_token: None,
             },
-        };
-        config.network = network_config;
+        }
+    }
 
-        let mock_store = ChainStore::default();
+    /// Expectations for the store reads and writes made by Node::new
+    fn expect_node_setup(mock_store: &mut ChainStore) {
+        mock_store.expect_get_metadata().returning(|_| Ok(None));
+        mock_store
+            .expect_get_banned_peers()
+            .returning(|| Ok(Vec::new()));
+        mock_store.expect_put_metadata().returning(|_, _| Ok(()));
+        mock_store
+            .expect_get_peer_records()
+            .returning(|| Ok(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_node_dial_timeout_does_not_hang() {
+        use libp2p::swarm::SwarmEvent;
+
+        // Use a local address that will refuse connections immediately
+        let unreachable_peer = "/ip4/127.0.0.1/tcp/65535".to_string(); // Fast fail
+
+        let mut config = test_config();
+        config.network.dial_peers = vec![unreachable_peer];
+        config.network.dial_timeout_secs = 2;
+
+        let mut mock_store = ChainStore::default();
+        expect_node_setup(&mut mock_store);
         let store = std::sync::Arc::new(mock_store);
 
         let mut node = Node::new(&config, store).expect("Node initialization failed");
@@ -578,4 +1696,84 @@ mod tests {
                                     }
         }
     }
+
+    #[tokio::test]
+    async fn test_node_catches_up_with_old_shares() {
+        use crate::node::messages::{GetData, Message};
+        use crate::node::sync::{ChainTip, SyncState};
+        use crate::test_utils::{build_block_from_work_components, genesis_for_tests};
+        use bitcoin::hashes::Hash;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // A share mined long before now, downloaded after being offline
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/b/");
+        let blockhash = share_block.block_hash();
+        let work = share_block.header.get_work();
+        assert!(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+                > share_block.header.bitcoin_header.time as u64
+                    + crate::shares::validation::MAX_TIME_DIFF
+        );
+
+        let added = std::sync::Arc::new(AtomicBool::new(false));
+        let mut mock_store = ChainStore::default();
+        expect_node_setup(&mut mock_store);
+        mock_store
+            .expect_get_share()
+            .withf(|hash| *hash == bitcoin::BlockHash::all_zeros())
+            .returning(|_| Some(genesis_for_tests()));
+        mock_store
+            .expect_get_target_at()
+            .returning(|_| Ok(0x207fffff));
+        let set_added = added.clone();
+        mock_store
+            .expect_add_share()
+            .times(1)
+            .returning(move |_, _| {
+                set_added.store(true, Ordering::SeqCst);
+                Ok(())
+            });
+        mock_store
+            .expect_put_share_balances()
+            .returning(|_, _| Ok(()));
+        let get_added = added.clone();
+        mock_store
+            .expect_get_tip_height()
+            .returning(move || Ok(Some(get_added.load(Ordering::SeqCst) as u32)));
+        mock_store.expect_get_total_work().returning(move || {
+            Ok(if added.load(Ordering::SeqCst) {
+                work
+            } else {
+                bitcoin::Work::from_le_bytes([0; 32])
+            })
+        });
+
+        let mut node = Node::new(&test_config(), std::sync::Arc::new(mock_store))
+            .expect("Node initialization failed");
+
+        // The peer is ahead of us by the downloaded share
+        let peer = libp2p::PeerId::random();
+        node.sync.peer_tip(
+            peer,
+            ChainTip {
+                height: 1,
+                chain_work: work,
+            },
+        );
+        let request_id = node
+            .swarm
+            .behaviour_mut()
+            .request_response
+            .send_request(&peer, Message::GetData(GetData::Block(blockhash)));
+        node.download_requests.insert(request_id, blockhash);
+
+        node.handle_response(peer, request_id, Message::ShareBlock(share_block))
+            .await;
+
+        assert_eq!(node.sync.state(), SyncState::Synced);
+    }
 }
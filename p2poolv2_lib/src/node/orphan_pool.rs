@@ -0,0 +1,253 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::shares::share_block::ShareBlock;
+use crate::shares::validation::ValidationError;
+use bitcoin::BlockHash;
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+
+/// The maximum number of orphan shares we hold
+pub const MAX_ORPHANS: usize = 100;
+
+/// Orphans are dropped if their missing share doesn't arrive in time
+pub const ORPHAN_EXPIRY_SECS: u64 = 10 * 60;
+
+/// The share an error says we are missing, for shares that failed
+/// validation because their parent or an uncle isn't in the store yet
+pub fn missing_share(error: &(dyn Error + Send + Sync + 'static)) -> Option<BlockHash> {
+    match error.downcast_ref::<ValidationError>()? {
+        ValidationError::PrevShareNotFound(blockhash)
+        | ValidationError::UncleNotFound(blockhash) => Some(*blockhash),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+struct Orphan {
+    share_block: ShareBlock,
+    /// Peer that sent us the share, we ask it for the missing share
+    peer: PeerId,
+    missing: BlockHash,
+    received_at: u64,
+}
+
+/// Shares received before their parent or uncles, keyed by the share
+/// they are waiting for.
+///
+/// Shares arrive out of order under normal propagation latency. We
+/// hold them here, fetch the missing shares, and process the orphans
+/// once the missing shares are added to the chain. The pool is bounded
+/// by size and age, evicting the oldest orphans first.
+#[derive(Debug)]
+pub struct OrphanPool {
+    orphans: HashMap<BlockHash, Orphan>,
+    /// Orphans waiting for each missing share
+    waiting_for: HashMap<BlockHash, HashSet<BlockHash>>,
+    max_orphans: usize,
+    expiry_secs: u64,
+    /// Number of orphans evicted since startup
+    evicted: u64,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new(MAX_ORPHANS, ORPHAN_EXPIRY_SECS)
+    }
+}
+
+impl OrphanPool {
+    pub fn new(max_orphans: usize, expiry_secs: u64) -> Self {
+        Self {
+            orphans: HashMap::new(),
+            waiting_for: HashMap::new(),
+            max_orphans,
+            expiry_secs,
+            evicted: 0,
+        }
+    }
+
+    /// Number of orphans in the pool
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    /// Number of orphans evicted by age or size since startup
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Add an orphan waiting for the missing share.
+    ///
+    /// Returns true if no other orphan is waiting for the missing
+    /// share, and the caller should request it.
+    pub fn add(
+        &mut self,
+        share_block: ShareBlock,
+        peer: PeerId,
+        missing: BlockHash,
+        now: u64,
+    ) -> bool {
+        self.expire(now);
+        let blockhash = share_block.block_hash();
+        if self.orphans.contains_key(&blockhash) {
+            return false;
+        }
+        if self.orphans.len() >= self.max_orphans {
+            self.evict_oldest();
+        }
+        let waiting = self.waiting_for.entry(missing).or_default();
+        let request = waiting.is_empty();
+        waiting.insert(blockhash);
+        self.orphans.insert(
+            blockhash,
+            Orphan {
+                share_block,
+                peer,
+                missing,
+                received_at: now,
+            },
+        );
+        request
+    }
+
+    /// Remove and return the orphans waiting for a share that has been
+    /// added to the chain, with the peers that sent them
+    pub fn take_waiting_for(&mut self, blockhash: &BlockHash) -> Vec<(PeerId, ShareBlock)> {
+        let Some(waiting) = self.waiting_for.remove(blockhash) else {
+            return Vec::new();
+        };
+        waiting
+            .into_iter()
+            .filter_map(|orphan| self.orphans.remove(&orphan))
+            .map(|orphan| (orphan.peer, orphan.share_block))
+            .collect()
+    }
+
+    /// Drop orphans older than the expiry time
+    pub fn expire(&mut self, now: u64) {
+        let expired: Vec<BlockHash> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| now >= orphan.received_at + self.expiry_secs)
+            .map(|(blockhash, _)| *blockhash)
+            .collect();
+        for blockhash in expired {
+            self.remove(&blockhash);
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(oldest) = self
+            .orphans
+            .iter()
+            .min_by_key(|(_, orphan)| orphan.received_at)
+            .map(|(blockhash, _)| *blockhash)
+        {
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, blockhash: &BlockHash) {
+        if let Some(orphan) = self.orphans.remove(blockhash) {
+            self.evicted += 1;
+            if let Some(waiting) = self.waiting_for.get_mut(&orphan.missing) {
+                waiting.remove(blockhash);
+                if waiting.is_empty() {
+                    self.waiting_for.remove(&orphan.missing);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestShareBlockBuilder;
+    use bitcoin::hashes::Hash as _;
+
+    fn orphan(nonce: u32) -> ShareBlock {
+        TestShareBlockBuilder::new().nonce(nonce).build()
+    }
+
+    #[test]
+    fn test_missing_share() {
+        let blockhash = BlockHash::from_byte_array([1; 32]);
+        let error: Box<dyn Error + Send + Sync> =
+            ValidationError::PrevShareNotFound(blockhash).into();
+        assert_eq!(missing_share(error.as_ref()), Some(blockhash));
+        let error: Box<dyn Error + Send + Sync> = ValidationError::UncleNotFound(blockhash).into();
+        assert_eq!(missing_share(error.as_ref()), Some(blockhash));
+        let error: Box<dyn Error + Send + Sync> = ValidationError::TooManyUncles.into();
+        assert_eq!(missing_share(error.as_ref()), None);
+    }
+
+    #[test]
+    fn test_add_and_take_orphans() {
+        let mut pool = OrphanPool::new(10, 600);
+        let parent = BlockHash::from_byte_array([1; 32]);
+        let peer = PeerId::random();
+
+        // Only the first orphan waiting for a share requests it
+        assert!(pool.add(orphan(1), peer, parent, 1000));
+        assert!(!pool.add(orphan(2), peer, parent, 1000));
+        // Duplicates are ignored
+        assert!(!pool.add(orphan(1), peer, parent, 1000));
+        assert_eq!(pool.len(), 2);
+
+        assert!(
+            pool.take_waiting_for(&BlockHash::from_byte_array([2; 32]))
+                .is_empty()
+        );
+        let children = pool.take_waiting_for(&parent);
+        assert_eq!(children.len(), 2);
+        assert!(children.iter().all(|(from, _)| *from == peer));
+        assert!(pool.is_empty());
+        assert_eq!(pool.evicted(), 0);
+
+        // Once taken, the next orphan requests the share again
+        assert!(pool.add(orphan(3), peer, parent, 1000));
+    }
+
+    #[test]
+    fn test_eviction_by_size_and_age() {
+        let mut pool = OrphanPool::new(2, 600);
+        let peer = PeerId::random();
+        let first = BlockHash::from_byte_array([1; 32]);
+        let second = BlockHash::from_byte_array([2; 32]);
+
+        pool.add(orphan(1), peer, first, 1000);
+        pool.add(orphan(2), peer, second, 1001);
+        // The pool is full, the oldest orphan is evicted
+        assert!(!pool.add(orphan(3), peer, second, 1002));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.evicted(), 1);
+        assert!(pool.take_waiting_for(&first).is_empty());
+
+        // Orphans expire after the expiry time
+        pool.expire(1601);
+        assert_eq!(pool.len(), 1);
+        pool.expire(1602);
+        assert!(pool.is_empty());
+        assert_eq!(pool.evicted(), 3);
+    }
+}
@@ -17,16 +17,35 @@
 pub mod receivers;
 pub mod senders;
 
-use crate::node::messages::{GetData, InventoryMessage, Message};
+use crate::config::{Parsed, StratumConfig};
+use crate::node::SwarmSend;
+use crate::node::messages::Message;
+use crate::node::orphan_pool::missing_share;
 use crate::service::p2p_service::RequestContext;
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::share_block::ShareBlock;
+use crate::shares::validation::ValidationMode;
 use crate::utils::time_provider::TimeProvider;
-use receivers::getblocks::handle_getblocks;
-use receivers::getheaders::handle_getheaders;
-use receivers::share_blocks::handle_share_block;
-use receivers::share_headers::handle_share_headers;
+use libp2p::PeerId;
+use receivers::{
+    handle_get_share_block_txns, handle_getblocks, handle_getdata, handle_getheaders,
+    handle_inventory, handle_share_block, handle_share_headers,
+};
 use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::mpsc;
 use tracing::{error, info};
 
+/// Error for messages that are only valid as a response to our own
+/// request, but were sent to us as a request.
+#[derive(Debug, thiserror::Error)]
+#[error("Unsolicited {0} message, only expected as a response")]
+pub struct UnsolicitedMessage(pub String);
+
 /// The Tower service that processes inbound P2P requests.
 pub async fn handle_request<C: Send + Sync + 'static, T: TimeProvider + Send + Sync + 'static>(
     ctx: RequestContext<C, T>,
@@ -54,48 +73,136 @@ pub async fn handle_request<C: Send + Sync + 'static, T: TimeProvider + Send + S
             .await
         }
         Message::ShareHeaders(share_headers) => {
-            handle_share_headers(share_headers, ctx.store, &ctx.time_provider).await
+            handle_share_headers(
+                ctx.peer,
+                share_headers,
+                ctx.store,
+                ctx.swarm_tx,
+                &ctx.time_provider,
+            )
+            .await
         }
         Message::ShareBlock(share_block) => {
-            if let Err(e) = handle_share_block(share_block, ctx.store, &ctx.time_provider).await {
-                error!("Failed to add share: {}", e);
-                return Err(format!("Failed to add share: {e}").into());
-            }
-            Ok(())
+            handle_and_relay_share_block(
+                ctx.peer,
+                share_block,
+                ctx.store,
+                &ctx.stratum_config,
+                ValidationMode::Relay,
+                ctx.swarm_tx,
+                &ctx.time_provider,
+            )
+            .await
         }
         Message::Inventory(inventory) => {
-            info!("Received inventory: {:?}", inventory);
-            match inventory {
-                InventoryMessage::BlockHashes(have_blocks) => {
-                    info!("Received share block inventory: {:?}", have_blocks);
-                }
-                InventoryMessage::TransactionHashes(have_transactions) => {
-                    info!(
-                        "Received share transaction inventory: {:?}",
-                        have_transactions
-                    );
-                }
-            }
-            Ok(())
+            handle_inventory(ctx.peer, inventory, ctx.store, ctx.swarm_tx).await
         }
         Message::NotFound(_) => {
             info!("Received not found message");
             Ok(())
         }
         Message::GetData(get_data) => {
-            info!("Received get data: {:?}", get_data);
-            match get_data {
-                GetData::Block(block_hash) => {
-                    info!("Received block hash: {:?}", block_hash);
-                }
-                GetData::Txid(txid) => {
-                    info!("Received txid: {:?}", txid);
-                }
-            }
+            handle_getdata(get_data, ctx.store, ctx.response_channel, ctx.swarm_tx).await
+        }
+        Message::GetShareBlockTxns(request) => {
+            handle_get_share_block_txns(request, ctx.store, ctx.response_channel, ctx.swarm_tx)
+                .await
+        }
+        // Transactions are only accepted in response to our GetData requests
+        Message::CompactShareBlock(_)
+        | Message::ShareBlockTxns(_)
+        | Message::VerAck(_)
+        | Message::Transaction(_) => Err(UnsolicitedMessage(ctx.request.to_string()).into()),
+        Message::Version(_) | Message::Tip(_) => {
+            info!(
+                "Ignoring {} from peer {}, chain tips are tracked by the node",
+                ctx.request, ctx.peer
+            );
             Ok(())
         }
-        Message::Transaction(transaction) => {
-            info!("Received transaction: {:?}", transaction);
+    }
+}
+
+/// Validate and add a share block received from a peer, then announce
+/// it to all connected peers. The mode says if the share was pushed
+/// to us or requested by us, see [ValidationMode].
+///
+/// Peers that already have the share ignore the inventory, so the
+/// announcement stops once the share has reached the whole network.
+pub(crate) async fn handle_and_relay_share_block<
+    C: Send + Sync + 'static,
+    T: TimeProvider + Send + Sync,
+>(
+    peer: PeerId,
+    share_block: ShareBlock,
+    store: Arc<ChainStore>,
+    config: &StratumConfig<Parsed>,
+    mode: ValidationMode,
+    swarm_tx: mpsc::Sender<SwarmSend<C>>,
+    time_provider: &T,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Err(e) =
+        handle_share_block(share_block.clone(), store, config, mode, time_provider).await
+    {
+        // Shares can arrive before their parent or uncles, hold them in the orphan pool
+        if let Some(missing) = missing_share(e.as_ref()) {
+            info!(
+                "Share {} from peer {} is missing share {}",
+                share_block.block_hash(),
+                peer,
+                missing
+            );
+            swarm_tx
+                .send(SwarmSend::Orphan(peer, share_block, missing))
+                .await
+                .map_err(|e| format!("Failed to send orphan share to swarm: {e}"))?;
+            return Ok(());
+        }
+        error!("Failed to add share from peer {}: {}", peer, e);
+        return Err(e);
+    }
+    swarm_tx
+        .send(SwarmSend::Inv(share_block))
+        .await
+        .map_err(|e| format!("Failed to send INV message to swarm: {e}"))?;
+    Ok(())
+}
+
+/// Handle responses received from a peer for requests sent by this node.
+///
+/// Responses have no response channel, so they are handled outside the
+/// request service.
+pub async fn handle_response<C: Send + Sync + 'static, T: TimeProvider + Send + Sync>(
+    peer: PeerId,
+    response: Message,
+    store: Arc<ChainStore>,
+    config: &StratumConfig<Parsed>,
+    swarm_tx: mpsc::Sender<SwarmSend<C>>,
+    time_provider: &T,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("Handling response {} from peer: {}", response, peer);
+    match response {
+        Message::ShareHeaders(share_headers) => {
+            handle_share_headers(peer, share_headers, store, swarm_tx, time_provider).await
+        }
+        Message::ShareBlock(share_block) => {
+            // Requested blocks are the missing parents of orphans, they
+            // can be older than shares relayed as they are mined
+            handle_and_relay_share_block(
+                peer,
+                share_block,
+                store,
+                config,
+                ValidationMode::Sync,
+                swarm_tx,
+                time_provider,
+            )
+            .await
+        }
+        // Requested transactions are matched to their request by the node
+        Message::Transaction(_) => Err(UnsolicitedMessage(response.to_string()).into()),
+        _ => {
+            info!("Ignoring response {} from peer: {}", response, peer);
             Ok(())
         }
     }
@@ -105,9 +212,11 @@ pub async fn handle_request<C: Send + Sync + 'static, T: TimeProvider + Send + S
 mod tests {
     use super::*;
     use crate::node::SwarmSend;
+    use crate::node::messages::{GetData, InventoryMessage};
     #[mockall_double::double]
     use crate::shares::chain::chain_store::ChainStore;
     use crate::shares::share_block::Txids;
+    use crate::shares::validation::ValidationError;
     use crate::test_utils::{
         TestShareBlockBuilder, build_block_from_work_components, genesis_for_tests,
     };
@@ -124,7 +233,7 @@ mod tests {
     #[tokio::test]
     async fn test_handle_share_block_request() {
         let mut store = ChainStore::default();
-        let (swarm_tx, _swarm_rx) = mpsc::channel(32);
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(32);
         let (response_channel_tx, _response_channel_rx) = oneshot::channel::<Message>();
         let peer_id = libp2p::PeerId::random();
         let mut time_provider = TestTimeProvider::new(SystemTime::now());
@@ -140,10 +249,12 @@ mod tests {
             .expect_add_share()
             .with(eq(share_block.clone()), eq(true))
             .returning(|_, _| Ok(()));
+        store.expect_put_share_balances().returning(|_, _| Ok(()));
         store
             .expect_get_share()
             .with(eq(bitcoin::BlockHash::all_zeros()))
             .returning(|_| Some(genesis_for_tests()));
+        store.expect_get_target_at().returning(|_| Ok(0x207fffff));
 
         store
             .expect_setup_share_for_chain()
@@ -161,11 +272,18 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
 
         assert!(result.is_ok());
+
+        // Share is relayed to peers after it is added
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Inv(relayed)) => assert_eq!(relayed, share_block),
+            _ => panic!("Expected SwarmSend::Inv"),
+        }
     }
 
     #[tokio::test]
@@ -196,15 +314,112 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
 
         assert!(result.is_err());
-        assert_eq!(
-            result.unwrap_err().to_string(),
-            "Failed to add share: Share block validation failed"
+        assert!(
+            result
+                .unwrap_err()
+                .downcast_ref::<ValidationError>()
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_request_share_block_missing_parent_is_orphaned() {
+        let peer_id = libp2p::PeerId::random();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(32);
+        let (response_channel_tx, _response_channel_rx) = oneshot::channel::<Message>();
+        let mut store = ChainStore::default();
+        let mut time_provider = TestTimeProvider::new(SystemTime::now());
+
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/a/");
+        let parent = share_block.header.prev_share_blockhash;
+
+        // The parent has not been received yet
+        store
+            .expect_get_share()
+            .with(eq(parent))
+            .returning(|_| None);
+        store.expect_add_share().never();
+
+        time_provider.set_time(
+            bitcoin::absolute::Time::from_consensus(share_block.header.bitcoin_header.time)
+                .unwrap(),
+        );
+
+        let ctx = RequestContext {
+            peer: peer_id,
+            request: Message::ShareBlock(share_block.clone()),
+            store: Arc::new(store),
+            response_channel: response_channel_tx,
+            swarm_tx,
+            time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
+        };
+
+        let result = handle_request(ctx).await;
+        assert!(result.is_ok());
+
+        // The share is handed to the node's orphan pool
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Orphan(from, orphan, missing)) => {
+                assert_eq!(from, peer_id);
+                assert_eq!(orphan, share_block);
+                assert_eq!(missing, parent);
+            }
+            _ => panic!("Expected SwarmSend::Orphan"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_response_accepts_old_requested_share() {
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(32);
+        let peer_id = libp2p::PeerId::random();
+        let mut store = ChainStore::default();
+
+        // A missing parent fetched for an orphan, mined long before now
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/b/");
+        let time_provider = TestTimeProvider::new(SystemTime::now());
+        assert!(
+            time_provider.seconds_since_epoch()
+                > share_block.header.bitcoin_header.time as u64
+                    + crate::shares::validation::MAX_TIME_DIFF
         );
+
+        store
+            .expect_get_share()
+            .with(eq(bitcoin::BlockHash::all_zeros()))
+            .returning(|_| Some(genesis_for_tests()));
+        store.expect_get_target_at().returning(|_| Ok(0x207fffff));
+        store
+            .expect_add_share()
+            .with(eq(share_block.clone()), eq(true))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        store.expect_put_share_balances().returning(|_, _| Ok(()));
+
+        let config = StratumConfig::new_for_test_default().parse().unwrap();
+        let result = handle_response(
+            peer_id,
+            Message::ShareBlock(share_block.clone()),
+            Arc::new(store),
+            &config,
+            swarm_tx,
+            &time_provider,
+        )
+        .await;
+
+        assert!(result.is_ok());
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Inv(relayed)) => assert_eq!(relayed, share_block),
+            _ => panic!("Expected SwarmSend::Inv"),
+        }
     }
 
     #[tokio::test]
@@ -235,6 +450,7 @@ mod tests {
             response_channel,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
@@ -278,6 +494,7 @@ mod tests {
             response_channel,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
@@ -299,9 +516,9 @@ mod tests {
     #[tokio::test]
     async fn test_handle_request_inventory_for_blocks() {
         let peer_id = libp2p::PeerId::random();
-        let (swarm_tx, _swarm_rx) = mpsc::channel(32);
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(32);
         let (response_channel_tx, _response_channel_rx) = oneshot::channel::<Message>();
-        let store = ChainStore::default();
+        let mut store = ChainStore::default();
         let time_provider = TestTimeProvider::new(SystemTime::now());
 
         // Test BlockHashes inventory
@@ -313,7 +530,13 @@ mod tests {
                 .parse::<BlockHash>()
                 .unwrap(),
         ];
-        let inventory = InventoryMessage::BlockHashes(block_hashes);
+        let inventory = InventoryMessage::BlockHashes(block_hashes.clone());
+
+        let missing = block_hashes[1];
+        store
+            .expect_get_missing_blockhashes()
+            .with(eq(block_hashes))
+            .returning(move |_| vec![missing]);
 
         let ctx = RequestContext {
             peer: peer_id,
@@ -322,11 +545,20 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
 
         assert!(result.is_ok());
+
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Request(peer, Message::GetData(GetData::CompactBlock(hash)))) => {
+                assert_eq!(peer, peer_id);
+                assert_eq!(hash, missing);
+            }
+            _ => panic!("Expected GetData request for missing block"),
+        }
     }
 
     #[tokio::test]
@@ -334,9 +566,11 @@ mod tests {
         let peer_id = libp2p::PeerId::random();
         let (swarm_tx, _swarm_rx) = mpsc::channel(32);
         let (response_channel_tx, _response_channel_rx) = oneshot::channel::<Message>();
-        let store = ChainStore::default();
+        let mut store = ChainStore::default();
         let time_provider = TestTimeProvider::new(SystemTime::now());
 
+        store.expect_get_missing_txids().returning(|_| vec![]);
+
         // Test TransactionHashes inventory
         let tx_hashes: Vec<bitcoin::Txid> = vec![
             "0000000000000000000000000000000000000000000000000000000000000001"
@@ -355,6 +589,7 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
@@ -377,6 +612,7 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
@@ -387,16 +623,21 @@ mod tests {
     #[tokio::test]
     async fn test_handle_request_get_data_for_block() {
         let peer_id = libp2p::PeerId::random();
-        let (swarm_tx, _swarm_rx) = mpsc::channel(32);
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(32);
         let (response_channel_tx, _response_channel_rx) = oneshot::channel::<Message>();
-        let store = ChainStore::default();
+        let mut store = ChainStore::default();
         let time_provider = TestTimeProvider::new(SystemTime::now());
 
-        let block_hash = "0000000000000000000000000000000000000000000000000000000000000001"
-            .parse::<BlockHash>()
-            .unwrap();
+        let share_block = TestShareBlockBuilder::new().build();
+        let block_hash = share_block.block_hash();
         let get_data = GetData::Block(block_hash);
 
+        let share_block_clone = share_block.clone();
+        store
+            .expect_get_share()
+            .with(eq(block_hash))
+            .returning(move |_| Some(share_block_clone.clone()));
+
         let ctx = RequestContext {
             peer: peer_id,
             request: Message::GetData(get_data),
@@ -404,19 +645,27 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
 
         assert!(result.is_ok());
+
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Response(_, Message::ShareBlock(received))) => {
+                assert_eq!(received, share_block);
+            }
+            _ => panic!("Expected ShareBlock response"),
+        }
     }
 
     #[tokio::test]
     async fn test_handle_request_get_data_for_txn() {
         let peer_id = libp2p::PeerId::random();
-        let (swarm_tx, _swarm_rx) = mpsc::channel(32);
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(32);
         let (response_channel_tx, _response_channel_rx) = oneshot::channel::<Message>();
-        let store = ChainStore::default();
+        let mut store = ChainStore::default();
         let time_provider = TestTimeProvider::new(SystemTime::now());
 
         // Test GetData message with txid
@@ -425,6 +674,11 @@ mod tests {
             .unwrap();
         let get_data = GetData::Txid(txid);
 
+        store
+            .expect_get_tx()
+            .with(eq(txid))
+            .returning(|_| Err("Transaction metadata not found".into()));
+
         let ctx = RequestContext {
             peer: peer_id,
             request: Message::GetData(get_data),
@@ -432,11 +686,17 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
 
         assert!(result.is_ok());
+
+        assert!(matches!(
+            swarm_rx.recv().await,
+            Some(SwarmSend::Response(_, Message::NotFound(())))
+        ));
     }
 
     #[tokio::test]
@@ -444,11 +704,15 @@ mod tests {
         let peer_id = libp2p::PeerId::random();
         let (swarm_tx, _swarm_rx) = mpsc::channel(32);
         let (response_channel_tx, _response_channel_rx) = oneshot::channel::<Message>();
-        let store = ChainStore::default();
+        let mut store = ChainStore::default();
         let time_provider = TestTimeProvider::new(SystemTime::now());
 
         // Create a test transaction
-        let transaction = crate::test_utils::test_coinbase_transaction();
+        let transaction = crate::test_utils::test_spending_transaction(1000);
+
+        // Transactions pushed to us without a GetData request are not stored
+        store.expect_get_missing_txids().never();
+        store.expect_add_transaction().never();
 
         let ctx = RequestContext {
             peer: peer_id,
@@ -457,11 +721,17 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
 
-        assert!(result.is_ok());
+        assert!(
+            result
+                .unwrap_err()
+                .downcast_ref::<UnsolicitedMessage>()
+                .is_some()
+        );
     }
 
     #[tokio::test]
@@ -472,16 +742,26 @@ mod tests {
         let mut store = ChainStore::default();
         let time_provider = TestTimeProvider::new(SystemTime::now());
 
-        // Create test share headers
-        let block1 = TestShareBlockBuilder::new().build();
-        let block2 = TestShareBlockBuilder::new().build();
+        // Create test share headers that meet the share target
+        let block1 = build_block_from_work_components("../tests/test_data/validation/stratum/a/");
+        let block2 = build_block_from_work_components("../tests/test_data/validation/stratum/b/");
 
         let share_headers = vec![block1.header.clone(), block2.header.clone()];
 
         // Set up mock expectations for processing headers
+        let mut expected_bits = vec![
+            block2.header.bits.to_consensus(),
+            block1.header.bits.to_consensus(),
+        ];
         store
-            .expect_get_headers_for_locator()
-            .returning(|_, _, _| Ok(vec![]));
+            .expect_get_target_at()
+            .times(2)
+            .returning(move |_| Ok(expected_bits.pop().unwrap()));
+        store
+            .expect_add_share_header()
+            .times(2)
+            .returning(|_| Ok(()));
+        store.expect_get_blocks_to_download().returning(|_| vec![]);
 
         let ctx = RequestContext {
             peer: peer_id,
@@ -490,6 +770,7 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx,
             time_provider,
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let result = handle_request(ctx).await;
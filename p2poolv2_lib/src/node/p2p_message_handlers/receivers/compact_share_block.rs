@@ -0,0 +1,271 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::node::Message;
+use crate::node::SwarmSend;
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::compact_block::{CompactShareBlock, PartialShareBlock};
+use crate::shares::share_block::ShareBlock;
+use bitcoin::bip152::{BlockTransactions, BlockTransactionsRequest};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+/// Reconstruct a compact share block received from a peer.
+///
+/// Bitcoin transactions are looked up in the transactions from our
+/// recent block templates. The caller requests any missing
+/// transactions from the peer with a GetShareBlockTxns message.
+pub fn reconstruct_compact_share_block(
+    compact: CompactShareBlock,
+    store: &Arc<ChainStore>,
+) -> Result<PartialShareBlock, Box<dyn Error + Send + Sync>> {
+    let block_hash = compact.block_hash();
+    let partial = PartialShareBlock::new(compact, |siphash_keys, short_ids| {
+        store.get_template_transactions(siphash_keys, short_ids)
+    })
+    .map_err(|e| format!("Failed to reconstruct compact share block {block_hash}: {e}"))?;
+    info!(
+        "Reconstructed compact share block {block_hash}, missing {} transactions",
+        partial.missing_indexes().len()
+    );
+    Ok(partial)
+}
+
+/// Complete a partially reconstructed share block with the
+/// transactions received in response to GetShareBlockTxns.
+pub fn handle_share_block_txns(
+    mut partial: PartialShareBlock,
+    block_transactions: BlockTransactions,
+) -> Result<ShareBlock, Box<dyn Error + Send + Sync>> {
+    if block_transactions.block_hash != partial.block_hash() {
+        return Err(format!(
+            "Received transactions for {} while waiting for {}",
+            block_transactions.block_hash,
+            partial.block_hash()
+        )
+        .into());
+    }
+    partial.fill(block_transactions.transactions)?;
+    Ok(partial.into_share_block()?)
+}
+
+/// Handle a GetShareBlockTxns request from a peer
+/// - Respond with the requested bitcoin transactions from the share block
+/// - Respond with NotFound if we don't have the share block or an index is out of range
+pub async fn handle_get_share_block_txns<C: 'static + Send + Sync>(
+    request: BlockTransactionsRequest,
+    store: Arc<ChainStore>,
+    response_channel: C,
+    swarm_tx: mpsc::Sender<SwarmSend<C>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!(
+        "Received request for {} transactions from share {}",
+        request.indexes.len(),
+        request.block_hash
+    );
+    let response = match store.get_share(&request.block_hash) {
+        Some(share_block) => {
+            let transactions = request
+                .indexes
+                .iter()
+                .map(|index| {
+                    share_block
+                        .bitcoin_transactions
+                        .get(*index as usize)
+                        .cloned()
+                })
+                .collect::<Option<Vec<_>>>();
+            match transactions {
+                Some(transactions) => Message::ShareBlockTxns(BlockTransactions {
+                    block_hash: request.block_hash,
+                    transactions,
+                }),
+                None => {
+                    debug!(
+                        "Transaction index out of range for share {}",
+                        request.block_hash
+                    );
+                    Message::NotFound(())
+                }
+            }
+        }
+        None => {
+            debug!(
+                "Share block {} not found for getshareblocktxns",
+                request.block_hash
+            );
+            Message::NotFound(())
+        }
+    };
+    swarm_tx
+        .send(SwarmSend::Response(response_channel, response))
+        .await
+        .map_err(|e| format!("Failed to send share block txns response: {e}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{TestShareBlockBuilder, test_coinbase_transaction};
+    use mockall::predicate::*;
+
+    /// Share block with a coinbase and two more bitcoin transactions
+    fn share_block_with_bitcoin_transactions() -> ShareBlock {
+        let mut share_block = TestShareBlockBuilder::new().build();
+        let mut tx1 = test_coinbase_transaction();
+        tx1.output[0].value = bitcoin::Amount::from_sat(1);
+        let mut tx2 = test_coinbase_transaction();
+        tx2.output[0].value = bitcoin::Amount::from_sat(2);
+        share_block.bitcoin_transactions = vec![test_coinbase_transaction(), tx1, tx2];
+        share_block
+    }
+
+    #[test]
+    fn test_reconstruct_and_fill_missing_transactions() {
+        let mut store = ChainStore::default();
+        let share_block = share_block_with_bitcoin_transactions();
+        let compact = CompactShareBlock::from_share_block(&share_block).unwrap();
+
+        // Only the last transaction is in our template mempool
+        let last_tx = share_block.bitcoin_transactions[2].clone();
+        store
+            .expect_get_template_transactions()
+            .returning(move |_, short_ids| {
+                assert_eq!(short_ids.len(), 2);
+                vec![None, Some(last_tx.clone())]
+            });
+
+        let partial = reconstruct_compact_share_block(compact, &Arc::new(store)).unwrap();
+        assert!(!partial.is_complete());
+        assert_eq!(partial.missing_indexes(), vec![1]);
+
+        let reconstructed = handle_share_block_txns(
+            partial,
+            BlockTransactions {
+                block_hash: share_block.block_hash(),
+                transactions: vec![share_block.bitcoin_transactions[1].clone()],
+            },
+        )
+        .unwrap();
+        assert_eq!(reconstructed, share_block);
+    }
+
+    #[test]
+    fn test_handle_share_block_txns_wrong_block() {
+        let mut store = ChainStore::default();
+        let share_block = share_block_with_bitcoin_transactions();
+        let compact = CompactShareBlock::from_share_block(&share_block).unwrap();
+
+        store
+            .expect_get_template_transactions()
+            .returning(|_, short_ids| vec![None; short_ids.len()]);
+
+        let partial = reconstruct_compact_share_block(compact, &Arc::new(store)).unwrap();
+        let result = handle_share_block_txns(
+            partial,
+            BlockTransactions {
+                block_hash: TestShareBlockBuilder::new().nonce(1).build().block_hash(),
+                transactions: share_block.bitcoin_transactions[1..].to_vec(),
+            },
+        );
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_get_share_block_txns() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(2);
+
+        let share_block = share_block_with_bitcoin_transactions();
+        let block_hash = share_block.block_hash();
+        let share_block_clone = share_block.clone();
+        store
+            .expect_get_share()
+            .with(eq(block_hash))
+            .returning(move |_| Some(share_block_clone.clone()));
+
+        let store = Arc::new(store);
+        handle_get_share_block_txns(
+            BlockTransactionsRequest {
+                block_hash,
+                indexes: vec![1, 2],
+            },
+            store.clone(),
+            1u32,
+            swarm_tx.clone(),
+        )
+        .await
+        .unwrap();
+        handle_get_share_block_txns(
+            BlockTransactionsRequest {
+                block_hash,
+                indexes: vec![3],
+            },
+            store,
+            2u32,
+            swarm_tx,
+        )
+        .await
+        .unwrap();
+
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Response(1, Message::ShareBlockTxns(received))) => {
+                assert_eq!(received.block_hash, block_hash);
+                assert_eq!(
+                    received.transactions,
+                    share_block.bitcoin_transactions[1..].to_vec()
+                );
+            }
+            _ => panic!("Expected ShareBlockTxns response"),
+        }
+        // Index out of range
+        assert!(matches!(
+            swarm_rx.recv().await,
+            Some(SwarmSend::Response(2, Message::NotFound(())))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handle_get_share_block_txns_not_found() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(1);
+
+        store.expect_get_share().returning(|_| None);
+
+        let result = handle_get_share_block_txns(
+            BlockTransactionsRequest {
+                block_hash: TestShareBlockBuilder::new().build().block_hash(),
+                indexes: vec![1],
+            },
+            Arc::new(store),
+            1u32,
+            swarm_tx,
+        )
+        .await;
+        assert!(result.is_ok());
+        assert!(matches!(
+            swarm_rx.recv().await,
+            Some(SwarmSend::Response(1, Message::NotFound(())))
+        ));
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::node::Message;
+use crate::node::SwarmSend;
+use crate::node::messages::GetData;
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::compact_block::CompactShareBlock;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
+
+/// Handle a GetData request from a peer
+/// - For a block, respond with the ShareBlock from the store
+/// - For a txid, respond with the Transaction from the store
+/// - For a compact block, respond with a CompactShareBlock built from the ShareBlock
+/// - Respond with NotFound if we don't have the requested data
+pub async fn handle_getdata<C: 'static + Send + Sync>(
+    get_data: GetData,
+    store: Arc<ChainStore>,
+    response_channel: C,
+    swarm_tx: mpsc::Sender<SwarmSend<C>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    info!("Received getdata: {:?}", get_data);
+    let response = match get_data {
+        GetData::Block(block_hash) => match store.get_share(&block_hash) {
+            Some(share_block) => Message::ShareBlock(share_block),
+            None => {
+                debug!("Share block {block_hash} not found for getdata");
+                Message::NotFound(())
+            }
+        },
+        GetData::CompactBlock(block_hash) => match store.get_share(&block_hash) {
+            Some(share_block) => match CompactShareBlock::from_share_block(&share_block) {
+                Ok(compact) => Message::CompactShareBlock(compact),
+                Err(e) => {
+                    error!("Failed to build compact share block {block_hash}: {e}");
+                    Message::NotFound(())
+                }
+            },
+            None => {
+                debug!("Share block {block_hash} not found for getdata");
+                Message::NotFound(())
+            }
+        },
+        GetData::Txid(txid) => match store.get_tx(&txid) {
+            Ok(tx) => Message::Transaction(tx),
+            Err(e) => {
+                debug!("Transaction {txid} not found for getdata: {e}");
+                Message::NotFound(())
+            }
+        },
+    };
+    swarm_tx
+        .send(SwarmSend::Response(response_channel, response))
+        .await
+        .map_err(|e| format!("Failed to send getdata response: {e}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestShareBlockBuilder;
+    use bitcoin::BlockHash;
+    use bitcoin::hashes::Hash;
+    use mockall::predicate::*;
+
+    #[tokio::test]
+    async fn test_handle_getdata_block_found() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(1);
+        let response_channel = 1u32;
+
+        let share_block = TestShareBlockBuilder::new().build();
+        let block_hash = share_block.block_hash();
+        let share_block_clone = share_block.clone();
+        store
+            .expect_get_share()
+            .with(eq(block_hash))
+            .returning(move |_| Some(share_block_clone.clone()));
+
+        let result = handle_getdata(
+            GetData::Block(block_hash),
+            Arc::new(store),
+            response_channel,
+            swarm_tx,
+        )
+        .await;
+        assert!(result.is_ok());
+
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Response(channel, Message::ShareBlock(received))) => {
+                assert_eq!(channel, response_channel);
+                assert_eq!(received, share_block);
+            }
+            _ => panic!("Expected ShareBlock response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_getdata_block_not_found() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(1);
+
+        store.expect_get_share().returning(|_| None);
+
+        let result = handle_getdata(
+            GetData::Block(BlockHash::all_zeros()),
+            Arc::new(store),
+            1u32,
+            swarm_tx,
+        )
+        .await;
+        assert!(result.is_ok());
+
+        assert!(matches!(
+            swarm_rx.recv().await,
+            Some(SwarmSend::Response(1, Message::NotFound(())))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handle_getdata_compact_block() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(1);
+
+        let share_block = TestShareBlockBuilder::new().build();
+        let block_hash = share_block.block_hash();
+        let share_block_clone = share_block.clone();
+        store
+            .expect_get_share()
+            .with(eq(block_hash))
+            .returning(move |_| Some(share_block_clone.clone()));
+
+        let result = handle_getdata(
+            GetData::CompactBlock(block_hash),
+            Arc::new(store),
+            1u32,
+            swarm_tx,
+        )
+        .await;
+        assert!(result.is_ok());
+
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Response(1, Message::CompactShareBlock(compact))) => {
+                assert_eq!(compact.header, share_block.header);
+                assert_eq!(compact.transactions, share_block.transactions);
+                assert_eq!(
+                    compact.short_ids.short_ids.len() + compact.short_ids.prefilled_txs.len(),
+                    share_block.bitcoin_transactions.len()
+                );
+            }
+            _ => panic!("Expected CompactShareBlock response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_getdata_txid() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel(2);
+
+        let share_block = TestShareBlockBuilder::new().build();
+        let tx = share_block.transactions[0].clone();
+        let txid = tx.compute_txid();
+        let missing_txid = bitcoin::Txid::from_byte_array([1; 32]);
+
+        let tx_clone = tx.clone();
+        store
+            .expect_get_tx()
+            .with(eq(txid))
+            .returning(move |_| Ok(tx_clone.clone()));
+        store
+            .expect_get_tx()
+            .with(eq(missing_txid))
+            .returning(|_| Err("Transaction metadata not found".into()));
+
+        let store = Arc::new(store);
+        handle_getdata(GetData::Txid(txid), store.clone(), 1u32, swarm_tx.clone())
+            .await
+            .unwrap();
+        handle_getdata(GetData::Txid(missing_txid), store, 2u32, swarm_tx)
+            .await
+            .unwrap();
+
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Response(1, Message::Transaction(received))) => {
+                assert_eq!(received, tx);
+            }
+            _ => panic!("Expected Transaction response"),
+        }
+        assert!(matches!(
+            swarm_rx.recv().await,
+            Some(SwarmSend::Response(2, Message::NotFound(())))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handle_getdata_channel_closed() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, swarm_rx) = mpsc::channel::<SwarmSend<u32>>(1);
+        drop(swarm_rx);
+
+        store.expect_get_share().returning(|_| None);
+
+        let result = handle_getdata(
+            GetData::Block(BlockHash::all_zeros()),
+            Arc::new(store),
+            1u32,
+            swarm_tx,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+}
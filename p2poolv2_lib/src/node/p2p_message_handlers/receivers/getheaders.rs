@@ -26,7 +26,7 @@ use std::error::Error;
 use tokio::sync::mpsc;
 use tracing::info;
 
-const MAX_HEADERS: usize = 2000;
+pub(crate) const MAX_HEADERS: usize = 2000;
 
 /// Handle a GetHeaders request from a peer
 /// - start from chain tip, find blockhashes up to the stop block hash
@@ -16,65 +16,61 @@
 
 use crate::node::Message;
 use crate::node::SwarmSend;
-use crate::node::messages::InventoryMessage;
+use crate::node::messages::{GetData, InventoryMessage};
 #[cfg(test)]
 #[mockall_double::double]
 use crate::shares::chain::chain_store::ChainStore;
 #[cfg(not(test))]
 use crate::shares::chain::chain_store::ChainStore;
+use libp2p::PeerId;
 use std::error::Error;
 use tokio::sync::mpsc;
 use tracing::info;
 
 /// Handle Inventory message request from a peer.
-/// inv is sent unsolicited, or in response to getblocks message,
-/// therefore we include this message in the handle_requests module.
+/// inv is sent unsolicited when a peer has a new share, or in response
+/// to getblocks message, therefore we include this message in the
+/// handle_requests module.
 ///
-/// We wrap all inventory update messages in the same message type
-/// - Depending on the type of the inventory, we query the database for the relevant data
-/// - Send the data to the peer via the swarm_tx channel
-/// - We send one message for each found object. See block and tx messages.
-///   Note: At the moment, we only support sending blockhashes as inventory.
-pub async fn handle_inventory<C: Clone + 'static>(
-    inventory: Vec<InventoryMessage>,
+/// - Find the blocks or transactions in the inventory that are missing from the store
+/// - Request each missing object from the peer with a GetData request
+/// - Share blocks are requested as compact blocks, so bitcoin
+///   transactions we already have from our templates are not sent again
+/// - The peer responds with a CompactShareBlock or Transaction message, or NotFound
+pub async fn handle_inventory<C: 'static>(
+    peer: PeerId,
+    inventory: InventoryMessage,
     store: std::sync::Arc<ChainStore>,
     swarm_tx: mpsc::Sender<SwarmSend<C>>,
-    response_channel: C,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("Received inventory update: {:?}", inventory);
 
-    for inv_item in inventory {
-        match inv_item {
-            InventoryMessage::BlockHashes(locator) => {
-                info!("Received block hashes locator: {:?}", locator);
-
-                // Check which blocks we're missing and request them
-                let missing_blocks = store.get_missing_blockhashes(&locator);
-
-                // Request missing blocks from the peer
-                if !missing_blocks.is_empty() {
-                    info!(
-                        "Requesting {} missing blocks from peer",
-                        missing_blocks.len()
-                    );
-                    // Send individual GetBlock requests for each missing block
-                    for block_hash in missing_blocks {
-                        let get_block_request =
-                            Message::GetData(crate::node::messages::GetData::Block(block_hash));
-                        swarm_tx
-                            .send(SwarmSend::Response(
-                                response_channel.clone(),
-                                get_block_request,
-                            ))
-                            .await?;
-                    }
-                }
-            }
-            // Handle other inventory types as needed
-            _ => {
-                info!("Unsupported inventory type: {:?}", inv_item);
-            }
-        }
+    let get_data_requests = match inventory {
+        InventoryMessage::BlockHashes(block_hashes) => store
+            .get_missing_blockhashes(&block_hashes)
+            .into_iter()
+            .map(GetData::CompactBlock)
+            .collect::<Vec<_>>(),
+        InventoryMessage::TransactionHashes(txids) => store
+            .get_missing_txids(&txids.0)
+            .into_iter()
+            .map(GetData::Txid)
+            .collect::<Vec<_>>(),
+    };
+
+    if !get_data_requests.is_empty() {
+        info!(
+            "Requesting {} missing objects from peer {}",
+            get_data_requests.len(),
+            peer
+        );
+    }
+    // Send individual GetData requests for each missing object
+    for get_data in get_data_requests {
+        swarm_tx
+            .send(SwarmSend::Request(peer, Message::GetData(get_data)))
+            .await
+            .map_err(|e| format!("Failed to send getdata request: {e}"))?;
     }
 
     Ok(())
@@ -83,19 +79,18 @@ pub async fn handle_inventory<C: Clone + 'static>(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::node::messages::{GetData, InventoryMessage};
-    use crate::node::p2p_message_handlers::receivers::inventory::handle_inventory;
-    use crate::node::{Message, SwarmSend};
+    use crate::shares::share_block::Txids;
     use crate::test_utils::TestShareBlockBuilder;
     use bitcoin::BlockHash;
+    use bitcoin::hashes::Hash;
     use mockall::predicate::*;
     use std::sync::Arc;
-    use tokio::sync::mpsc;
 
     #[tokio::test]
     async fn test_handle_inventory_block_hashes() {
         // Setup
         let mut store = ChainStore::default();
+        let peer_id = PeerId::random();
 
         let block1 = TestShareBlockBuilder::new().build();
 
@@ -118,39 +113,32 @@ mod tests {
             .returning(move |_| missing_blocks.clone());
 
         // Create channels for swarm communication
-        let (swarm_tx, mut swarm_rx) = mpsc::channel(10);
-        let response_channel = "test_peer_id".to_string(); // Using String as the channel type for simplicity
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(10);
 
         // Execute
-        let inventory = vec![InventoryMessage::BlockHashes(locator)];
-        let result = handle_inventory(
-            inventory,
-            Arc::new(store),
-            swarm_tx,
-            response_channel.clone(),
-        )
-        .await;
+        let inventory = InventoryMessage::BlockHashes(locator);
+        let result = handle_inventory(peer_id, inventory, Arc::new(store), swarm_tx).await;
 
         // Verify
         assert!(result.is_ok(), "handle_inventory should return Ok");
 
-        // Verify that GetData messages were sent for each missing block
+        // Verify that GetData requests were sent for each missing block
         let message1 = swarm_rx.recv().await.unwrap();
         match message1 {
-            SwarmSend::Response(channel, Message::GetData(GetData::Block(hash))) => {
-                assert_eq!(channel, response_channel);
+            SwarmSend::Request(peer, Message::GetData(GetData::CompactBlock(hash))) => {
+                assert_eq!(peer, peer_id);
                 assert_eq!(hash, block_hash1);
             }
-            _ => panic!("Expected GetData::Block message for block_hash1"),
+            _ => panic!("Expected GetData::CompactBlock message for block_hash1"),
         }
 
         let message2 = swarm_rx.recv().await.unwrap();
         match message2 {
-            SwarmSend::Response(channel, Message::GetData(GetData::Block(hash))) => {
-                assert_eq!(channel, response_channel);
+            SwarmSend::Request(peer, Message::GetData(GetData::CompactBlock(hash))) => {
+                assert_eq!(peer, peer_id);
                 assert_eq!(hash, block_hash3);
             }
-            _ => panic!("Expected GetData::Block message for block_hash3"),
+            _ => panic!("Expected GetData::CompactBlock message for block_hash3"),
         }
 
         // Verify no more messages were sent
@@ -159,4 +147,48 @@ mod tests {
             "No more messages should have been sent"
         );
     }
+
+    #[tokio::test]
+    async fn test_handle_inventory_transaction_hashes() {
+        let mut store = ChainStore::default();
+        let peer_id = PeerId::random();
+
+        let txid1 = bitcoin::Txid::from_byte_array([1; 32]);
+        let txid2 = bitcoin::Txid::from_byte_array([2; 32]);
+
+        store
+            .expect_get_missing_txids()
+            .with(eq(vec![txid1, txid2]))
+            .returning(move |_| vec![txid2]);
+
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(10);
+
+        let inventory = InventoryMessage::TransactionHashes(Txids(vec![txid1, txid2]));
+        let result = handle_inventory(peer_id, inventory, Arc::new(store), swarm_tx).await;
+        assert!(result.is_ok());
+
+        match swarm_rx.recv().await.unwrap() {
+            SwarmSend::Request(peer, Message::GetData(GetData::Txid(txid))) => {
+                assert_eq!(peer, peer_id);
+                assert_eq!(txid, txid2);
+            }
+            _ => panic!("Expected GetData::Txid message for txid2"),
+        }
+        assert!(swarm_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_inventory_nothing_missing() {
+        let mut store = ChainStore::default();
+        let block_hash = TestShareBlockBuilder::new().build().block_hash();
+
+        store.expect_get_missing_blockhashes().returning(|_| vec![]);
+
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(10);
+
+        let inventory = InventoryMessage::BlockHashes(vec![block_hash]);
+        let result = handle_inventory(PeerId::random(), inventory, Arc::new(store), swarm_tx).await;
+        assert!(result.is_ok());
+        assert!(swarm_rx.try_recv().is_err());
+    }
 }
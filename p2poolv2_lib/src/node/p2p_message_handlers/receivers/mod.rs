@@ -14,14 +14,22 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+pub mod compact_share_block;
 pub mod getblocks;
+pub mod getdata;
 pub mod getheaders;
 pub mod inventory;
 pub mod share_blocks;
 pub mod share_headers;
+pub mod transaction;
 
+pub use compact_share_block::{
+    handle_get_share_block_txns, handle_share_block_txns, reconstruct_compact_share_block,
+};
 pub use getblocks::handle_getblocks;
+pub use getdata::handle_getdata;
 pub use getheaders::handle_getheaders;
 pub use inventory::handle_inventory;
 pub use share_blocks::handle_share_block;
 pub use share_headers::handle_share_headers;
+pub use transaction::handle_transaction;
@@ -14,13 +14,15 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::accounting::simple_pplns::share_chain_payout;
+use crate::config::{Parsed, StratumConfig};
 #[cfg(test)]
 #[mockall_double::double]
 use crate::shares::chain::chain_store::ChainStore;
 #[cfg(not(test))]
 use crate::shares::chain::chain_store::ChainStore;
 use crate::shares::share_block::ShareBlock;
-use crate::shares::validation;
+use crate::shares::validation::{self, ValidationMode};
 use crate::utils::time_provider::TimeProvider;
 use std::error::Error;
 use std::sync::Arc;
@@ -28,18 +30,32 @@ use tracing::{error, info};
 
 /// Handle a ShareBlock received from a peer in response to a getblocks request.
 ///
-/// Validate the ShareBlock and store it in the chain
+/// Validate the ShareBlock and store it in the chain. The mode says
+/// whether the share was relayed as it was mined, or requested by us
+/// and so can be of any age.
+/// Validation failures are returned as the ValidationError so callers
+/// can decide whether to penalise the peer that sent the share.
 /// We do not send any inventory message as we do not want to gossip the share block.
 /// Share blocks are gossiped using the libp2p gossipsub protocol.
 pub async fn handle_share_block<T: TimeProvider + Send + Sync>(
     share_block: ShareBlock,
     chain_store: Arc<ChainStore>,
+    config: &StratumConfig<Parsed>,
+    mode: ValidationMode,
     time_provider: &T,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("Received share block: {:?}", share_block);
-    if let Err(e) = validation::validate(&share_block, chain_store.clone(), time_provider).await {
+    if let Err(e) = validation::validate(
+        &share_block,
+        chain_store.clone(),
+        config,
+        mode,
+        time_provider,
+    )
+    .await
+    {
         error!("Share block validation failed: {}", e);
-        return Err("Share block validation failed".into());
+        return Err(e.into());
     }
 
     // TODO: Check if this will be an uncle, for now add to main chain
@@ -47,6 +63,9 @@ pub async fn handle_share_block<T: TimeProvider + Send + Sync>(
         error!("Failed to add share: {}", e);
         return Err("Error adding share to chain".into());
     }
+    if let Err(e) = share_chain_payout::store_balances(&chain_store, &share_block) {
+        error!("Failed to store ledger balances for share: {}", e);
+    }
 
     info!("Successfully added share blocks to chain");
     Ok(())
@@ -75,10 +94,15 @@ mod tests {
             .expect_add_share()
             .with(eq(share_block.clone()), eq(true))
             .returning(|_, _| Ok(()));
+        store
+            .expect_put_share_balances()
+            .times(1)
+            .returning(|_, _| Ok(()));
         store
             .expect_get_share()
             .with(eq(bitcoin::BlockHash::all_zeros()))
             .returning(|_| Some(genesis_for_tests()));
+        store.expect_get_target_at().returning(|_| Ok(0x207fffff));
 
         let mut time_provider = TestTimeProvider::new(SystemTime::now());
         time_provider.set_time(
@@ -86,7 +110,15 @@ mod tests {
                 .unwrap(),
         );
 
-        let result = handle_share_block(share_block, Arc::new(store), &time_provider).await;
+        let config = StratumConfig::new_for_test_default().parse().unwrap();
+        let result = handle_share_block(
+            share_block,
+            Arc::new(store),
+            &config,
+            ValidationMode::Relay,
+            &time_provider,
+        )
+        .await;
         assert!(result.is_ok());
     }
 
@@ -97,11 +129,21 @@ mod tests {
 
         let time_provider = TestTimeProvider::new(SystemTime::now());
 
-        let result = handle_share_block(share_block, Arc::new(store), &time_provider).await;
+        let config = StratumConfig::new_for_test_default().parse().unwrap();
+        let result = handle_share_block(
+            share_block,
+            Arc::new(store),
+            &config,
+            ValidationMode::Relay,
+            &time_provider,
+        )
+        .await;
         assert!(result.is_err());
-        assert_eq!(
-            result.unwrap_err().to_string(),
-            "Share block validation failed"
+        assert!(
+            result
+                .unwrap_err()
+                .downcast_ref::<validation::ValidationError>()
+                .is_some()
         );
     }
 
@@ -120,6 +162,7 @@ mod tests {
             .expect_get_share()
             .with(eq(bitcoin::BlockHash::all_zeros()))
             .returning(|_| Some(genesis_for_tests()));
+        store.expect_get_target_at().returning(|_| Ok(0x207fffff));
 
         let mut time_provider = TestTimeProvider::new(SystemTime::now());
         time_provider.set_time(
@@ -127,11 +170,71 @@ mod tests {
                 .unwrap(),
         );
 
-        let result = handle_share_block(share_block, Arc::new(store), &time_provider).await;
+        let config = StratumConfig::new_for_test_default().parse().unwrap();
+        let result = handle_share_block(
+            share_block,
+            Arc::new(store),
+            &config,
+            ValidationMode::Relay,
+            &time_provider,
+        )
+        .await;
         assert!(result.is_err());
         assert_eq!(
             result.unwrap_err().to_string(),
             "Error adding share to chain"
         );
     }
+
+    #[tokio::test]
+    async fn test_handle_share_block_sync_accepts_old_share() {
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/b/");
+        // The share was mined long before now
+        let time_provider = TestTimeProvider::new(SystemTime::now());
+        assert!(
+            time_provider.seconds_since_epoch()
+                > share_block.header.bitcoin_header.time as u64 + validation::MAX_TIME_DIFF
+        );
+
+        let mut store = ChainStore::default();
+        store
+            .expect_get_share()
+            .with(eq(bitcoin::BlockHash::all_zeros()))
+            .returning(|_| Some(genesis_for_tests()));
+        store.expect_get_target_at().returning(|_| Ok(0x207fffff));
+        store
+            .expect_add_share()
+            .with(eq(share_block.clone()), eq(true))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        store.expect_put_share_balances().returning(|_, _| Ok(()));
+        let store = Arc::new(store);
+        let config = StratumConfig::new_for_test_default().parse().unwrap();
+
+        let relayed = handle_share_block(
+            share_block.clone(),
+            store.clone(),
+            &config,
+            ValidationMode::Relay,
+            &time_provider,
+        )
+        .await;
+        assert!(matches!(
+            relayed
+                .unwrap_err()
+                .downcast_ref::<validation::ValidationError>(),
+            Some(validation::ValidationError::InvalidTimestamp { .. })
+        ));
+
+        let synced = handle_share_block(
+            share_block,
+            store,
+            &config,
+            ValidationMode::Sync,
+            &time_provider,
+        )
+        .await;
+        assert!(synced.is_ok());
+    }
 }
@@ -14,27 +14,297 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::node::SwarmSend;
+use crate::node::messages::Message;
+use crate::node::p2p_message_handlers::receivers::getheaders::MAX_HEADERS;
 #[cfg(test)]
 #[mockall_double::double]
 use crate::shares::chain::chain_store::ChainStore;
 #[cfg(not(test))]
 use crate::shares::chain::chain_store::ChainStore;
 use crate::shares::share_block::ShareHeader;
+use crate::shares::validation::{validate_header_bits, validate_header_pow};
 use crate::utils::time_provider::TimeProvider;
+use bitcoin::BlockHash;
+use bitcoin::hashes::Hash;
+use libp2p::PeerId;
 use std::error::Error;
-use tracing::info;
-
-/// Handle ShareHeaders received from a peer
-/// We need to:
-/// 1. TODO: Validate the PoW on the share header
-/// 2. TODO: Store the headers
-/// 2. TODO: Push the header into a task queue to fetch txs to build the ShareBlock
-/// 3. TODO: We need to start a task in node to pull from the task queue and send getData message for txs
-pub async fn handle_share_headers<T: TimeProvider + Send + Sync>(
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// The maximum number of share blocks queued for download after each headers message
+const MAX_BLOCKS_TO_DOWNLOAD: usize = 2 * MAX_HEADERS;
+
+/// Handle ShareHeaders received from a peer as part of headers-first sync.
+///
+/// 1. Validate the bits and PoW on each share header. Failures are
+///    returned as the ValidationError so the peer can be penalised.
+/// 2. Store the headers as header only entries. The chain store tracks
+///    chain work for the headers and picks the best header chain.
+/// 3. If the peer sent a full batch, ask it for the next batch of headers
+/// 4. Queue the share blocks on the best header chain for download. The
+///    node spreads the GetData requests across connected peers.
+pub async fn handle_share_headers<C: 'static, T: TimeProvider + Send + Sync>(
+    peer: PeerId,
     share_headers: Vec<ShareHeader>,
-    _store: std::sync::Arc<ChainStore>,
+    store: Arc<ChainStore>,
+    swarm_tx: mpsc::Sender<SwarmSend<C>>,
     _time_provider: &T,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    info!("Received share headers: {:?}", share_headers);
+    info!(
+        "Received {} share headers from peer {}",
+        share_headers.len(),
+        peer
+    );
+    let last_blockhash = match share_headers.last() {
+        Some(header) => header.block_hash(),
+        None => return Ok(()),
+    };
+    let received_full_batch = share_headers.len() >= MAX_HEADERS;
+
+    for header in share_headers {
+        let blockhash = header.block_hash();
+        if let Err(e) = validate_header_bits(&header, &store) {
+            error!("Share header {blockhash} has unexpected bits: {e}");
+            return Err(e.into());
+        }
+        if let Err(e) = validate_header_pow(&header) {
+            error!("Share header {blockhash} failed validation: {e}");
+            return Err(e.into());
+        }
+        if let Err(e) = store.add_share_header(header) {
+            error!("Failed to add share header {blockhash}: {e}");
+            return Err(format!("Failed to add share header: {e}").into());
+        }
+    }
+
+    if received_full_batch {
+        let getheaders_request =
+            Message::GetShareHeaders(vec![last_blockhash], BlockHash::all_zeros());
+        swarm_tx
+            .send(SwarmSend::Request(peer, getheaders_request))
+            .await
+            .map_err(|e| format!("Failed to send getheaders request: {e}"))?;
+    }
+
+    let blocks_to_download = store.get_blocks_to_download(MAX_BLOCKS_TO_DOWNLOAD);
+    if !blocks_to_download.is_empty() {
+        swarm_tx
+            .send(SwarmSend::DownloadShares(blocks_to_download))
+            .await
+            .map_err(|e| format!("Failed to queue share downloads: {e}"))?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NetworkConfig;
+    use crate::middleware::peer_score::{Offence, PeerScores, penalise_peer};
+    use crate::test_utils::{TestShareBlockBuilder, build_block_from_work_components};
+    use crate::utils::time_provider::TestTimeProvider;
+    use std::time::SystemTime;
+
+    #[tokio::test]
+    async fn test_handle_share_headers_queues_downloads() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(8);
+        let peer_id = PeerId::random();
+        let time_provider = TestTimeProvider::new(SystemTime::now());
+
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/a/");
+        let blockhash = share_block.block_hash();
+        let bits = share_block.header.bits.to_consensus();
+        store.expect_get_target_at().returning(move |_| Ok(bits));
+
+        store
+            .expect_add_share_header()
+            .withf(move |header| header.block_hash() == blockhash)
+            .times(1)
+            .returning(|_| Ok(()));
+        store
+            .expect_get_blocks_to_download()
+            .with(mockall::predicate::eq(MAX_BLOCKS_TO_DOWNLOAD))
+            .returning(move |_| vec![blockhash]);
+
+        let result = handle_share_headers(
+            peer_id,
+            vec![share_block.header],
+            Arc::new(store),
+            swarm_tx,
+            &time_provider,
+        )
+        .await;
+        assert!(result.is_ok());
+
+        // Less than a full batch, so no further getheaders, only downloads
+        match swarm_rx.recv().await {
+            Some(SwarmSend::DownloadShares(blockhashes)) => {
+                assert_eq!(blockhashes, vec![blockhash]);
+            }
+            _ => panic!("Expected DownloadShares"),
+        }
+        assert!(swarm_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_share_headers_requests_next_batch() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(8);
+        let peer_id = PeerId::random();
+        let time_provider = TestTimeProvider::new(SystemTime::now());
+
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/a/");
+        let blockhash = share_block.block_hash();
+        let bits = share_block.header.bits.to_consensus();
+        store.expect_get_target_at().returning(move |_| Ok(bits));
+        let share_headers = vec![share_block.header; MAX_HEADERS];
+
+        store
+            .expect_add_share_header()
+            .times(MAX_HEADERS)
+            .returning(|_| Ok(()));
+        store.expect_get_blocks_to_download().returning(|_| vec![]);
+
+        let result = handle_share_headers(
+            peer_id,
+            share_headers,
+            Arc::new(store),
+            swarm_tx,
+            &time_provider,
+        )
+        .await;
+        assert!(result.is_ok());
+
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Request(sent_peer_id, Message::GetShareHeaders(locator, stop))) => {
+                assert_eq!(sent_peer_id, peer_id);
+                assert_eq!(locator, vec![blockhash]);
+                assert_eq!(stop, BlockHash::all_zeros());
+            }
+            _ => panic!("Expected GetShareHeaders request"),
+        }
+        // Nothing to download
+        assert!(swarm_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_share_headers_rejects_insufficient_work() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(8);
+        let time_provider = TestTimeProvider::new(SystemTime::now());
+
+        let mut header = TestShareBlockBuilder::new().build().header;
+        // Hardest possible target is not met by the test header
+        header.bits = bitcoin::CompactTarget::from_consensus(0x03000001);
+
+        store.expect_get_target_at().returning(|_| Ok(0x03000001));
+        store.expect_add_share_header().never();
+        store.expect_get_blocks_to_download().never();
+
+        let result = handle_share_headers(
+            PeerId::random(),
+            vec![header],
+            Arc::new(store),
+            swarm_tx,
+            &time_provider,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(swarm_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_share_headers_stops_on_store_error() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(8);
+        let time_provider = TestTimeProvider::new(SystemTime::now());
+
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/a/");
+        let bits = share_block.header.bits.to_consensus();
+        store.expect_get_target_at().returning(move |_| Ok(bits));
+
+        store
+            .expect_add_share_header()
+            .times(1)
+            .returning(|_| Err("Parent not found".into()));
+        store.expect_get_blocks_to_download().never();
+
+        let result = handle_share_headers(
+            PeerId::random(),
+            vec![share_block.header.clone(), share_block.header],
+            Arc::new(store),
+            swarm_tx,
+            &time_provider,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(swarm_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_share_headers_rejects_unexpected_bits() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(8);
+        let time_provider = TestTimeProvider::new(SystemTime::now());
+
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/a/");
+        // The chain expects a harder target than the header claims
+        let bits = share_block.header.bits.to_consensus();
+        store
+            .expect_get_target_at()
+            .returning(move |_| Ok(bits - 1));
+        store.expect_add_share_header().never();
+        store.expect_get_blocks_to_download().never();
+
+        let result = handle_share_headers(
+            PeerId::random(),
+            vec![share_block.header],
+            Arc::new(store),
+            swarm_tx,
+            &time_provider,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(swarm_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handle_share_headers_invalid_header_penalises_peer() {
+        let mut store = ChainStore::default();
+        let (swarm_tx, _swarm_rx) = mpsc::channel::<SwarmSend<u32>>(8);
+        let time_provider = TestTimeProvider::new(SystemTime::now());
+        let peer_id = PeerId::random();
+
+        let mut header = TestShareBlockBuilder::new().build().header;
+        header.bits = bitcoin::CompactTarget::from_consensus(0x03000001);
+        store.expect_get_target_at().returning(|_| Ok(0x03000001));
+        store.expect_add_share_header().never();
+        store.expect_get_banned_peers().returning(|| Ok(Vec::new()));
+
+        let store = Arc::new(store);
+        let scores = PeerScores::new(&NetworkConfig::default(), store.clone(), 1000);
+        let result = handle_share_headers(
+            peer_id,
+            vec![header],
+            store,
+            swarm_tx.clone(),
+            &time_provider,
+        )
+        .await;
+        let error = result.unwrap_err();
+        assert_eq!(
+            Offence::from_error(error.as_ref()),
+            Some(Offence::InvalidPow)
+        );
+
+        penalise_peer(&scores, peer_id, error.as_ref(), 1000, &swarm_tx).await;
+        assert_eq!(scores.score(&peer_id, 1000), Offence::InvalidPow.penalty());
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::validation::validate_transaction;
+use bitcoin::Transaction;
+use std::error::Error;
+use std::sync::Arc;
+use tracing::{debug, info};
+
+/// Handle a Transaction received from a peer in response to a GetData
+/// request for a txid we found missing in an inventory.
+///
+/// The caller must check the transaction was requested, so peers can't
+/// fill our store with transactions nobody asked for. Transactions
+/// already in the store are ignored. New transactions are validated and
+/// stored unconfirmed, they are confirmed when a share including them
+/// is added to the chain.
+pub async fn handle_transaction(
+    transaction: Transaction,
+    store: Arc<ChainStore>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let txid = transaction.compute_txid();
+    info!("Received transaction: {txid}");
+    if store.get_missing_txids(&[txid]).is_empty() {
+        debug!("Transaction {txid} already in store");
+        return Ok(());
+    }
+    validate_transaction(&transaction)?;
+    store
+        .add_transaction(&transaction)
+        .map_err(|e| format!("Failed to add transaction {txid}: {e}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shares::validation::ValidationError;
+    use crate::test_utils::test_spending_transaction;
+    use mockall::predicate::*;
+
+    #[tokio::test]
+    async fn test_handle_transaction_adds_missing() {
+        let mut store = ChainStore::default();
+        let tx = test_spending_transaction(1000);
+        let txid = tx.compute_txid();
+
+        store
+            .expect_get_missing_txids()
+            .with(eq(vec![txid]))
+            .returning(move |_| vec![txid]);
+        store
+            .expect_add_transaction()
+            .with(eq(tx.clone()))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        assert!(handle_transaction(tx, Arc::new(store)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handle_transaction_skips_known() {
+        let mut store = ChainStore::default();
+        let tx = test_spending_transaction(1000);
+
+        store.expect_get_missing_txids().returning(|_| vec![]);
+        store.expect_add_transaction().never();
+
+        assert!(handle_transaction(tx, Arc::new(store)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handle_transaction_rejects_invalid() {
+        let mut store = ChainStore::default();
+        let mut tx = test_spending_transaction(1000);
+        tx.output.clear();
+        let txid = tx.compute_txid();
+
+        store
+            .expect_get_missing_txids()
+            .returning(move |_| vec![txid]);
+        store.expect_add_transaction().never();
+
+        let result = handle_transaction(tx, Arc::new(store)).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<ValidationError>(),
+            Some(ValidationError::InvalidTransaction { .. })
+        ));
+    }
+}
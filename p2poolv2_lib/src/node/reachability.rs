@@ -0,0 +1,130 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Reachability of the node from the network.
+//!
+//! AutoNAT asks peers to dial us back to find out if we are publicly
+//! reachable. Nodes behind NAT listen through relays instead, so peers
+//! can still connect to them, and DCUtR upgrades relayed connections
+//! to direct connections by hole punching.
+
+use libp2p::Multiaddr;
+use libp2p::autonat::NatStatus;
+use libp2p::multiaddr::Protocol;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+
+/// Whether peers can dial us, as detected by AutoNAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Default)]
+pub enum NatState {
+    /// AutoNAT is disabled or has not finished probing
+    #[default]
+    Unknown,
+    /// Peers can dial us on our public address
+    Public,
+    /// Peers can't dial us, we are behind NAT or a firewall
+    Private,
+}
+
+/// Reachability status shared with the API
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct Reachability {
+    pub nat_state: NatState,
+    /// Address AutoNAT confirmed peers can dial us on
+    pub public_address: Option<String>,
+    /// Addresses we listen on, including QUIC and relayed addresses
+    pub listen_addresses: Vec<String>,
+    /// Relayed addresses peers can reach us through
+    pub relay_addresses: Vec<String>,
+}
+
+/// Handle to read the node's reachability from other tasks
+#[derive(Debug, Clone, Default)]
+pub struct ReachabilityHandle(Arc<RwLock<Reachability>>);
+
+impl ReachabilityHandle {
+    pub fn get(&self) -> Reachability {
+        self.0.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_nat_status(&self, status: &NatStatus) {
+        let mut reachability = self.0.write().unwrap();
+        (reachability.nat_state, reachability.public_address) = match status {
+            NatStatus::Public(address) => (NatState::Public, Some(address.to_string())),
+            NatStatus::Private => (NatState::Private, None),
+            NatStatus::Unknown => (NatState::Unknown, None),
+        };
+    }
+
+    pub(crate) fn add_listen_address(&self, address: &Multiaddr) {
+        let mut reachability = self.0.write().unwrap();
+        let addresses = if is_relayed(address) {
+            &mut reachability.relay_addresses
+        } else {
+            &mut reachability.listen_addresses
+        };
+        let address = address.to_string();
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    pub(crate) fn remove_listen_address(&self, address: &Multiaddr) {
+        let mut reachability = self.0.write().unwrap();
+        let address = address.to_string();
+        reachability.listen_addresses.retain(|a| *a != address);
+        reachability.relay_addresses.retain(|a| *a != address);
+    }
+}
+
+/// Is the address a circuit through a relay
+pub fn is_relayed(address: &Multiaddr) -> bool {
+    address.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reachability_handle() {
+        let handle = ReachabilityHandle::default();
+        assert_eq!(handle.get().nat_state, NatState::Unknown);
+
+        let public: Multiaddr = "/ip4/1.2.3.4/tcp/6884".parse().unwrap();
+        handle.set_nat_status(&NatStatus::Public(public.clone()));
+        assert_eq!(handle.get().nat_state, NatState::Public);
+        assert_eq!(handle.get().public_address, Some(public.to_string()));
+        handle.set_nat_status(&NatStatus::Private);
+        assert_eq!(handle.get().nat_state, NatState::Private);
+        assert_eq!(handle.get().public_address, None);
+
+        let relayed: Multiaddr = format!(
+            "/ip4/1.2.3.4/tcp/6884/p2p/{}/p2p-circuit",
+            libp2p::PeerId::random()
+        )
+        .parse()
+        .unwrap();
+        handle.add_listen_address(&public);
+        handle.add_listen_address(&relayed);
+        handle.add_listen_address(&relayed);
+        assert_eq!(handle.get().listen_addresses, vec![public.to_string()]);
+        assert_eq!(handle.get().relay_addresses, vec![relayed.to_string()]);
+
+        handle.remove_listen_address(&relayed);
+        assert!(handle.get().relay_addresses.is_empty());
+    }
+}
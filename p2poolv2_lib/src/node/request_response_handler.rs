@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::config::{Parsed, StratumConfig};
 use crate::node::SwarmSend;
 use crate::node::behaviour::request_response::RequestResponseEvent;
 use crate::node::messages::Message;
@@ -37,6 +38,7 @@ pub async fn handle_request_response_event(
     event: RequestResponseEvent,
     store: std::sync::Arc<ChainStore>,
     swarm_tx: mpsc::Sender<SwarmSend<ResponseChannel<Message>>>,
+    stratum_config: std::sync::Arc<StratumConfig<Parsed>>,
 ) -> Result<(), Box<dyn Error>> {
     info!("Request-response event: {:?}", event);
     match event {
@@ -58,6 +60,7 @@ pub async fn handle_request_response_event(
                 response_channel,
                 swarm_tx,
                 time_provider,
+                stratum_config,
             };
             if let Err(e) = handle_request(request_context).await {
                 error!("Error handling request: {}", e);
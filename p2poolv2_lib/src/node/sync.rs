@@ -0,0 +1,390 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Share chain sync state machine.
+//!
+//! Peers tell us their chain tip in their Version message and announce
+//! it again whenever it changes. When a peer has more work than our
+//! chain we request headers from it, download the share blocks for
+//! those headers and repeat until we have caught up.
+//!
+//! A sync peer that does not send headers in time, or whose headers do
+//! not add up to the chain work it announced, is dropped as sync peer
+//! and reported so the node can penalise it.
+//!
+//! Idle -> HeadersSync -> BlocksSync -> HeadersSync ... -> Synced
+
+#[cfg(test)]
+#[mockall_double::double]
+use crate::shares::chain::chain_store::ChainStore;
+#[cfg(not(test))]
+use crate::shares::chain::chain_store::ChainStore;
+use bitcoin::Work;
+use libp2p::PeerId;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+/// Seconds to wait for headers from the sync peer before asking again
+const HEADERS_TIMEOUT_SECS: u64 = 30;
+
+/// Where the node is in catching up with its peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Default)]
+pub enum SyncState {
+    /// No peers have told us their chain tip yet
+    #[default]
+    Idle,
+    /// Requesting headers from a peer with more work
+    HeadersSync,
+    /// Downloading share blocks for the received headers
+    BlocksSync,
+    /// No peer has more work than our chain
+    Synced,
+}
+
+/// Chain tip announced by a peer or of the local chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainTip {
+    pub height: u32,
+    pub chain_work: Work,
+}
+
+/// Get the tip of our share chain
+pub fn local_tip(store: &ChainStore) -> ChainTip {
+    let height = store.get_tip_height().unwrap_or_else(|e| {
+        warn!("Failed to get chain tip height: {}", e);
+        None
+    });
+    let chain_work = store.get_total_work().unwrap_or_else(|e| {
+        warn!("Failed to get chain work: {}", e);
+        Work::from_le_bytes([0; 32])
+    });
+    ChainTip {
+        height: height.unwrap_or_default(),
+        chain_work,
+    }
+}
+
+/// Sync status shared with the API
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct SyncStatus {
+    pub state: SyncState,
+    pub local_height: u32,
+    pub local_chain_work: String,
+    /// Peer we are syncing from, if any
+    pub sync_peer: Option<String>,
+    /// Highest share height announced by any peer
+    pub best_peer_height: u32,
+    /// Number of peers that announced their chain tip
+    pub peers: usize,
+    /// Shares waiting for their parent or uncles
+    pub orphans: usize,
+    /// Orphans dropped by age or pool size since startup
+    pub orphans_evicted: u64,
+}
+
+/// Handle to read the node's sync status from other tasks
+#[derive(Debug, Clone, Default)]
+pub struct SyncStatusHandle(Arc<RwLock<SyncStatus>>);
+
+impl SyncStatusHandle {
+    pub fn get(&self) -> SyncStatus {
+        self.0.read().unwrap().clone()
+    }
+
+    fn set(&self, status: SyncStatus) {
+        *self.0.write().unwrap() = status;
+    }
+
+    /// Update the orphan pool counts
+    pub(crate) fn set_orphans(&self, orphans: usize, orphans_evicted: u64) {
+        let mut status = self.0.write().unwrap();
+        status.orphans = orphans;
+        status.orphans_evicted = orphans_evicted;
+    }
+}
+
+/// Tracks peer chain tips and decides when to request headers
+#[derive(Debug, Default)]
+pub struct SyncManager {
+    state: SyncState,
+    peer_tips: HashMap<PeerId, ChainTip>,
+    sync_peer: Option<PeerId>,
+    /// When we last requested headers from the sync peer, zero when
+    /// no request is outstanding
+    headers_requested_at: u64,
+    /// Sync peers that did not deliver, to be penalised by the node
+    failed_peers: Vec<PeerId>,
+    status: SyncStatusHandle,
+}
+
+impl SyncManager {
+    pub fn new(status: SyncStatusHandle) -> Self {
+        Self {
+            status,
+            ..Default::default()
+        }
+    }
+
+    pub fn state(&self) -> SyncState {
+        self.state
+    }
+
+    /// Handle to read the sync status from other tasks
+    pub fn status(&self) -> SyncStatusHandle {
+        self.status.clone()
+    }
+
+    /// Record the chain tip a peer announced
+    pub fn peer_tip(&mut self, peer: PeerId, tip: ChainTip) {
+        self.peer_tips.insert(peer, tip);
+    }
+
+    /// Forget a disconnected peer, picking a new sync peer on the next update
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        self.peer_tips.remove(peer);
+        if self.sync_peer == Some(*peer) {
+            self.sync_peer = None;
+            self.headers_requested_at = 0;
+        }
+    }
+
+    /// Record headers received from a peer. If the peer sent a full
+    /// batch, the next batch has already been requested.
+    ///
+    /// Once the sync peer has sent its last batch, our best header
+    /// chain must have at least the chain work the peer announced,
+    /// otherwise the peer failed to deliver its claimed chain.
+    pub fn headers_received(
+        &mut self,
+        peer: &PeerId,
+        more_requested: bool,
+        header_work: Work,
+        now: u64,
+    ) {
+        if self.sync_peer != Some(*peer) {
+            return;
+        }
+        if more_requested {
+            self.headers_requested_at = now;
+            return;
+        }
+        self.headers_requested_at = 0;
+        if self
+            .peer_tips
+            .get(peer)
+            .is_some_and(|tip| tip.chain_work > header_work)
+        {
+            warn!("Sync peer {peer} did not deliver the chain work it announced");
+            self.sync_failed(*peer);
+        }
+    }
+
+    /// Sync peers that failed to deliver since the last call
+    pub fn take_failed_peers(&mut self) -> Vec<PeerId> {
+        std::mem::take(&mut self.failed_peers)
+    }
+
+    /// Drop a sync peer's announced tip so another peer is picked, until
+    /// it announces a tip again
+    fn sync_failed(&mut self, peer: PeerId) {
+        self.peer_tips.remove(&peer);
+        self.sync_peer = None;
+        self.headers_requested_at = 0;
+        self.failed_peers.push(peer);
+    }
+
+    /// Move the state machine forward given our chain tip and whether
+    /// share downloads are still pending.
+    ///
+    /// Returns the peer to request headers from, if we need to.
+    pub fn update(&mut self, local: ChainTip, downloads_pending: bool, now: u64) -> Option<PeerId> {
+        if let Some(peer) = self.sync_peer
+            && self.headers_requested_at != 0
+            && now >= self.headers_requested_at + HEADERS_TIMEOUT_SECS
+        {
+            warn!("Sync peer {peer} did not send headers in time");
+            self.sync_failed(peer);
+        }
+
+        let best_peer = self
+            .peer_tips
+            .iter()
+            .filter(|(_, tip)| tip.chain_work > local.chain_work)
+            .max_by_key(|(_, tip)| tip.chain_work)
+            .map(|(peer, _)| *peer);
+
+        let request = match best_peer {
+            None => {
+                self.sync_peer = None;
+                self.headers_requested_at = 0;
+                self.state = if self.peer_tips.is_empty() {
+                    SyncState::Idle
+                } else {
+                    SyncState::Synced
+                };
+                None
+            }
+            Some(_) if downloads_pending => {
+                self.state = SyncState::BlocksSync;
+                None
+            }
+            Some(best_peer) => {
+                // Keep syncing from the current peer while it is ahead of us
+                let sync_peer = match self.sync_peer {
+                    Some(peer)
+                        if self
+                            .peer_tips
+                            .get(&peer)
+                            .is_some_and(|tip| tip.chain_work > local.chain_work) =>
+                    {
+                        peer
+                    }
+                    _ => best_peer,
+                };
+                let waiting = self.state == SyncState::HeadersSync
+                    && self.sync_peer == Some(sync_peer)
+                    && self.headers_requested_at != 0;
+                self.state = SyncState::HeadersSync;
+                self.sync_peer = Some(sync_peer);
+                if waiting {
+                    None
+                } else {
+                    self.headers_requested_at = now;
+                    Some(sync_peer)
+                }
+            }
+        };
+
+        self.status.set(SyncStatus {
+            state: self.state,
+            local_height: local.height,
+            local_chain_work: local.chain_work.to_string(),
+            sync_peer: self.sync_peer.map(|peer| peer.to_string()),
+            best_peer_height: self
+                .peer_tips
+                .values()
+                .map(|tip| tip.height)
+                .max()
+                .unwrap_or_default(),
+            peers: self.peer_tips.len(),
+            ..self.status.get()
+        });
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tip(height: u32, work: u8) -> ChainTip {
+        let mut bytes = [0u8; 32];
+        bytes[0] = work;
+        ChainTip {
+            height,
+            chain_work: Work::from_le_bytes(bytes),
+        }
+    }
+
+    #[test]
+    fn test_sync_state_transitions() {
+        let status = SyncStatusHandle::default();
+        let mut sync = SyncManager::new(status.clone());
+        let peer = PeerId::random();
+
+        assert_eq!(sync.update(tip(1, 1), false, 100), None);
+        assert_eq!(sync.state(), SyncState::Idle);
+
+        // Peer with more work triggers a headers request
+        sync.peer_tip(peer, tip(10, 10));
+        assert_eq!(sync.update(tip(1, 1), false, 100), Some(peer));
+        assert_eq!(sync.state(), SyncState::HeadersSync);
+        assert_eq!(status.get().sync_peer, Some(peer.to_string()));
+
+        // No repeated request while waiting for headers
+        assert_eq!(sync.update(tip(1, 1), false, 110), None);
+
+        // Headers received, downloads pending
+        sync.headers_received(&peer, false, tip(10, 10).chain_work, 120);
+        assert_eq!(sync.update(tip(1, 1), true, 120), None);
+        assert_eq!(sync.state(), SyncState::BlocksSync);
+
+        // Downloads done but still behind, ask for more headers
+        assert_eq!(sync.update(tip(5, 5), false, 130), Some(peer));
+        assert_eq!(sync.state(), SyncState::HeadersSync);
+
+        // Caught up
+        sync.headers_received(&peer, false, tip(10, 10).chain_work, 140);
+        assert_eq!(sync.update(tip(10, 10), false, 140), None);
+        assert_eq!(sync.state(), SyncState::Synced);
+        assert_eq!(status.get().state, SyncState::Synced);
+        assert_eq!(status.get().best_peer_height, 10);
+
+        sync.peer_disconnected(&peer);
+        assert_eq!(sync.update(tip(10, 10), false, 150), None);
+        assert_eq!(sync.state(), SyncState::Idle);
+    }
+
+    #[test]
+    fn test_sync_replaces_peer_after_headers_timeout() {
+        let mut sync = SyncManager::new(SyncStatusHandle::default());
+        let peer = PeerId::random();
+        let other_peer = PeerId::random();
+        sync.peer_tip(peer, tip(10, 10));
+        sync.peer_tip(other_peer, tip(3, 3));
+
+        assert_eq!(sync.update(tip(1, 1), false, 100), Some(peer));
+        assert!(sync.take_failed_peers().is_empty());
+
+        // Stalled sync peer is reported and replaced by the next peer ahead of us
+        assert_eq!(
+            sync.update(tip(1, 1), false, 100 + HEADERS_TIMEOUT_SECS),
+            Some(other_peer)
+        );
+        assert_eq!(sync.take_failed_peers(), vec![peer]);
+
+        // Sync peer leaves, nobody else is ahead of us
+        sync.peer_disconnected(&other_peer);
+        assert_eq!(sync.update(tip(1, 1), false, 200), None);
+        assert_eq!(sync.state(), SyncState::Idle);
+    }
+
+    #[test]
+    fn test_sync_replaces_peer_without_claimed_work() {
+        let mut sync = SyncManager::new(SyncStatusHandle::default());
+        let liar = PeerId::random();
+        let honest = PeerId::random();
+        sync.peer_tip(liar, tip(100, 100));
+        sync.peer_tip(honest, tip(10, 10));
+
+        assert_eq!(sync.update(tip(1, 1), false, 100), Some(liar));
+
+        // A full batch leaves the claim to be checked after the last batch
+        sync.headers_received(&liar, true, tip(2, 2).chain_work, 110);
+        assert!(sync.take_failed_peers().is_empty());
+
+        // The last batch does not reach the announced chain work
+        sync.headers_received(&liar, false, tip(3, 3).chain_work, 120);
+        assert_eq!(sync.take_failed_peers(), vec![liar]);
+        assert_eq!(sync.update(tip(1, 1), false, 120), Some(honest));
+
+        // Headers from the honest peer reach its announced chain work
+        sync.headers_received(&honest, false, tip(10, 10).chain_work, 130);
+        assert!(sync.take_failed_peers().is_empty());
+    }
+}
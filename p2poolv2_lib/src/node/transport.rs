@@ -0,0 +1,126 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Transports for the P2P node.
+//!
+//! TCP connections, with DNS resolution, and connections through
+//! relays are secured with noise and multiplexed with yamux. QUIC has
+//! its own encryption and multiplexing and is enabled by config.
+
+use crate::config::NetworkConfig;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::{Boxed, OrTransport};
+use libp2p::core::upgrade::Version;
+use libp2p::futures::future::Either;
+use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::tcp::Config as TcpConfig;
+use libp2p::{Multiaddr, PeerId, Transport, relay};
+use std::error::Error;
+use std::time::Duration;
+
+/// Build the transport, combining TCP, relayed connections and QUIC
+/// if enabled
+pub fn build_transport(
+    id_keys: &Keypair,
+    config: &NetworkConfig,
+    relay_transport: relay::client::Transport,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error>> {
+    let tcp_config = TcpConfig::default().nodelay(true);
+    let noise_config = libp2p::noise::Config::new(id_keys)?;
+
+    // Resolve dns and dnsaddr multiaddrs, e.g. for DNS seeds
+    let tcp_transport = libp2p::tcp::tokio::Transport::new(tcp_config);
+    let tcp_transport = libp2p::dns::tokio::Transport::system(tcp_transport)?;
+
+    let transport = OrTransport::new(relay_transport, tcp_transport)
+        .upgrade(Version::V1)
+        .authenticate(noise_config)
+        .multiplex(libp2p::yamux::Config::default())
+        .timeout(Duration::from_secs(config.dial_timeout_secs))
+        .boxed();
+    if !config.enable_quic {
+        return Ok(transport);
+    }
+
+    let mut quic_config = libp2p::quic::Config::new(id_keys);
+    quic_config.handshake_timeout = Duration::from_secs(config.dial_timeout_secs);
+    let quic_transport = libp2p::quic::tokio::Transport::new(quic_config);
+    Ok(transport
+        .or_transport(quic_transport)
+        .map(|output, _| match output {
+            Either::Left((peer_id, muxer)) => (peer_id, muxer),
+            Either::Right((peer_id, connection)) => (peer_id, StreamMuxerBox::new(connection)),
+        })
+        .boxed())
+}
+
+/// QUIC address on the UDP port matching a TCP listen address
+pub fn quic_listen_address(tcp_address: &Multiaddr) -> Option<Multiaddr> {
+    let mut has_tcp = false;
+    let address = tcp_address
+        .iter()
+        .flat_map(|protocol| match protocol {
+            Protocol::Tcp(port) => {
+                has_tcp = true;
+                vec![Protocol::Udp(port), Protocol::QuicV1]
+            }
+            protocol => vec![protocol],
+        })
+        .collect();
+    has_tcp.then_some(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quic_listen_address() {
+        assert_eq!(
+            quic_listen_address(&"/ip4/0.0.0.0/tcp/6884".parse().unwrap()),
+            Some("/ip4/0.0.0.0/udp/6884/quic-v1".parse().unwrap())
+        );
+        assert_eq!(
+            quic_listen_address(&"/ip4/0.0.0.0/udp/6884/quic-v1".parse().unwrap()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_transport_with_quic() {
+        let id_keys = Keypair::generate_ed25519();
+        let config = NetworkConfig {
+            enable_quic: true,
+            ..Default::default()
+        };
+        let (relay_transport, _) = relay::client::new(id_keys.public().to_peer_id());
+        let mut transport = build_transport(&id_keys, &config, relay_transport).unwrap();
+
+        let tcp: Multiaddr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+        let quic = quic_listen_address(&tcp).unwrap();
+        assert!(
+            transport
+                .listen_on(libp2p::core::transport::ListenerId::next(), tcp)
+                .is_ok()
+        );
+        assert!(
+            transport
+                .listen_on(libp2p::core::transport::ListenerId::next(), quic)
+                .is_ok()
+        );
+    }
+}
@@ -18,6 +18,8 @@ pub mod p2p_service;
 
 use crate::config::NetworkConfig;
 use crate::middleware::inactivity::InactivityLayer;
+use crate::middleware::message_rate_limit::MessageRateLimitLayer;
+use crate::middleware::peer_score::{PeerScoreLayer, PeerScores};
 use crate::node::SwarmSend;
 use crate::service::p2p_service::{P2PService, RequestContext};
 use crate::utils::time_provider::TimeProvider;
@@ -30,6 +32,7 @@ use tower::{ServiceBuilder, limit::RateLimitLayer, util::BoxService};
 pub fn build_service<C, T>(
     config: NetworkConfig,
     swarm_tx: Sender<SwarmSend<C>>,
+    peer_scores: PeerScores,
 ) -> BoxService<RequestContext<C, T>, (), Box<dyn Error + Send + Sync>>
 where
     C: Send + Sync + 'static,
@@ -39,15 +42,21 @@ where
 
     let inactivity_layer = InactivityLayer::new(
         Duration::from_secs(config.peer_inactivity_timeout_secs),
-        swarm_tx,
+        swarm_tx.clone(),
     );
 
+    let peer_score_layer = PeerScoreLayer::new(peer_scores, swarm_tx);
+
+    let message_rate_limit_layer = MessageRateLimitLayer::new(&config);
+
     let builder = ServiceBuilder::new()
         .layer(RateLimitLayer::new(
             config.max_requests_per_second,
             Duration::from_secs(1),
         ))
-        .layer(inactivity_layer);
+        .layer(inactivity_layer)
+        .layer(peer_score_layer)
+        .layer(message_rate_limit_layer);
 
     let service = builder.service(base_service);
 
@@ -57,7 +66,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::NetworkConfig;
+    use crate::config::{NetworkConfig, StratumConfig};
     use crate::node::SwarmSend;
     use crate::node::messages::Message;
     use crate::service::p2p_service::{P2PService, RequestContext};
@@ -167,6 +176,7 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx: swarm_tx.clone(),
             time_provider: time_provider.clone(),
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let ctx2 = RequestContext {
@@ -176,6 +186,7 @@ mod tests {
             response_channel: response_channel_tx1,
             swarm_tx: swarm_tx.clone(),
             time_provider: time_provider.clone(),
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let ctx3 = RequestContext {
@@ -185,6 +196,7 @@ mod tests {
             response_channel: response_channel_tx2,
             swarm_tx: swarm_tx.clone(),
             time_provider: time_provider.clone(),
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         // First request should succeed
@@ -270,6 +282,7 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx: swarm_tx.clone(),
             time_provider: time_provider.clone(),
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         // Try service.ready(), and on failure, trigger disconnect manually
@@ -331,6 +344,20 @@ mod tests {
             max_requests_per_second: 1,
             peer_inactivity_timeout_secs: 30,
             dial_timeout_secs: 30,
+            node_key_path: None,
+            max_redial_peers: 8,
+            ban_threshold: 100,
+            ban_duration_secs: 86400,
+            bootstrap_peers: vec![],
+            dns_seeds: vec![],
+            target_outbound_peers: 8,
+            discovery_interval_secs: 60,
+            max_peers_per_subnet: 2,
+            enable_quic: false,
+            enable_autonat: false,
+            enable_relay_client: false,
+            relay_servers: vec![],
+            enable_relay_server: false,
         };
 
         let peer_id = PeerId::random();
@@ -341,6 +368,7 @@ mod tests {
             response_channel: response_channel_tx.clone(),
             swarm_tx: swarm_tx.clone(),
             time_provider: time_provider.clone(),
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
         let ctx1 = RequestContext {
@@ -350,10 +378,19 @@ mod tests {
             response_channel: response_channel_tx.clone(),
             swarm_tx: swarm_tx.clone(),
             time_provider: time_provider.clone(),
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
-        let mut service =
-            build_service::<Sender<Message>, _>(network_config.clone(), swarm_tx.clone());
+        let mut scores_store = ChainStore::default();
+        scores_store
+            .expect_get_banned_peers()
+            .returning(|| Ok(Vec::new()));
+        let peer_scores = PeerScores::new(&network_config, Arc::new(scores_store), 0);
+        let mut service = build_service::<Sender<Message>, _>(
+            network_config.clone(),
+            swarm_tx.clone(),
+            peer_scores,
+        );
 
         // First request should succeed immediately
         assert!(
@@ -416,6 +453,20 @@ mod tests {
             max_requests_per_second: 1,
             peer_inactivity_timeout_secs: 30,
             dial_timeout_secs: 30,
+            node_key_path: None,
+            max_redial_peers: 8,
+            ban_threshold: 100,
+            ban_duration_secs: 86400,
+            bootstrap_peers: vec![],
+            dns_seeds: vec![],
+            target_outbound_peers: 8,
+            discovery_interval_secs: 60,
+            max_peers_per_subnet: 2,
+            enable_quic: false,
+            enable_autonat: false,
+            enable_relay_client: false,
+            relay_servers: vec![],
+            enable_relay_server: false,
         };
 
         let peer_id = PeerId::random();
@@ -426,10 +477,19 @@ mod tests {
             response_channel: response_channel_tx,
             swarm_tx: swarm_tx.clone(),
             time_provider: time_provider.clone(),
+            stratum_config: Arc::new(StratumConfig::new_for_test_default().parse().unwrap()),
         };
 
-        let mut service =
-            build_service::<Sender<Message>, _>(network_config.clone(), swarm_tx.clone());
+        let mut scores_store = ChainStore::default();
+        scores_store
+            .expect_get_banned_peers()
+            .returning(|| Ok(Vec::new()));
+        let peer_scores = PeerScores::new(&network_config, Arc::new(scores_store), 0);
+        let mut service = build_service::<Sender<Message>, _>(
+            network_config.clone(),
+            swarm_tx.clone(),
+            peer_scores,
+        );
 
         // First request succeeds
         assert!(
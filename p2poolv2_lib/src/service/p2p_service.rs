@@ -22,6 +22,7 @@ use futures::Future;
 use tokio::sync::mpsc;
 use tower::Service;
 
+use crate::config::{Parsed, StratumConfig};
 use crate::node::SwarmSend;
 use crate::node::messages::Message;
 use crate::node::p2p_message_handlers::handle_request;
@@ -41,6 +42,8 @@ pub struct RequestContext<C, T> {
     pub response_channel: C,
     pub swarm_tx: mpsc::Sender<SwarmSend<C>>,
     pub time_provider: T,
+    /// Stratum config used to verify the payouts of received shares
+    pub stratum_config: Arc<StratumConfig<Parsed>>,
 }
 
 /// The Tower service that processes inbound P2P requests.
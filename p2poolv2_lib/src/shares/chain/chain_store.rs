@@ -14,21 +14,28 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::accounting::LedgerUpdate;
 use crate::accounting::simple_pplns::SimplePplnsShare;
+use crate::shares::chain::retarget;
 use crate::shares::share_block::{ShareBlock, ShareHeader};
+use crate::shares::template_mempool::TemplateMempool;
+use crate::shares::validation::{MAX_UNCLES, check_uncles};
 use crate::store::Store;
+use crate::store::block_tx_metadata::BlockStatus;
+use crate::store::peer_book::PeerRecord;
+use bitcoin::bip152::ShortId;
 use bitcoin::hashes::Hash;
-use bitcoin::{BlockHash, Work};
-use std::collections::{HashMap, HashSet};
+use bitcoin::{Address, BlockHash, CompressedPublicKey, Transaction, Txid, Work};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use tracing::{debug, error, info};
 
 /// The minimum number of shares that must be on the chain for a share to be considered confirmed
 const MIN_CONFIRMATION_DEPTH: usize = 100;
 
 /// The maximum depth up to which we include the uncles in the chain
-const MAX_UNCLE_DEPTH: usize = 3;
+pub const MAX_UNCLE_DEPTH: usize = 3;
 
 /// Common ancestor depth we look at when finding common ancestors
 /// For now it is the same as PPLNS window
@@ -45,6 +52,8 @@ pub struct ChainStore {
     pub store: Arc<Store>,
     /// Network type for the chain stored here
     pub network: bitcoin::Network,
+    /// Transactions from recent block templates, used to reconstruct compact share blocks
+    template_mempool: RwLock<TemplateMempool>,
 }
 
 #[allow(dead_code)]
@@ -55,7 +64,11 @@ impl ChainStore {
     pub fn new(store: Arc<Store>, genesis_block: ShareBlock, network: bitcoin::Network) -> Self {
         let genesis_block_hash = genesis_block.header.block_hash();
         let genesis_in_store = store.get_share(&genesis_block_hash);
-        let chain = Self { store, network };
+        let chain = Self {
+            store,
+            network,
+            template_mempool: RwLock::new(TemplateMempool::default()),
+        };
 
         // Initialize chain state if needed
         if genesis_in_store.is_none() {
@@ -218,6 +231,107 @@ impl ChainStore {
             .get_pplns_shares_filtered(limit, start_time, end_time)
     }
 
+    /// Get outstanding payout ledger balances in satoshis, keyed by btcaddress
+    pub fn get_ledger_balances(
+        &self,
+    ) -> Result<HashMap<String, i64>, Box<dyn Error + Send + Sync>> {
+        self.store.get_ledger_balances()
+    }
+
+    /// Get the share chain ledger balances at the chain tip in satoshis,
+    /// keyed by btcaddress
+    pub fn get_tip_share_balances(
+        &self,
+    ) -> Result<HashMap<String, i64>, Box<dyn Error + Send + Sync>> {
+        let tip = self.store.get_chain_tip();
+        let balances = self.store.get_share_balances(&tip)?.unwrap_or_default();
+        Ok(balances
+            .into_iter()
+            .map(|(pubkey, balance)| (Address::p2pkh(pubkey, self.network).to_string(), balance))
+            .collect())
+    }
+
+    /// Get the share chain ledger balances stored after a share
+    pub fn get_share_balances(
+        &self,
+        blockhash: &BlockHash,
+    ) -> Result<Option<BTreeMap<CompressedPublicKey, i64>>, Box<dyn Error + Send + Sync>> {
+        self.store.get_share_balances(blockhash)
+    }
+
+    /// Store the share chain ledger balances for a share that found a bitcoin block
+    pub fn put_share_balances(
+        &self,
+        blockhash: &BlockHash,
+        balances: &BTreeMap<CompressedPublicKey, i64>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store.put_share_balances(blockhash, balances)
+    }
+
+    /// Apply carried forward and settled amounts to the payout ledger
+    pub fn apply_ledger_updates(
+        &self,
+        updates: &[LedgerUpdate],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store.apply_ledger_updates(updates)
+    }
+
+    /// Get a value stored in the metadata column family
+    pub fn get_metadata(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        self.store.get_metadata(key)
+    }
+
+    /// Store a value in the metadata column family
+    pub fn put_metadata(
+        &self,
+        key: &str,
+        value: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store.put_metadata(key, value)
+    }
+
+    /// Get all peers in the peer address book
+    pub fn get_peer_records(
+        &self,
+    ) -> Result<Vec<(String, PeerRecord)>, Box<dyn Error + Send + Sync>> {
+        self.store.get_peer_records()
+    }
+
+    /// Record addresses learned for a peer in the peer address book
+    pub fn add_peer_addresses(
+        &self,
+        peer_id: &str,
+        addresses: &[String],
+        now: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store.add_peer_addresses(peer_id, addresses, now)
+    }
+
+    /// Record the outcome of a connection attempt in the peer address book
+    pub fn record_peer_connection(
+        &self,
+        peer_id: &str,
+        success: bool,
+        now: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store.record_peer_connection(peer_id, success, now)
+    }
+
+    /// Get banned peers with the time their ban ends
+    pub fn get_banned_peers(&self) -> Result<Vec<(String, u64)>, Box<dyn Error + Send + Sync>> {
+        self.store.get_banned_peers()
+    }
+
+    /// Persist a peer ban until the given time
+    pub fn ban_peer(&self, peer_id: &str, until: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store.ban_peer(peer_id, until)
+    }
+
+    /// Remove a persisted peer ban
+    pub fn unban_peer(&self, peer_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store.unban_peer(peer_id)
+    }
+
     /// Remove a blockhash from the tips set
     /// If the blockhash is not in the tips set, this is a no-op
     pub fn remove_from_tips(&self, blockhash: &BlockHash) {
@@ -280,6 +394,12 @@ impl ChainStore {
         self.store.get_share_headers(share_hashes)
     }
 
+    /// Get a share header, including headers of shares whose block
+    /// is not downloaded yet
+    pub fn get_share_header(&self, share_hash: &BlockHash) -> Option<ShareHeader> {
+        self.store.get_share_header(share_hash)
+    }
+
     /// Get blockhashes for locator
     /// Returns a list of shares starting from the earliest block from the block hashes
     pub fn get_headers_for_locator(
@@ -312,6 +432,19 @@ impl ChainStore {
         Ok(metadata.height)
     }
 
+    /// Get the total work on the share chain
+    pub fn get_total_work(&self) -> Result<Work, Box<dyn Error + Send + Sync>> {
+        self.store.get_total_work()
+    }
+
+    /// Get the chain work at the best header tip
+    pub fn get_best_header_work(&self) -> Result<Work, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .store
+            .get_block_metadata(&self.get_best_header_tip())?
+            .chain_work)
+    }
+
     /// Get a locator for the chain.
     /// - Start from the tip, go back until we hit genesis block
     /// - After 10 blocks, double the step size each time
@@ -363,14 +496,27 @@ impl ChainStore {
     /// Limit the uncles to up to max uncle depth from the tip
     ///
     /// Uncles: By picking tips, we make sure we are picking uncles
-    /// that haven't been included as an uncle yet.
+    /// that haven't been included as an uncle yet. Tips are further
+    /// filtered by the same rules validation applies to uncles, and
+    /// the shallowest MAX_UNCLES are kept.
     pub fn get_chain_tip_and_uncles(&self) -> (BlockHash, HashSet<BlockHash>) {
-        let mut uncles = self.store.get_tips();
-        uncles.retain(|uncle| {
-            self.get_depth(uncle).unwrap_or(MAX_UNCLE_DEPTH + 1) <= MAX_UNCLE_DEPTH
-        });
         let chain_tip = self.store.get_chain_tip();
-        uncles.remove(&chain_tip);
+        let ancestors = self.get_ancestor_headers(&chain_tip, MAX_UNCLE_DEPTH + 2);
+        let mut candidates: Vec<(usize, BlockHash)> = self
+            .store
+            .get_tips()
+            .into_iter()
+            .filter(|uncle| *uncle != chain_tip)
+            .filter(|uncle| {
+                self.store
+                    .get_share_header(uncle)
+                    .is_some_and(|header| check_uncles(&[header], &ancestors).is_ok())
+            })
+            .filter_map(|uncle| self.get_depth(&uncle).map(|depth| (depth, uncle)))
+            .collect();
+        candidates.sort();
+        candidates.truncate(MAX_UNCLES);
+        let uncles = candidates.into_iter().map(|(_, uncle)| uncle).collect();
         (chain_tip, uncles)
     }
 
@@ -399,6 +545,124 @@ impl ChainStore {
         self.store.get_missing_blockhashes(blockhashes)
     }
 
+    /// Check which txids from the provided list are missing from the store
+    pub fn get_missing_txids(&self, txids: &[Txid]) -> Vec<Txid> {
+        self.store.get_missing_txids(txids)
+    }
+
+    /// Get a share chain transaction by txid
+    pub fn get_tx(&self, txid: &Txid) -> Result<Transaction, Box<dyn Error + Send + Sync>> {
+        self.store.get_tx(txid)
+    }
+
+    /// Add a share chain transaction received from a peer
+    pub fn add_transaction(&self, tx: &Transaction) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.store.add_transaction(tx)
+    }
+
+    /// Add the bitcoin transactions from a new block template to the
+    /// mempool view used to reconstruct compact share blocks
+    pub fn add_template_transactions(&self, transactions: Vec<Transaction>) {
+        self.template_mempool
+            .write()
+            .unwrap()
+            .add_template_transactions(transactions);
+    }
+
+    /// Find bitcoin transactions from recent block templates matching
+    /// the short ids from a compact share block
+    pub fn get_template_transactions(
+        &self,
+        siphash_keys: (u64, u64),
+        short_ids: &[ShortId],
+    ) -> Vec<Option<Transaction>> {
+        self.template_mempool
+            .read()
+            .unwrap()
+            .get_by_short_ids(siphash_keys, short_ids)
+    }
+
+    /// Add a share header received during headers-first sync
+    ///
+    /// The parent must be known, either as a full share or as a
+    /// header. Headers already known are skipped. The chain work is
+    /// accumulated from the parent and the best header tip is moved if
+    /// the new header has more chain work than the current best.
+    pub fn add_share_header(
+        &self,
+        header: ShareHeader,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let blockhash = header.block_hash();
+        if self.store.get_block_metadata(&blockhash).is_ok() {
+            debug!("Share header {blockhash} already in store");
+            return Ok(());
+        }
+        let prev_metadata = self
+            .store
+            .get_block_metadata(&header.prev_share_blockhash)
+            .map_err(|_| format!("Parent {} not found", header.prev_share_blockhash))?;
+        let height = prev_metadata.height.unwrap_or_default() + 1;
+        let chain_work = prev_metadata.chain_work + header.get_work();
+
+        let mut batch = Store::get_write_batch();
+        self.store
+            .add_share_header(header, height, chain_work, &mut batch)?;
+        self.store.commit_batch(batch)?;
+
+        let best_header_tip = self.get_best_header_tip();
+        let best_chain_work = self.store.get_block_metadata(&best_header_tip)?.chain_work;
+        if chain_work > best_chain_work {
+            self.store.set_header_tip(blockhash)?;
+        }
+        Ok(())
+    }
+
+    /// Get the tip of the header chain with the most chain work
+    ///
+    /// Falls back to the chain tip if no headers have been received,
+    /// or if the full share chain has overtaken the header chain.
+    pub fn get_best_header_tip(&self) -> BlockHash {
+        let chain_tip = self.store.get_chain_tip();
+        let header_tip = match self.store.get_header_tip() {
+            Some(header_tip) => header_tip,
+            None => return chain_tip,
+        };
+        match (
+            self.store.get_block_metadata(&header_tip),
+            self.store.get_block_metadata(&chain_tip),
+        ) {
+            (Ok(header_metadata), Ok(chain_metadata))
+                if header_metadata.chain_work > chain_metadata.chain_work =>
+            {
+                header_tip
+            }
+            _ => chain_tip,
+        }
+    }
+
+    /// Get blockhashes for header only shares on the best header chain
+    ///
+    /// Walks back from the best header tip till a share with the full
+    /// block is found. Returns up to limit blockhashes ordered by
+    /// height, so that parents are downloaded before children.
+    pub fn get_blocks_to_download(&self, limit: usize) -> Vec<BlockHash> {
+        let mut blockhashes = Vec::new();
+        let mut current = self.get_best_header_tip();
+        while let Ok(metadata) = self.store.get_block_metadata(&current) {
+            if metadata.status != BlockStatus::HeaderOnly {
+                break;
+            }
+            blockhashes.push(current);
+            current = match self.store.get_share_header(&current) {
+                Some(header) => header.prev_share_blockhash,
+                None => break,
+            };
+        }
+        blockhashes.reverse();
+        blockhashes.truncate(limit);
+        blockhashes
+    }
+
     /// Get the depth of a blockhash from chain tip
     /// Returns None if blockhash is not found in chain
     /// Returns 0 if blockhash is the chain tip
@@ -450,14 +714,49 @@ impl ChainStore {
         self.store.add_user(btcaddress)
     }
 
+    /// Get up to count share headers walking back from the given share
+    ///
+    /// The given share's header comes first, followed by its parent
+    /// and so on. Stops early at genesis or at a header missing from
+    /// the store. Returns an empty vector if the share is not found.
+    pub fn get_ancestor_headers(&self, blockhash: &BlockHash, count: usize) -> Vec<ShareHeader> {
+        let mut headers = Vec::with_capacity(count);
+        let mut current = *blockhash;
+        while headers.len() < count && current != BlockHash::all_zeros() {
+            match self.store.get_share_header(&current) {
+                Some(header) => {
+                    current = header.prev_share_blockhash;
+                    headers.push(header);
+                }
+                None => break,
+            }
+        }
+        headers
+    }
+
     /// Get the target for the tip share block
     pub fn get_current_target(&self) -> Result<u32, Box<dyn Error + Send + Sync>> {
         let tip = self.store.get_chain_tip();
-        let headers = self.get_share_headers(&[tip]).unwrap();
-        match headers.first() {
-            None => Err("No tips found".into()),
-            Some(header) => Ok(header.bits.to_consensus()),
+        self.get_target_at(&tip)
+    }
+
+    /// Get the target a share built on top of the given share must use
+    ///
+    /// Used to build commitments at the chain tip and to validate
+    /// the bits of shares received from peers at their parent.
+    ///
+    /// The target is retargeted from the last RETARGET_WINDOW shares
+    /// ending at the given share, see [retarget::next_target].
+    pub fn get_target_at(
+        &self,
+        blockhash: &BlockHash,
+    ) -> Result<u32, Box<dyn Error + Send + Sync>> {
+        let mut headers = self.get_ancestor_headers(blockhash, retarget::RETARGET_WINDOW);
+        if headers.is_empty() {
+            return Err(format!("Share {blockhash} not found").into());
         }
+        headers.reverse();
+        Ok(retarget::next_target(&headers).to_consensus())
     }
 }
 
@@ -479,17 +778,43 @@ mock! {
         pub fn setup_share_for_chain(&self, share_block: ShareBlock) -> ShareBlock;
         pub fn get_share(&self, share_hash: &BlockHash) -> Option<ShareBlock>;
         pub fn get_pplns_shares_filtered(&self, limit: Option<usize>, start_time: Option<u64>, end_time: Option<u64>) -> Vec<SimplePplnsShare>;
+        pub fn get_ledger_balances(&self) -> Result<HashMap<String, i64>, Box<dyn Error + Send + Sync>>;
+        pub fn get_tip_share_balances(&self) -> Result<HashMap<String, i64>, Box<dyn Error + Send + Sync>>;
+        pub fn get_share_balances(&self, blockhash: &BlockHash) -> Result<Option<BTreeMap<CompressedPublicKey, i64>>, Box<dyn Error + Send + Sync>>;
+        pub fn put_share_balances(&self, blockhash: &BlockHash, balances: &BTreeMap<CompressedPublicKey, i64>) -> Result<(), Box<dyn Error + Send + Sync>>;
+        pub fn apply_ledger_updates(&self, updates: &[LedgerUpdate]) -> Result<(), Box<dyn Error + Send + Sync>>;
+        pub fn get_metadata(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>>;
+        pub fn put_metadata(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>>;
+        pub fn get_peer_records(&self) -> Result<Vec<(String, PeerRecord)>, Box<dyn Error + Send + Sync>>;
+        pub fn add_peer_addresses(&self, peer_id: &str, addresses: &[String], now: u64) -> Result<(), Box<dyn Error + Send + Sync>>;
+        pub fn record_peer_connection(&self, peer_id: &str, success: bool, now: u64) -> Result<(), Box<dyn Error + Send + Sync>>;
+        pub fn get_banned_peers(&self) -> Result<Vec<(String, u64)>, Box<dyn Error + Send + Sync>>;
+        pub fn ban_peer(&self, peer_id: &str, until: u64) -> Result<(), Box<dyn Error + Send + Sync>>;
+        pub fn unban_peer(&self, peer_id: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
         pub fn get_shares_at_height(&self, height: u32) -> Result<HashMap<BlockHash, ShareBlock>, Box<dyn Error + Send + Sync>>;
+        pub fn get_share_header(&self, share_hash: &BlockHash) -> Option<ShareHeader>;
         pub fn get_share_headers(&self, share_hashes: Vec<BlockHash>) -> Result<Vec<ShareHeader>, Box<dyn Error + Send + Sync>>;
         pub fn get_headers_for_locator(&self, block_hashes: &[BlockHash], stop_block_hash: &BlockHash, max_headers: usize) -> Result<Vec<ShareHeader>, Box<dyn Error + Send + Sync>>;
         pub fn get_blockhashes_for_locator(&self, locator: &[BlockHash], stop_block_hash: &BlockHash, max_blockhashes: usize) -> Result<Vec<BlockHash>, Box<dyn Error + Send + Sync>>;
         pub fn build_locator(&self) -> Result<Vec<BlockHash>, Box<dyn Error + Send + Sync>>;
         pub fn get_missing_blockhashes(&self, blockhashes: &[BlockHash]) -> Vec<BlockHash>;
+        pub fn get_missing_txids(&self, txids: &[Txid]) -> Vec<Txid>;
+        pub fn get_tx(&self, txid: &Txid) -> Result<Transaction, Box<dyn Error + Send + Sync>>;
+        pub fn add_transaction(&self, tx: &Transaction) -> Result<(), Box<dyn Error + Send + Sync>>;
+        pub fn add_template_transactions(&self, transactions: Vec<Transaction>);
+        pub fn get_template_transactions(&self, siphash_keys: (u64, u64), short_ids: &[ShortId]) -> Vec<Option<Transaction>>;
+        pub fn add_share_header(&self, header: ShareHeader) -> Result<(), Box<dyn Error + Send + Sync>>;
+        pub fn get_best_header_tip(&self) -> BlockHash;
+        pub fn get_blocks_to_download(&self, limit: usize) -> Vec<BlockHash>;
         pub fn get_tip_height(&self) -> Result<Option<u32>, Box<dyn Error + Send + Sync>>;
+        pub fn get_total_work(&self) -> Result<Work, Box<dyn Error + Send + Sync>>;
+        pub fn get_best_header_work(&self) -> Result<Work, Box<dyn Error + Send + Sync>>;
         pub fn add_job(&self, serialized_notify: String) -> Result<(), Box<dyn Error + Send + Sync>>;
         pub fn get_jobs(&self, start_time: Option<u64>, end_time: Option<u64>, limit: usize) -> Result<Vec<(u64, String)>, Box<dyn Error + Send + Sync>>;
         pub fn add_user(&self, btcaddress: String) -> Result<u64, Box<dyn Error + Send + Sync>>;
         pub fn get_current_target(&self) -> Result<u32, Box<dyn Error + Send + Sync>>;
+        pub fn get_target_at(&self, blockhash: &BlockHash) -> Result<u32, Box<dyn Error + Send + Sync>>;
+        pub fn get_ancestor_headers(&self, blockhash: &BlockHash, count: usize) -> Vec<ShareHeader>;
     }
 
 
@@ -1190,12 +1515,176 @@ mod chain_tests {
 
         chain.add_share(share1.clone(), true).unwrap();
 
-        // Get current target should now return share1's target (the new tip)
+        // Get current target should now be retargeted from genesis and share1
         let new_target = chain.get_current_target().unwrap();
-        assert_eq!(new_target, share1.header.bits.to_consensus());
+        assert_eq!(
+            new_target,
+            retarget::next_target(&[genesis.header.clone(), share1.header.clone()]).to_consensus()
+        );
 
         // Verify that chain tip has changed
         assert_eq!(chain.store.get_chain_tip(), share1.block_hash());
+
+        // Target at genesis is still the genesis target
+        assert_eq!(
+            chain.get_target_at(&genesis.block_hash()).unwrap(),
+            genesis.header.bits.to_consensus()
+        );
+
+        // Unknown shares have no target
+        assert!(chain.get_target_at(&BlockHash::all_zeros()).is_err());
+    }
+
+    /// Mine shares on the chain at the current target with a simulated
+    /// hashrate, returning the seconds taken for each share.
+    fn simulate_hashrate(
+        chain: &ChainStore,
+        hashrate: u128,
+        shares: usize,
+        time: &mut u32,
+    ) -> Vec<u32> {
+        let mut intervals = Vec::with_capacity(shares);
+        for _ in 0..shares {
+            let tip = chain.store.get_chain_tip();
+            let bits = chain.get_current_target().unwrap();
+
+            // Check the per share clamp against the parent's work
+            let parent_work =
+                retarget::work_to_u128(chain.get_share_headers(&[tip]).unwrap()[0].get_work());
+            let work = retarget::work_to_u128(
+                bitcoin::Target::from_compact(bitcoin::CompactTarget::from_consensus(bits))
+                    .to_work(),
+            );
+            assert!(work <= parent_work + parent_work / 10 + parent_work / 1000 + 1);
+            assert!(work + parent_work / 11 + parent_work / 1000 + 1 >= parent_work);
+
+            let interval = (work / hashrate).max(1) as u32;
+            *time += interval;
+            intervals.push(interval);
+
+            let mut share = TestShareBlockBuilder::new()
+                .prev_share_blockhash(tip.to_string())
+                .build();
+            share.header.bits = bitcoin::CompactTarget::from_consensus(bits);
+            share.header.time = *time;
+            share.header.bitcoin_header.time = *time;
+            chain.add_share(share, true).unwrap();
+        }
+        intervals
+    }
+
+    fn average_interval(intervals: &[u32]) -> f64 {
+        let window = &intervals[intervals.len() - retarget::RETARGET_WINDOW..];
+        window.iter().sum::<u32>() as f64 / window.len() as f64
+    }
+
+    #[test]
+    fn test_retarget_follows_hashrate() {
+        let temp_dir = tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+        let genesis = genesis_for_tests();
+        let mut time = genesis.header.bitcoin_header.time;
+        // Start with shares found every 20 seconds at the genesis target
+        let hashrate = retarget::work_to_u128(genesis.header.get_work()) / 20;
+        let chain = ChainStore::new(Arc::new(store), genesis, bitcoin::Network::Signet);
+
+        let spacing = retarget::TARGET_SHARE_SPACING_SECS as f64;
+
+        // Target gets easier till shares are found every 10 seconds
+        let intervals = simulate_hashrate(&chain, hashrate, 100, &mut time);
+        assert!(intervals[0] >= 19);
+        let average = average_interval(&intervals);
+        assert!((average - spacing).abs() < 2.0, "average {average}");
+
+        // Hashrate jumps up, shares are found faster till the target catches up
+        let intervals = simulate_hashrate(&chain, hashrate * 4, 100, &mut time);
+        assert!(intervals[0] < 5);
+        let average = average_interval(&intervals);
+        assert!((average - spacing).abs() < 2.0, "average {average}");
+
+        // Hashrate drops, shares are found slower till the target catches up
+        let intervals = simulate_hashrate(&chain, hashrate, 100, &mut time);
+        assert!(intervals[0] > 20);
+        let average = average_interval(&intervals);
+        assert!((average - spacing).abs() < 2.0, "average {average}");
+    }
+
+    #[test]
+    fn test_add_share_header_and_get_blocks_to_download() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+        let genesis = genesis_for_tests();
+
+        let chain = ChainStore::new(Arc::new(store), genesis.clone(), bitcoin::Network::Signet);
+        assert_eq!(chain.get_best_header_tip(), genesis.block_hash());
+
+        let share1 = TestShareBlockBuilder::new()
+            .prev_share_blockhash(genesis.block_hash().to_string())
+            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+            .work(2)
+            .build();
+        let share2 = TestShareBlockBuilder::new()
+            .prev_share_blockhash(share1.block_hash().to_string())
+            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+            .work(2)
+            .build();
+
+        chain.add_share_header(share1.header.clone()).unwrap();
+        chain.add_share_header(share2.header.clone()).unwrap();
+        // Adding a known header is a no-op
+        chain.add_share_header(share2.header.clone()).unwrap();
+
+        assert_eq!(chain.get_best_header_tip(), share2.block_hash());
+        assert_eq!(chain.store.get_chain_tip(), genesis.block_hash());
+        assert_eq!(
+            chain.get_blocks_to_download(10),
+            vec![share1.block_hash(), share2.block_hash()]
+        );
+        assert_eq!(chain.get_blocks_to_download(1), vec![share1.block_hash()]);
+
+        // Header only shares are still missing share blocks
+        assert_eq!(
+            chain.get_missing_blockhashes(&[share1.block_hash()]),
+            vec![share1.block_hash()]
+        );
+        let metadata = chain
+            .store
+            .get_block_metadata(&share2.block_hash())
+            .unwrap();
+        assert_eq!(metadata.height, Some(2));
+        assert_eq!(metadata.status, BlockStatus::HeaderOnly);
+
+        chain.add_share(share1.clone(), true).unwrap();
+        assert_eq!(chain.get_blocks_to_download(10), vec![share2.block_hash()]);
+        let metadata = chain
+            .store
+            .get_block_metadata(&share1.block_hash())
+            .unwrap();
+        assert_eq!(metadata.status, BlockStatus::Complete);
+
+        chain.add_share(share2.clone(), true).unwrap();
+        assert_eq!(chain.get_best_header_tip(), share2.block_hash());
+        assert!(chain.get_blocks_to_download(10).is_empty());
+    }
+
+    #[test]
+    fn test_add_share_header_with_unknown_parent() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+        let genesis = genesis_for_tests();
+
+        let chain = ChainStore::new(Arc::new(store), genesis.clone(), bitcoin::Network::Signet);
+
+        let orphan = TestShareBlockBuilder::new()
+            .prev_share_blockhash(
+                "0000000000000000000000000000000000000000000000000000000000000001".into(),
+            )
+            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+            .build();
+
+        assert!(chain.add_share_header(orphan.header.clone()).is_err());
+        assert_eq!(chain.get_best_header_tip(), genesis.block_hash());
+        assert!(chain.store.get_share_header(&orphan.block_hash()).is_none());
     }
 
     #[test]
@@ -0,0 +1,172 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::shares::share_block::ShareHeader;
+use bitcoin::{CompactTarget, Target, Work};
+
+/// Target seconds between shares, 6 shares per minute as assumed for the PPLNS window
+pub const TARGET_SHARE_SPACING_SECS: u64 = 10;
+
+/// Number of shares on the chain used to estimate the hashrate
+pub const RETARGET_WINDOW: usize = 60;
+
+/// Easiest share target allowed, same as the genesis share target
+pub const MAX_SHARE_TARGET: u32 = 0x207fffff;
+
+/// Work for a share can change by at most 1/MAX_STEP_DIVISOR of the
+/// parent's work at each share.
+const MAX_STEP_DIVISOR: u128 = 10;
+
+/// Compute the target for a share built on top of the last share in
+/// headers.
+///
+/// headers are the last shares on the chain, oldest first. The
+/// hashrate is estimated from the work done after the first share and
+/// the time taken as per the bitcoin header timestamps, and the target
+/// is set so that the hashrate finds a share every
+/// TARGET_SHARE_SPACING_SECS. The change in work from
+/// the parent is clamped to a tenth per share, so the target cannot
+/// swing wildly due to a few shares with bad timestamps.
+///
+/// Only integer arithmetic is used, so all nodes compute the same
+/// target. Returns the parent target if there are not enough shares.
+pub fn next_target(headers: &[ShareHeader]) -> CompactTarget {
+    let parent = match headers.last() {
+        Some(parent) => parent,
+        None => return CompactTarget::from_consensus(MAX_SHARE_TARGET),
+    };
+    if headers.len() < 2 {
+        return parent.bits;
+    }
+
+    // Use the bitcoin header time, which is checked against the node's
+    // clock when the share is received. The share header time is set by
+    // the miner and not validated. Shares can have timestamps earlier
+    // than their parents, use at least a second.
+    let timespan = (parent.bitcoin_header.time as u64)
+        .saturating_sub(headers[0].bitcoin_header.time as u64)
+        .max(1) as u128;
+    let window_work = headers[1..]
+        .iter()
+        .map(|header| work_to_u128(header.get_work()))
+        .fold(0u128, u128::saturating_add);
+
+    let estimated = window_work.saturating_mul(TARGET_SHARE_SPACING_SECS as u128) / timespan;
+
+    let parent_work = work_to_u128(parent.get_work());
+    // Always allow a change of at least one, so easy targets can move
+    let max_work = parent_work.saturating_add((parent_work / MAX_STEP_DIVISOR).max(1));
+    let min_work = parent_work.saturating_sub((parent_work / (MAX_STEP_DIVISOR + 1)).max(1));
+    let next_work = estimated.clamp(min_work, max_work).max(1);
+
+    let target = u128_to_work(next_work).to_target();
+    let max_target = Target::from_compact(CompactTarget::from_consensus(MAX_SHARE_TARGET));
+    if target > max_target {
+        return max_target.to_compact_lossy();
+    }
+    target.to_compact_lossy()
+}
+
+/// Work as u128, saturating for work beyond any realistic hashrate
+pub(crate) fn work_to_u128(work: Work) -> u128 {
+    let bytes = work.to_be_bytes();
+    if bytes[..16].iter().any(|byte| *byte != 0) {
+        return u128::MAX;
+    }
+    u128::from_be_bytes(bytes[16..].try_into().expect("16 byte slice"))
+}
+
+fn u128_to_work(work: u128) -> Work {
+    let mut bytes = [0u8; 32];
+    bytes[16..].copy_from_slice(&work.to_be_bytes());
+    Work::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestShareBlockBuilder;
+
+    /// Build a window of headers with the given bits, spaced interval seconds apart
+    fn headers_with_spacing(bits: u32, interval: u32, count: usize) -> Vec<ShareHeader> {
+        (0..count)
+            .map(|i| {
+                let mut header = TestShareBlockBuilder::new().build().header;
+                header.bits = CompactTarget::from_consensus(bits);
+                header.bitcoin_header.time = 1_700_000_000 + i as u32 * interval;
+                header
+            })
+            .collect()
+    }
+
+    fn work_of(bits: CompactTarget) -> u128 {
+        work_to_u128(Target::from_compact(bits).to_work())
+    }
+
+    #[test]
+    fn test_next_target_without_enough_shares() {
+        assert_eq!(next_target(&[]).to_consensus(), MAX_SHARE_TARGET);
+
+        let headers = headers_with_spacing(0x1e0fffff, 10, 1);
+        assert_eq!(next_target(&headers).to_consensus(), 0x1e0fffff);
+    }
+
+    #[test]
+    fn test_next_target_on_schedule_is_unchanged() {
+        let headers = headers_with_spacing(0x1d00ffff, TARGET_SHARE_SPACING_SECS as u32, 20);
+        let next = next_target(&headers);
+        let parent_work = work_of(headers[0].bits);
+        let next_work = work_of(next);
+        // Only compact rounding differences
+        assert!(next_work.abs_diff(parent_work) <= parent_work / 1000);
+    }
+
+    #[test]
+    fn test_next_target_is_clamped() {
+        let bits = 0x1d00ffff;
+        let parent_work = work_of(CompactTarget::from_consensus(bits));
+
+        // Shares found too fast, work increases by at most a tenth
+        let fast = next_target(&headers_with_spacing(bits, 1, 20));
+        let fast_work = work_of(fast);
+        assert!(fast_work > parent_work);
+        assert!(fast_work <= parent_work + parent_work / 10 + parent_work / 1000);
+
+        // Shares found too slow, work decreases by at most a tenth
+        let slow = next_target(&headers_with_spacing(bits, 100, 20));
+        let slow_work = work_of(slow);
+        assert!(slow_work < parent_work);
+        assert!(slow_work >= parent_work - parent_work / 11 - parent_work / 1000);
+    }
+
+    #[test]
+    fn test_next_target_never_easier_than_max_target() {
+        let headers = headers_with_spacing(MAX_SHARE_TARGET, 1000, 20);
+        assert_eq!(next_target(&headers).to_consensus(), MAX_SHARE_TARGET);
+    }
+
+    #[test]
+    fn test_next_target_ignores_share_header_time() {
+        let mut headers = headers_with_spacing(0x1d00ffff, TARGET_SHARE_SPACING_SECS as u32, 20);
+        let expected = next_target(&headers);
+
+        // A miner claiming shares took longer can't make the target easier
+        for (i, header) in headers.iter_mut().enumerate() {
+            header.time = 1_700_000_000 + i as u32 * 100;
+        }
+        assert_eq!(next_target(&headers), expected);
+    }
+}
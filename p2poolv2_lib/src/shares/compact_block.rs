@@ -14,8 +14,13 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::shares::share_block::short_ids::ShortIds;
+use crate::shares::share_block::{ShareBlock, ShareHeader};
+use bitcoin::bip152::ShortId;
+use bitcoin::consensus::{Decodable, Encodable};
 use bitcoin::secp256k1::rand::{self, RngCore};
 use bitcoin::{Block, bip152::HeaderAndShortIds, p2p::message_compact_blocks::CmpctBlock};
+use bitcoin::{BlockHash, Transaction};
 use tokio::sync::mpsc;
 use tracing::warn;
 
@@ -58,6 +63,192 @@ pub async fn send_share_compact_block(
     }
 }
 
+/// Errors building or reconstructing a compact share block
+#[derive(Debug, thiserror::Error)]
+pub enum CompactBlockError {
+    #[error("Failed to build compact share block: {0}")]
+    Build(#[from] bitcoin::bip152::Error),
+    #[error("Prefilled transaction index {0} is out of range")]
+    PrefilledIndexOutOfRange(usize),
+    #[error("Expected {expected} missing transactions, received {received}")]
+    UnexpectedTransactionCount { expected: usize, received: usize },
+    #[error("Compact share block is missing {0} transactions")]
+    MissingTransactions(usize),
+}
+
+/// A share block relayed with short ids in place of the bitcoin
+/// transactions, BIP152 style.
+///
+/// Share chain transactions are sent in full. The bitcoin coinbase
+/// is always prefilled, the remaining bitcoin transactions are sent
+/// as short ids and are expected to be in the receiver's mempool.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CompactShareBlock {
+    /// Header for the share block
+    pub header: ShareHeader,
+    /// Short ids and prefilled transactions for the bitcoin transactions
+    pub short_ids: ShortIds,
+    /// Share chain transactions
+    pub transactions: Vec<Transaction>,
+}
+
+impl CompactShareBlock {
+    /// Build a compact share block using a random nonce for the short ids
+    pub fn from_share_block(share_block: &ShareBlock) -> Result<Self, CompactBlockError> {
+        Self::from_share_block_with_nonce(share_block, rand::thread_rng().next_u64())
+    }
+
+    /// Build a compact share block using the given nonce for the short ids
+    pub fn from_share_block_with_nonce(
+        share_block: &ShareBlock,
+        nonce: u64,
+    ) -> Result<Self, CompactBlockError> {
+        let block = Block {
+            header: share_block.header.bitcoin_header,
+            txdata: share_block.bitcoin_transactions.clone(),
+        };
+        let header_and_short_ids =
+            HeaderAndShortIds::from_block(&block, nonce, COMPACT_BLOCK_VERSION, &[])?;
+        Ok(Self {
+            header: share_block.header.clone(),
+            short_ids: ShortIds {
+                nonce: header_and_short_ids.nonce,
+                short_ids: header_and_short_ids.short_ids,
+                prefilled_txs: header_and_short_ids.prefilled_txs,
+            },
+            transactions: share_block.transactions.clone(),
+        })
+    }
+
+    /// Compute and return the block hash for this share block
+    pub fn block_hash(&self) -> BlockHash {
+        self.header.block_hash()
+    }
+
+    /// The siphash keys used to compute the short ids
+    pub fn siphash_keys(&self) -> (u64, u64) {
+        ShortId::calculate_siphash_keys(&self.header.bitcoin_header, self.short_ids.nonce)
+    }
+}
+
+impl Encodable for CompactShareBlock {
+    fn consensus_encode<W: bitcoin::io::Write + ?Sized>(
+        &self,
+        w: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        let mut len = 0;
+        len += self.header.consensus_encode(w)?;
+        len += self.short_ids.consensus_encode(w)?;
+        len += self.transactions.consensus_encode(w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for CompactShareBlock {
+    fn consensus_decode<R: bitcoin::io::Read + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, bitcoin::consensus::encode::Error> {
+        Ok(CompactShareBlock {
+            header: ShareHeader::consensus_decode(r)?,
+            short_ids: ShortIds::consensus_decode(r)?,
+            transactions: Vec::<Transaction>::consensus_decode(r)?,
+        })
+    }
+}
+
+/// A compact share block being reconstructed.
+///
+/// Bitcoin transactions are filled in from the prefilled
+/// transactions and the local mempool view. Any transactions still
+/// missing are requested from the peer by their index, and filled in
+/// when the peer responds.
+#[derive(Clone, Debug)]
+pub struct PartialShareBlock {
+    compact: CompactShareBlock,
+    bitcoin_transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialShareBlock {
+    /// Place the prefilled transactions and fill the rest using lookup.
+    ///
+    /// lookup receives the siphash keys and the short ids, and returns
+    /// the matching transaction, if any, for each short id.
+    pub fn new<F>(compact: CompactShareBlock, lookup: F) -> Result<Self, CompactBlockError>
+    where
+        F: FnOnce((u64, u64), &[ShortId]) -> Vec<Option<Transaction>>,
+    {
+        let total = compact.short_ids.short_ids.len() + compact.short_ids.prefilled_txs.len();
+        let mut bitcoin_transactions: Vec<Option<Transaction>> = vec![None; total];
+
+        // Prefilled indexes are differentially encoded
+        let mut next_index = 0;
+        for prefilled in compact.short_ids.prefilled_txs.iter() {
+            let index = next_index + prefilled.idx as usize;
+            if index >= total {
+                return Err(CompactBlockError::PrefilledIndexOutOfRange(index));
+            }
+            bitcoin_transactions[index] = Some(prefilled.tx.clone());
+            next_index = index + 1;
+        }
+
+        let mut found = lookup(compact.siphash_keys(), &compact.short_ids.short_ids).into_iter();
+        for slot in bitcoin_transactions.iter_mut().filter(|tx| tx.is_none()) {
+            *slot = found.next().flatten();
+        }
+
+        Ok(Self {
+            compact,
+            bitcoin_transactions,
+        })
+    }
+
+    pub fn block_hash(&self) -> BlockHash {
+        self.compact.block_hash()
+    }
+
+    /// Indexes of the bitcoin transactions still missing
+    pub fn missing_indexes(&self) -> Vec<u64> {
+        self.bitcoin_transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u64)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.bitcoin_transactions.iter().all(Option::is_some)
+    }
+
+    /// Fill the missing transactions, in the order of missing_indexes
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> Result<(), CompactBlockError> {
+        let missing = self.missing_indexes();
+        if missing.len() != transactions.len() {
+            return Err(CompactBlockError::UnexpectedTransactionCount {
+                expected: missing.len(),
+                received: transactions.len(),
+            });
+        }
+        for (index, tx) in missing.into_iter().zip(transactions) {
+            self.bitcoin_transactions[index as usize] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// Return the reconstructed share block, errors if transactions are still missing
+    pub fn into_share_block(self) -> Result<ShareBlock, CompactBlockError> {
+        let missing = self.missing_indexes().len();
+        if missing > 0 {
+            return Err(CompactBlockError::MissingTransactions(missing));
+        }
+        Ok(ShareBlock {
+            header: self.compact.header,
+            transactions: self.compact.transactions,
+            bitcoin_transactions: self.bitcoin_transactions.into_iter().flatten().collect(),
+        })
+    }
+}
+
 #[cfg(test)]
 mod send_share_block_tests {
 
@@ -102,3 +293,107 @@ mod send_share_block_tests {
         assert!(received_block.compact_block.header == block.header);
     }
 }
+
+#[cfg(test)]
+mod compact_share_block_tests {
+    use super::*;
+    use crate::shares::template_mempool::TemplateMempool;
+    use crate::test_utils::{TestShareBlockBuilder, test_coinbase_transaction};
+    use bitcoin::absolute::LockTime;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, TxIn, TxOut, Witness};
+
+    fn test_tx(value: u64) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    fn share_block_with_bitcoin_transactions() -> ShareBlock {
+        let mut share_block = TestShareBlockBuilder::new().build();
+        share_block.bitcoin_transactions = vec![
+            test_coinbase_transaction(),
+            test_tx(1),
+            test_tx(2),
+            test_tx(3),
+        ];
+        share_block
+    }
+
+    #[test]
+    fn test_compact_share_block_roundtrip() {
+        let share_block = share_block_with_bitcoin_transactions();
+        let compact = CompactShareBlock::from_share_block(&share_block).unwrap();
+
+        assert_eq!(compact.block_hash(), share_block.block_hash());
+        assert_eq!(compact.short_ids.short_ids.len(), 3);
+        assert_eq!(compact.short_ids.prefilled_txs.len(), 1);
+
+        let decoded: CompactShareBlock = deserialize(&serialize(&compact)).unwrap();
+        assert_eq!(decoded, compact);
+    }
+
+    #[test]
+    fn test_reconstruct_from_mempool() {
+        let share_block = share_block_with_bitcoin_transactions();
+        let compact = CompactShareBlock::from_share_block(&share_block).unwrap();
+
+        let mut mempool = TemplateMempool::default();
+        mempool.add_template_transactions(share_block.bitcoin_transactions[1..].to_vec());
+
+        let partial = PartialShareBlock::new(compact, |keys, short_ids| {
+            mempool.get_by_short_ids(keys, short_ids)
+        })
+        .unwrap();
+        assert!(partial.is_complete());
+        assert_eq!(partial.into_share_block().unwrap(), share_block);
+    }
+
+    #[test]
+    fn test_reconstruct_with_missing_transactions() {
+        let share_block = share_block_with_bitcoin_transactions();
+        let compact = CompactShareBlock::from_share_block(&share_block).unwrap();
+
+        // Mempool only has the second transaction
+        let mut mempool = TemplateMempool::default();
+        mempool.add_template_transactions(vec![share_block.bitcoin_transactions[2].clone()]);
+
+        let mut partial = PartialShareBlock::new(compact, |keys, short_ids| {
+            mempool.get_by_short_ids(keys, short_ids)
+        })
+        .unwrap();
+        assert_eq!(partial.missing_indexes(), vec![1, 3]);
+        assert!(matches!(
+            partial.clone().into_share_block(),
+            Err(CompactBlockError::MissingTransactions(2))
+        ));
+
+        assert!(matches!(
+            partial.fill(vec![share_block.bitcoin_transactions[1].clone()]),
+            Err(CompactBlockError::UnexpectedTransactionCount {
+                expected: 2,
+                received: 1
+            })
+        ));
+
+        partial
+            .fill(vec![
+                share_block.bitcoin_transactions[1].clone(),
+                share_block.bitcoin_transactions[3].clone(),
+            ])
+            .unwrap();
+        assert_eq!(partial.into_share_block().unwrap(), share_block);
+    }
+}
@@ -14,36 +14,230 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::accounting::simple_pplns::share_chain_payout;
+use crate::accounting::stats::metrics::MetricsHandle;
+use crate::node::SwarmSend;
 #[cfg(test)]
 #[mockall_double::double]
 use crate::shares::chain::chain_store::ChainStore;
 #[cfg(not(test))]
 use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::share_block::{ShareBlock, ShareHeader};
+use crate::shares::share_commitment::ShareCommitment;
+use crate::shares::validation::validate_pow;
 use crate::stratum::emission::EmissionReceiver;
-use crate::{accounting::stats::metrics::MetricsHandle, shares::share_block::ShareHeader};
+use bitcoin::Block;
+use std::error::Error;
 use std::sync::Arc;
-use tracing::info;
+use tokio::sync::mpsc;
+use tracing::{debug, error, info};
 
 /// Save share to database for persistence in case we need to recover from a crash
 /// Shares are saved with a TTL for 1 week or when we reach accumulated work required for 5 blocks at current difficulty.
-pub async fn handle_stratum_shares(
+///
+/// Shares that carry a share commitment and meet the share chain
+/// target are added to the share chain and announced to peers.
+pub async fn handle_stratum_shares<C: 'static>(
     mut emissions_rx: EmissionReceiver,
     store: Arc<ChainStore>,
     _metrics: MetricsHandle,
+    swarm_tx: mpsc::Sender<SwarmSend<C>>,
 ) {
     while let Some(emission) = emissions_rx.recv().await {
         info!("Received share: {:?}", emission.pplns);
 
         let _ = store.add_pplns_share(emission.pplns);
-        // TODO: Send block as Message::ShareBlock to peers. It should include the block as compact block.
-        if emission.share_commitment.is_none() {
-            info!("No share commitment emitted by stratum. Won't send share to peers");
-            return;
+        if let Err(e) = send_share_block(
+            emission.block,
+            emission.share_commitment,
+            store.clone(),
+            swarm_tx.clone(),
+        )
+        .await
+        {
+            error!("Failed to send share block to peers: {}", e);
         }
-        let share_header = ShareHeader::from_commitment_and_header(
-            emission.share_commitment.unwrap(),
-            emission.block.header,
-        );
     }
     info!("Shares channel closed, stopping share handler.");
 }
+
+/// Build a share block from the emitted block and commitment, add it to the share chain
+/// and send an inventory for it to peers.
+///
+/// Returns Ok without sending anything if there is no share
+/// commitment or the share does not meet the share chain target.
+async fn send_share_block<C: 'static>(
+    block: Block,
+    share_commitment: Option<ShareCommitment>,
+    store: Arc<ChainStore>,
+    swarm_tx: mpsc::Sender<SwarmSend<C>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let commitment = match share_commitment {
+        Some(commitment) => commitment,
+        None => {
+            info!("No share commitment emitted by stratum. Won't send share to peers");
+            return Ok(());
+        }
+    };
+    let share_block = ShareBlock {
+        header: ShareHeader::from_commitment_and_header(commitment, block.header),
+        transactions: vec![],
+        bitcoin_transactions: block.txdata,
+    };
+    if validate_pow(&share_block).is_err() {
+        debug!("Share does not meet share chain target. Won't send share to peers");
+        return Ok(());
+    }
+    // Share we found, always goes to main chain
+    store
+        .add_share(share_block.clone(), true)
+        .map_err(|e| format!("Error adding share to chain: {e}"))?;
+    if let Err(e) = share_chain_payout::store_balances(&store, &share_block) {
+        error!("Failed to store ledger balances for share: {}", e);
+    }
+    swarm_tx
+        .send(SwarmSend::Inv(share_block))
+        .await
+        .map_err(|e| format!("Failed to send INV message to swarm: {e}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounting::simple_pplns::SimplePplnsShare;
+    use crate::accounting::stats::metrics;
+    use crate::stratum::emission::Emission;
+    use crate::test_utils::build_block_from_work_components;
+
+    fn build_emission(share_commitment: Option<ShareCommitment>) -> (Emission, ShareBlock) {
+        let share_block =
+            build_block_from_work_components("../tests/test_data/validation/stratum/a/");
+        let emission = Emission {
+            pplns: SimplePplnsShare::new(
+                1,
+                100,
+                "addr1".to_string(),
+                "worker1".to_string(),
+                1500,
+                "job1".to_string(),
+                "extra1".to_string(),
+                "nonce1".to_string(),
+            ),
+            block: Block {
+                header: share_block.header.bitcoin_header,
+                txdata: share_block.bitcoin_transactions.clone(),
+            },
+            share_commitment,
+        };
+        (emission, share_block)
+    }
+
+    async fn test_metrics() -> (MetricsHandle, tempfile::TempDir) {
+        let stats_dir = tempfile::tempdir().unwrap();
+        let metrics_handle = metrics::start_metrics(stats_dir.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        (metrics_handle, stats_dir)
+    }
+
+    #[tokio::test]
+    async fn test_handle_stratum_shares_adds_and_announces_share() {
+        let (emission, share_block) = build_emission(None);
+        let commitment = ShareCommitment::from(&share_block.header);
+        let emission = Emission {
+            share_commitment: Some(commitment),
+            ..emission
+        };
+        let expected_blockhash = share_block.block_hash();
+
+        let mut store = ChainStore::default();
+        store
+            .expect_add_pplns_share()
+            .times(1)
+            .returning(|_| Ok(()));
+        store
+            .expect_add_share()
+            .withf(move |share, confirm_txs| {
+                share.block_hash() == expected_blockhash && *confirm_txs
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        store.expect_put_share_balances().returning(|_, _| Ok(()));
+
+        let (emissions_tx, emissions_rx) = mpsc::channel(10);
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(10);
+        let (metrics_handle, _stats_dir) = test_metrics().await;
+
+        emissions_tx.send(emission).await.unwrap();
+        drop(emissions_tx);
+
+        handle_stratum_shares(emissions_rx, Arc::new(store), metrics_handle, swarm_tx).await;
+
+        match swarm_rx.recv().await {
+            Some(SwarmSend::Inv(sent)) => {
+                assert_eq!(sent.block_hash(), expected_blockhash);
+                assert_eq!(sent.bitcoin_transactions, share_block.bitcoin_transactions);
+            }
+            _ => panic!("Expected SwarmSend::Inv message"),
+        }
+        assert!(swarm_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handle_stratum_shares_continues_without_commitment() {
+        let (emission_without_commitment, share_block) = build_emission(None);
+        let (emission, _) = build_emission(Some(ShareCommitment::from(&share_block.header)));
+
+        let mut store = ChainStore::default();
+        store
+            .expect_add_pplns_share()
+            .times(2)
+            .returning(|_| Ok(()));
+        store.expect_add_share().times(1).returning(|_, _| Ok(()));
+        store.expect_put_share_balances().returning(|_, _| Ok(()));
+
+        let (emissions_tx, emissions_rx) = mpsc::channel(10);
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(10);
+        let (metrics_handle, _stats_dir) = test_metrics().await;
+
+        emissions_tx
+            .send(emission_without_commitment)
+            .await
+            .unwrap();
+        emissions_tx.send(emission).await.unwrap();
+        drop(emissions_tx);
+
+        handle_stratum_shares(emissions_rx, Arc::new(store), metrics_handle, swarm_tx).await;
+
+        assert!(matches!(swarm_rx.recv().await, Some(SwarmSend::Inv(_))));
+        assert!(swarm_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handle_stratum_shares_skips_share_below_share_target() {
+        let (_, share_block) = build_emission(None);
+        let mut commitment = ShareCommitment::from(&share_block.header);
+        // Hardest possible target is not met by the test header
+        commitment.bits = bitcoin::CompactTarget::from_consensus(0x03000001);
+        let (emission, _) = build_emission(Some(commitment));
+
+        let mut store = ChainStore::default();
+        store
+            .expect_add_pplns_share()
+            .times(1)
+            .returning(|_| Ok(()));
+        store.expect_add_share().never();
+
+        let (emissions_tx, emissions_rx) = mpsc::channel(10);
+        let (swarm_tx, mut swarm_rx) = mpsc::channel::<SwarmSend<u32>>(10);
+        let (metrics_handle, _stats_dir) = test_metrics().await;
+
+        emissions_tx.send(emission).await.unwrap();
+        drop(emissions_tx);
+
+        handle_stratum_shares(emissions_rx, Arc::new(store), metrics_handle, swarm_tx).await;
+
+        assert!(swarm_rx.recv().await.is_none());
+    }
+}
@@ -15,11 +15,12 @@
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
 pub mod chain;
-pub(crate) mod compact_block;
+pub mod compact_block;
 pub mod genesis;
 pub mod handle_mining_message;
 pub mod handle_stratum_shares;
 pub mod share_block;
 pub mod share_commitment;
+pub mod template_mempool;
 pub mod transactions;
 pub mod validation;
@@ -19,7 +19,7 @@ pub mod storage_share_block;
 
 use super::transactions;
 use crate::shares::genesis;
-use crate::shares::share_commitment::ShareCommitment;
+use crate::shares::share_commitment::{CoinbaseCut, ShareCommitment, decode_cut, encode_cut};
 use bitcoin::{
     Block, BlockHash, CompactTarget, CompressedPublicKey, Target, Transaction, TxMerkleNode, Txid,
     VarInt,
@@ -36,8 +36,7 @@ pub use storage_share_block::StorageShareBlock;
 ///
 /// Exludes bitcoin compact block and share chain transactions.
 /// Includes the bitcoin block hash for the bitcoin compact block instead.
-///
-/// TODO(pool2win): Add the donation and fee details used to build the coinbase.
+/// Includes the donation and fee cuts used to build the coinbase.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct ShareHeader {
     /// The hash of the prev share block, will be None for genesis block
@@ -54,6 +53,10 @@ pub struct ShareHeader {
     pub bits: CompactTarget,
     /// Timestamp for the share, as set by the miner
     pub time: u32,
+    /// Donation cut of the coinbase, if any
+    pub donation: Option<CoinbaseCut>,
+    /// Pool fee cut of the coinbase, if any
+    pub fee: Option<CoinbaseCut>,
 }
 
 impl ShareHeader {
@@ -79,9 +82,16 @@ impl ShareHeader {
             bitcoin_header,
             bits: commitment.bits,
             time: commitment.time,
+            donation: commitment.donation,
+            fee: commitment.fee,
         }
     }
 
+    /// Coinbase cuts in the order they are taken, donation first
+    pub fn coinbase_cuts(&self) -> Vec<&CoinbaseCut> {
+        self.donation.iter().chain(self.fee.iter()).collect()
+    }
+
     /// Block hash for the share header
     pub fn block_hash(&self) -> BlockHash {
         let mut engine = BlockHash::engine();
@@ -109,6 +119,8 @@ impl ShareHeader {
             .consensus_encode(&mut serialized_without_bitcoin_header)?;
         self.time
             .consensus_encode(&mut serialized_without_bitcoin_header)?;
+        encode_cut(&self.donation, &mut serialized_without_bitcoin_header)?;
+        encode_cut(&self.fee, &mut serialized_without_bitcoin_header)?;
 
         Ok(bitcoin::hashes::sha256::Hash::hash(
             &serialized_without_bitcoin_header,
@@ -127,10 +139,16 @@ impl Encodable for ShareHeader {
         len += self.uncles.consensus_encode(w)?;
         self.miner_pubkey.write_into(w)?;
         len += 33; // Compressedpublickey is 33 bytes
-        len += self.merkle_root.unwrap().consensus_encode(w)?;
+        // A share without share chain transactions encodes its merkle root as all zeros
+        len += self
+            .merkle_root
+            .unwrap_or_else(TxMerkleNode::all_zeros)
+            .consensus_encode(w)?;
         len += self.bitcoin_header.consensus_encode(w)?;
         len += self.bits.consensus_encode(w)?;
         len += self.time.consensus_encode(w)?;
+        len += encode_cut(&self.donation, w)?;
+        len += encode_cut(&self.fee, w)?;
         Ok(len)
     }
 }
@@ -144,10 +162,13 @@ impl Decodable for ShareHeader {
             prev_share_blockhash: BlockHash::consensus_decode(r)?,
             uncles: Vec::<BlockHash>::consensus_decode(r)?,
             miner_pubkey: CompressedPublicKey::read_from(r)?,
-            merkle_root: Some(TxMerkleNode::consensus_decode(r)?),
+            merkle_root: Some(TxMerkleNode::consensus_decode(r)?)
+                .filter(|root| *root != TxMerkleNode::all_zeros()),
             bitcoin_header: Header::consensus_decode(r)?,
             bits: CompactTarget::consensus_decode(r)?,
             time: u32::consensus_decode(r)?,
+            donation: decode_cut(r)?,
+            fee: decode_cut(r)?,
         })
     }
 }
@@ -186,6 +207,8 @@ impl ShareBlock {
             bitcoin_header: block.header,
             time: 1700000000u32,
             bits: CompactTarget::from_consensus(0x207fffff),
+            donation: None,
+            fee: None,
         };
         Self {
             header,
@@ -225,6 +248,8 @@ impl ShareBlock {
             merkle_root: Some(merkle_root),
             time: 1700000000u32,
             bits: CompactTarget::from_consensus(0x207fffff),
+            donation: None,
+            fee: None,
         };
         Ok(Self {
             header,
@@ -286,6 +311,8 @@ impl ShareBlock {
             merkle_root: Some(merkle_root),
             time: 1700000000u32,
             bits: CompactTarget::from_consensus(0x207fffff),
+            donation: None,
+            fee: None,
         };
         Self {
             header,
@@ -517,6 +544,8 @@ mod tests {
             merkle_root: None,
             bits: CompactTarget::from_consensus(0x207fffff),
             time: 1700000000,
+            donation: None,
+            fee: None,
         };
 
         let cloned = commitment.clone();
@@ -533,4 +562,16 @@ mod tests {
         let hashed = cloned.hash();
         assert_ne!(hashed, bitcoin::hashes::sha256::Hash::all_zeros());
     }
+
+    #[test]
+    fn test_share_header_without_merkle_root_roundtrip() {
+        let mut header = TestShareBlockBuilder::new().build().header;
+        header.merkle_root = None;
+
+        let serialized = bitcoin::consensus::serialize(&header);
+        let deserialized: ShareHeader = bitcoin::consensus::deserialize(&serialized).unwrap();
+
+        assert_eq!(deserialized.merkle_root, None);
+        assert_eq!(deserialized.block_hash(), header.block_hash());
+    }
 }
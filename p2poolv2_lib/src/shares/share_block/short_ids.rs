@@ -29,7 +29,7 @@ use bitcoin::consensus::{Decodable, Encodable};
 /// nonce, vector of shortids and a vector of prefilled transactions.
 /// In other words, this the same as HeaderAndShortIds, except that
 /// the Header is missing. The Header is instead in the ShareHeader.
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct ShortIds {
     ///  A nonce for use in short transaction ID calculations.
     pub nonce: u64,
@@ -14,21 +14,105 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::config::{Parsed, StratumConfig};
 #[cfg(test)]
 #[mockall_double::double]
 use crate::shares::chain::chain_store::ChainStore;
 #[cfg(not(test))]
 use crate::shares::chain::chain_store::ChainStore;
+use crate::shares::share_block::ShareHeader;
 use crate::stratum::work::block_template::BlockTemplate;
 use crate::utils::time_provider::{SystemTimeProvider, TimeProvider};
 use bitcoin::consensus::{Decodable, Encodable};
 use bitcoin::hashes::Hash;
 use bitcoin::io::{Read, Write};
-use bitcoin::{BlockHash, CompactTarget, CompressedPublicKey, TxMerkleNode, hashes};
-use serde::Serialize;
+use bitcoin::{
+    Address, Amount, BlockHash, CompactTarget, CompressedPublicKey, ScriptBuf, TxMerkleNode, hashes,
+};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::sync::Arc;
 
+/// Basis points making up the whole coinbase value
+pub const BASIS_POINTS: u16 = 10_000;
+
+/// A cut of the coinbase value paid to a script before the PPLNS
+/// distribution, i.e. the donation or pool fee set in the miner's
+/// stratum config.
+///
+/// Cuts are committed in the share so that peers can check the
+/// coinbase pays them and splits the rest between miners.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct CoinbaseCut {
+    /// Script the cut is paid to
+    pub script_pubkey: ScriptBuf,
+    /// Cut in basis points of the amount left after earlier cuts
+    pub basis_points: u16,
+}
+
+impl CoinbaseCut {
+    /// Build a cut from configured address and basis points. There is
+    /// no cut unless both are set and the basis points are not zero.
+    pub fn from_config(address: Option<&Address>, basis_points: Option<u16>) -> Option<Self> {
+        match (address, basis_points) {
+            (Some(address), Some(basis_points)) if basis_points > 0 => Some(Self {
+                script_pubkey: address.script_pubkey(),
+                basis_points,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Amount of the cut from total, rounded down. Cuts above
+    /// BASIS_POINTS take the whole amount.
+    pub fn amount(&self, total: Amount) -> Amount {
+        let basis_points = self.basis_points.min(BASIS_POINTS) as u128;
+        Amount::from_sat((total.to_sat() as u128 * basis_points / BASIS_POINTS as u128) as u64)
+    }
+}
+
+impl Encodable for CoinbaseCut {
+    fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, bitcoin::io::Error> {
+        let mut len = 0;
+        len += self.script_pubkey.consensus_encode(w)?;
+        len += self.basis_points.consensus_encode(w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for CoinbaseCut {
+    fn consensus_decode<R: Read + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, bitcoin::consensus::encode::Error> {
+        Ok(CoinbaseCut {
+            script_pubkey: ScriptBuf::consensus_decode(r)?,
+            basis_points: u16::consensus_decode(r)?,
+        })
+    }
+}
+
+/// Encode an optional cut with a flag for whether it is present
+pub(crate) fn encode_cut<W: Write + ?Sized>(
+    cut: &Option<CoinbaseCut>,
+    w: &mut W,
+) -> Result<usize, bitcoin::io::Error> {
+    match cut {
+        Some(cut) => Ok(true.consensus_encode(w)? + cut.consensus_encode(w)?),
+        None => false.consensus_encode(w),
+    }
+}
+
+/// Decode an optional cut encoded by [encode_cut]
+pub(crate) fn decode_cut<R: Read + ?Sized>(
+    r: &mut R,
+) -> Result<Option<CoinbaseCut>, bitcoin::consensus::encode::Error> {
+    if bool::consensus_decode(r)? {
+        Ok(Some(CoinbaseCut::consensus_decode(r)?))
+    } else {
+        Ok(None)
+    }
+}
+
 /// Share commitment created by miner and embedded in the bitcoin
 /// coinbase to tie the share to the bitcoin weak block
 ///
@@ -57,9 +141,18 @@ pub struct ShareCommitment {
     pub bits: CompactTarget,
     /// Timestamp for the share, as set by the miner
     pub time: u32,
+    /// Donation cut of the coinbase, if any
+    pub donation: Option<CoinbaseCut>,
+    /// Pool fee cut of the coinbase, if any
+    pub fee: Option<CoinbaseCut>,
 }
 
 impl ShareCommitment {
+    /// Coinbase cuts in the order they are taken, donation first
+    pub fn coinbase_cuts(&self) -> Vec<&CoinbaseCut> {
+        self.donation.iter().chain(self.fee.iter()).collect()
+    }
+
     /// Make a SHA256 hash for commitment using consensus encoding
     pub fn hash(&self) -> hashes::sha256::Hash {
         let mut serialized = Vec::new();
@@ -69,6 +162,23 @@ impl ShareCommitment {
     }
 }
 
+impl From<&ShareHeader> for ShareCommitment {
+    /// Rebuild the commitment a share header was mined against, so
+    /// that its hash can be checked against the bitcoin coinbase.
+    fn from(header: &ShareHeader) -> Self {
+        Self {
+            prev_share_blockhash: header.prev_share_blockhash,
+            uncles: header.uncles.clone(),
+            miner_pubkey: header.miner_pubkey,
+            merkle_root: header.merkle_root,
+            bits: header.bits,
+            time: header.time,
+            donation: header.donation.clone(),
+            fee: header.fee.clone(),
+        }
+    }
+}
+
 impl Encodable for ShareCommitment {
     fn consensus_encode<W: Write + ?Sized>(&self, w: &mut W) -> Result<usize, bitcoin::io::Error> {
         let mut len = 0;
@@ -92,6 +202,8 @@ impl Encodable for ShareCommitment {
 
         len += self.bits.consensus_encode(w)?;
         len += self.time.consensus_encode(w)?;
+        len += encode_cut(&self.donation, w)?;
+        len += encode_cut(&self.fee, w)?;
         Ok(len)
     }
 }
@@ -116,6 +228,8 @@ impl Decodable for ShareCommitment {
 
         let bits = CompactTarget::consensus_decode(r)?;
         let time = u32::consensus_decode(r)?;
+        let donation = decode_cut(r)?;
+        let fee = decode_cut(r)?;
 
         Ok(ShareCommitment {
             prev_share_blockhash,
@@ -124,6 +238,8 @@ impl Decodable for ShareCommitment {
             merkle_root,
             bits,
             time,
+            donation,
+            fee,
         })
     }
 }
@@ -131,11 +247,12 @@ impl Decodable for ShareCommitment {
 /// Build share commitment by querying the database for fields to set.
 ///
 /// Query the chain store for previous share and uncles.
-/// Uses the current timestamp
+/// Uses the current timestamp, and the donation and fee from config.
 pub(crate) fn build_share_commitment(
     chain_store: &Arc<ChainStore>,
     template: &Arc<BlockTemplate>,
     miner_pubkey: Option<CompressedPublicKey>,
+    config: &StratumConfig<Parsed>,
 ) -> Result<Option<ShareCommitment>, Box<dyn Error + Send + Sync>> {
     let target = match chain_store.get_current_target() {
         Ok(target) => target,
@@ -153,6 +270,8 @@ pub(crate) fn build_share_commitment(
             merkle_root,
             bits: CompactTarget::from_consensus(target),
             time,
+            donation: CoinbaseCut::from_config(config.donation_address(), config.donation),
+            fee: CoinbaseCut::from_config(config.fee_address(), config.fee),
         })),
         None => Ok(None),
     }
@@ -170,6 +289,10 @@ mod tests {
     use std::path::Path;
     use std::str::FromStr;
 
+    fn test_config() -> StratumConfig<Parsed> {
+        StratumConfig::new_for_test_default().parse().unwrap()
+    }
+
     #[test]
     fn test_hash_produces_valid_sha256() {
         let commitment = create_test_commitment();
@@ -285,6 +408,69 @@ mod tests {
         assert_eq!(decoded, commitment);
     }
 
+    #[test]
+    fn test_consensus_encode_decode_with_cuts() {
+        let mut commitment = create_test_commitment();
+        let script_pubkey = ScriptBuf::new_p2wpkh(&commitment.miner_pubkey.wpubkey_hash());
+        commitment.donation = Some(CoinbaseCut {
+            script_pubkey: script_pubkey.clone(),
+            basis_points: 100,
+        });
+        commitment.fee = Some(CoinbaseCut {
+            script_pubkey,
+            basis_points: 200,
+        });
+
+        let mut serialized = Vec::new();
+        commitment.consensus_encode(&mut serialized).unwrap();
+
+        let decoded = ShareCommitment::consensus_decode(&mut &serialized[..]).unwrap();
+
+        assert_eq!(decoded, commitment);
+        assert_ne!(commitment.hash(), create_test_commitment().hash());
+    }
+
+    #[test]
+    fn test_coinbase_cut_amount() {
+        let cut = |basis_points| CoinbaseCut {
+            script_pubkey: ScriptBuf::new(),
+            basis_points,
+        };
+        assert_eq!(
+            cut(100).amount(Amount::from_sat(1_000_099)),
+            Amount::from_sat(10_000)
+        );
+        assert_eq!(cut(0).amount(Amount::from_sat(1_000)), Amount::ZERO);
+        assert_eq!(
+            cut(BASIS_POINTS + 1).amount(Amount::from_sat(1_000)),
+            Amount::from_sat(1_000)
+        );
+    }
+
+    #[test]
+    fn test_coinbase_cut_from_config() {
+        let mut config = StratumConfig::new_for_test_default();
+        config.donation_address = Some(config.bootstrap_address.clone());
+        config.donation = Some(100);
+        config.fee_address = Some(config.bootstrap_address.clone());
+        config.fee = Some(0);
+        let config = config.parse().unwrap();
+
+        assert_eq!(
+            CoinbaseCut::from_config(config.donation_address(), config.donation),
+            Some(CoinbaseCut {
+                script_pubkey: config.bootstrap_address().script_pubkey(),
+                basis_points: 100,
+            })
+        );
+        // A zero cut or a cut without an address is left out
+        assert_eq!(
+            CoinbaseCut::from_config(config.fee_address(), config.fee),
+            None
+        );
+        assert_eq!(CoinbaseCut::from_config(None, Some(100)), None);
+    }
+
     #[test]
     fn test_hash_changes_with_different_fields() {
         let commitment1 = create_test_commitment();
@@ -374,7 +560,7 @@ mod tests {
         });
 
         let store = Arc::new(mock_store);
-        let result = build_share_commitment(&store, &template, Some(miner_pubkey));
+        let result = build_share_commitment(&store, &template, Some(miner_pubkey), &test_config());
 
         assert!(result.is_ok());
         let commitment = result.unwrap().unwrap();
@@ -430,7 +616,7 @@ mod tests {
             });
 
         let store = Arc::new(mock_store);
-        let result = build_share_commitment(&store, &template, Some(miner_pubkey));
+        let result = build_share_commitment(&store, &template, Some(miner_pubkey), &test_config());
 
         assert!(result.is_ok());
         let commitment = result.unwrap().unwrap();
@@ -463,7 +649,7 @@ mod tests {
             .returning(|| Err("Failed to get target".into()));
 
         let store = Arc::new(mock_store);
-        let result = build_share_commitment(&store, &template, Some(miner_pubkey));
+        let result = build_share_commitment(&store, &template, Some(miner_pubkey), &test_config());
 
         assert!(result.is_err());
     }
@@ -499,7 +685,7 @@ mod tests {
             .returning(|| Ok(0x207fffff));
 
         let store = Arc::new(mock_store);
-        let result = build_share_commitment(&store, &template, None);
+        let result = build_share_commitment(&store, &template, None, &test_config());
 
         assert!(result.is_ok());
         assert!(result.unwrap().is_none());
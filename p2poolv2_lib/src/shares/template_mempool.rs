@@ -0,0 +1,181 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use bitcoin::bip152::ShortId;
+use bitcoin::{Transaction, Wtxid};
+use std::collections::{HashMap, VecDeque};
+
+/// The number of recent block templates whose transactions are kept
+pub const TEMPLATE_MEMPOOL_GENERATIONS: usize = 3;
+
+/// Local view of the bitcoin mempool built from our own block templates.
+///
+/// Peers build shares from templates that are close to ours, so the
+/// transactions from the last few templates are enough to reconstruct
+/// most compact share blocks without fetching transactions.
+///
+/// Transactions are kept while they appear in any of the last
+/// `max_generations` templates.
+#[derive(Debug)]
+pub struct TemplateMempool {
+    transactions: HashMap<Wtxid, (Transaction, usize)>,
+    generations: VecDeque<Vec<Wtxid>>,
+    max_generations: usize,
+}
+
+impl Default for TemplateMempool {
+    fn default() -> Self {
+        Self::new(TEMPLATE_MEMPOOL_GENERATIONS)
+    }
+}
+
+impl TemplateMempool {
+    pub fn new(max_generations: usize) -> Self {
+        Self {
+            transactions: HashMap::new(),
+            generations: VecDeque::new(),
+            max_generations,
+        }
+    }
+
+    /// Add the transactions from a new block template, evicting
+    /// transactions only found in templates older than the last
+    /// `max_generations`.
+    pub fn add_template_transactions(&mut self, transactions: Vec<Transaction>) {
+        let mut generation = Vec::with_capacity(transactions.len());
+        for tx in transactions {
+            let wtxid = tx.compute_wtxid();
+            self.transactions.entry(wtxid).or_insert((tx, 0)).1 += 1;
+            generation.push(wtxid);
+        }
+        self.generations.push_back(generation);
+
+        while self.generations.len() > self.max_generations {
+            for wtxid in self.generations.pop_front().unwrap_or_default() {
+                if let Some((_, count)) = self.transactions.get_mut(&wtxid) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.transactions.remove(&wtxid);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find transactions matching the short ids, computed with the
+    /// siphash keys for a compact block.
+    ///
+    /// Returns a transaction or None for each short id, in the same
+    /// order. Short ids matching more than one transaction are
+    /// returned as None, so the transaction is fetched from the peer.
+    pub fn get_by_short_ids(
+        &self,
+        siphash_keys: (u64, u64),
+        short_ids: &[ShortId],
+    ) -> Vec<Option<Transaction>> {
+        let mut by_short_id: HashMap<ShortId, Option<&Transaction>> = HashMap::new();
+        for (wtxid, (tx, _)) in self.transactions.iter() {
+            let short_id = ShortId::with_siphash_keys(&wtxid.to_raw_hash(), siphash_keys);
+            by_short_id
+                .entry(short_id)
+                .and_modify(|found| *found = None)
+                .or_insert(Some(tx));
+        }
+        short_ids
+            .iter()
+            .map(|short_id| by_short_id.get(short_id).copied().flatten().cloned())
+            .collect()
+    }
+
+    /// Number of transactions in the view
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::transaction::Version;
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, TxIn, TxOut, Witness};
+
+    fn test_tx(value: u64) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(value),
+                script_pubkey: ScriptBuf::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_get_by_short_ids() {
+        let mut mempool = TemplateMempool::default();
+        let tx1 = test_tx(1);
+        let tx2 = test_tx(2);
+        mempool.add_template_transactions(vec![tx1.clone(), tx2.clone()]);
+
+        let siphash_keys = (7, 11);
+        let short_id = |tx: &Transaction| {
+            ShortId::with_siphash_keys(&tx.compute_wtxid().to_raw_hash(), siphash_keys)
+        };
+        let unknown = short_id(&test_tx(3));
+
+        assert_eq!(
+            mempool.get_by_short_ids(siphash_keys, &[short_id(&tx2), unknown, short_id(&tx1)]),
+            vec![Some(tx2), None, Some(tx1)]
+        );
+    }
+
+    #[test]
+    fn test_old_templates_are_evicted() {
+        let mut mempool = TemplateMempool::new(2);
+        let tx1 = test_tx(1);
+        let tx2 = test_tx(2);
+        let tx3 = test_tx(3);
+
+        mempool.add_template_transactions(vec![tx1.clone(), tx2.clone()]);
+        mempool.add_template_transactions(vec![tx2.clone()]);
+        assert_eq!(mempool.len(), 2);
+
+        // tx1 was only in the oldest template, tx2 is still in the last two
+        mempool.add_template_transactions(vec![tx2.clone(), tx3.clone()]);
+        assert_eq!(mempool.len(), 2);
+
+        let siphash_keys = (1, 2);
+        let short_ids: Vec<ShortId> = [&tx1, &tx2, &tx3]
+            .iter()
+            .map(|tx| ShortId::with_siphash_keys(&tx.compute_wtxid().to_raw_hash(), siphash_keys))
+            .collect();
+        assert_eq!(
+            mempool.get_by_short_ids(siphash_keys, &short_ids),
+            vec![None, Some(tx2), Some(tx3)]
+        );
+    }
+}
@@ -16,42 +16,130 @@
 
 mod bitcoin_block_validation;
 
+use crate::accounting::simple_pplns::share_chain_payout;
+use crate::config::{Parsed, StratumConfig};
 #[cfg(test)]
 #[mockall_double::double]
 use crate::shares::chain::chain_store::ChainStore;
 #[cfg(not(test))]
 use crate::shares::chain::chain_store::ChainStore;
-use crate::shares::share_block::ShareBlock;
+use crate::shares::chain::chain_store::MAX_UNCLE_DEPTH;
+use crate::shares::share_block::{ShareBlock, ShareHeader};
+use crate::shares::share_commitment::ShareCommitment;
 use crate::utils::time_provider::TimeProvider;
-use std::error::Error;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::hashes::Hash;
+use bitcoin::{
+    Address, Amount, BlockHash, ScriptBuf, Target, Transaction, TxMerkleNode, Txid, Weight,
+};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 pub const MAX_UNCLES: usize = 3;
 pub const MAX_TIME_DIFF: u64 = 60;
 
-/// Validate the share block, returning Error in case of failure to validate
-/// validate nonce and blockhash meets difficulty
+/// Largest share chain transaction accepted from peers, same as the
+/// bitcoin standardness limit
+pub const MAX_TRANSACTION_WEIGHT: Weight = Weight::from_wu(400_000);
+
+/// Each expected payout may be short by this much to allow for rounding
+pub const PAYOUT_ROUNDING_TOLERANCE: Amount = Amount::from_sat(1);
+
+/// Largest total of the donation and fee cuts a share may commit to, in
+/// basis points, so that a share can not take the coinbase from the
+/// PPLNS miners with its own cuts
+pub const MAX_COINBASE_CUTS_BASIS_POINTS: u32 = 1_000;
+
+/// How a share block reached us, which decides how its timestamp is
+/// checked against the current time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// Shares gossiped or pushed as they are mined must be within
+    /// MAX_TIME_DIFF of the current time.
+    Relay,
+    /// Shares we requested while syncing, or as the missing parent of
+    /// an orphan, can be of any age. Only timestamps more than
+    /// MAX_TIME_DIFF in the future are rejected.
+    Sync,
+}
+
+/// Reasons a share block received from a peer fails consensus validation.
+///
+/// Each variant identifies a distinct offence so the caller can
+/// decide how to treat the peer that sent the share.
+#[derive(Debug, thiserror::Error)]
+pub enum ValidationError {
+    #[error(
+        "Share timestamp {share_time} is more than {} seconds from current time {current_time}",
+        MAX_TIME_DIFF
+    )]
+    InvalidTimestamp { share_time: u64, current_time: u64 },
+    #[error("Prev share blockhash {0} not found in store")]
+    PrevShareNotFound(BlockHash),
+    #[error("Too many uncles")]
+    TooManyUncles,
+    #[error("Uncle {0} not found in store")]
+    UncleNotFound(BlockHash),
+    #[error("Uncle {0} is included more than once")]
+    DuplicateUncle(BlockHash),
+    #[error("Uncle {0} is an ancestor of the share")]
+    UncleOnChain(BlockHash),
+    #[error("Uncle {0} is more than {depth} shares deep", depth = MAX_UNCLE_DEPTH)]
+    UncleTooDeep(BlockHash),
+    #[error("Uncle {0} is already included by an ancestor")]
+    UncleAlreadyIncluded(BlockHash),
+    #[error("Share bits {actual:#010x} do not match expected bits {expected:#010x}")]
+    UnexpectedBits { expected: u32, actual: u32 },
+    #[error("Share {0} does not meet the share target")]
+    InsufficientWork(BlockHash),
+    #[error("Share has no bitcoin coinbase transaction")]
+    MissingCoinbase,
+    #[error("Bitcoin coinbase does not commit to the share header")]
+    CommitmentMismatch,
+    #[error("Bitcoin header merkle root does not match bitcoin transactions")]
+    MerkleRootMismatch,
+    #[error("Coinbase pays {actual} to {address}, expected {expected}")]
+    PayoutMismatch {
+        address: Address,
+        expected: Amount,
+        actual: Amount,
+    },
+    #[error(
+        "Coinbase cuts of {0} basis points exceed {max}",
+        max = MAX_COINBASE_CUTS_BASIS_POINTS
+    )]
+    CoinbaseCutsTooLarge(u32),
+    #[error("Transaction {txid} is invalid: {reason}")]
+    InvalidTransaction { txid: Txid, reason: &'static str },
+    #[error("Failed to read chain state: {0}")]
+    ChainState(String),
+}
+
+/// Validate the share block, returning a ValidationError in case of failure to validate
+/// validate timestamp is within MAX_TIME_DIFF of current time, or not
+/// too far in the future when syncing, see [ValidationMode]
 /// validate prev_share_blockhash is in store
-/// validate uncles are in store and no more than MAX_UNCLES
-/// validate timestamp is within the last 10 minutes
-/// validate merkle root
-/// validate coinbase transaction
+/// validate uncles are in store, no more than MAX_UNCLES and follow uncle rules
+/// validate bits match the share chain target at the parent
+/// validate bitcoin header hash meets the share target
+/// validate merkle root covers the bitcoin transactions
+/// validate coinbase commits to the share header
+/// validate coinbase pays the PPLNS distribution at the parent
 pub async fn validate(
     share: &ShareBlock,
     store: Arc<ChainStore>,
+    config: &StratumConfig<Parsed>,
+    mode: ValidationMode,
     time_provider: &impl TimeProvider,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    if let Err(e) = validate_timestamp(share, time_provider).await {
-        return Err(format!("Share timestamp validation failed: {e}").into());
-    }
-    if let Err(e) = validate_prev_share_blockhash(share, store.clone()).await {
-        return Err(format!("Share prev_share_blockhash validation failed: {e}").into());
-    }
-    if let Err(e) = validate_uncles(share, store.clone()).await {
-        return Err(format!("Share uncles validation failed: {e}").into());
-    }
-    // TODO: Populate bitcoin block from ShortIDs in share and use bitcoin_block_validation to validate difficulty
-    // OR - Fetch diffculty from bitcoind rpc and validate share blockhash meets difficulty
+) -> Result<(), ValidationError> {
+    validate_timestamp(share, mode, time_provider).await?;
+    validate_prev_share_blockhash(share, store.clone()).await?;
+    validate_uncles(share, store.clone()).await?;
+    validate_bits(share, store.clone())?;
+    validate_pow(share)?;
+    validate_merkle_root(share)?;
+    validate_commitment(share)?;
+    validate_coinbase_outputs(share, store, config.network)?;
     Ok(())
 }
 
@@ -59,51 +147,286 @@ pub async fn validate(
 pub async fn validate_prev_share_blockhash(
     share: &ShareBlock,
     store: Arc<ChainStore>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), ValidationError> {
     if store
         .get_share(&share.header.prev_share_blockhash)
         .is_none()
     {
-        return Err(format!(
-            "Prev share blockhash {} not found in store",
-            share.header.prev_share_blockhash
-        )
-        .into());
+        return Err(ValidationError::PrevShareNotFound(
+            share.header.prev_share_blockhash,
+        ));
     }
     Ok(())
 }
 
 /// Validate the share uncles are in store and no more than MAX_UNCLES
+/// and that they follow the rules in [check_uncles]
 pub async fn validate_uncles(
     share: &ShareBlock,
     store: Arc<ChainStore>,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), ValidationError> {
     if share.header.uncles.len() > MAX_UNCLES {
-        return Err("Too many uncles".into());
+        return Err(ValidationError::TooManyUncles);
+    }
+    if share.header.uncles.is_empty() {
+        return Ok(());
     }
+    let mut uncle_headers = Vec::with_capacity(share.header.uncles.len());
     for uncle in &share.header.uncles {
-        if store.get_share(uncle).is_none() {
-            return Err(format!("Uncle {uncle} not found in store").into());
+        match store.get_share(uncle) {
+            Some(uncle_share) => uncle_headers.push(uncle_share.header),
+            None => return Err(ValidationError::UncleNotFound(*uncle)),
+        }
+    }
+    let ancestors =
+        store.get_ancestor_headers(&share.header.prev_share_blockhash, MAX_UNCLE_DEPTH + 2);
+    check_uncles(&uncle_headers, &ancestors)
+}
+
+/// Check uncle headers against the ancestors of the share including them
+///
+/// Ancestors are ordered nearest first, starting with the share's
+/// parent, and should span MAX_UNCLE_DEPTH + 2 shares. An uncle must
+/// not be listed twice, must not be an ancestor itself, must not have
+/// been included as an uncle by an ancestor already, and its parent
+/// must be an ancestor so the uncle is at most MAX_UNCLE_DEPTH shares
+/// below the share's parent.
+pub fn check_uncles(
+    uncles: &[ShareHeader],
+    ancestors: &[ShareHeader],
+) -> Result<(), ValidationError> {
+    let ancestor_hashes: Vec<BlockHash> = ancestors.iter().map(|h| h.block_hash()).collect();
+    let already_included: HashSet<BlockHash> = ancestors
+        .iter()
+        .flat_map(|h| h.uncles.iter().copied())
+        .collect();
+    let mut seen = HashSet::with_capacity(uncles.len());
+    for uncle in uncles {
+        let uncle_hash = uncle.block_hash();
+        if !seen.insert(uncle_hash) {
+            return Err(ValidationError::DuplicateUncle(uncle_hash));
+        }
+        if ancestor_hashes.contains(&uncle_hash) {
+            return Err(ValidationError::UncleOnChain(uncle_hash));
+        }
+        if already_included.contains(&uncle_hash) {
+            return Err(ValidationError::UncleAlreadyIncluded(uncle_hash));
+        }
+        if !ancestor_hashes
+            .iter()
+            .skip(1)
+            .any(|hash| *hash == uncle.prev_share_blockhash)
+        {
+            return Err(ValidationError::UncleTooDeep(uncle_hash));
         }
     }
     Ok(())
 }
 
-/// Validate the share timestamp is within the last 60 seconds
+/// Validate the share timestamp is within MAX_TIME_DIFF of the
+/// current time. When syncing only future timestamps are bounded, as
+/// shares from the past are expected.
 pub async fn validate_timestamp(
     share: &ShareBlock,
+    mode: ValidationMode,
     time_provider: &impl TimeProvider,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), ValidationError> {
     let current_time = time_provider.seconds_since_epoch();
 
     let block_timestamp = share.header.bitcoin_header.time as u64;
-    let time_diff = current_time.abs_diff(block_timestamp);
+    let too_far = match mode {
+        ValidationMode::Relay => current_time.abs_diff(block_timestamp) > MAX_TIME_DIFF,
+        ValidationMode::Sync => block_timestamp > current_time.saturating_add(MAX_TIME_DIFF),
+    };
 
-    if time_diff > MAX_TIME_DIFF {
-        return Err(format!(
-            "Share timestamp {block_timestamp} is more than {MAX_TIME_DIFF} seconds from current time {current_time}",
-        )
-        .into());
+    if too_far {
+        return Err(ValidationError::InvalidTimestamp {
+            share_time: block_timestamp,
+            current_time,
+        });
+    }
+    Ok(())
+}
+
+/// Validate the share bits are the same as the target the chain
+/// store computes for a share built on top of the parent share.
+pub fn validate_bits(share: &ShareBlock, store: Arc<ChainStore>) -> Result<(), ValidationError> {
+    validate_header_bits(&share.header, &store)
+}
+
+/// Validate the bits for a share header, used for headers received
+/// before the share block during headers-first sync. The parent must
+/// be in the store, either as a share block or as a header.
+pub fn validate_header_bits(
+    header: &ShareHeader,
+    store: &ChainStore,
+) -> Result<(), ValidationError> {
+    let expected = store
+        .get_target_at(&header.prev_share_blockhash)
+        .map_err(|e| ValidationError::ChainState(e.to_string()))?;
+    let actual = header.bits.to_consensus();
+    if actual != expected {
+        return Err(ValidationError::UnexpectedBits { expected, actual });
+    }
+    Ok(())
+}
+
+/// Validate the bitcoin header hash meets the share target set in bits
+pub fn validate_pow(share: &ShareBlock) -> Result<(), ValidationError> {
+    validate_header_pow(&share.header)
+}
+
+/// Validate the PoW for a share header, used for headers received
+/// before the share block during headers-first sync.
+pub fn validate_header_pow(header: &ShareHeader) -> Result<(), ValidationError> {
+    let target = Target::from_compact(header.bits);
+    let bitcoin_blockhash = header.bitcoin_header.block_hash();
+    if !target.is_met_by(bitcoin_blockhash) {
+        return Err(ValidationError::InsufficientWork(header.block_hash()));
+    }
+    Ok(())
+}
+
+/// Validate the bitcoin header merkle root is computed from the
+/// bitcoin transactions shipped with the share.
+pub fn validate_merkle_root(share: &ShareBlock) -> Result<(), ValidationError> {
+    let merkle_root: Option<TxMerkleNode> = bitcoin::merkle_tree::calculate_root(
+        share
+            .bitcoin_transactions
+            .iter()
+            .map(Transaction::compute_txid),
+    )
+    .map(|root| root.into());
+    match merkle_root {
+        None => Err(ValidationError::MissingCoinbase),
+        Some(root) if root != share.header.bitcoin_header.merkle_root => {
+            Err(ValidationError::MerkleRootMismatch)
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Validate the bitcoin coinbase scriptsig includes the hash of the
+/// share commitment built from the share header.
+///
+/// The commitment is pushed right after the block height, as done by
+/// stratum when building the coinbase for a job.
+pub fn validate_commitment(share: &ShareBlock) -> Result<(), ValidationError> {
+    let coinbase = match share.bitcoin_transactions.first() {
+        Some(tx) if tx.is_coinbase() => tx,
+        _ => return Err(ValidationError::MissingCoinbase),
+    };
+    let commitment_hash = ShareCommitment::from(&share.header).hash();
+    match coinbase.input[0].script_sig.instructions().nth(1) {
+        Some(Ok(Instruction::PushBytes(bytes)))
+            if bytes.as_bytes() == commitment_hash.as_byte_array() =>
+        {
+            Ok(())
+        }
+        _ => Err(ValidationError::CommitmentMismatch),
+    }
+}
+
+/// Validate the bitcoin coinbase pays the output distribution
+/// computed from the share chain at the share's parent.
+///
+/// The coinbase value is split as done by stratum when building the
+/// job, see [share_chain_payout::get_output_distribution]. The
+/// distribution only depends on the share chain, the donation and fee
+/// cuts committed in the share and protocol constants, never on local
+/// config, so every node expects the same outputs. The cuts may add up
+/// to at most MAX_COINBASE_CUTS_BASIS_POINTS. Each expected payout must be in the coinbase and may be
+/// short by up to PAYOUT_ROUNDING_TOLERANCE. Outputs to the same
+/// script are added up.
+pub fn validate_coinbase_outputs(
+    share: &ShareBlock,
+    store: Arc<ChainStore>,
+    network: bitcoin::Network,
+) -> Result<(), ValidationError> {
+    let coinbase = match share.bitcoin_transactions.first() {
+        Some(tx) if tx.is_coinbase() => tx,
+        _ => return Err(ValidationError::MissingCoinbase),
+    };
+    let cuts = share.header.coinbase_cuts();
+    let cut_basis_points: u32 = cuts.iter().map(|cut| cut.basis_points as u32).sum();
+    if cut_basis_points > MAX_COINBASE_CUTS_BASIS_POINTS {
+        return Err(ValidationError::CoinbaseCutsTooLarge(cut_basis_points));
+    }
+    let mut paid: HashMap<ScriptBuf, Amount> = HashMap::with_capacity(coinbase.output.len());
+    let mut total_amount = Amount::ZERO;
+    for output in &coinbase.output {
+        *paid
+            .entry(output.script_pubkey.clone())
+            .or_insert(Amount::ZERO) += output.value;
+        total_amount += output.value;
+    }
+
+    let expected = share_chain_payout::get_output_distribution(
+        &store,
+        &share.header.prev_share_blockhash,
+        &share.header.miner_pubkey,
+        share_chain_payout::window_work(share.header.bits),
+        total_amount,
+        &cuts,
+        network,
+    )
+    .map_err(|e| ValidationError::ChainState(e.to_string()))?;
+
+    for output in expected {
+        let actual = paid
+            .get(&output.address.script_pubkey())
+            .copied()
+            .unwrap_or(Amount::ZERO);
+        if actual + PAYOUT_ROUNDING_TOLERANCE < output.amount {
+            return Err(ValidationError::PayoutMismatch {
+                address: output.address,
+                expected: output.amount,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Context free checks on a share chain transaction received from a
+/// peer, before it is stored.
+///
+/// The transaction must have inputs and outputs, must not be a
+/// coinbase, must not spend the same output twice, must stay within
+/// MAX_TRANSACTION_WEIGHT and must not create more than MAX_MONEY.
+pub fn validate_transaction(transaction: &Transaction) -> Result<(), ValidationError> {
+    let invalid = |reason| ValidationError::InvalidTransaction {
+        txid: transaction.compute_txid(),
+        reason,
+    };
+    if transaction.input.is_empty() {
+        return Err(invalid("no inputs"));
+    }
+    if transaction.output.is_empty() {
+        return Err(invalid("no outputs"));
+    }
+    if transaction.is_coinbase() {
+        return Err(invalid("coinbase"));
+    }
+    if transaction.weight() > MAX_TRANSACTION_WEIGHT {
+        return Err(invalid("too large"));
+    }
+    let mut spent = HashSet::with_capacity(transaction.input.len());
+    if !transaction
+        .input
+        .iter()
+        .all(|input| spent.insert(input.previous_output))
+    {
+        return Err(invalid("duplicate inputs"));
+    }
+    let total = transaction
+        .output
+        .iter()
+        .try_fold(Amount::ZERO, |total, output| {
+            total.checked_add(output.value)
+        });
+    if total.is_none_or(|total| total > Amount::MAX_MONEY) {
+        return Err(invalid("output value out of range"));
     }
     Ok(())
 }
@@ -111,6 +434,7 @@ pub async fn validate_timestamp(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::shares::share_commitment::CoinbaseCut;
     use crate::test_utils::{TestShareBlockBuilder, genesis_for_tests};
     use crate::utils::time_provider::TestTimeProvider;
     use bitcoin::{BlockHash, hashes::Hash};
@@ -130,7 +454,7 @@ mod tests {
         time_provider
             .set_time(bitcoin::absolute::Time::from_consensus(share_timestamp as u32).unwrap());
 
-        let result = validate_timestamp(&share, &time_provider).await;
+        let result = validate_timestamp(&share, ValidationMode::Relay, &time_provider).await;
         assert_eq!(
             result.err().unwrap().to_string(),
             format!(
@@ -155,7 +479,45 @@ mod tests {
             .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
             .build();
 
-        assert!(validate_timestamp(&share, &time_provider).await.is_err());
+        assert!(
+            validate_timestamp(&share, ValidationMode::Relay, &time_provider)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate_timestamp_sync_mode_bounds_only_future() {
+        let share = TestShareBlockBuilder::new()
+            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+            .build();
+        let share_time = share.header.bitcoin_header.time;
+        let mut time_provider = TestTimeProvider::new(SystemTime::now());
+
+        // Shares from long ago are accepted while syncing
+        time_provider.set_time(
+            bitcoin::absolute::Time::from_consensus(share_time + 10 * MAX_TIME_DIFF as u32)
+                .unwrap(),
+        );
+        assert!(
+            validate_timestamp(&share, ValidationMode::Sync, &time_provider)
+                .await
+                .is_ok()
+        );
+        assert!(
+            validate_timestamp(&share, ValidationMode::Relay, &time_provider)
+                .await
+                .is_err()
+        );
+
+        // Shares from the future are not
+        time_provider.set_time(
+            bitcoin::absolute::Time::from_consensus(share_time - 2 * MAX_TIME_DIFF as u32).unwrap(),
+        );
+        assert!(matches!(
+            validate_timestamp(&share, ValidationMode::Sync, &time_provider).await,
+            Err(ValidationError::InvalidTimestamp { .. })
+        ));
     }
 
     #[tokio::test]
@@ -172,7 +534,11 @@ mod tests {
             .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
             .build();
 
-        assert!(validate_timestamp(&share, &time_provider).await.is_ok());
+        assert!(
+            validate_timestamp(&share, ValidationMode::Relay, &time_provider)
+                .await
+                .is_ok()
+        );
     }
 
     #[tokio::test]
@@ -229,75 +595,58 @@ mod tests {
 
     #[tokio::test]
     async fn test_validate_uncles() {
-        let mut seq = mockall::Sequence::new();
         let mut store = ChainStore::default();
 
-        // Create initial shares to use as uncles
-        let uncle1 = TestShareBlockBuilder::new()
-            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
-            .build();
-
-        let uncle1_clone = uncle1.clone();
-        store
-            .expect_get_share()
-            .times(1)
-            .in_sequence(&mut seq)
-            .with(mockall::predicate::eq(uncle1.block_hash()))
-            .returning(move |_| Some(uncle1_clone.clone()));
-
-        let uncle2 = TestShareBlockBuilder::new()
-            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+        // Uncles are siblings of the share's parent
+        let grandparent = TestShareBlockBuilder::new().nonce(1).build();
+        let parent = TestShareBlockBuilder::new()
+            .prev_share_blockhash(grandparent.block_hash().to_string())
+            .nonce(2)
             .build();
+        let uncles: Vec<ShareBlock> = (10..14)
+            .map(|nonce| {
+                TestShareBlockBuilder::new()
+                    .prev_share_blockhash(grandparent.block_hash().to_string())
+                    .nonce(nonce)
+                    .build()
+            })
+            .collect();
 
-        let uncle2_clone = uncle2.clone();
-
+        let uncles_clone = uncles.clone();
+        store.expect_get_share().returning(move |hash| {
+            uncles_clone
+                .iter()
+                .find(|uncle| uncle.block_hash() == *hash)
+                .cloned()
+        });
+        let ancestors = vec![parent.header.clone(), grandparent.header.clone()];
         store
-            .expect_get_share()
-            .times(1)
-            .in_sequence(&mut seq)
-            .with(mockall::predicate::eq(uncle2.block_hash()))
-            .returning(move |_| Some(uncle2_clone.clone()));
-
-        let uncle3 = TestShareBlockBuilder::new()
-            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
-            .build();
-
-        let uncle3_clone = uncle3.clone();
-
-        store
-            .expect_get_share()
-            .times(1)
-            .in_sequence(&mut seq)
-            .with(mockall::predicate::eq(uncle3.block_hash()))
-            .returning(move |_| Some(uncle3_clone.clone()));
-
-        let uncle4 = TestShareBlockBuilder::new()
-            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
-            .build();
-
-        let _uncle4_clone = uncle4.clone();
+            .expect_get_ancestor_headers()
+            .with(
+                mockall::predicate::eq(parent.block_hash()),
+                mockall::predicate::eq(MAX_UNCLE_DEPTH + 2),
+            )
+            .returning(move |_, _| ancestors.clone());
+        let arc_store = Arc::new(store);
 
         // Test share with non-existent uncle
         let non_existent_hash = "0000000086704a35f17580d06f76d4c02d2b1f68774800675fb45f0411205bb7"
             .parse::<BlockHash>()
             .unwrap();
-
-        let _invalid_share_b = TestShareBlockBuilder::new()
-            .uncles(vec![uncle1.block_hash(), non_existent_hash])
-            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+        let invalid_share = TestShareBlockBuilder::new()
+            .prev_share_blockhash(parent.block_hash().to_string())
+            .uncles(vec![uncles[0].block_hash(), non_existent_hash])
             .build();
+        assert!(matches!(
+            validate_uncles(&invalid_share, arc_store.clone()).await,
+            Err(ValidationError::UncleNotFound(hash)) if hash == non_existent_hash
+        ));
 
         // Test share with valid number of uncles (MAX_UNCLES = 3)
         let valid_share = TestShareBlockBuilder::new()
-            .uncles(vec![
-                uncle1.block_hash(),
-                uncle2.block_hash(),
-                uncle3.block_hash(),
-            ])
-            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+            .prev_share_blockhash(parent.block_hash().to_string())
+            .uncles(uncles[..3].iter().map(|uncle| uncle.block_hash()).collect())
             .build();
-
-        let arc_store = Arc::new(store);
         assert!(
             validate_uncles(&valid_share, arc_store.clone())
                 .await
@@ -306,26 +655,80 @@ mod tests {
 
         // Test share with too many uncles (> MAX_UNCLES)
         let invalid_share = TestShareBlockBuilder::new()
-            .uncles(vec![
-                uncle1.block_hash(),
-                uncle2.block_hash(),
-                uncle3.block_hash(),
-                uncle4.block_hash(),
-            ])
-            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+            .prev_share_blockhash(parent.block_hash().to_string())
+            .uncles(uncles.iter().map(|uncle| uncle.block_hash()).collect())
             .build();
+        assert!(matches!(
+            validate_uncles(&invalid_share, arc_store.clone()).await,
+            Err(ValidationError::TooManyUncles)
+        ));
 
-        assert!(
-            validate_uncles(&invalid_share, arc_store.clone())
-                .await
-                .is_err()
-        );
+        // Test share listing the same uncle twice
+        let invalid_share = TestShareBlockBuilder::new()
+            .prev_share_blockhash(parent.block_hash().to_string())
+            .uncles(vec![uncles[0].block_hash(), uncles[0].block_hash()])
+            .build();
+        assert!(matches!(
+            validate_uncles(&invalid_share, arc_store.clone()).await,
+            Err(ValidationError::DuplicateUncle(_))
+        ));
+    }
 
-        assert!(
-            validate_uncles(&invalid_share, arc_store.clone())
-                .await
-                .is_err()
-        );
+    #[test]
+    fn test_check_uncles() {
+        // Main chain: genesis <- s1 <- s2 <- s3 <- s4 <- s5 (parent of the new share)
+        let mut chain = vec![TestShareBlockBuilder::new().nonce(0).build()];
+        for nonce in 1..6 {
+            let prev = chain.last().unwrap().block_hash();
+            chain.push(
+                TestShareBlockBuilder::new()
+                    .prev_share_blockhash(prev.to_string())
+                    .nonce(nonce)
+                    .build(),
+            );
+        }
+        let uncle_at = |height: usize, nonce: u32| {
+            TestShareBlockBuilder::new()
+                .prev_share_blockhash(chain[height - 1].block_hash().to_string())
+                .nonce(nonce)
+                .build()
+                .header
+        };
+        let ancestors: Vec<ShareHeader> = chain
+            .iter()
+            .rev()
+            .take(MAX_UNCLE_DEPTH + 2)
+            .map(|share| share.header.clone())
+            .collect();
+
+        // Siblings of s5 down to s2 are within MAX_UNCLE_DEPTH of the parent
+        for height in 2..=5 {
+            let uncle = uncle_at(height, 100 + height as u32);
+            assert!(check_uncles(&[uncle], &ancestors).is_ok());
+        }
+
+        // A sibling of s1 is too deep
+        let deep = uncle_at(1, 101);
+        assert!(matches!(
+            check_uncles(&[deep], &ancestors),
+            Err(ValidationError::UncleTooDeep(_))
+        ));
+
+        // A share on the main chain can't be an uncle
+        let on_chain = chain[4].header.clone();
+        assert!(matches!(
+            check_uncles(&[on_chain], &ancestors),
+            Err(ValidationError::UncleOnChain(_))
+        ));
+
+        // An uncle already included by an ancestor can't be included again
+        let uncle = uncle_at(3, 103);
+        let mut ancestors_with_uncle = ancestors.clone();
+        ancestors_with_uncle[1].uncles.push(uncle.block_hash());
+        assert!(matches!(
+            check_uncles(&[uncle], &ancestors_with_uncle),
+            Err(ValidationError::UncleAlreadyIncluded(_))
+        ));
     }
 
     #[tokio::test]
@@ -348,6 +751,7 @@ mod tests {
             .expect_get_share()
             .with(eq(bitcoin::BlockHash::all_zeros()))
             .returning(|_| Some(genesis_for_tests()));
+        store.expect_get_target_at().returning(|_| Ok(0x207fffff));
 
         store
             .expect_setup_share_for_chain()
@@ -360,8 +764,352 @@ mod tests {
         );
 
         // Test handle_request directly without request_id
-        let result = validate(&share_block, Arc::new(store), &time_provider).await;
+        let config = StratumConfig::new_for_test_default().parse().unwrap();
+        let result = validate(
+            &share_block,
+            Arc::new(store),
+            &config,
+            ValidationMode::Relay,
+            &time_provider,
+        )
+        .await;
 
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_validate_coinbase_outputs() {
+        let miner: &str = "020202020202020202020202020202020202020202020202020202020202020202";
+        let thief: bitcoin::CompressedPublicKey =
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let parent = TestShareBlockBuilder::new().miner_pubkey(miner).build();
+        let parent_header = parent.header.clone();
+        let mut store = ChainStore::default();
+        store
+            .expect_get_share_header()
+            .with(eq(parent.block_hash()))
+            .returning(move |_| Some(parent_header.clone()));
+        store.expect_get_share_balances().returning(|_| Ok(None));
+        let store = Arc::new(store);
+        let config = StratumConfig::new_for_test_default().parse().unwrap();
+
+        let miner_script =
+            Address::p2pkh(parent.header.miner_pubkey, config.network).script_pubkey();
+        let thief_script = Address::p2pkh(thief, config.network).script_pubkey();
+        let share_paying = |outputs: Vec<(ScriptBuf, u64)>| {
+            let mut share = TestShareBlockBuilder::new()
+                .prev_share_blockhash(parent.block_hash().to_string())
+                .build();
+            share.bitcoin_transactions[0].output = outputs
+                .into_iter()
+                .map(|(script_pubkey, sats)| bitcoin::TxOut {
+                    value: Amount::from_sat(sats),
+                    script_pubkey,
+                })
+                .collect();
+            share
+        };
+
+        // Everything goes to the only miner in the window
+        let share = share_paying(vec![(miner_script.clone(), 1_000_000)]);
+        assert!(validate_coinbase_outputs(&share, store.clone(), config.network).is_ok());
+
+        // A rounding difference is tolerated
+        let share = share_paying(vec![
+            (miner_script.clone(), 999_999),
+            (thief_script.clone(), 1),
+        ]);
+        assert!(validate_coinbase_outputs(&share, store.clone(), config.network).is_ok());
+
+        // Paying the share author instead of the miners is rejected
+        let share = share_paying(vec![
+            (miner_script.clone(), 500_000),
+            (thief_script.clone(), 500_000),
+        ]);
+        assert!(matches!(
+            validate_coinbase_outputs(&share, store.clone(), config.network),
+            Err(ValidationError::PayoutMismatch { expected, actual, .. })
+                if expected == Amount::from_sat(1_000_000) && actual == Amount::from_sat(500_000)
+        ));
+
+        // Small payouts can not be redirected either
+        let share = share_paying(vec![(thief_script, 545)]);
+        assert!(matches!(
+            validate_coinbase_outputs(&share, store, config.network),
+            Err(ValidationError::PayoutMismatch { expected, actual, .. })
+                if expected == Amount::from_sat(545) && actual == Amount::ZERO
+        ));
+    }
+
+    #[test]
+    fn test_validate_coinbase_outputs_with_committed_cuts() {
+        let miner: &str = "020202020202020202020202020202020202020202020202020202020202020202";
+        let donor: bitcoin::CompressedPublicKey =
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let parent = TestShareBlockBuilder::new().miner_pubkey(miner).build();
+        let parent_header = parent.header.clone();
+        let mut store = ChainStore::default();
+        store
+            .expect_get_share_header()
+            .with(eq(parent.block_hash()))
+            .returning(move |_| Some(parent_header.clone()));
+        store.expect_get_share_balances().returning(|_| Ok(None));
+        let store = Arc::new(store);
+        let network = bitcoin::Network::Signet;
+
+        let miner_script = Address::p2pkh(parent.header.miner_pubkey, network).script_pubkey();
+        let donation_script = Address::p2pkh(donor, network).script_pubkey();
+        let share_paying = |basis_points: u16, outputs: Vec<(ScriptBuf, u64)>| {
+            let mut share = TestShareBlockBuilder::new()
+                .prev_share_blockhash(parent.block_hash().to_string())
+                .build();
+            share.header.donation = Some(CoinbaseCut {
+                script_pubkey: donation_script.clone(),
+                basis_points,
+            });
+            share.bitcoin_transactions[0].output = outputs
+                .into_iter()
+                .map(|(script_pubkey, sats)| bitcoin::TxOut {
+                    value: Amount::from_sat(sats),
+                    script_pubkey,
+                })
+                .collect();
+            share
+        };
+
+        // The committed donation is paid before the miners
+        let share = share_paying(
+            100,
+            vec![
+                (donation_script.clone(), 10_000),
+                (miner_script.clone(), 990_000),
+            ],
+        );
+        assert!(validate_coinbase_outputs(&share, store.clone(), network).is_ok());
+
+        // Leaving the committed donation out is rejected
+        let share = share_paying(100, vec![(miner_script.clone(), 1_000_000)]);
+        assert!(matches!(
+            validate_coinbase_outputs(&share, store.clone(), network),
+            Err(ValidationError::PayoutMismatch { expected, actual, .. })
+                if expected == Amount::from_sat(10_000) && actual == Amount::ZERO
+        ));
+
+        // Cuts can not take the coinbase from the miners
+        let share = share_paying(10_000, vec![(donation_script.clone(), 1_000_000)]);
+        assert!(matches!(
+            validate_coinbase_outputs(&share, store, network),
+            Err(ValidationError::CoinbaseCutsTooLarge(10_000))
+        ));
+    }
+
+    #[test]
+    fn test_validate_coinbase_outputs_window_ignores_bitcoin_bits() {
+        const MINER_A: &str = "020202020202020202020202020202020202020202020202020202020202020202";
+        const MINER_B: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let miner_a: bitcoin::CompressedPublicKey = MINER_A.parse().unwrap();
+        let miner_b: bitcoin::CompressedPublicKey = MINER_B.parse().unwrap();
+        let grandparent = TestShareBlockBuilder::new().miner_pubkey(MINER_A).build();
+        let parent = TestShareBlockBuilder::new()
+            .prev_share_blockhash(grandparent.block_hash().to_string())
+            .miner_pubkey(MINER_B)
+            .build();
+        let headers: HashMap<BlockHash, ShareHeader> = [&grandparent, &parent]
+            .into_iter()
+            .map(|share| (share.block_hash(), share.header.clone()))
+            .collect();
+        let mut store = ChainStore::default();
+        store
+            .expect_get_share_header()
+            .returning(move |hash| headers.get(hash).cloned());
+        store.expect_get_share_balances().returning(|_| Ok(None));
+        let store = Arc::new(store);
+        let config = StratumConfig::new_for_test_default().parse().unwrap();
+
+        // The author claims the easiest bitcoin target to shrink the
+        // window to its parent and pays miner_b only
+        let mut share = TestShareBlockBuilder::new()
+            .prev_share_blockhash(parent.block_hash().to_string())
+            .build();
+        share.header.bitcoin_header.bits = bitcoin::CompactTarget::from_consensus(0x207fffff);
+        share.bitcoin_transactions[0].output = vec![bitcoin::TxOut {
+            value: Amount::from_sat(1_000_000),
+            script_pubkey: Address::p2pkh(miner_b, config.network).script_pubkey(),
+        }];
+
+        // The window comes from the share bits and still covers miner_a
+        assert!(matches!(
+            validate_coinbase_outputs(&share, store, config.network),
+            Err(ValidationError::PayoutMismatch { address, .. })
+                if address == Address::p2pkh(miner_a, config.network)
+        ));
+    }
+
+    fn valid_share_and_store() -> (ShareBlock, ChainStore) {
+        let share_block = crate::test_utils::build_block_from_work_components(
+            "../tests/test_data/validation/stratum/b/",
+        );
+        let mut store = ChainStore::default();
+        store
+            .expect_get_target_at()
+            .with(eq(bitcoin::BlockHash::all_zeros()))
+            .returning(|_| Ok(0x207fffff));
+        (share_block, store)
+    }
+
+    #[test]
+    fn test_validate_bits_mismatch() {
+        let (share_block, mut store) = valid_share_and_store();
+        store.checkpoint();
+        store.expect_get_target_at().returning(|_| Ok(0x1d00ffff));
+
+        let result = validate_bits(&share_block, Arc::new(store));
+        assert!(matches!(
+            result,
+            Err(ValidationError::UnexpectedBits {
+                expected: 0x1d00ffff,
+                actual: 0x207fffff
+            })
+        ));
+    }
+
+    #[test]
+    fn test_validate_bits_missing_parent() {
+        let (share_block, mut store) = valid_share_and_store();
+        store.checkpoint();
+        store
+            .expect_get_target_at()
+            .returning(|_| Err("Share not found".into()));
+
+        let result = validate_bits(&share_block, Arc::new(store));
+        assert!(matches!(result, Err(ValidationError::ChainState(_))));
+    }
+
+    #[test]
+    fn test_validate_bits_matches_parent_target() {
+        let (share_block, store) = valid_share_and_store();
+        assert!(validate_bits(&share_block, Arc::new(store)).is_ok());
+    }
+
+    #[test]
+    fn test_validate_pow() {
+        let (mut share_block, _) = valid_share_and_store();
+        assert!(validate_pow(&share_block).is_ok());
+
+        // Hardest possible target is not met by the test header
+        share_block.header.bits = bitcoin::CompactTarget::from_consensus(0x03000001);
+        assert!(matches!(
+            validate_pow(&share_block),
+            Err(ValidationError::InsufficientWork(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_merkle_root() {
+        let (mut share_block, _) = valid_share_and_store();
+        assert!(validate_merkle_root(&share_block).is_ok());
+
+        share_block
+            .bitcoin_transactions
+            .push(crate::test_utils::test_coinbase_transaction());
+        assert!(matches!(
+            validate_merkle_root(&share_block),
+            Err(ValidationError::MerkleRootMismatch)
+        ));
+
+        share_block.bitcoin_transactions.clear();
+        assert!(matches!(
+            validate_merkle_root(&share_block),
+            Err(ValidationError::MissingCoinbase)
+        ));
+    }
+
+    #[test]
+    fn test_validate_commitment() {
+        let (share_block, _) = valid_share_and_store();
+        assert!(validate_commitment(&share_block).is_ok());
+
+        // Changing any committed header field breaks the commitment
+        let mut changed_time = share_block.clone();
+        changed_time.header.time += 1;
+        assert!(matches!(
+            validate_commitment(&changed_time),
+            Err(ValidationError::CommitmentMismatch)
+        ));
+
+        let mut changed_miner = share_block.clone();
+        changed_miner.header.miner_pubkey =
+            "02ac493f2130ca56cb5c3a559860cef9a84f90b5a85dfe4ec6e6067eeee17f4d2d"
+                .parse()
+                .unwrap();
+        assert!(matches!(
+            validate_commitment(&changed_miner),
+            Err(ValidationError::CommitmentMismatch)
+        ));
+
+        // A coinbase without the commitment push is rejected
+        let mut no_commitment = share_block.clone();
+        no_commitment.bitcoin_transactions[0].input[0].script_sig = bitcoin::ScriptBuf::new();
+        assert!(matches!(
+            validate_commitment(&no_commitment),
+            Err(ValidationError::CommitmentMismatch)
+        ));
+
+        let mut no_coinbase = share_block;
+        no_coinbase.bitcoin_transactions.clear();
+        assert!(matches!(
+            validate_commitment(&no_coinbase),
+            Err(ValidationError::MissingCoinbase)
+        ));
+    }
+
+    #[test]
+    fn test_validate_transaction() {
+        use crate::test_utils::test_spending_transaction;
+        use bitcoin::{OutPoint, TxOut};
+
+        let transaction = test_spending_transaction(1000);
+        let input = transaction.input[0].clone();
+        let output = transaction.output[0].clone();
+        assert!(validate_transaction(&transaction).is_ok());
+
+        let invalid_reason = |transaction: &Transaction| match validate_transaction(transaction) {
+            Err(ValidationError::InvalidTransaction { reason, .. }) => reason,
+            other => panic!("Expected invalid transaction, got {other:?}"),
+        };
+
+        let mut no_inputs = transaction.clone();
+        no_inputs.input.clear();
+        assert_eq!(invalid_reason(&no_inputs), "no inputs");
+
+        let mut no_outputs = transaction.clone();
+        no_outputs.output.clear();
+        assert_eq!(invalid_reason(&no_outputs), "no outputs");
+
+        let mut coinbase = transaction.clone();
+        coinbase.input[0].previous_output = OutPoint::null();
+        assert_eq!(invalid_reason(&coinbase), "coinbase");
+
+        let mut duplicate_inputs = transaction.clone();
+        duplicate_inputs.input.push(input);
+        assert_eq!(invalid_reason(&duplicate_inputs), "duplicate inputs");
+
+        let mut too_much = transaction.clone();
+        too_much.output = vec![
+            TxOut {
+                value: Amount::MAX_MONEY,
+                ..output.clone()
+            },
+            output.clone(),
+        ];
+        assert_eq!(invalid_reason(&too_much), "output value out of range");
+
+        let mut too_large = transaction;
+        too_large.output[0].script_pubkey = ScriptBuf::from_bytes(vec![0; 100_001]);
+        assert_eq!(invalid_reason(&too_large), "too large");
+    }
 }
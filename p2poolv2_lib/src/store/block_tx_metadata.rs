@@ -69,6 +69,48 @@ impl Decodable for TxMetadata {
     }
 }
 
+/// Status of a share block in the store
+///
+/// Headers received during headers-first sync are stored before their
+/// bodies are downloaded. These are marked as HeaderOnly till the full
+/// share block is received and added to the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BlockStatus {
+    /// Only the header has been validated and stored
+    HeaderOnly,
+    /// Full share block, with transactions, is stored
+    Complete,
+}
+
+impl Encodable for BlockStatus {
+    #[inline]
+    fn consensus_encode<W: bitcoin::io::Write + ?Sized>(
+        &self,
+        w: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        let status: u8 = match self {
+            BlockStatus::HeaderOnly => 0,
+            BlockStatus::Complete => 1,
+        };
+        status.consensus_encode(w)
+    }
+}
+
+impl Decodable for BlockStatus {
+    #[inline]
+    fn consensus_decode<R: bitcoin::io::Read + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, bitcoin::consensus::encode::Error> {
+        match u8::consensus_decode(r)? {
+            0 => Ok(BlockStatus::HeaderOnly),
+            1 => Ok(BlockStatus::Complete),
+            _ => Err(bitcoin::consensus::encode::Error::ParseFailed(
+                "Invalid block status",
+            )),
+        }
+    }
+}
+
 /// ShareBlock metadata capturing if a share is valid and confirmed
 /// This is stored indexed by the blockhash, we can later optimise to internal key, if needed.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -77,6 +119,8 @@ pub struct BlockMetadata {
     pub height: Option<u32>,
     /// Total chain work up to the share block
     pub chain_work: Work,
+    /// Whether we have the full share block or only its header
+    pub status: BlockStatus,
 }
 
 impl Encodable for BlockMetadata {
@@ -99,6 +143,7 @@ impl Encodable for BlockMetadata {
         }
 
         len += self.chain_work.to_le_bytes().consensus_encode(w)?;
+        len += self.status.consensus_encode(w)?;
         Ok(len)
     }
 }
@@ -116,7 +161,49 @@ impl Decodable for BlockMetadata {
         };
 
         let chain_work = Work::from_le_bytes(<[u8; 32]>::consensus_decode(r)?);
+        // Metadata stored before block status was added ends after the
+        // chain work. Only full share blocks were stored then.
+        let status = match BlockStatus::consensus_decode(r) {
+            Ok(status) => status,
+            Err(bitcoin::consensus::encode::Error::Io(e))
+                if e.kind() == bitcoin::io::ErrorKind::UnexpectedEof =>
+            {
+                BlockStatus::Complete
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(BlockMetadata {
+            height,
+            chain_work,
+            status,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::encode;
+
+    #[test]
+    fn test_block_metadata_without_status_decodes_as_complete() {
+        let metadata = BlockMetadata {
+            height: Some(7),
+            chain_work: Work::from_le_bytes([3u8; 32]),
+            status: BlockStatus::HeaderOnly,
+        };
+        let mut serialized = encode::serialize(&metadata);
+        assert_eq!(
+            encode::deserialize::<BlockMetadata>(&serialized).unwrap(),
+            metadata
+        );
 
-        Ok(BlockMetadata { height, chain_work })
+        // Drop the status byte, as written by earlier versions
+        serialized.pop();
+        let legacy: BlockMetadata = encode::deserialize(&serialized).unwrap();
+        assert_eq!(legacy.height, Some(7));
+        assert_eq!(legacy.chain_work, metadata.chain_work);
+        assert_eq!(legacy.status, BlockStatus::Complete);
     }
 }
@@ -18,6 +18,7 @@
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ColumnFamily {
     Block,
+    ShareHeader,
     BlockTxids,
     BitcoinTxids,
     Inputs,
@@ -33,12 +34,17 @@ pub enum ColumnFamily {
     UserIndex,
     Metadata,
     UnspentOutputs,
+    Ledger,
+    ShareBalances,
+    PeerAddressBook,
+    BannedPeers,
 }
 
 impl ColumnFamily {
     pub fn as_str(&self) -> &'static str {
         match self {
             ColumnFamily::Block => "block",
+            ColumnFamily::ShareHeader => "share_header",
             ColumnFamily::BlockTxids => "block_txids",
             ColumnFamily::BitcoinTxids => "bitcoin_txids",
             ColumnFamily::Inputs => "inputs",
@@ -54,6 +60,10 @@ impl ColumnFamily {
             ColumnFamily::UserIndex => "user_index",
             ColumnFamily::Metadata => "metadata",
             ColumnFamily::UnspentOutputs => "unspent_outputs",
+            ColumnFamily::Ledger => "ledger",
+            ColumnFamily::ShareBalances => "share_balances",
+            ColumnFamily::PeerAddressBook => "peer_address_book",
+            ColumnFamily::BannedPeers => "banned_peers",
         }
     }
 }
@@ -0,0 +1,234 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use super::{Store, column_families::ColumnFamily};
+use crate::accounting::LedgerUpdate;
+use bitcoin::{BlockHash, CompressedPublicKey};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+
+/// Serialized size of a pubkey and its balance in share balances
+const SHARE_BALANCE_ENTRY_SIZE: usize = 33 + 8;
+
+impl Store {
+    /// Get outstanding ledger balances in satoshis, keyed by btcaddress
+    ///
+    /// Positive balances are owed to the address, negative balances
+    /// were paid ahead and are taken from its later entitlements.
+    pub fn get_ledger_balances(
+        &self,
+    ) -> Result<HashMap<String, i64>, Box<dyn Error + Send + Sync>> {
+        let ledger_cf = self.db.cf_handle(&ColumnFamily::Ledger).unwrap();
+        let mut balances = HashMap::new();
+        for item in self
+            .db
+            .iterator_cf(&ledger_cf, rocksdb::IteratorMode::Start)
+        {
+            let (key, value) = item?;
+            let address = String::from_utf8(key.to_vec())?;
+            let balance = i64::from_be_bytes(
+                value
+                    .as_ref()
+                    .try_into()
+                    .map_err(|_| format!("Invalid ledger balance for {address}"))?,
+            );
+            balances.insert(address, balance);
+        }
+        Ok(balances)
+    }
+
+    /// Apply balance changes to the ledger in a single write batch
+    /// Balances that drop to zero are removed. Errors if an update would
+    /// overflow a balance, in which case nothing is written.
+    pub fn apply_ledger_updates(
+        &self,
+        updates: &[LedgerUpdate],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let ledger_cf = self.db.cf_handle(&ColumnFamily::Ledger).unwrap();
+
+        // Fold updates per address first, so repeated addresses are read once
+        let mut deltas: HashMap<&str, i128> = HashMap::new();
+        for update in updates {
+            *deltas.entry(update.address.as_str()).or_insert(0) += update.delta as i128;
+        }
+
+        let mut batch = Self::get_write_batch();
+        for (address, delta) in deltas {
+            let current = match self.db.get_cf(&ledger_cf, address.as_bytes())? {
+                Some(value) => i64::from_be_bytes(
+                    value
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| format!("Invalid ledger balance for {address}"))?,
+                ),
+                None => 0,
+            };
+            let balance = i64::try_from(current as i128 + delta)
+                .map_err(|_| format!("Ledger balance for {address} out of range"))?;
+            if balance == 0 {
+                batch.delete_cf(&ledger_cf, address.as_bytes());
+            } else {
+                batch.put_cf(&ledger_cf, address.as_bytes(), balance.to_be_bytes());
+            }
+        }
+        self.commit_batch(batch)?;
+        Ok(())
+    }
+
+    /// Get the share chain ledger balances after a share, keyed by
+    /// miner pubkey
+    pub fn get_share_balances(
+        &self,
+        blockhash: &BlockHash,
+    ) -> Result<Option<BTreeMap<CompressedPublicKey, i64>>, Box<dyn Error + Send + Sync>> {
+        let share_balances_cf = self.db.cf_handle(&ColumnFamily::ShareBalances).unwrap();
+        let Some(value) = self.db.get_cf(&share_balances_cf, blockhash)? else {
+            return Ok(None);
+        };
+        let (entries, rest) = value.as_chunks::<SHARE_BALANCE_ENTRY_SIZE>();
+        if !rest.is_empty() {
+            return Err(format!("Invalid share balances for {blockhash}").into());
+        }
+        let mut balances = BTreeMap::new();
+        for entry in entries {
+            let (pubkey, balance) = entry.split_at(33);
+            balances.insert(
+                CompressedPublicKey::from_slice(pubkey)?,
+                i64::from_be_bytes(balance.try_into()?),
+            );
+        }
+        Ok(Some(balances))
+    }
+
+    /// Store the share chain ledger balances after a share
+    pub fn put_share_balances(
+        &self,
+        blockhash: &BlockHash,
+        balances: &BTreeMap<CompressedPublicKey, i64>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let share_balances_cf = self.db.cf_handle(&ColumnFamily::ShareBalances).unwrap();
+        let mut value = Vec::with_capacity(balances.len() * SHARE_BALANCE_ENTRY_SIZE);
+        for (pubkey, balance) in balances {
+            value.extend_from_slice(&pubkey.to_bytes());
+            value.extend_from_slice(&balance.to_be_bytes());
+        }
+        self.db.put_cf(&share_balances_cf, blockhash, value)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounting::LedgerUpdate;
+    use crate::store::Store;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{BlockHash, CompressedPublicKey};
+    use std::collections::BTreeMap;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_apply_ledger_updates() {
+        let temp_dir = tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+
+        assert!(store.get_ledger_balances().unwrap().is_empty());
+
+        store
+            .apply_ledger_updates(&[
+                LedgerUpdate {
+                    address: "addr1".to_string(),
+                    delta: 300,
+                },
+                LedgerUpdate {
+                    address: "addr2".to_string(),
+                    delta: 100,
+                },
+                LedgerUpdate {
+                    address: "addr1".to_string(),
+                    delta: 200,
+                },
+            ])
+            .unwrap();
+
+        let balances = store.get_ledger_balances().unwrap();
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances["addr1"], 500);
+        assert_eq!(balances["addr2"], 100);
+
+        // Settling a full balance removes the address
+        store
+            .apply_ledger_updates(&[
+                LedgerUpdate {
+                    address: "addr1".to_string(),
+                    delta: -150,
+                },
+                LedgerUpdate {
+                    address: "addr2".to_string(),
+                    delta: -100,
+                },
+            ])
+            .unwrap();
+
+        let balances = store.get_ledger_balances().unwrap();
+        assert_eq!(balances.len(), 1);
+        assert_eq!(balances["addr1"], 350);
+
+        // Paying ahead of entitlement leaves a negative balance
+        store
+            .apply_ledger_updates(&[LedgerUpdate {
+                address: "addr1".to_string(),
+                delta: -351,
+            }])
+            .unwrap();
+        assert_eq!(store.get_ledger_balances().unwrap()["addr1"], -1);
+
+        // Overflowing a balance fails without changing the ledger
+        assert!(
+            store
+                .apply_ledger_updates(&[LedgerUpdate {
+                    address: "addr1".to_string(),
+                    delta: i64::MIN,
+                }])
+                .is_err()
+        );
+        assert_eq!(store.get_ledger_balances().unwrap()["addr1"], -1);
+    }
+
+    #[test]
+    fn test_share_balances() {
+        let temp_dir = tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+        let blockhash = BlockHash::from_byte_array([1; 32]);
+
+        assert!(store.get_share_balances(&blockhash).unwrap().is_none());
+
+        let miner_a: CompressedPublicKey =
+            "020202020202020202020202020202020202020202020202020202020202020202"
+                .parse()
+                .unwrap();
+        let miner_b: CompressedPublicKey =
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let balances = BTreeMap::from([(miner_a, 1_000), (miner_b, -1_000)]);
+        store.put_share_balances(&blockhash, &balances).unwrap();
+
+        assert_eq!(
+            store.get_share_balances(&blockhash).unwrap(),
+            Some(balances)
+        );
+    }
+}
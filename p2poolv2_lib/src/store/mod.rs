@@ -17,7 +17,7 @@
 use crate::accounting::simple_pplns::SimplePplnsShare;
 use crate::shares::chain::chain_store::COMMON_ANCESTOR_DEPTH;
 use crate::shares::share_block::{ShareBlock, ShareHeader, StorageShareBlock, Txids};
-use crate::store::block_tx_metadata::{BlockMetadata, TxMetadata};
+use crate::store::block_tx_metadata::{BlockMetadata, BlockStatus, TxMetadata};
 use crate::store::column_families::ColumnFamily;
 use crate::store::user::StoredUser;
 use crate::utils::snowflake_simplified::get_next_id;
@@ -32,11 +32,16 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::debug;
 
 pub mod background_tasks;
-mod block_tx_metadata;
+pub mod block_tx_metadata;
 pub mod column_families;
+mod ledger;
+pub mod peer_book;
 mod pplns_shares;
 pub mod user;
 
+/// Metadata key for the best header chain tip
+const HEADER_TIP_METADATA_KEY: &str = "header_tip";
+
 /// A store for share blocks.
 /// RocksDB as is used as the underlying database.
 /// We use column families to store different types of data, so that compactions are independent for each type.
@@ -53,6 +58,8 @@ pub struct Store {
     genesis_block_hash: Arc<RwLock<Option<BlockHash>>>,
     chain_tip: Arc<RwLock<BlockHash>>,
     tips: Arc<RwLock<HashSet<BlockHash>>>,
+    // Tip of the best header chain found during headers-first sync
+    header_tip: Arc<RwLock<Option<BlockHash>>>,
 }
 
 /// Merge operator for appending BlockHashes to a Vec<BlockHash>
@@ -91,6 +98,8 @@ impl Store {
     pub fn new(path: String, read_only: bool) -> Result<Self, Box<dyn Error + Send + Sync>> {
         // for now we use default options for all column families, we can tweak this later based on performance testing
         let block_cf = ColumnFamilyDescriptor::new(ColumnFamily::Block, RocksDbOptions::default());
+        let share_header_cf =
+            ColumnFamilyDescriptor::new(ColumnFamily::ShareHeader, RocksDbOptions::default());
         let block_txids_cf =
             ColumnFamilyDescriptor::new(ColumnFamily::BlockTxids, RocksDbOptions::default());
         let inputs_cf =
@@ -127,8 +136,21 @@ impl Store {
         let unspent_outputs_cf =
             ColumnFamilyDescriptor::new(ColumnFamily::UnspentOutputs, RocksDbOptions::default());
 
+        let ledger_cf =
+            ColumnFamilyDescriptor::new(ColumnFamily::Ledger, RocksDbOptions::default());
+
+        let share_balances_cf =
+            ColumnFamilyDescriptor::new(ColumnFamily::ShareBalances, RocksDbOptions::default());
+
+        let peer_address_book_cf =
+            ColumnFamilyDescriptor::new(ColumnFamily::PeerAddressBook, RocksDbOptions::default());
+
+        let banned_peers_cf =
+            ColumnFamilyDescriptor::new(ColumnFamily::BannedPeers, RocksDbOptions::default());
+
         let cfs = vec![
             block_cf,
+            share_header_cf,
             block_txids_cf,
             inputs_cf,
             outputs_cf,
@@ -142,6 +164,10 @@ impl Store {
             user_index_cf,
             metadata_cf,
             unspent_outputs_cf,
+            ledger_cf,
+            share_balances_cf,
+            peer_address_book_cf,
+            banned_peers_cf,
         ];
 
         // for the db too, we use default options for now
@@ -160,6 +186,7 @@ impl Store {
             genesis_block_hash: Arc::new(RwLock::new(None)),
             chain_tip: Arc::new(RwLock::new(BlockHash::all_zeros())),
             tips: Arc::new(RwLock::new(HashSet::new())),
+            header_tip: Arc::new(RwLock::new(None)),
         };
         Ok(store)
     }
@@ -175,6 +202,23 @@ impl Store {
         self.db.write(batch)
     }
 
+    /// Get a value from the metadata column family
+    pub fn get_metadata(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let metadata_cf = self.db.cf_handle(&ColumnFamily::Metadata).unwrap();
+        Ok(self.db.get_cf(&metadata_cf, key.as_bytes())?)
+    }
+
+    /// Store a value in the metadata column family
+    pub fn put_metadata(
+        &self,
+        key: &str,
+        value: &[u8],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let metadata_cf = self.db.cf_handle(&ColumnFamily::Metadata).unwrap();
+        self.db.put_cf(&metadata_cf, key.as_bytes(), value)?;
+        Ok(())
+    }
+
     /// Store a user by btcaddress, returns the user ID
     pub fn add_user(&self, btcaddress: String) -> Result<u64, Box<dyn Error + Send + Sync>> {
         let user_cf = self.db.cf_handle(&ColumnFamily::User).unwrap();
@@ -358,9 +402,14 @@ impl Store {
         let block_metadata = BlockMetadata {
             height: Some(height),
             chain_work,
+            status: BlockStatus::Complete,
         };
         self.set_block_metadata(&blockhash, &block_metadata, batch)?;
 
+        // Header is now stored with the block, drop any header only entry
+        let share_header_cf = self.db.cf_handle(&ColumnFamily::ShareHeader).unwrap();
+        batch.delete_cf::<&[u8]>(&share_header_cf, blockhash.as_ref());
+
         // Add the share block itself
         let storage_share_block: StorageShareBlock = share.into();
         let block_cf = self.db.cf_handle(&ColumnFamily::Block).unwrap();
@@ -371,6 +420,56 @@ impl Store {
         Ok(())
     }
 
+    /// Add a share header received during headers-first sync
+    ///
+    /// Only the header and block metadata with HeaderOnly status are
+    /// stored. The block and height indexes are updated when the full
+    /// share block is added using add_share.
+    pub fn add_share_header(
+        &self,
+        header: ShareHeader,
+        height: u32,
+        chain_work: Work,
+        batch: &mut rocksdb::WriteBatch,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let blockhash = header.block_hash();
+        debug!(
+            "Adding share header to store: {:?} at height: {}",
+            blockhash, height
+        );
+
+        let block_metadata = BlockMetadata {
+            height: Some(height),
+            chain_work,
+            status: BlockStatus::HeaderOnly,
+        };
+        self.set_block_metadata(&blockhash, &block_metadata, batch)?;
+
+        let share_header_cf = self.db.cf_handle(&ColumnFamily::ShareHeader).unwrap();
+        let mut encoded_header = Vec::new();
+        header.consensus_encode(&mut encoded_header)?;
+        batch.put_cf::<&[u8], Vec<u8>>(&share_header_cf, blockhash.as_ref(), encoded_header);
+        Ok(())
+    }
+
+    /// Get a share header for a blockhash, looking up full share
+    /// blocks first and then header only entries.
+    pub fn get_share_header(&self, blockhash: &BlockHash) -> Option<ShareHeader> {
+        if let Ok(mut headers) = self.get_share_headers(&[*blockhash])
+            && let Some(header) = headers.pop()
+        {
+            return Some(header);
+        }
+        let share_header_cf = self.db.cf_handle(&ColumnFamily::ShareHeader).unwrap();
+        match self
+            .db
+            .get_cf::<&[u8]>(&share_header_cf, blockhash.as_ref())
+        {
+            Ok(Some(header)) => encode::deserialize(&header).ok(),
+            Ok(None) | Err(_) => None,
+        }
+    }
+
     /// Add PPLNS Share to pplns_share_cf
     /// btcaddress and workername are skipped during serialization (serde(skip)) to minimize storage
     ///
@@ -537,6 +636,15 @@ impl Store {
         Ok(txs_metadata)
     }
 
+    /// Add a single share chain transaction received from a peer.
+    /// The transaction is stored unconfirmed till a share including it is added.
+    pub fn add_transaction(&self, tx: &Transaction) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut batch = Self::get_write_batch();
+        self.add_sharechain_txs(std::slice::from_ref(tx), false, &mut batch)?;
+        self.commit_batch(batch)?;
+        Ok(())
+    }
+
     /// Marks the transaction as successfully validated, this prevents us validating it again.
     /// We only validate what is dependent on the chain state. Once valid, the txid is never made invalid.
     fn mark_transaction_valid(
@@ -1324,6 +1432,15 @@ impl Store {
             .collect()
     }
 
+    /// Check which txids from the provided list are missing from the store
+    pub fn get_missing_txids(&self, txids: &[Txid]) -> Vec<Txid> {
+        txids
+            .iter()
+            .filter(|txid| self.get_tx_metadata(txid).is_err())
+            .cloned()
+            .collect()
+    }
+
     /// Set the block metadata for a blockhash
     fn set_block_metadata(
         &self,
@@ -1393,6 +1510,19 @@ impl Store {
         self.tips.write().unwrap().remove(hash)
     }
 
+    /// Get the best header chain tip, if headers have been received
+    pub fn get_header_tip(&self) -> Option<BlockHash> {
+        *self.header_tip.read().unwrap()
+    }
+
+    /// Set the best header chain tip, persisting it so headers-first
+    /// sync resumes from it after a restart
+    pub fn set_header_tip(&self, hash: BlockHash) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.put_metadata(HEADER_TIP_METADATA_KEY, &consensus::serialize(&hash))?;
+        *self.header_tip.write().unwrap() = Some(hash);
+        Ok(())
+    }
+
     /// Get total work from chain state
     pub fn get_total_work(&self) -> Result<Work, Box<dyn Error + Send + Sync>> {
         let tip = self.get_block_metadata(&self.chain_tip.read().unwrap())?;
@@ -1443,6 +1573,9 @@ impl Store {
             self.set_chain_tip(chain_tip);
             self.update_tips(tips);
         }
+        if let Some(header_tip) = self.get_metadata(HEADER_TIP_METADATA_KEY)? {
+            *self.header_tip.write().unwrap() = Some(encode::deserialize(&header_tip)?);
+        }
         debug!(
             "Initialized chain state: tip={:?}, work={}, tips_count={}",
             self.get_chain_tip(),
@@ -1794,6 +1927,29 @@ mod tests {
         assert_eq!(store.get_tx(&tx2_id).unwrap(), tx2);
     }
 
+    #[test]
+    fn test_add_transaction_and_get_missing_txids() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+
+        let tx = crate::test_utils::test_coinbase_transaction();
+        let txid = tx.compute_txid();
+        let unknown_txid = Txid::from_byte_array([1; 32]);
+
+        assert_eq!(
+            store.get_missing_txids(&[txid, unknown_txid]),
+            vec![txid, unknown_txid]
+        );
+
+        store.add_transaction(&tx).unwrap();
+
+        assert_eq!(
+            store.get_missing_txids(&[txid, unknown_txid]),
+            vec![unknown_txid]
+        );
+        assert_eq!(store.get_tx(&txid).unwrap(), tx);
+    }
+
     #[test]
     fn test_get_share_header() {
         // Create a new store with a temporary path
@@ -1838,6 +1994,49 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_add_share_header_then_share() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+
+        let share = TestShareBlockBuilder::new()
+            .miner_pubkey("020202020202020202020202020202020202020202020202020202020202020202")
+            .build();
+        let blockhash = share.block_hash();
+
+        let mut batch = rocksdb::WriteBatch::default();
+        store
+            .add_share_header(share.header.clone(), 1, share.header.get_work(), &mut batch)
+            .unwrap();
+        store.commit_batch(batch).unwrap();
+
+        // Header is available, the share block is not
+        assert_eq!(
+            store.get_share_header(&blockhash),
+            Some(share.header.clone())
+        );
+        assert!(store.get_share(&blockhash).is_none());
+        assert_eq!(store.get_missing_blockhashes(&[blockhash]), vec![blockhash]);
+        let metadata = store.get_block_metadata(&blockhash).unwrap();
+        assert_eq!(metadata.status, BlockStatus::HeaderOnly);
+        assert_eq!(metadata.height, Some(1));
+
+        let mut batch = rocksdb::WriteBatch::default();
+        store
+            .add_share(share.clone(), 1, share.header.get_work(), true, &mut batch)
+            .unwrap();
+        store.commit_batch(batch).unwrap();
+
+        assert_eq!(
+            store.get_share_header(&blockhash),
+            Some(share.header.clone())
+        );
+        assert!(store.get_share(&blockhash).is_some());
+        assert!(store.get_missing_blockhashes(&[blockhash]).is_empty());
+        let metadata = store.get_block_metadata(&blockhash).unwrap();
+        assert_eq!(metadata.status, BlockStatus::Complete);
+    }
+
     #[test]
     fn test_get_share_header_nonexistent() {
         // Create a new store with a temporary path
@@ -2502,8 +2701,13 @@ mod tests {
         store.remove_tip(&share2.block_hash());
         assert!(!store.get_tips().contains(&share2.block_hash()));
 
+        // Header tip is persisted, forget the cached value to reload it
+        store.set_header_tip(share3.block_hash()).unwrap();
+        *store.header_tip.write().unwrap() = None;
+
         // Test initialization from store
         store.init_chain_state_from_store(genesis_hash).unwrap();
+        assert_eq!(store.get_header_tip(), Some(share3.block_hash()));
 
         // After initialization, tip should be set to last block in main chain
         assert_eq!(store.get_chain_tip(), share3.block_hash());
@@ -0,0 +1,269 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use super::{Store, column_families::ColumnFamily};
+use bitcoin::VarInt;
+use bitcoin::consensus::encode::{self, Decodable, Encodable};
+use std::error::Error;
+
+/// Maximum number of addresses remembered for a single peer
+const MAX_ADDRESSES_PER_PEER: usize = 8;
+
+/// Address book entry for a peer we learned about via identify or kademlia
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerRecord {
+    /// Multiaddrs the peer is reachable on, most recently learned first
+    pub addresses: Vec<String>,
+    /// Last time we heard from the peer, in seconds since epoch
+    pub last_seen: u64,
+    /// Number of successful connections to the peer
+    pub successes: u32,
+    /// Number of failed dials to the peer
+    pub failures: u32,
+}
+
+impl PeerRecord {
+    /// Score used to pick peers to redial, peers that connect reliably first
+    pub fn score(&self) -> i64 {
+        self.successes as i64 - self.failures as i64
+    }
+}
+
+impl Encodable for PeerRecord {
+    fn consensus_encode<W: bitcoin::io::Write + ?Sized>(
+        &self,
+        w: &mut W,
+    ) -> Result<usize, bitcoin::io::Error> {
+        let mut len = 0;
+        len += VarInt(self.addresses.len() as u64).consensus_encode(w)?;
+        for address in &self.addresses {
+            len += address.consensus_encode(w)?;
+        }
+        len += self.last_seen.consensus_encode(w)?;
+        len += self.successes.consensus_encode(w)?;
+        len += self.failures.consensus_encode(w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for PeerRecord {
+    #[inline]
+    fn consensus_decode<R: bitcoin::io::Read + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, bitcoin::consensus::encode::Error> {
+        let count = VarInt::consensus_decode(r)?.0 as usize;
+        let mut addresses = Vec::with_capacity(count.min(MAX_ADDRESSES_PER_PEER));
+        for _ in 0..count {
+            addresses.push(String::consensus_decode(r)?);
+        }
+        Ok(PeerRecord {
+            addresses,
+            last_seen: u64::consensus_decode(r)?,
+            successes: u32::consensus_decode(r)?,
+            failures: u32::consensus_decode(r)?,
+        })
+    }
+}
+
+impl Store {
+    /// Get all peers in the address book, keyed by their peer id
+    pub fn get_peer_records(
+        &self,
+    ) -> Result<Vec<(String, PeerRecord)>, Box<dyn Error + Send + Sync>> {
+        let peer_cf = self.db.cf_handle(&ColumnFamily::PeerAddressBook).unwrap();
+        let mut records = Vec::new();
+        for item in self.db.iterator_cf(&peer_cf, rocksdb::IteratorMode::Start) {
+            let (key, value) = item?;
+            let record: PeerRecord = encode::deserialize(&value)?;
+            records.push((String::from_utf8(key.to_vec())?, record));
+        }
+        Ok(records)
+    }
+
+    /// Get the address book entry for a peer
+    pub fn get_peer_record(
+        &self,
+        peer_id: &str,
+    ) -> Result<Option<PeerRecord>, Box<dyn Error + Send + Sync>> {
+        let peer_cf = self.db.cf_handle(&ColumnFamily::PeerAddressBook).unwrap();
+        match self.db.get_cf(&peer_cf, peer_id.as_bytes())? {
+            Some(value) => Ok(Some(encode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_peer_record(
+        &self,
+        peer_id: &str,
+        record: &PeerRecord,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let peer_cf = self.db.cf_handle(&ColumnFamily::PeerAddressBook).unwrap();
+        self.db
+            .put_cf(&peer_cf, peer_id.as_bytes(), encode::serialize(record))?;
+        Ok(())
+    }
+
+    /// Record addresses learned for a peer and mark it as seen at `now`
+    /// Newly learned addresses are moved to the front and the list is
+    /// capped at MAX_ADDRESSES_PER_PEER.
+    pub fn add_peer_addresses(
+        &self,
+        peer_id: &str,
+        addresses: &[String],
+        now: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut record = self.get_peer_record(peer_id)?.unwrap_or_default();
+        let mut merged = addresses.to_vec();
+        merged.dedup();
+        merged.extend(
+            record
+                .addresses
+                .into_iter()
+                .filter(|address| !addresses.contains(address)),
+        );
+        merged.truncate(MAX_ADDRESSES_PER_PEER);
+        record.addresses = merged;
+        record.last_seen = now;
+        self.put_peer_record(peer_id, &record)
+    }
+
+    /// Record the outcome of a connection attempt to a peer
+    pub fn record_peer_connection(
+        &self,
+        peer_id: &str,
+        success: bool,
+        now: u64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut record = self.get_peer_record(peer_id)?.unwrap_or_default();
+        if success {
+            record.successes = record.successes.saturating_add(1);
+            record.last_seen = now;
+        } else {
+            record.failures = record.failures.saturating_add(1);
+        }
+        self.put_peer_record(peer_id, &record)
+    }
+
+    /// Get banned peers with the time their ban ends, in seconds since epoch
+    pub fn get_banned_peers(&self) -> Result<Vec<(String, u64)>, Box<dyn Error + Send + Sync>> {
+        let banned_cf = self.db.cf_handle(&ColumnFamily::BannedPeers).unwrap();
+        let mut banned = Vec::new();
+        for item in self
+            .db
+            .iterator_cf(&banned_cf, rocksdb::IteratorMode::Start)
+        {
+            let (key, value) = item?;
+            let until: u64 = encode::deserialize(&value)?;
+            banned.push((String::from_utf8(key.to_vec())?, until));
+        }
+        Ok(banned)
+    }
+
+    /// Ban a peer until the given time, in seconds since epoch
+    pub fn ban_peer(&self, peer_id: &str, until: u64) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let banned_cf = self.db.cf_handle(&ColumnFamily::BannedPeers).unwrap();
+        self.db
+            .put_cf(&banned_cf, peer_id.as_bytes(), encode::serialize(&until))?;
+        Ok(())
+    }
+
+    /// Lift the ban on a peer, a no-op if the peer is not banned
+    pub fn unban_peer(&self, peer_id: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let banned_cf = self.db.cf_handle(&ColumnFamily::BannedPeers).unwrap();
+        self.db.delete_cf(&banned_cf, peer_id.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_peer_record_serialization() {
+        let record = PeerRecord {
+            addresses: vec![
+                "/ip4/127.0.0.1/tcp/6884".to_string(),
+                "/ip4/10.0.0.1/tcp/6884".to_string(),
+            ],
+            last_seen: 1_700_000_000,
+            successes: 3,
+            failures: 1,
+        };
+        let decoded: PeerRecord = encode::deserialize(&encode::serialize(&record)).unwrap();
+        assert_eq!(decoded, record);
+    }
+
+    #[test]
+    fn test_peer_address_book() {
+        let temp_dir = tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+
+        assert!(store.get_peer_records().unwrap().is_empty());
+
+        store
+            .add_peer_addresses("peer1", &["/ip4/127.0.0.1/tcp/6884".to_string()], 100)
+            .unwrap();
+        store
+            .add_peer_addresses(
+                "peer1",
+                &[
+                    "/ip4/10.0.0.1/tcp/6884".to_string(),
+                    "/ip4/127.0.0.1/tcp/6884".to_string(),
+                ],
+                200,
+            )
+            .unwrap();
+        store.record_peer_connection("peer1", true, 300).unwrap();
+        store.record_peer_connection("peer1", false, 400).unwrap();
+        store.record_peer_connection("peer2", false, 400).unwrap();
+
+        let record = store.get_peer_record("peer1").unwrap().unwrap();
+        assert_eq!(
+            record.addresses,
+            vec![
+                "/ip4/10.0.0.1/tcp/6884".to_string(),
+                "/ip4/127.0.0.1/tcp/6884".to_string()
+            ]
+        );
+        assert_eq!(record.last_seen, 300);
+        assert_eq!(record.successes, 1);
+        assert_eq!(record.failures, 1);
+
+        let records = store.get_peer_records().unwrap();
+        assert_eq!(records.len(), 2);
+        let peer2 = records.iter().find(|(peer, _)| peer == "peer2").unwrap();
+        assert!(peer2.1.addresses.is_empty());
+        assert_eq!(peer2.1.score(), -1);
+    }
+
+    #[test]
+    fn test_banned_peers() {
+        let temp_dir = tempdir().unwrap();
+        let store = Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap();
+
+        store.ban_peer("peer1", 1000).unwrap();
+        store.ban_peer("peer2", 2000).unwrap();
+        store.unban_peer("peer2").unwrap();
+        store.unban_peer("peer3").unwrap();
+
+        assert_eq!(
+            store.get_banned_peers().unwrap(),
+            vec![("peer1".to_string(), 1000)]
+        );
+    }
+}
@@ -42,6 +42,13 @@ pub enum ClientConnectionCommand {
         addr: SocketAddr,
         message: Arc<String>,
     },
+    Addrs {
+        response: oneshot::Sender<Vec<SocketAddr>>,
+    },
+    Count {
+        response: oneshot::Sender<usize>,
+    },
+    ShutdownAll,
 }
 
 /// A handle to interact with the ClientConnections actor
@@ -81,6 +88,32 @@ impl ClientConnectionsHandle {
         let cmd = ClientConnectionCommand::SendToClient { addr, message };
         self.cmd_tx.send(cmd).await.is_ok()
     }
+
+    /// Get the addresses of all open client connections
+    pub async fn addrs(&self) -> Vec<SocketAddr> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .cmd_tx
+            .send(ClientConnectionCommand::Addrs { response: tx })
+            .await;
+        rx.await.unwrap_or_default()
+    }
+
+    /// Get the number of open client connections
+    pub async fn count(&self) -> usize {
+        let (tx, rx) = oneshot::channel();
+        let _ = self
+            .cmd_tx
+            .send(ClientConnectionCommand::Count { response: tx })
+            .await;
+        rx.await.unwrap_or_default()
+    }
+
+    /// Send a shutdown signal to all clients.
+    /// Don't wait for the actor to respond. Fire and forget.
+    pub async fn shutdown_all(&self) {
+        let _ = self.cmd_tx.send(ClientConnectionCommand::ShutdownAll).await;
+    }
 }
 
 #[cfg(test)]
@@ -89,6 +122,9 @@ mockall::mock! {
         pub async fn add(&self, addr: SocketAddr) -> (mpsc::Receiver<Arc<String>>, oneshot::Receiver<()>);
         pub async fn send_to_all(&self, message: Arc<String>);
         pub async fn send_to_client(&self, addr: SocketAddr, message: Arc<String>) -> bool;
+        pub async fn addrs(&self) -> Vec<SocketAddr>;
+        pub async fn count(&self) -> usize;
+        pub async fn shutdown_all(&self);
     }
 }
 
@@ -169,6 +205,31 @@ impl ClientConnections {
         }
         false
     }
+
+    /// Forgets clients whose connection has ended and dropped its receiver.
+    fn prune(&mut self) {
+        self.clients
+            .retain(|_, channels| !channels.message_tx.is_closed());
+    }
+
+    /// Returns the addresses of all open client connections.
+    fn addrs(&mut self) -> Vec<SocketAddr> {
+        self.prune();
+        self.clients.keys().copied().collect()
+    }
+
+    /// Returns the number of open client connections.
+    fn count(&mut self) -> usize {
+        self.prune();
+        self.clients.len()
+    }
+
+    /// Removes all client connections, sending each a shutdown signal.
+    fn shutdown_all(&mut self) {
+        for (_, channels) in self.clients.drain() {
+            let _ = channels.shutdown_tx.send(());
+        }
+    }
 }
 
 /// Spawn a new ClientConnections actor and return a handle to it
@@ -191,6 +252,15 @@ pub async fn start_connections_handler() -> ClientConnectionsHandle {
                 ClientConnectionCommand::SendToClient { addr, message } => {
                     connections.send_to_client(&addr, message);
                 }
+                ClientConnectionCommand::Addrs { response } => {
+                    let _ = response.send(connections.addrs());
+                }
+                ClientConnectionCommand::Count { response } => {
+                    let _ = response.send(connections.count());
+                }
+                ClientConnectionCommand::ShutdownAll => {
+                    connections.shutdown_all();
+                }
             }
         }
     });
@@ -290,6 +360,37 @@ mod tests {
         assert!(!success);
     }
 
+    #[test]
+    fn test_client_connections_count_skips_closed_connections() {
+        let mut connections = ClientConnections::default();
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+
+        let (message_rx1, _) = connections.add(addr1);
+        let (_message_rx2, _) = connections.add(addr2);
+        assert_eq!(connections.count(), 2);
+
+        // The connection task drops its receiver when it ends
+        drop(message_rx1);
+        assert_eq!(connections.count(), 1);
+        assert_eq!(connections.addrs(), vec![addr2]);
+    }
+
+    #[test]
+    fn test_client_connections_shutdown_all() {
+        let mut connections = ClientConnections::default();
+        let addr1 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let addr2 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
+
+        let (_message_rx1, mut shutdown_rx1) = connections.add(addr1);
+        let (_message_rx2, mut shutdown_rx2) = connections.add(addr2);
+
+        connections.shutdown_all();
+        assert_eq!(connections.clients.len(), 0);
+        assert!(shutdown_rx1.try_recv().is_ok());
+        assert!(shutdown_rx2.try_recv().is_ok());
+    }
+
     #[tokio::test]
     async fn test_client_connections_handle() {
         // Spawn a new ClientConnections actor
@@ -0,0 +1,216 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::stratum::client_connections::ClientConnectionsHandle;
+use crate::stratum::messages::{Message, ReconnectNotification};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+/// Number of clients sent client.reconnect at a time
+const DEFAULT_BATCH_SIZE: usize = 100;
+/// Pause between batches, so reconnecting miners don't all land on the
+/// target pool at once
+const DEFAULT_BATCH_INTERVAL_MS: u64 = 1000;
+/// Time given to miners to disconnect on their own before their
+/// connections are closed
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+/// Time given to connections to finish in-flight messages after the
+/// shutdown signal
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval to check if connections have closed
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A request to drain stratum connections before shutting down the node.
+///
+/// host, port and wait are passed on to miners in client.reconnect. They
+/// are positional, so port requires host and wait requires port.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DrainRequest {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub wait: Option<u64>,
+    pub batch_size: Option<usize>,
+    pub batch_interval_ms: Option<u64>,
+    pub timeout_secs: Option<u64>,
+}
+
+impl DrainRequest {
+    /// Check the request can be turned into a client.reconnect message
+    pub fn validate(&self) -> Result<(), String> {
+        if self.port.is_some() && self.host.is_none() {
+            return Err("port requires host".into());
+        }
+        if self.wait.is_some() && self.port.is_none() {
+            return Err("wait requires host and port".into());
+        }
+        if self.batch_size == Some(0) {
+            return Err("batch_size must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+/// Ask all connected miners to reconnect and wait for their connections to
+/// end.
+///
+/// client.reconnect is sent in batches of batch_size, batch_interval_ms
+/// apart. SV2 connections translate it to their Reconnect message. Connections handle one message at a time, so a connection ends
+/// only after its in-flight submits are processed. Connections still open
+/// after timeout_secs are sent a shutdown signal.
+///
+/// Returns the number of connections that had to be closed.
+pub async fn drain_connections(
+    connections_handle: &ClientConnectionsHandle,
+    request: &DrainRequest,
+) -> usize {
+    let notification = Message::Reconnect(ReconnectNotification::new(
+        request.host.as_deref(),
+        request.port,
+        request.wait,
+    ));
+    let message = Arc::new(serde_json::to_string(&notification).unwrap());
+    let batch_size = request.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1);
+    let batch_interval = Duration::from_millis(
+        request
+            .batch_interval_ms
+            .unwrap_or(DEFAULT_BATCH_INTERVAL_MS),
+    );
+    let timeout = Duration::from_secs(request.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+
+    let addrs = connections_handle.addrs().await;
+    info!("Sending client.reconnect to {} clients", addrs.len());
+    for (i, batch) in addrs.chunks(batch_size).enumerate() {
+        if i > 0 {
+            tokio::time::sleep(batch_interval).await;
+        }
+        for addr in batch {
+            connections_handle
+                .send_to_client(*addr, message.clone())
+                .await;
+        }
+    }
+
+    if wait_for_close(connections_handle, timeout).await {
+        return 0;
+    }
+    let remaining = connections_handle.count().await;
+    info!("Closing {remaining} clients that did not reconnect");
+    connections_handle.shutdown_all().await;
+    wait_for_close(connections_handle, CLOSE_TIMEOUT).await;
+    remaining
+}
+
+/// Wait until there are no open connections. Returns false on timeout.
+async fn wait_for_close(connections_handle: &ClientConnectionsHandle, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if connections_handle.count().await == 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stratum::client_connections::start_connections_handler;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_drain_request_validate() {
+        assert!(DrainRequest::default().validate().is_ok());
+        let request = DrainRequest {
+            host: Some("pool.example.com".into()),
+            port: Some(3333),
+            wait: Some(5),
+            ..Default::default()
+        };
+        assert!(request.validate().is_ok());
+
+        let request = DrainRequest {
+            port: Some(3333),
+            ..Default::default()
+        };
+        assert_eq!(request.validate().unwrap_err(), "port requires host");
+
+        let request = DrainRequest {
+            host: Some("pool.example.com".into()),
+            wait: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(
+            request.validate().unwrap_err(),
+            "wait requires host and port"
+        );
+
+        let request = DrainRequest {
+            batch_size: Some(0),
+            ..Default::default()
+        };
+        assert!(request.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drain_connections_sends_reconnect_and_closes_stragglers() {
+        let handle = start_connections_handler().await;
+        let addr1: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let addr2: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+
+        // The first client disconnects when asked to reconnect
+        let (mut message_rx1, _shutdown_rx1) = handle.add(addr1).await;
+        let client1 = tokio::spawn(async move { message_rx1.recv().await });
+
+        // The second client ignores client.reconnect and waits for shutdown
+        let (mut message_rx2, shutdown_rx2) = handle.add(addr2).await;
+        let client2 = tokio::spawn(async move {
+            let message = message_rx2.recv().await;
+            let shutdown = shutdown_rx2.await;
+            (message, shutdown.is_ok())
+        });
+
+        let request = DrainRequest {
+            host: Some("pool.example.com".into()),
+            port: Some(3333),
+            batch_size: Some(1),
+            batch_interval_ms: Some(10),
+            timeout_secs: Some(1),
+            ..Default::default()
+        };
+        let closed = drain_connections(&handle, &request).await;
+        assert_eq!(closed, 1);
+        assert_eq!(handle.count().await, 0);
+
+        let expected = r#"{"method":"client.reconnect","params":["pool.example.com",3333]}"#;
+        assert_eq!(client1.await.unwrap().unwrap().as_str(), expected);
+        let (message, shutdown) = client2.await.unwrap();
+        assert_eq!(message.unwrap().as_str(), expected);
+        assert!(shutdown);
+    }
+
+    #[tokio::test]
+    async fn test_drain_connections_without_clients() {
+        let handle = start_connections_handler().await;
+        let closed = drain_connections(&handle, &DrainRequest::default()).await;
+        assert_eq!(closed, 0);
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::stratum::difficulty_adjuster::DifficultyAdjusterTrait;
+use crate::stratum::error::Error;
+use crate::stratum::messages::{Message, Response, SimpleRequest};
+use crate::stratum::session::Session;
+use serde_json::json;
+use tracing::debug;
+
+/// Handle the "mining.extranonce.subscribe" message
+/// The miner asks to be sent mining.set_extranonce when the session's extranonce1
+/// changes. A session keeps its extranonce1 for the life of the connection, so we
+/// only record the subscription and acknowledge it.
+pub async fn handle_extranonce_subscribe<'a, D: DifficultyAdjusterTrait>(
+    message: SimpleRequest<'a>,
+    session: &mut Session<D>,
+) -> Result<Vec<Message<'a>>, Error> {
+    debug!("Handling mining.extranonce.subscribe message");
+    session.extranonce_subscribed = true;
+    Ok(vec![Message::Response(Response::new_ok(
+        message.id,
+        json!(true),
+    ))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stratum::difficulty_adjuster::DifficultyAdjuster;
+    use crate::stratum::messages::Id;
+    use std::borrow::Cow;
+
+    #[tokio::test]
+    async fn test_handle_extranonce_subscribe() {
+        let mut session = Session::<DifficultyAdjuster>::new(1, 1, None, 0x1fffe000);
+        let message = SimpleRequest {
+            id: Some(Id::Number(3)),
+            method: Cow::Borrowed("mining.extranonce.subscribe"),
+            params: Cow::Owned(vec![]),
+        };
+
+        let response = handle_extranonce_subscribe(message, &mut session)
+            .await
+            .unwrap();
+
+        match &response[..] {
+            [Message::Response(response)] => {
+                assert_eq!(response.id, Some(Id::Number(3)));
+                assert_eq!(response.result, Some(json!(true)));
+            }
+            _ => panic!("Expected a Response message"),
+        }
+        assert!(session.extranonce_subscribed);
+    }
+}
@@ -23,6 +23,8 @@ use crate::stratum::server::StratumContext;
 use crate::stratum::session::Session;
 use authorize_response::handle_authorize;
 use configure::handle_configure;
+use extranonce_subscribe::handle_extranonce_subscribe;
+use optional_methods::{handle_get_transactions, handle_get_version, handle_multi_version};
 use submit::handle_submit;
 use subscribe::handle_subscribe;
 use suggest_difficulty::handle_suggest_difficulty;
@@ -30,6 +32,8 @@ use tracing::debug;
 
 pub mod authorize_response;
 pub mod configure;
+pub mod extranonce_subscribe;
+pub mod optional_methods;
 pub mod submit;
 pub mod subscribe;
 pub mod suggest_difficulty;
@@ -50,6 +54,9 @@ pub(crate) async fn handle_message<'a, D: DifficultyAdjusterTrait>(
 ) -> Result<Vec<Message<'a>>, Error> {
     match message {
         Request::MiningConfigureRequest(_) => handle_configure(message, session).await,
+        Request::MultiVersionRequest(multi_version_request) => {
+            handle_multi_version(multi_version_request).await
+        }
         Request::SuggestDifficultyRequest(suggest_difficulty_request) => {
             handle_suggest_difficulty(suggest_difficulty_request, session).await
         }
@@ -70,6 +77,9 @@ async fn handle_simple_request<'a, D: DifficultyAdjusterTrait>(
         "mining.subscribe" => handle_subscribe(message, session, ctx.start_difficulty).await,
         "mining.authorize" => handle_authorize(message, session, addr, ctx).await,
         "mining.submit" => handle_submit(message, session, ctx).await,
+        "mining.extranonce.subscribe" => handle_extranonce_subscribe(message, session).await,
+        "mining.get_transactions" => handle_get_transactions(message, &ctx.tracker_handle).await,
+        "client.get_version" => handle_get_version(message).await,
         method => Err(Error::InvalidMethod(method.to_string())),
     }
 }
@@ -0,0 +1,176 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Optional stratum methods some miners and proxies send. We answer
+//! them instead of treating them as invalid methods, which would close
+//! the connection.
+
+use crate::stratum::error::Error;
+use crate::stratum::messages::{Message, MultiVersion, RejectReason, Response, SimpleRequest};
+use crate::stratum::work::tracker::{JobId, TrackerHandle};
+use serde_json::json;
+use tracing::debug;
+
+/// Handle the "mining.get_transactions" message
+/// Responds with the hex encoded transactions of the job's block template,
+/// letting miners verify what they are mining.
+pub async fn handle_get_transactions<'a>(
+    message: SimpleRequest<'a>,
+    tracker_handle: &TrackerHandle,
+) -> Result<Vec<Message<'a>>, Error> {
+    debug!("Handling mining.get_transactions message");
+    let job_id = message
+        .params
+        .first()
+        .and_then(|param| param.as_deref())
+        .and_then(|job_id| u64::from_str_radix(job_id, 16).ok());
+    let job = match job_id {
+        Some(job_id) => tracker_handle.get_job(JobId(job_id)).await.ok().flatten(),
+        None => None,
+    };
+    let response = match job {
+        Some(job) => {
+            let transactions: Vec<&str> = job
+                .blocktemplate
+                .transactions
+                .iter()
+                .map(|transaction| transaction.data.as_str())
+                .collect();
+            Response::new_ok(message.id, json!(transactions))
+        }
+        None => Response::new_rejection(message.id, RejectReason::JobNotFound),
+    };
+    Ok(vec![Message::Response(response)])
+}
+
+/// Handle the "client.get_version" message
+/// Responds with the pool software's name and version.
+pub async fn handle_get_version<'a>(message: SimpleRequest<'a>) -> Result<Vec<Message<'a>>, Error> {
+    debug!("Handling client.get_version message");
+    Ok(vec![Message::Response(Response::new_ok(
+        message.id,
+        json!(format!("p2poolv2/{}", env!("CARGO_PKG_VERSION"))),
+    ))])
+}
+
+/// Handle the "mining.multi_version" message
+/// Miners announce how many versions they roll with this message, version
+/// rolling is negotiated with mining.configure, so we only acknowledge it.
+pub async fn handle_multi_version<'a>(message: MultiVersion) -> Result<Vec<Message<'a>>, Error> {
+    debug!("Handling mining.multi_version message");
+    Ok(vec![Message::Response(Response::new_ok(
+        message.id,
+        json!(true),
+    ))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stratum::messages::Id;
+    use crate::stratum::work::tracker::start_tracker_actor;
+    use crate::test_utils::load_valid_stratum_work_components;
+    use std::borrow::Cow;
+    use std::sync::Arc;
+
+    fn get_transactions(job_id: &str) -> SimpleRequest<'static> {
+        SimpleRequest {
+            id: Some(Id::Number(5)),
+            method: Cow::Borrowed("mining.get_transactions"),
+            params: Cow::Owned(vec![Some(job_id.to_string())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_get_transactions() {
+        let tracker_handle = start_tracker_actor();
+        let (mut template, notify, _, _) =
+            load_valid_stratum_work_components("../tests/test_data/validation/stratum/b/");
+        let transaction: crate::stratum::work::block_template::TemplateTransaction =
+            serde_json::from_value(json!({
+                "data": "0200",
+                "txid": "00".repeat(32),
+                "hash": "00".repeat(32),
+                "depends": [],
+                "fee": 0,
+                "sigops": 0,
+                "weight": 0,
+            }))
+            .unwrap();
+        template.transactions = vec![transaction];
+        tracker_handle
+            .insert_job(
+                Arc::new(template),
+                notify.params.coinbase1.clone(),
+                notify.params.coinbase2.clone(),
+                None,
+                JobId(u64::from_str_radix(&notify.params.job_id, 16).unwrap()),
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+
+        let response =
+            handle_get_transactions(get_transactions(&notify.params.job_id), &tracker_handle)
+                .await
+                .unwrap();
+        match &response[..] {
+            [Message::Response(response)] => {
+                assert_eq!(response.id, Some(Id::Number(5)));
+                assert_eq!(response.result, Some(json!(["0200"])));
+            }
+            _ => panic!("Expected a Response message"),
+        }
+
+        // Unknown jobs are answered with an error, the connection stays open
+        let response = handle_get_transactions(get_transactions("ff"), &tracker_handle)
+            .await
+            .unwrap();
+        match &response[..] {
+            [Message::Response(response)] => {
+                assert_eq!(
+                    response.error.as_ref().unwrap().code,
+                    RejectReason::JobNotFound.code()
+                );
+            }
+            _ => panic!("Expected a Response message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_get_version() {
+        let message = SimpleRequest {
+            id: Some(Id::Number(6)),
+            method: Cow::Borrowed("client.get_version"),
+            params: Cow::Owned(vec![]),
+        };
+        let response = handle_get_version(message).await.unwrap();
+        match &response[..] {
+            [Message::Response(response)] => {
+                assert!(
+                    response
+                        .result
+                        .as_ref()
+                        .unwrap()
+                        .as_str()
+                        .unwrap()
+                        .starts_with("p2poolv2/")
+                );
+            }
+            _ => panic!("Expected a Response message"),
+        }
+    }
+}
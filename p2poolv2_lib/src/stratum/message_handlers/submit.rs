@@ -18,11 +18,13 @@ use crate::accounting::simple_pplns::SimplePplnsShare;
 use crate::stratum::difficulty_adjuster::DifficultyAdjusterTrait;
 use crate::stratum::emission::Emission;
 use crate::stratum::error::Error;
-use crate::stratum::messages::{Message, Response, SetDifficultyNotification, SimpleRequest};
+use crate::stratum::messages::{
+    Id, Message, RejectReason, Response, SetDifficultyNotification, SimpleRequest,
+};
 use crate::stratum::server::StratumContext;
-use crate::stratum::session::Session;
+use crate::stratum::session::{Session, ShareKey};
 use crate::stratum::work::difficulty::validate::validate_submission_difficulty;
-use crate::stratum::work::tracker::JobId;
+use crate::stratum::work::tracker::{JobDetails, JobId, TrackerHandle};
 use bitcoin::blockdata::block::Block;
 use bitcoin::hashes::Hash;
 use bitcoindrpc::{BitcoinRpcConfig, BitcoindRpcClient};
@@ -45,16 +47,23 @@ use tracing::{debug, error, info};
 /// {"id": 1, "method": "mining.submit", "params": ["username", "4f", "fe36a31b", "504e86ed", "e9695791", "1fffe000"]}
 ///
 /// Handling version mask, we check mask is valid and then apply it to the block header
+///
+/// Rejected shares are answered with the conventional stratum error codes,
+/// see [`RejectReason`], and counted in the pool metrics.
 pub(crate) async fn handle_submit<'a, D: DifficultyAdjusterTrait>(
     message: SimpleRequest<'a>,
     session: &mut Session<D>,
     stratum_context: StratumContext,
 ) -> Result<Vec<Message<'a>>, Error> {
     debug!("Handling mining.submit message");
-    if message.params.len() < 4 {
+    if message.params.len() < 5 || message.params[..5].iter().any(Option::is_none) {
         return Err(Error::InvalidParams("Missing parameters".into()));
     }
 
+    if session.user_id.is_none() {
+        return reject(message.id, RejectReason::Unauthorized, &stratum_context).await;
+    }
+
     let id = message.params[1].as_ref().unwrap();
 
     let job_id =
@@ -64,13 +73,47 @@ pub(crate) async fn handle_submit<'a, D: DifficultyAdjusterTrait>(
         Ok(Some(job)) => job,
         _ => {
             debug!("Job not found for job_id: {}", job_id);
-            return Ok(vec![Message::Response(Response::new_ok(
-                message.id,
-                json!(false),
-            ))]);
+            return reject(message.id, RejectReason::JobNotFound, &stratum_context).await;
         }
     };
 
+    if is_stale(&job, &stratum_context.tracker_handle).await {
+        debug!("Stale job for job_id: {}", job_id);
+        return reject(message.id, RejectReason::StaleJob, &stratum_context).await;
+    }
+
+    let ntime_hex = message.params[3].as_ref().unwrap();
+    let ntime = u32::from_str_radix(ntime_hex, 16)
+        .map_err(|_| Error::InvalidParams("Invalid ntime".into()))?;
+    if ntime < job.blocktemplate.mintime
+        || ntime
+            > job
+                .blocktemplate
+                .curtime
+                .saturating_add(MAX_NTIME_OFFSET_SECS)
+    {
+        debug!("Ntime {} out of range for job_id: {}", ntime, job_id);
+        return reject(message.id, RejectReason::NtimeOutOfRange, &stratum_context).await;
+    }
+
+    let share_key = ShareKey::new(
+        message.params[2].as_ref().unwrap(),
+        ntime_hex,
+        message.params[4].as_ref().unwrap(),
+        message.params.get(5).and_then(|param| param.as_deref()),
+    );
+    let now = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if !session
+        .submitted_shares
+        .insert(JobId(job_id), job.generation_timestamp, share_key, now)
+    {
+        debug!("Duplicate share for job_id: {}", job_id);
+        return reject(message.id, RejectReason::Duplicate, &stratum_context).await;
+    }
+
     // version mask from the session - we ignore different version mask sent in a submit message
     let version_mask = session.version_mask;
 
@@ -86,17 +129,23 @@ pub(crate) async fn handle_submit<'a, D: DifficultyAdjusterTrait>(
         Ok(result) => result,
         Err(e) => {
             debug!("Share validation failed: {}", e);
-            // return error to asic client if our server is failing to run validation. They will know something is wrong.
-            return Ok(vec![Message::Response(Response::new_ok(
-                message.id,
-                json!(false),
-            ))]);
+            return reject(message.id, RejectReason::InvalidShare, &stratum_context).await;
         }
     };
 
     if validation_result.meets_bitcoin_difficulty {
         // Submit block asap, do difficulty adjustment after submission
-        submit_block(&validation_result.block, stratum_context.bitcoinrpc_config).await;
+        let submitted =
+            submit_block(&validation_result.block, stratum_context.bitcoinrpc_config).await;
+        // Carried forward entitlements only change once the coinbase is in a block
+        if submitted
+            && !job.ledger_updates.is_empty()
+            && let Err(e) = stratum_context
+                .store
+                .apply_ledger_updates(&job.ledger_updates)
+        {
+            error!("Failed to apply payout ledger updates: {}", e);
+        }
     }
 
     // Mining difficulties are tracked as `truediffone`, i.e. difficulty is computed relative to mainnet
@@ -118,29 +167,37 @@ pub(crate) async fn handle_submit<'a, D: DifficultyAdjusterTrait>(
         message.params[4].as_ref().unwrap().to_string(),
     );
 
-    stratum_context
-        .emissions_tx
-        .send(Emission {
-            pplns: stratum_share.clone(),
-            block: validation_result.block,
-            share_commitment: job.share_commitment.clone(),
-        })
-        .await
-        .map_err(|e| Error::SubmitFailure(format!("Failed to send share to store: {e}")))?;
-
     session.last_share_time = Some(SystemTime::now());
 
     let meets_session_difficulty =
         truediff >= session.difficulty_adjuster.get_current_difficulty() as u128;
 
-    if meets_session_difficulty {
+    let mut response = if meets_session_difficulty {
+        // Only shares that meet the session difficulty are emitted to the share chain
+        stratum_context
+            .emissions_tx
+            .send(Emission {
+                pplns: stratum_share.clone(),
+                block: validation_result.block,
+                share_commitment: job.share_commitment.clone(),
+            })
+            .await
+            .map_err(|e| Error::SubmitFailure(format!("Failed to send share to store: {e}")))?;
         let _ = stratum_context
             .metrics
             .record_share_accepted(stratum_share, truediff as u64)
             .await;
+        vec![Message::Response(Response::new_ok(message.id, json!(true)))]
     } else {
-        let _ = stratum_context.metrics.record_share_rejected().await;
-    }
+        let _ = stratum_context
+            .metrics
+            .record_share_rejected(RejectReason::LowDifficulty)
+            .await;
+        vec![Message::Response(Response::new_rejection(
+            message.id,
+            RejectReason::LowDifficulty,
+        ))]
+    };
 
     let (new_difficulty, _is_first_share) = session.difficulty_adjuster.record_share_submission(
         truediff,
@@ -149,10 +206,6 @@ pub(crate) async fn handle_submit<'a, D: DifficultyAdjusterTrait>(
         SystemTime::now(),
     );
 
-    let mut response = vec![Message::Response(Response::new_ok(
-        message.id,
-        json!(meets_session_difficulty),
-    ))];
     match new_difficulty {
         Some(difficulty) => {
             response.push(Message::SetDifficulty(SetDifficultyNotification::new(
@@ -164,10 +217,39 @@ pub(crate) async fn handle_submit<'a, D: DifficultyAdjusterTrait>(
     }
 }
 
+/// Shares can be timestamped up to two hours after the template's
+/// current time, the limit bitcoin allows for block timestamps
+const MAX_NTIME_OFFSET_SECS: u32 = 2 * 60 * 60;
+
+/// Respond with a stratum error for a rejected share and count the reason
+async fn reject<'a>(
+    id: Option<Id>,
+    reason: RejectReason,
+    stratum_context: &StratumContext,
+) -> Result<Vec<Message<'a>>, Error> {
+    let _ = stratum_context.metrics.record_share_rejected(reason).await;
+    Ok(vec![Message::Response(Response::new_rejection(id, reason))])
+}
+
+/// A job is stale once a clean_jobs notify for a new bitcoin block has
+/// replaced it, i.e. the latest job builds on a different block
+async fn is_stale(job: &JobDetails, tracker_handle: &TrackerHandle) -> bool {
+    let Ok(latest_job_id) = tracker_handle.get_latest_job_id().await else {
+        return false;
+    };
+    match tracker_handle.get_job(latest_job_id).await {
+        Ok(Some(latest)) => {
+            latest.blocktemplate.previousblockhash != job.blocktemplate.previousblockhash
+        }
+        _ => false,
+    }
+}
+
 /// Submit block to bitcoind
 ///
 /// Build bitcoindrpc from config and call submit block
-pub async fn submit_block(block: &Block, bitcoinrpc_config: BitcoinRpcConfig) {
+/// Returns true if bitcoind accepted the block
+pub async fn submit_block(block: &Block, bitcoinrpc_config: BitcoinRpcConfig) -> bool {
     info!(
         "Submitting block to bitcoind: {:?}",
         block.header.block_hash()
@@ -179,11 +261,18 @@ pub async fn submit_block(block: &Block, bitcoinrpc_config: BitcoinRpcConfig) {
     );
     match rpc {
         Ok(bitcoind) => match bitcoind.submit_block(block).await {
-            Ok(_) => info!("Block submitted successfully"),
-            Err(e) => error!("Failed to submit block: {}", e),
+            Ok(_) => {
+                info!("Block submitted successfully");
+                true
+            }
+            Err(e) => {
+                error!("Failed to submit block: {}", e);
+                false
+            }
         },
         Err(e) => {
             error!("Failed to create Bitcoind RPC client: {}", e);
+            false
         }
     }
 }
@@ -253,6 +342,7 @@ mod handle_submit_tests {
                 notify.params.coinbase2.to_string(),
                 Some(create_test_commitment()),
                 job_id,
+                Vec::new(),
             )
             .await;
 
@@ -339,6 +429,7 @@ mod handle_submit_tests {
                 notify.params.coinbase2.to_string(),
                 Some(create_test_commitment()),
                 job_id,
+                Vec::new(),
             )
             .await;
 
@@ -429,6 +520,7 @@ mod handle_submit_tests {
                 notify.params.coinbase2.to_string(),
                 Some(create_test_commitment()),
                 job_id,
+                Vec::new(),
             )
             .await;
 
@@ -516,6 +608,7 @@ mod handle_submit_tests {
                 notify.params.coinbase2.to_string(),
                 Some(create_test_commitment()),
                 job_id,
+                Vec::new(),
             )
             .await;
 
@@ -567,7 +660,7 @@ mod handle_submit_tests {
     }
 
     #[tokio::test]
-    async fn test_handle_submit_with_unknown_job_id_returns_false() {
+    async fn test_handle_submit_with_unknown_job_id_returns_job_not_found() {
         let mut session = Session::<DifficultyAdjuster>::new(1, 1, None, 0x1fffe000);
         let tracker_handle = start_tracker_actor();
 
@@ -617,8 +710,13 @@ mod handle_submit_tests {
             _ => panic!("Expected a Response message"),
         };
 
-        // Should return result false for unknown job_id
-        assert_eq!(response.result, Some(json!(false)));
+        // Should return the job not found error for unknown job_id
+        assert_eq!(response.result, None);
+        assert_eq!(response.error.as_ref().unwrap().code, 21);
+        assert_eq!(
+            metrics_handle.get_metrics().await.rejections[&RejectReason::JobNotFound],
+            1
+        );
     }
 
     #[tokio::test]
@@ -650,6 +748,7 @@ mod handle_submit_tests {
                 notify.params.coinbase2.to_string(),
                 Some(create_test_commitment()),
                 job_id,
+                Vec::new(),
             )
             .await;
 
@@ -688,19 +787,12 @@ mod handle_submit_tests {
 
         assert_eq!(response.id, Some(Id::Number(4)));
 
-        // The response should indicate that the share met required difficulty
-        assert_eq!(response.result, Some(json!(false)));
+        // The response should reject the share as below session difficulty
+        assert_eq!(response.result, None);
+        assert_eq!(response.error.as_ref().unwrap().code, 23);
 
-        let share = emissions_rx.try_recv().unwrap();
-        assert_eq!(share.pplns.btcaddress, Some(session.btcaddress.unwrap()));
-
-        // Verify share_commitment is properly set
-        assert!(share.share_commitment.is_some());
-        let commitment = share.share_commitment.unwrap();
-        assert_eq!(
-            commitment.miner_pubkey,
-            create_test_commitment().miner_pubkey
-        );
+        // The low difficulty share is not emitted to the share chain
+        assert!(emissions_rx.try_recv().is_err());
 
         // Verify that the block is submitted to the mock server
         mock_server.verify().await;
@@ -708,4 +800,212 @@ mod handle_submit_tests {
         assert_eq!(metrics_handle.get_metrics().await.accepted_total, 0);
         assert_eq!(metrics_handle.get_metrics().await.rejected_total, 1);
     }
+
+    #[tokio::test]
+    async fn test_handle_submit_duplicate_share_is_rejected() {
+        let mut session = Session::<DifficultyAdjuster>::new(1, 1, None, 0x1fffe000);
+        let tracker_handle = start_tracker_actor();
+
+        let (mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        mock_submit_block_with_any_body(&mock_server).await;
+
+        let (template, notify, submit, authorize_response) =
+            load_valid_stratum_work_components("../tests/test_data/validation/stratum/b/");
+
+        let enonce1 = authorize_response.result.unwrap()[1].clone();
+        let enonce1: &str = enonce1.as_str().unwrap();
+        session.enonce1 =
+            u32::from_le_bytes(hex::decode(enonce1).unwrap().as_slice().try_into().unwrap());
+        session.enonce1_hex = enonce1.to_string();
+        session.btcaddress = Some("tb1q3udk7r26qs32ltf9nmqrjaaa7tr55qmkk30q5d".to_string());
+        session.user_id = Some(1);
+
+        let job_id = JobId(u64::from_str_radix(&notify.params.job_id, 16).unwrap());
+
+        let _ = tracker_handle
+            .insert_job(
+                Arc::new(template),
+                notify.params.coinbase1.to_string(),
+                notify.params.coinbase2.to_string(),
+                Some(create_test_commitment()),
+                job_id,
+                Vec::new(),
+            )
+            .await;
+
+        let (emissions_tx, mut emissions_rx) = mpsc::channel(10);
+        let stats_dir = tempfile::tempdir().unwrap();
+        let metrics_handle = metrics::start_metrics(stats_dir.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+
+        let (notify_tx, _notify_rx) = mpsc::channel(10);
+        let temp_dir = tempdir().unwrap();
+        let store = Arc::new(ChainStore::new(
+            Arc::new(Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap()),
+            ShareBlock::build_genesis_for_network(bitcoin::network::Network::Signet),
+            bitcoin::network::Network::Signet,
+        ));
+        let ctx = StratumContext {
+            notify_tx,
+            tracker_handle,
+            bitcoinrpc_config,
+            start_difficulty: 10000,
+            minimum_difficulty: 1,
+            maximum_difficulty: Some(2),
+            emissions_tx,
+            network: bitcoin::network::Network::Signet,
+            metrics: metrics_handle.clone(),
+            store,
+        };
+
+        let first = handle_submit(submit.clone(), &mut session, ctx.clone())
+            .await
+            .unwrap();
+        match &first[..] {
+            [Message::Response(response)] => assert_eq!(response.result, Some(json!(true))),
+            _ => panic!("Expected a Response message"),
+        };
+        assert!(emissions_rx.try_recv().is_ok());
+
+        let second = handle_submit(submit, &mut session, ctx).await.unwrap();
+        let response = match &second[..] {
+            [Message::Response(response)] => response,
+            _ => panic!("Expected a Response message"),
+        };
+        assert_eq!(response.id, Some(Id::Number(4)));
+        assert_eq!(response.result, None);
+        assert_eq!(response.error.as_ref().unwrap().code, 22);
+
+        // The duplicate is neither emitted nor counted as accepted
+        assert!(emissions_rx.try_recv().is_err());
+        let metrics = metrics_handle.get_metrics().await;
+        assert_eq!(metrics.accepted_total, 1);
+        assert_eq!(metrics.rejections[&RejectReason::Duplicate], 1);
+    }
+
+    #[tokio::test]
+    async fn test_handle_submit_invalid_share_is_rejected_with_code() {
+        let mut session = Session::<DifficultyAdjuster>::new(1, 1, None, 0x1fffe000);
+        let tracker_handle = start_tracker_actor();
+
+        let (_mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+
+        let (template, notify, mut submit, authorize_response) =
+            load_valid_stratum_work_components("../tests/test_data/validation/stratum/b/");
+
+        let enonce1 = authorize_response.result.unwrap()[1].clone();
+        let enonce1: &str = enonce1.as_str().unwrap();
+        session.enonce1 =
+            u32::from_le_bytes(hex::decode(enonce1).unwrap().as_slice().try_into().unwrap());
+        session.enonce1_hex = enonce1.to_string();
+        session.btcaddress = Some("tb1q3udk7r26qs32ltf9nmqrjaaa7tr55qmkk30q5d".to_string());
+        session.user_id = Some(1);
+
+        let job_id = JobId(u64::from_str_radix(&notify.params.job_id, 16).unwrap());
+
+        let _ = tracker_handle
+            .insert_job(
+                Arc::new(template),
+                notify.params.coinbase1.to_string(),
+                notify.params.coinbase2.to_string(),
+                Some(create_test_commitment()),
+                job_id,
+                Vec::new(),
+            )
+            .await;
+
+        let (emissions_tx, mut emissions_rx) = mpsc::channel(10);
+        let stats_dir = tempfile::tempdir().unwrap();
+        let metrics_handle = metrics::start_metrics(stats_dir.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+
+        let (notify_tx, _notify_rx) = mpsc::channel(10);
+        let temp_dir = tempdir().unwrap();
+        let store = Arc::new(ChainStore::new(
+            Arc::new(Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap()),
+            ShareBlock::build_genesis_for_network(bitcoin::network::Network::Signet),
+            bitcoin::network::Network::Signet,
+        ));
+        let ctx = StratumContext {
+            notify_tx,
+            tracker_handle,
+            bitcoinrpc_config,
+            start_difficulty: 10000,
+            minimum_difficulty: 1,
+            maximum_difficulty: Some(2),
+            emissions_tx,
+            network: bitcoin::network::Network::Signet,
+            metrics: metrics_handle.clone(),
+            store,
+        };
+
+        // An extranonce2 that is not hex cannot build the coinbase
+        submit.params.to_mut()[2] = Some("zzzzzzzz".to_string());
+
+        let message = handle_submit(submit, &mut session, ctx).await.unwrap();
+        let response = match &message[..] {
+            [Message::Response(response)] => response,
+            _ => panic!("Expected a Response message"),
+        };
+        assert_eq!(response.id, Some(Id::Number(4)));
+        assert_eq!(response.result, None);
+        assert_eq!(response.error.as_ref().unwrap().code, 20);
+
+        assert!(emissions_rx.try_recv().is_err());
+        let metrics = metrics_handle.get_metrics().await;
+        assert_eq!(metrics.accepted_total, 0);
+        assert_eq!(metrics.rejections[&RejectReason::InvalidShare], 1);
+    }
+
+    #[tokio::test]
+    async fn test_handle_submit_without_authorization_is_rejected() {
+        let mut session = Session::<DifficultyAdjuster>::new(1, 1, None, 0x1fffe000);
+        let tracker_handle = start_tracker_actor();
+
+        let (_mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+
+        let (_template, _notify, submit, _authorize_response) =
+            load_valid_stratum_work_components("../tests/test_data/validation/stratum/b/");
+
+        let (emissions_tx, mut emissions_rx) = mpsc::channel(10);
+        let stats_dir = tempfile::tempdir().unwrap();
+        let metrics_handle = metrics::start_metrics(stats_dir.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+
+        let (notify_tx, _notify_rx) = mpsc::channel(10);
+        let temp_dir = tempdir().unwrap();
+        let store = Arc::new(ChainStore::new(
+            Arc::new(Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap()),
+            ShareBlock::build_genesis_for_network(bitcoin::network::Network::Signet),
+            bitcoin::network::Network::Signet,
+        ));
+        let ctx = StratumContext {
+            notify_tx,
+            tracker_handle,
+            bitcoinrpc_config,
+            start_difficulty: 10000,
+            minimum_difficulty: 1,
+            maximum_difficulty: Some(2),
+            emissions_tx,
+            network: bitcoin::network::Network::Signet,
+            metrics: metrics_handle.clone(),
+            store,
+        };
+
+        let message = handle_submit(submit, &mut session, ctx).await.unwrap();
+        let response = match &message[..] {
+            [Message::Response(response)] => response,
+            _ => panic!("Expected a Response message"),
+        };
+        assert_eq!(response.result, None);
+        assert_eq!(response.error.as_ref().unwrap().code, 24);
+        assert!(emissions_rx.try_recv().is_err());
+        assert_eq!(
+            metrics_handle.get_metrics().await.rejections[&RejectReason::Unauthorized],
+            1
+        );
+    }
 }
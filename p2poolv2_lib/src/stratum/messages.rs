@@ -69,6 +69,68 @@ pub struct Error<'a> {
     pub data: Option<Value>,
 }
 
+/// Reasons for rejecting a mining.submit, with the conventional
+/// stratum error codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    /// The job is unknown or has expired
+    JobNotFound,
+    /// The job was replaced by a clean_jobs notify for a new block
+    StaleJob,
+    /// The share was already submitted
+    Duplicate,
+    /// The share does not meet the session difficulty
+    LowDifficulty,
+    /// The session has not authorized a worker
+    Unauthorized,
+    /// The share's ntime is outside the range allowed by the job
+    NtimeOutOfRange,
+    /// The share could not be built or validated from the job
+    InvalidShare,
+}
+
+impl RejectReason {
+    /// Stratum error code for the reason
+    pub fn code(&self) -> i32 {
+        match self {
+            RejectReason::NtimeOutOfRange | RejectReason::InvalidShare => 20,
+            RejectReason::JobNotFound | RejectReason::StaleJob => 21,
+            RejectReason::Duplicate => 22,
+            RejectReason::LowDifficulty => 23,
+            RejectReason::Unauthorized => 24,
+        }
+    }
+
+    /// Name of the reason, used as a metrics label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::JobNotFound => "job_not_found",
+            RejectReason::StaleJob => "stale_job",
+            RejectReason::Duplicate => "duplicate",
+            RejectReason::LowDifficulty => "low_difficulty",
+            RejectReason::Unauthorized => "unauthorized",
+            RejectReason::NtimeOutOfRange => "ntime_out_of_range",
+            RejectReason::InvalidShare => "invalid_share",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            RejectReason::JobNotFound => "Job not found",
+            RejectReason::StaleJob => "Stale job",
+            RejectReason::Duplicate => "Duplicate share",
+            RejectReason::LowDifficulty => "Low difficulty share",
+            RejectReason::Unauthorized => "Unauthorized worker",
+            RejectReason::NtimeOutOfRange => "Ntime out of range",
+            RejectReason::InvalidShare => "Invalid share",
+        };
+        write!(f, "{message}")
+    }
+}
+
 /// Message type capturing all possible stratum message types.
 /// This allows our message handlers to return any of the types and be able to send updates to clients as required.
 #[derive(Debug, Clone, Serialize)]
@@ -78,6 +140,7 @@ pub enum Message<'a> {
     Response(Response<'a>),
     Notify(Notify),
     SetDifficulty(SetDifficultyNotification<'a>),
+    Reconnect(ReconnectNotification<'a>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -85,6 +148,7 @@ pub enum Message<'a> {
 pub enum Request<'a> {
     SimpleRequest(SimpleRequest<'a>),
     MiningConfigureRequest(MiningConfigure<'a>),
+    MultiVersionRequest(MultiVersion),
     SuggestDifficultyRequest(SuggestDifficulty<'a>),
 }
 
@@ -115,6 +179,26 @@ pub struct SuggestDifficulty<'a> {
     pub params: Cow<'a, Vec<u64>>,
 }
 
+/// mining.multi_version announces how many versions the miner rolls, the
+/// params are a single integer. The method is checked when parsing, so the
+/// request isn't taken for mining.suggest_difficulty, which also has
+/// integer params.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultiVersion {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Id>,
+    pub method: MultiVersionMethod,
+    #[serde(default)]
+    pub params: Vec<u32>,
+}
+
+/// The only method a MultiVersion request can have
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MultiVersionMethod {
+    #[serde(rename = "mining.multi_version")]
+    MultiVersion,
+}
+
 /// Struct for mining.configure messages
 /// These messages have mixed types in params and those are defined in MiningConfigureParams.
 ///
@@ -181,6 +265,17 @@ pub struct SetDifficultyNotification<'a> {
     pub params: Vec<u64>,
 }
 
+/// client.reconnect message asks a miner to drop the connection and
+/// reconnect. The optional params are the host, port and the number of
+/// seconds to wait before reconnecting. Without params the miner
+/// reconnects to the same host and port.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconnectNotification<'a> {
+    #[serde(borrow)]
+    pub method: Cow<'a, str>,
+    pub params: Vec<Value>,
+}
+
 /// NotifyParams represents the parameters for the mining.notify message
 /// It includes job_id, prevhash, coinbase1, coinbase2, merkle_branches,
 /// version, nbits, ntime, and clean_jobs
@@ -384,6 +479,11 @@ impl Response<'_> {
             }),
         }
     }
+
+    /// Error response for a rejected mining.submit
+    pub fn new_rejection(id: Option<Id>, reason: RejectReason) -> Self {
+        Self::new_error(id, reason.code(), reason.to_string())
+    }
 }
 
 /// Notify represents a Stratum notification message from the server to the client
@@ -408,10 +508,56 @@ impl SetDifficultyNotification<'_> {
     }
 }
 
+impl ReconnectNotification<'_> {
+    /// Creates a new client.reconnect notification. Params are positional,
+    /// so a port is only sent along with a host, and a wait along with both.
+    pub fn new(host: Option<&str>, port: Option<u16>, wait: Option<u64>) -> Self {
+        let mut params = Vec::new();
+        if let Some(host) = host {
+            params.push(Value::from(host));
+            if let Some(port) = port {
+                params.push(Value::from(port));
+                if let Some(wait) = wait {
+                    params.push(Value::from(wait));
+                }
+            }
+        }
+        ReconnectNotification {
+            method: Cow::Borrowed("client.reconnect"),
+            params,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_reconnect_notification() {
+        let message = Message::Reconnect(ReconnectNotification::new(None, None, None));
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"method":"client.reconnect","params":[]}"#
+        );
+
+        let message = Message::Reconnect(ReconnectNotification::new(
+            Some("pool.example.com"),
+            Some(3333),
+            Some(5),
+        ));
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"method":"client.reconnect","params":["pool.example.com",3333,5]}"#
+        );
+
+        let message = Message::Reconnect(ReconnectNotification::new(None, Some(3333), Some(5)));
+        assert_eq!(
+            serde_json::to_string(&message).unwrap(),
+            r#"{"method":"client.reconnect","params":[]}"#
+        );
+    }
+
     #[test]
     fn test_new_subscribe() {
         let message = SimpleRequest::new_subscribe(1, "agent".to_string(), "1.0".to_string(), None);
@@ -435,6 +581,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_multi_version_request() {
+        let message = serde_json::from_str::<Request>(
+            r#"{"id":2,"method":"mining.multi_version","params":[4]}"#,
+        )
+        .unwrap();
+        match message {
+            Request::MultiVersionRequest(request) => {
+                assert_eq!(request.id, Some(Id::Number(2)));
+                assert_eq!(request.params, vec![4]);
+            }
+            other => panic!("Expected MultiVersionRequest, got {other:?}"),
+        }
+
+        // Other requests with integer params are not multi_version
+        let message = serde_json::from_str::<Request>(
+            r#"{"id":3,"method":"mining.suggest_difficulty","params":[512]}"#,
+        )
+        .unwrap();
+        assert!(matches!(message, Request::SuggestDifficultyRequest(_)));
+    }
+
     #[test]
     fn test_new_subscribe_from_luxminer() {
         let message = serde_json::from_str::<Request>(
@@ -16,6 +16,7 @@
 
 pub mod client_connections;
 pub mod difficulty_adjuster;
+pub mod drain;
 pub mod emission;
 pub mod error;
 pub mod message_handlers;
@@ -23,6 +24,8 @@ pub mod messages;
 pub mod server;
 pub mod session;
 pub mod session_timeout;
+pub mod sv2;
+pub mod tls;
 pub mod util;
 mod validate_username;
 pub mod work;
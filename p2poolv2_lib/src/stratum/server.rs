@@ -38,10 +38,14 @@ use std::sync::Arc;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpListener;
 use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::TlsAcceptor;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, LinesCodec};
 use tracing::{debug, error, info};
 
+/// Time allowed for a miner to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 // A struct to represent a Stratum server configuration
 // This struct contains the port and address of the Stratum server
 pub struct StratumServer {
@@ -52,6 +56,7 @@ pub struct StratumServer {
     pub maximum_difficulty: Option<u64>,
     pub network: bitcoin::Network,
     pub version_mask: i32,
+    tls_acceptor: Option<TlsAcceptor>,
     shutdown_rx: oneshot::Receiver<()>,
     connections_handle: ClientConnectionsHandle,
     emissions_tx: EmissionSender,
@@ -68,6 +73,7 @@ pub struct StratumServerBuilder {
     maximum_difficulty: Option<Option<u64>>,
     network: Option<bitcoin::Network>,
     version_mask: Option<i32>,
+    tls_acceptor: Option<TlsAcceptor>,
     shutdown_rx: Option<oneshot::Receiver<()>>,
     connections_handle: Option<ClientConnectionsHandle>,
     emissions_tx: Option<EmissionSender>,
@@ -111,6 +117,12 @@ impl StratumServerBuilder {
         self
     }
 
+    /// Serve the listener over TLS, plain TCP when not set
+    pub fn tls_acceptor(mut self, tls_acceptor: TlsAcceptor) -> Self {
+        self.tls_acceptor = Some(tls_acceptor);
+        self
+    }
+
     pub fn shutdown_rx(mut self, shutdown_rx: oneshot::Receiver<()>) -> Self {
         self.shutdown_rx = Some(shutdown_rx);
         self
@@ -151,6 +163,7 @@ impl StratumServerBuilder {
                 .ok_or("maximum_difficulty is required")?,
             network: self.network.ok_or("network is required")?,
             version_mask: self.version_mask.ok_or("version_mask is required")?,
+            tls_acceptor: self.tls_acceptor,
             shutdown_rx: self.shutdown_rx.ok_or("shutdown_rx is required")?,
             connections_handle: self
                 .connections_handle
@@ -203,8 +216,6 @@ impl StratumServer {
                             let (stream, addr) = connection;
                             info!("New connection from: {}", addr);
                             let (message_rx, shutdown_rx) = self.connections_handle.add(addr).await;
-                            let (reader, writer) = stream.into_split();
-                            let buf_reader = BufReader::new(reader);
 
                             let ctx = StratumContext {
                                 notify_tx: notify_tx.clone(),
@@ -219,10 +230,32 @@ impl StratumServer {
                                 store: self.store.clone(),
                             };
                             let version_mask = self.version_mask;
+                            let tls_acceptor = self.tls_acceptor.clone();
                             // Spawn a new task for each connection
                             tokio::spawn(async move {
                                 // Handle the connection with graceful shutdown support
-                                if handle_connection(buf_reader, writer, addr, message_rx, shutdown_rx, version_mask, ctx, &SystemTimeProvider{}).await.is_err() {
+                                let result = match tls_acceptor {
+                                    Some(tls_acceptor) => {
+                                        let stream = match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream)).await {
+                                            Ok(Ok(stream)) => stream,
+                                            Ok(Err(e)) => {
+                                                info!("TLS handshake with {addr} failed: {e}");
+                                                return;
+                                            }
+                                            Err(_) => {
+                                                info!("TLS handshake with {addr} timed out");
+                                                return;
+                                            }
+                                        };
+                                        let (reader, writer) = tokio::io::split(stream);
+                                        handle_connection(BufReader::new(reader), writer, addr, message_rx, shutdown_rx, version_mask, ctx, &SystemTimeProvider{}).await
+                                    }
+                                    None => {
+                                        let (reader, writer) = stream.into_split();
+                                        handle_connection(BufReader::new(reader), writer, addr, message_rx, shutdown_rx, version_mask, ctx, &SystemTimeProvider{}).await
+                                    }
+                                };
+                                if result.is_err() {
                                         error!("Error occurred while handling connection {addr}. Closing connection.");
                                 }
                             });
@@ -507,6 +540,114 @@ mod stratum_server_tests {
         let _ = server_handle.await;
     }
 
+    #[tokio::test]
+    async fn test_tls_listener_serves_stratum() {
+        use crate::stratum::tls::{load_tls_acceptor, tests::self_signed_cert};
+        use rustls_pki_types::pem::PemObject;
+        use rustls_pki_types::{CertificateDer, ServerName};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::TlsConnector;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore, crypto::ring};
+
+        let (_cert_dir, cert_path, key_path) = self_signed_cert();
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+
+        // Keep the connection's channels open for the duration of the test
+        let open_channels = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut connections_handle = ClientConnectionsHandle::default();
+        let channels = open_channels.clone();
+        connections_handle.expect_add().returning(move |_| {
+            let (message_tx, message_rx) = mpsc::channel(10);
+            let (shutdown_tx, shutdown_rx) = oneshot::channel();
+            channels.lock().unwrap().push((message_tx, shutdown_tx));
+            (message_rx, shutdown_rx)
+        });
+
+        let (_mock_rpc_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        let (shares_tx, _shares_rx) = mpsc::channel(10);
+        let stats_dir = tempfile::tempdir().unwrap();
+        let metrics_handle = metrics::start_metrics(stats_dir.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        let temp_dir = tempdir().unwrap();
+        let store = Arc::new(ChainStore::new(
+            Arc::new(Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap()),
+            ShareBlock::build_genesis_for_network(bitcoin::network::Network::Signet),
+            bitcoin::network::Network::Signet,
+        ));
+
+        let mut server = StratumServerBuilder::default()
+            .hostname("127.0.0.1".to_string())
+            .port(12347)
+            .start_difficulty(1)
+            .minimum_difficulty(1)
+            .maximum_difficulty(None)
+            .network(bitcoin::network::Network::Signet)
+            .version_mask(0x1fffe000)
+            .tls_acceptor(load_tls_acceptor(&cert_path, &key_path).unwrap())
+            .shutdown_rx(shutdown_rx)
+            .connections_handle(connections_handle)
+            .emissions_tx(shares_tx)
+            .store(store)
+            .build()
+            .await
+            .unwrap();
+
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let (notify_tx, _notify_rx) = mpsc::channel(10);
+        let server_handle = tokio::spawn(async move {
+            let _ = server
+                .start(
+                    Some(ready_tx),
+                    notify_tx,
+                    start_tracker_actor(),
+                    bitcoinrpc_config,
+                    metrics_handle,
+                )
+                .await;
+        });
+        ready_rx.await.expect("Server should signal readiness");
+
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(&cert_path).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect("127.0.0.1:12347")
+            .await
+            .unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        let subscribe =
+            SimpleRequest::new_subscribe(1, "agent".to_string(), "1.0".to_string(), None);
+        let subscribe = serde_json::to_string(&subscribe).unwrap() + "\n";
+        stream.write_all(subscribe.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buffer = [0; 1024];
+        let bytes_read = stream.read(&mut buffer).await.unwrap();
+        let line = std::str::from_utf8(&buffer[..bytes_read])
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .to_string();
+        let response: crate::stratum::messages::Response = serde_json::from_str(&line).unwrap();
+        assert_eq!(response.id, Some(crate::stratum::messages::Id::Number(1)));
+        assert!(response.error.is_none());
+        assert_eq!(open_channels.lock().unwrap().len(), 1);
+
+        server_handle.abort();
+        let _ = server_handle.await;
+    }
+
     #[tokio::test]
     async fn test_handle_connection_with_new_subscription_check_response_is_valid() {
         // Mock data
@@ -693,6 +834,89 @@ mod stratum_server_tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_handle_connection_answers_optional_methods() {
+        let input = concat!(
+            r#"{"id":1,"method":"mining.extranonce.subscribe","params":[]}"#,
+            "\n",
+            r#"{"id":2,"method":"mining.multi_version","params":[4]}"#,
+            "\n",
+            r#"{"id":3,"method":"client.get_version","params":[]}"#,
+            "\n",
+            r#"{"id":4,"method":"mining.subscribe","params":["agent/1.0"]}"#,
+            "\n",
+        );
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+
+        let reader = input.as_bytes();
+        let mut writer = Vec::new();
+        let (_, message_rx) = mpsc::channel(10);
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (notify_tx, _notify_rx) = mpsc::channel(10);
+        let tracker_handle = start_tracker_actor();
+        let (_mock_rpc_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        let (emissions_tx, _emissions_rx) = mpsc::channel(10);
+        let stats_dir = tempfile::tempdir().unwrap();
+        let metrics_handle = metrics::start_metrics(stats_dir.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+
+        let temp_dir = tempdir().unwrap();
+        let store = Arc::new(ChainStore::new(
+            Arc::new(Store::new(temp_dir.path().to_str().unwrap().to_string(), false).unwrap()),
+            ShareBlock::build_genesis_for_network(bitcoin::network::Network::Signet),
+            bitcoin::network::Network::Signet,
+        ));
+
+        let ctx = StratumContext {
+            notify_tx,
+            tracker_handle,
+            bitcoinrpc_config,
+            metrics: metrics_handle,
+            start_difficulty: 10000,
+            minimum_difficulty: 1,
+            maximum_difficulty: None,
+            emissions_tx,
+            network: bitcoin::network::Network::Regtest,
+            store,
+        };
+
+        let result = handle_connection(
+            reader,
+            &mut writer,
+            addr,
+            message_rx,
+            shutdown_rx,
+            0x1fffe000,
+            ctx,
+            &SystemTimeProvider {},
+        )
+        .await;
+        assert!(result.is_ok());
+
+        let responses: Vec<serde_json::Value> = std::str::from_utf8(&writer)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[0]["result"], true);
+        assert_eq!(responses[1]["id"], 2);
+        assert_eq!(responses[1]["result"], true);
+        assert_eq!(responses[2]["id"], 3);
+        assert!(
+            responses[2]["result"]
+                .as_str()
+                .unwrap()
+                .starts_with("p2poolv2/")
+        );
+        // The connection stays open for the subscription, and multi_version
+        // did not change the difficulty
+        assert_eq!(responses[3]["id"], 4);
+        assert_eq!(responses[4]["method"], "mining.set_difficulty");
+        assert_eq!(responses[4]["params"][0], 10000);
+    }
+
     #[tokio::test]
     async fn test_handle_connection_line_too_long() {
         // Create a line that exceeds the max length (8KB)
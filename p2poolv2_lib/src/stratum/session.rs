@@ -14,11 +14,13 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::stratum::work::tracker::{JobId, MAX_JOB_AGE_SECS};
 use crate::utils::time_provider::SystemTimeProvider;
 use crate::{
     stratum::difficulty_adjuster::DifficultyAdjusterTrait, utils::time_provider::TimeProvider,
 };
 use bitcoin::secp256k1::rand::{self, Rng};
+use std::collections::{HashMap, HashSet};
 use std::time::SystemTime;
 
 /// Use 4 byte extranonce1
@@ -38,6 +40,8 @@ pub struct Session<D: DifficultyAdjusterTrait> {
     pub enonce1_hex: String,
     /// Did the mine subscribe already?
     pub subscribed: bool,
+    /// Did the miner subscribe to extranonce changes with mining.extranonce.subscribe?
+    pub extranonce_subscribed: bool,
     /// Optional username of the miner, supplied by the miner, we just store it in session
     pub username: Option<String>,
     /// bitcoin address used as user identifier
@@ -60,6 +64,52 @@ pub struct Session<D: DifficultyAdjusterTrait> {
     pub connected_at: SystemTime,
     /// Instant when the last valid share was submitted
     pub last_share_time: Option<SystemTime>,
+    /// Shares submitted in this session, to reject duplicates
+    pub submitted_shares: SubmittedShares,
+}
+
+/// Fields of a mining.submit that identify a share within a job
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ShareKey {
+    pub extranonce2: String,
+    pub ntime: String,
+    pub nonce: String,
+    pub version_bits: Option<String>,
+}
+
+impl ShareKey {
+    /// Build a key, ignoring the case of the hex fields
+    pub fn new(extranonce2: &str, ntime: &str, nonce: &str, version_bits: Option<&str>) -> Self {
+        Self {
+            extranonce2: extranonce2.to_lowercase(),
+            ntime: ntime.to_lowercase(),
+            nonce: nonce.to_lowercase(),
+            version_bits: version_bits.map(str::to_lowercase),
+        }
+    }
+}
+
+/// Shares submitted by a session for each job.
+///
+/// Jobs are forgotten once the tracker expires them, as shares for
+/// expired jobs are rejected before the duplicate check.
+#[derive(Debug, Default)]
+pub struct SubmittedShares {
+    jobs: HashMap<JobId, (u64, HashSet<ShareKey>)>,
+}
+
+impl SubmittedShares {
+    /// Record a share for a job generated at `generated_at`. Returns
+    /// false if the share was already submitted.
+    pub fn insert(&mut self, job_id: JobId, generated_at: u64, key: ShareKey, now: u64) -> bool {
+        self.jobs
+            .retain(|_, (generated_at, _)| now.saturating_sub(*generated_at) < MAX_JOB_AGE_SECS);
+        self.jobs
+            .entry(job_id)
+            .or_insert_with(|| (generated_at, HashSet::new()))
+            .1
+            .insert(key)
+    }
 }
 
 impl<D: DifficultyAdjusterTrait> Session<D> {
@@ -78,6 +128,7 @@ impl<D: DifficultyAdjusterTrait> Session<D> {
             enonce1,
             enonce1_hex: hex::encode(enonce1.to_le_bytes()),
             subscribed: false,
+            extranonce_subscribed: false,
             username: None,
             workername: None,
             btcaddress: None,
@@ -89,6 +140,7 @@ impl<D: DifficultyAdjusterTrait> Session<D> {
             suggested_difficulty: None,
             connected_at: now,
             last_share_time: None,
+            submitted_shares: SubmittedShares::default(),
         }
     }
 
@@ -158,6 +210,33 @@ mod tests {
         assert!(!session.subscribed);
     }
 
+    #[test]
+    fn test_submitted_shares_rejects_duplicates() {
+        let mut shares = SubmittedShares::default();
+        let key = ShareKey::new("fe36a31b", "504e86ed", "e9695791", None);
+
+        assert!(shares.insert(JobId(1), 1000, key.clone(), 1000));
+        assert!(!shares.insert(JobId(1), 1000, key.clone(), 1001));
+        // Hex case does not make a different share
+        assert!(!shares.insert(
+            JobId(1),
+            1000,
+            ShareKey::new("FE36A31B", "504e86ed", "e9695791", None),
+            1001
+        ));
+        // The same fields for another job or version bits are a different share
+        assert!(shares.insert(JobId(2), 1000, key.clone(), 1001));
+        assert!(shares.insert(
+            JobId(1),
+            1000,
+            ShareKey::new("fe36a31b", "504e86ed", "e9695791", Some("00002000")),
+            1001
+        ));
+
+        // Expired jobs are forgotten
+        assert!(shares.insert(JobId(1), 1000, key, 1000 + MAX_JOB_AGE_SECS));
+    }
+
     #[test]
     fn test_get_current_difficulty() {
         let session = Session::<DifficultyAdjuster>::new(100, 2000, Some(3000), 0x1fffe000);
@@ -0,0 +1,132 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::stratum::sv2::noise::Sv2Error;
+use codec_sv2::binary_sv2::{self, GetSize, encodable::EncodableField};
+use common_messages_sv2::{
+    CHANNEL_BIT_SETUP_CONNECTION, CHANNEL_BIT_SETUP_CONNECTION_ERROR,
+    CHANNEL_BIT_SETUP_CONNECTION_SUCCESS, MESSAGE_TYPE_RECONNECT, MESSAGE_TYPE_SETUP_CONNECTION,
+    MESSAGE_TYPE_SETUP_CONNECTION_ERROR, MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS, Reconnect,
+    SetupConnection, SetupConnectionError, SetupConnectionSuccess,
+};
+use mining_sv2::*;
+
+/// Reconnect is addressed to the connection, not a channel
+const CHANNEL_BIT_RECONNECT: bool = false;
+
+/// Declare the SV2 messages used by the mining endpoint with their
+/// message types and channel bits, and the encoding glue for them.
+macro_rules! sv2_messages {
+    ($($variant:ident($ty:ty) = $message_type:path, $channel_bit:path;)*) => {
+        /// SV2 messages of the common and mining protocols spoken by the
+        /// mining endpoint
+        #[derive(Debug, Clone)]
+        pub enum Sv2Message<'a> {
+            $($variant($ty),)*
+        }
+
+        impl Sv2Message<'_> {
+            /// The message type sent in the frame header
+            pub fn message_type(&self) -> u8 {
+                match self {
+                    $(Self::$variant(_) => $message_type,)*
+                }
+            }
+
+            /// Whether the message is addressed to a channel
+            pub fn channel_bit(&self) -> bool {
+                match self {
+                    $(Self::$variant(_) => $channel_bit,)*
+                }
+            }
+        }
+
+        impl<'a> From<Sv2Message<'a>> for EncodableField<'a> {
+            fn from(message: Sv2Message<'a>) -> Self {
+                match message {
+                    $(Sv2Message::$variant(message) => message.into(),)*
+                }
+            }
+        }
+
+        impl GetSize for Sv2Message<'_> {
+            fn get_size(&self) -> usize {
+                match self {
+                    $(Self::$variant(message) => message.get_size(),)*
+                }
+            }
+        }
+
+        $(
+            impl<'a> From<$ty> for Sv2Message<'a> {
+                fn from(message: $ty) -> Self {
+                    Self::$variant(message)
+                }
+            }
+        )*
+
+        /// Decode a frame payload of the given message type
+        pub fn decode<'a>(
+            message_type: u8,
+            payload: &'a mut [u8],
+        ) -> Result<Sv2Message<'static>, Sv2Error> {
+            match message_type {
+                $($message_type => binary_sv2::from_bytes::<$ty>(payload)
+                    .map(|message| Sv2Message::$variant(message.into_static()))
+                    .map_err(|e| Sv2Error::Codec(format!("{e:?}"))),)*
+                other => Err(Sv2Error::Protocol(format!("Unsupported message type {other:#x}"))),
+            }
+        }
+    };
+}
+
+sv2_messages! {
+    SetupConnection(SetupConnection<'a>) =
+        MESSAGE_TYPE_SETUP_CONNECTION, CHANNEL_BIT_SETUP_CONNECTION;
+    SetupConnectionSuccess(SetupConnectionSuccess) =
+        MESSAGE_TYPE_SETUP_CONNECTION_SUCCESS, CHANNEL_BIT_SETUP_CONNECTION_SUCCESS;
+    SetupConnectionError(SetupConnectionError<'a>) =
+        MESSAGE_TYPE_SETUP_CONNECTION_ERROR, CHANNEL_BIT_SETUP_CONNECTION_ERROR;
+    Reconnect(Reconnect<'a>) = MESSAGE_TYPE_RECONNECT, CHANNEL_BIT_RECONNECT;
+    OpenStandardMiningChannel(OpenStandardMiningChannel<'a>) =
+        MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL, CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL;
+    OpenStandardMiningChannelSuccess(OpenStandardMiningChannelSuccess<'a>) =
+        MESSAGE_TYPE_OPEN_STANDARD_MINING_CHANNEL_SUCCESS,
+        CHANNEL_BIT_OPEN_STANDARD_MINING_CHANNEL_SUCCESS;
+    OpenExtendedMiningChannel(OpenExtendedMiningChannel<'a>) =
+        MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL, CHANNEL_BIT_OPEN_EXTENDED_MINING_CHANNEL;
+    OpenExtendedMiningChannelSuccess(OpenExtendedMiningChannelSuccess<'a>) =
+        MESSAGE_TYPE_OPEN_EXTENDED_MINING_CHANNEL_SUCCESS,
+        CHANNEL_BIT_OPEN_EXTENDED_MINING_CHANNEL_SUCCESS;
+    OpenMiningChannelError(OpenMiningChannelError<'a>) =
+        MESSAGE_TYPE_OPEN_MINING_CHANNEL_ERROR, CHANNEL_BIT_OPEN_MINING_CHANNEL_ERROR;
+    UpdateChannel(UpdateChannel<'a>) = MESSAGE_TYPE_UPDATE_CHANNEL, CHANNEL_BIT_UPDATE_CHANNEL;
+    CloseChannel(CloseChannel<'a>) = MESSAGE_TYPE_CLOSE_CHANNEL, CHANNEL_BIT_CLOSE_CHANNEL;
+    NewMiningJob(NewMiningJob<'a>) = MESSAGE_TYPE_NEW_MINING_JOB, CHANNEL_BIT_NEW_MINING_JOB;
+    NewExtendedMiningJob(NewExtendedMiningJob<'a>) =
+        MESSAGE_TYPE_NEW_EXTENDED_MINING_JOB, CHANNEL_BIT_NEW_EXTENDED_MINING_JOB;
+    SetNewPrevHash(SetNewPrevHash<'a>) =
+        MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH, CHANNEL_BIT_MINING_SET_NEW_PREV_HASH;
+    SetTarget(SetTarget<'a>) = MESSAGE_TYPE_SET_TARGET, CHANNEL_BIT_SET_TARGET;
+    SubmitSharesStandard(SubmitSharesStandard) =
+        MESSAGE_TYPE_SUBMIT_SHARES_STANDARD, CHANNEL_BIT_SUBMIT_SHARES_STANDARD;
+    SubmitSharesExtended(SubmitSharesExtended<'a>) =
+        MESSAGE_TYPE_SUBMIT_SHARES_EXTENDED, CHANNEL_BIT_SUBMIT_SHARES_EXTENDED;
+    SubmitSharesSuccess(SubmitSharesSuccess) =
+        MESSAGE_TYPE_SUBMIT_SHARES_SUCCESS, CHANNEL_BIT_SUBMIT_SHARES_SUCCESS;
+    SubmitSharesError(SubmitSharesError<'a>) =
+        MESSAGE_TYPE_SUBMIT_SHARES_ERROR, CHANNEL_BIT_SUBMIT_SHARES_ERROR;
+}
@@ -0,0 +1,1013 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Mining protocol state of a single SV2 connection.
+//!
+//! Every channel opened on the connection is backed by a stratum
+//! [`Session`], so authorization, difficulty adjustment, share
+//! validation and emission all go through the same handlers as the
+//! stratum v1 server. Jobs are translated from the v1 notify messages
+//! the connection receives from the notifier.
+
+use crate::stratum::difficulty_adjuster::{DifficultyAdjuster, DifficultyAdjusterTrait};
+use crate::stratum::message_handlers::authorize_response::handle_authorize;
+use crate::stratum::message_handlers::submit::handle_submit;
+use crate::stratum::messages::{Id, Message, Notify, ReconnectNotification, SimpleRequest};
+use crate::stratum::server::StratumContext;
+use crate::stratum::session::{EXTRANONCE2_SIZE, Session};
+use crate::stratum::sv2::messages::Sv2Message;
+use crate::stratum::sv2::noise::Sv2Error;
+use crate::stratum::work::difficulty::validate::build_coinbase_from_components;
+use crate::stratum::work::gbt::build_merkle_branches_for_template;
+use crate::stratum::work::tracker::{JobDetails, JobId};
+use bitcoin::BlockHash;
+use bitcoin::hashes::{Hash, sha256d};
+use codec_sv2::binary_sv2::{Seq0255, Sv2Option, U256};
+use common_messages_sv2::{Protocol, Reconnect, SetupConnectionError, SetupConnectionSuccess};
+use mining_sv2::{
+    NewExtendedMiningJob, NewMiningJob, OpenExtendedMiningChannel,
+    OpenExtendedMiningChannelSuccess, OpenMiningChannelError, OpenStandardMiningChannel,
+    OpenStandardMiningChannelSuccess, SetNewPrevHash, SetTarget, SubmitSharesError,
+    SubmitSharesSuccess,
+};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tracing::{debug, info};
+
+/// The only mining protocol version we speak
+const PROTOCOL_VERSION: u16 = 2;
+
+/// Standard channels can't roll the extranonce, their coinbase uses
+/// the channel's extranonce prefix followed by zeros
+const STANDARD_EXTRANONCE2: [u8; EXTRANONCE2_SIZE] = [0; EXTRANONCE2_SIZE];
+
+/// Most channels a connection can open. Proxies open a channel for
+/// each of their miners, every channel holds an authorized session.
+const MAX_CHANNELS_PER_CONNECTION: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChannelKind {
+    Standard,
+    Extended,
+}
+
+/// An open mining channel, backed by a stratum session
+struct Channel {
+    kind: ChannelKind,
+    session: Session<DifficultyAdjuster>,
+    /// SV2 job ids sent on this channel, mapped to tracker job ids
+    jobs: HashMap<u32, JobId>,
+    /// Previous block hash of the last job sent on the channel
+    prev_hash: Option<String>,
+}
+
+/// Mining protocol state for one SV2 connection
+pub(crate) struct MiningConnection {
+    addr: SocketAddr,
+    version_mask: i32,
+    ctx: StratumContext,
+    setup_done: bool,
+    channels: HashMap<u32, Channel>,
+    max_channels: usize,
+    next_channel_id: u32,
+    next_job_id: u32,
+}
+
+impl MiningConnection {
+    pub(crate) fn new(addr: SocketAddr, version_mask: i32, ctx: StratumContext) -> Self {
+        Self {
+            addr,
+            version_mask,
+            ctx,
+            setup_done: false,
+            channels: HashMap::new(),
+            max_channels: MAX_CHANNELS_PER_CONNECTION,
+            next_channel_id: 1,
+            next_job_id: 1,
+        }
+    }
+
+    /// Handle a message from the miner and return the replies.
+    ///
+    /// Errors are protocol violations and close the connection.
+    pub(crate) async fn handle_message(
+        &mut self,
+        message: Sv2Message<'static>,
+    ) -> Result<Vec<Sv2Message<'static>>, Sv2Error> {
+        match message {
+            Sv2Message::SetupConnection(setup) => Ok(vec![self.setup_connection(
+                setup.protocol,
+                setup.min_version,
+                setup.max_version,
+            )]),
+            _ if !self.setup_done => Err(Sv2Error::Protocol(
+                "Received message before SetupConnection".into(),
+            )),
+            Sv2Message::OpenStandardMiningChannel(open) => Ok(self.open_standard(open).await),
+            Sv2Message::OpenExtendedMiningChannel(open) => Ok(self.open_extended(open).await),
+            Sv2Message::SubmitSharesStandard(submit) => Ok(self
+                .submit(
+                    submit.channel_id,
+                    submit.sequence_number,
+                    submit.job_id,
+                    None,
+                    submit.ntime,
+                    submit.nonce,
+                    submit.version,
+                )
+                .await),
+            Sv2Message::SubmitSharesExtended(submit) => Ok(self
+                .submit(
+                    submit.channel_id,
+                    submit.sequence_number,
+                    submit.job_id,
+                    Some(submit.extranonce.to_vec()),
+                    submit.ntime,
+                    submit.nonce,
+                    submit.version,
+                )
+                .await),
+            Sv2Message::CloseChannel(close) => {
+                if let Some(channel) = self.channels.remove(&close.channel_id) {
+                    self.release(channel).await;
+                }
+                Ok(vec![])
+            }
+            Sv2Message::UpdateChannel(_) => Ok(vec![]),
+            other => Err(Sv2Error::Protocol(format!(
+                "Unexpected message type {:#04x}",
+                other.message_type()
+            ))),
+        }
+    }
+
+    fn setup_connection(
+        &mut self,
+        protocol: Protocol,
+        min_version: u16,
+        max_version: u16,
+    ) -> Sv2Message<'static> {
+        let error_code = if protocol != Protocol::MiningProtocol {
+            "unsupported-protocol"
+        } else if !(min_version..=max_version).contains(&PROTOCOL_VERSION) {
+            "protocol-version-mismatch"
+        } else {
+            self.setup_done = true;
+            return SetupConnectionSuccess {
+                used_version: PROTOCOL_VERSION,
+                flags: 0,
+            }
+            .into();
+        };
+        SetupConnectionError {
+            flags: 0,
+            error_code: str0255(error_code),
+        }
+        .into()
+    }
+
+    async fn open_standard(
+        &mut self,
+        open: OpenStandardMiningChannel<'static>,
+    ) -> Vec<Sv2Message<'static>> {
+        let request_id = open.request_id.as_u32();
+        let user_identity = open.user_identity.as_utf8_or_hex();
+        let (channel_id, channel) = match self
+            .open_channel(request_id, &user_identity, ChannelKind::Standard)
+            .await
+        {
+            Ok(opened) => opened,
+            Err(error_code) => return vec![open_error(request_id, error_code)],
+        };
+        let mut extranonce_prefix = hex::decode(&channel.session.enonce1_hex).unwrap_or_default();
+        extranonce_prefix.extend_from_slice(&STANDARD_EXTRANONCE2);
+        vec![
+            OpenStandardMiningChannelSuccess {
+                request_id: request_id.into(),
+                channel_id,
+                target: target_for_difficulty(
+                    channel.session.difficulty_adjuster.get_current_difficulty(),
+                ),
+                extranonce_prefix: extranonce_prefix.try_into().unwrap(),
+                group_channel_id: 0,
+            }
+            .into(),
+        ]
+    }
+
+    async fn open_extended(
+        &mut self,
+        open: OpenExtendedMiningChannel<'static>,
+    ) -> Vec<Sv2Message<'static>> {
+        if open.min_extranonce_size as usize > EXTRANONCE2_SIZE {
+            return vec![open_error(
+                open.request_id,
+                "unsupported-min-extranonce-size",
+            )];
+        }
+        let user_identity = open.user_identity.as_utf8_or_hex();
+        let (channel_id, channel) = match self
+            .open_channel(open.request_id, &user_identity, ChannelKind::Extended)
+            .await
+        {
+            Ok(opened) => opened,
+            Err(error_code) => return vec![open_error(open.request_id, error_code)],
+        };
+        vec![
+            OpenExtendedMiningChannelSuccess {
+                request_id: open.request_id,
+                channel_id,
+                target: target_for_difficulty(
+                    channel.session.difficulty_adjuster.get_current_difficulty(),
+                ),
+                extranonce_size: EXTRANONCE2_SIZE as u16,
+                extranonce_prefix: hex::decode(&channel.session.enonce1_hex)
+                    .unwrap_or_default()
+                    .try_into()
+                    .unwrap(),
+            }
+            .into(),
+        ]
+    }
+
+    /// Authorize the user identity on a new session and register the
+    /// channel. Authorization asks the notifier for a job, which opens
+    /// the channel's first job.
+    async fn open_channel(
+        &mut self,
+        request_id: u32,
+        user_identity: &str,
+        kind: ChannelKind,
+    ) -> Result<(u32, &Channel), &'static str> {
+        if self.channels.len() >= self.max_channels {
+            debug!(
+                "Refusing channel for {} from {}, {} channels open",
+                user_identity,
+                self.addr,
+                self.channels.len()
+            );
+            return Err("too-many-channels");
+        }
+        let mut session = Session::<DifficultyAdjuster>::new(
+            self.ctx.start_difficulty,
+            self.ctx.minimum_difficulty,
+            self.ctx.maximum_difficulty,
+            self.version_mask,
+        );
+        let authorize = SimpleRequest::new_authorize(
+            request_id as u64,
+            user_identity.to_string(),
+            Some(String::new()),
+        );
+        if let Err(e) = handle_authorize(authorize, &mut session, self.addr, self.ctx.clone()).await
+        {
+            debug!("Failed to open channel for {}: {}", user_identity, e);
+            return Err("unknown-user");
+        }
+
+        let channel_id = self.next_channel_id;
+        self.next_channel_id = self.next_channel_id.wrapping_add(1);
+        info!(
+            "Opened {:?} channel {} for {} from {}",
+            kind, channel_id, user_identity, self.addr
+        );
+        let channel = self.channels.entry(channel_id).or_insert(Channel {
+            kind,
+            session,
+            jobs: HashMap::new(),
+            prev_hash: None,
+        });
+        Ok((channel_id, channel))
+    }
+
+    /// Translate a stratum v1 notify into jobs for every open channel.
+    ///
+    /// A job on a new previous block hash is sent as a future job and
+    /// activated with SetNewPrevHash, which also drops the channel's
+    /// older jobs. The client.reconnect sent when draining the server is
+    /// translated to Reconnect.
+    pub(crate) async fn handle_notify(&mut self, notify: &str) -> Vec<Sv2Message<'static>> {
+        if let Some(reconnect) = reconnect_message(notify) {
+            info!("Asking SV2 connection {} to reconnect", self.addr);
+            return vec![reconnect];
+        }
+        if self.channels.is_empty() {
+            return vec![];
+        }
+        let Ok(notify) = serde_json::from_str::<Notify>(notify) else {
+            return vec![];
+        };
+        let Ok(job_id) = u64::from_str_radix(&notify.params.job_id, 16) else {
+            return vec![];
+        };
+        let job = match self.ctx.tracker_handle.get_job(JobId(job_id)).await {
+            Ok(Some(job)) => job,
+            _ => {
+                debug!("Job {} not found for SV2 connection {}", job_id, self.addr);
+                return vec![];
+            }
+        };
+        let Ok(work) = SharedWork::new(&job) else {
+            return vec![];
+        };
+
+        let sv2_job_id = self.next_job_id;
+        self.next_job_id = self.next_job_id.wrapping_add(1);
+
+        let mut messages = Vec::new();
+        for (channel_id, channel) in self.channels.iter_mut() {
+            let new_prev_hash =
+                channel.prev_hash.as_deref() != Some(&job.blocktemplate.previousblockhash);
+            let min_ntime = (!new_prev_hash).then_some(job.blocktemplate.curtime);
+            let Ok(job_message) = work.job_for_channel(*channel_id, channel, sv2_job_id, min_ntime)
+            else {
+                continue;
+            };
+            messages.push(job_message);
+            if new_prev_hash {
+                channel.jobs.clear();
+                channel.prev_hash = Some(job.blocktemplate.previousblockhash.clone());
+                messages.push(
+                    SetNewPrevHash {
+                        channel_id: *channel_id,
+                        job_id: sv2_job_id,
+                        prev_hash: work.prev_hash.into(),
+                        min_ntime: job.blocktemplate.curtime,
+                        nbits: work.nbits,
+                    }
+                    .into(),
+                );
+            }
+            channel.jobs.insert(sv2_job_id, JobId(job_id));
+        }
+        messages
+    }
+
+    /// Validate a share through the stratum submit handler and map the
+    /// result to the SV2 replies.
+    #[allow(clippy::too_many_arguments)]
+    async fn submit(
+        &mut self,
+        channel_id: u32,
+        sequence_number: u32,
+        job_id: u32,
+        extranonce: Option<Vec<u8>>,
+        ntime: u32,
+        nonce: u32,
+        version: u32,
+    ) -> Vec<Sv2Message<'static>> {
+        let submit_error = |error_code: &str| -> Sv2Message<'static> {
+            SubmitSharesError {
+                channel_id,
+                sequence_number,
+                error_code: str0255(error_code),
+            }
+            .into()
+        };
+        let Some(channel) = self.channels.get_mut(&channel_id) else {
+            return vec![submit_error("invalid-channel-id")];
+        };
+        let Some(tracker_job_id) = channel.jobs.get(&job_id) else {
+            return vec![submit_error("invalid-job-id")];
+        };
+        let extranonce = match (channel.kind, extranonce) {
+            (ChannelKind::Extended, Some(extranonce)) => extranonce,
+            _ => STANDARD_EXTRANONCE2.to_vec(),
+        };
+        if extranonce.len() != EXTRANONCE2_SIZE {
+            return vec![submit_error("invalid-extranonce-size")];
+        }
+
+        let request = SimpleRequest {
+            id: Some(Id::Number(sequence_number as u64)),
+            method: Cow::Borrowed("mining.submit"),
+            params: Cow::Owned(vec![
+                channel.session.username.clone(),
+                Some(format!("{:016x}", tracker_job_id.0)),
+                Some(hex::encode(extranonce)),
+                Some(format!("{ntime:08x}")),
+                Some(format!("{nonce:08x}")),
+                Some(format!("{version:08x}")),
+            ]),
+        };
+        let difficulty = channel.session.difficulty_adjuster.get_current_difficulty();
+        let responses = match handle_submit(request, &mut channel.session, self.ctx.clone()).await {
+            Ok(responses) => responses,
+            Err(e) => {
+                debug!("Share submission failed on channel {}: {}", channel_id, e);
+                return vec![submit_error("invalid-share")];
+            }
+        };
+
+        responses
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::Response(response) => match (response.result, response.error) {
+                    (Some(serde_json::Value::Bool(true)), _) => Some(
+                        SubmitSharesSuccess {
+                            channel_id,
+                            last_sequence_number: sequence_number,
+                            new_submits_accepted_count: 1,
+                            new_shares_sum: difficulty,
+                        }
+                        .into(),
+                    ),
+                    (_, Some(error)) => Some(submit_error(submit_error_code(error.code))),
+                    _ => Some(submit_error("invalid-share")),
+                },
+                Message::SetDifficulty(set_difficulty) => {
+                    set_difficulty.params.first().map(|difficulty| {
+                        SetTarget {
+                            channel_id,
+                            maximum_target: target_for_difficulty(*difficulty),
+                        }
+                        .into()
+                    })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether the miner has any channel open
+    pub(crate) fn has_channels(&self) -> bool {
+        !self.channels.is_empty()
+    }
+
+    /// Release the worker counts of all channels when the connection closes
+    pub(crate) async fn close(&mut self) {
+        let channels: Vec<Channel> = self.channels.drain().map(|(_, channel)| channel).collect();
+        for channel in channels {
+            self.release(channel).await;
+        }
+    }
+
+    async fn release(&self, channel: Channel) {
+        let _ = self
+            .ctx
+            .metrics
+            .decrement_worker_count(
+                channel.session.btcaddress.clone(),
+                channel.session.workername.clone().unwrap_or_default(),
+            )
+            .await;
+    }
+}
+
+/// The parts of a job shared by all channels
+struct SharedWork {
+    prev_hash: [u8; 32],
+    nbits: u32,
+    version: u32,
+    merkle_path: Vec<sha256d::Hash>,
+    coinbase1: String,
+    coinbase2: String,
+}
+
+impl SharedWork {
+    fn new(job: &JobDetails) -> Result<Self, Sv2Error> {
+        let template = &job.blocktemplate;
+        let prev_hash = BlockHash::from_str(&template.previousblockhash)
+            .map_err(|e| Sv2Error::Protocol(format!("Invalid previous block hash: {e}")))?;
+        let nbits = u32::from_str_radix(&template.bits, 16)
+            .map_err(|e| Sv2Error::Protocol(format!("Invalid bits: {e}")))?;
+        Ok(Self {
+            prev_hash: prev_hash.to_byte_array(),
+            nbits,
+            version: template.version as u32,
+            merkle_path: build_merkle_branches_for_template(template),
+            coinbase1: job.coinbase1.clone(),
+            coinbase2: job.coinbase2.clone(),
+        })
+    }
+
+    fn job_for_channel(
+        &self,
+        channel_id: u32,
+        channel: &Channel,
+        job_id: u32,
+        min_ntime: Option<u32>,
+    ) -> Result<Sv2Message<'static>, Sv2Error> {
+        match channel.kind {
+            ChannelKind::Extended => Ok(NewExtendedMiningJob {
+                channel_id,
+                job_id,
+                min_ntime: Sv2Option::new(min_ntime),
+                version: self.version,
+                version_rolling_allowed: true,
+                merkle_path: Seq0255::new(
+                    self.merkle_path
+                        .iter()
+                        .map(|hash| U256::from(hash.to_byte_array()))
+                        .collect(),
+                )
+                .map_err(|e| Sv2Error::Codec(format!("{e:?}")))?,
+                coinbase_tx_prefix: decode_hex(&self.coinbase1)?
+                    .try_into()
+                    .map_err(|e| Sv2Error::Codec(format!("{e:?}")))?,
+                coinbase_tx_suffix: decode_hex(&self.coinbase2)?
+                    .try_into()
+                    .map_err(|e| Sv2Error::Codec(format!("{e:?}")))?,
+            }
+            .into()),
+            ChannelKind::Standard => Ok(NewMiningJob {
+                channel_id,
+                job_id,
+                min_ntime: Sv2Option::new(min_ntime),
+                version: self.version,
+                merkle_root: self.merkle_root(&channel.session.enonce1_hex)?.into(),
+            }
+            .into()),
+        }
+    }
+
+    /// Merkle root for a standard channel's coinbase
+    fn merkle_root(&self, enonce1_hex: &str) -> Result<[u8; 32], Sv2Error> {
+        let coinbase = build_coinbase_from_components(
+            &self.coinbase1,
+            enonce1_hex,
+            &hex::encode(STANDARD_EXTRANONCE2),
+            &self.coinbase2,
+        )
+        .map_err(|e| Sv2Error::Protocol(e.to_string()))?;
+        let root =
+            self.merkle_path
+                .iter()
+                .fold(coinbase.compute_txid().to_raw_hash(), |root, branch| {
+                    sha256d::Hash::hash(&[root.to_byte_array(), branch.to_byte_array()].concat())
+                });
+        Ok(root.to_byte_array())
+    }
+}
+
+/// Translate a stratum v1 client.reconnect into Reconnect. SV2 has no
+/// wait before reconnecting, an empty host and zero port mean the miner
+/// reconnects to the same server.
+fn reconnect_message(message: &str) -> Option<Sv2Message<'static>> {
+    let reconnect = serde_json::from_str::<ReconnectNotification>(message).ok()?;
+    if reconnect.method != "client.reconnect" {
+        return None;
+    }
+    let new_host = reconnect
+        .params
+        .first()
+        .and_then(|host| host.as_str())
+        .unwrap_or_default();
+    let new_port = reconnect
+        .params
+        .get(1)
+        .and_then(|port| port.as_u64())
+        .and_then(|port| u16::try_from(port).ok())
+        .unwrap_or_default();
+    Some(
+        Reconnect {
+            new_host: str0255(new_host),
+            new_port,
+        }
+        .into(),
+    )
+}
+
+fn decode_hex(data: &str) -> Result<Vec<u8>, Sv2Error> {
+    hex::decode(data).map_err(|e| Sv2Error::Protocol(format!("Invalid hex: {e}")))
+}
+
+fn open_error(request_id: u32, error_code: &str) -> Sv2Message<'static> {
+    OpenMiningChannelError {
+        request_id,
+        error_code: str0255(error_code),
+    }
+    .into()
+}
+
+fn str0255(value: &str) -> codec_sv2::binary_sv2::Str0255<'static> {
+    value.to_string().try_into().unwrap()
+}
+
+/// Map the stratum error codes returned by the submit handler to the
+/// SV2 SubmitShares.Error codes
+fn submit_error_code(code: i32) -> &'static str {
+    match code {
+        20 => "invalid-timestamp",
+        21 => "stale-share",
+        22 => "duplicate-share",
+        23 => "difficulty-too-low",
+        24 => "unauthorized",
+        _ => "invalid-share",
+    }
+}
+
+/// Target for a pool difficulty, the difficulty 1 target divided by
+/// the difficulty, as a little endian U256
+pub(crate) fn target_for_difficulty(difficulty: u64) -> U256<'static> {
+    // Difficulty 1 target 0xffff << 208 as little endian u64 limbs
+    let limbs: [u64; 4] = [0, 0, 0, 0xffff << 16];
+    let divisor = difficulty.max(1) as u128;
+    let mut quotient = [0u64; 4];
+    let mut remainder: u128 = 0;
+    for i in (0..4).rev() {
+        let current = (remainder << 64) | limbs[i] as u128;
+        quotient[i] = (current / divisor) as u64;
+        remainder = current % divisor;
+    }
+    let mut target = [0u8; 32];
+    for (i, limb) in quotient.iter().enumerate() {
+        target[i * 8..(i + 1) * 8].copy_from_slice(&limb.to_le_bytes());
+    }
+    target.into()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::accounting::stats::metrics;
+    use crate::shares::chain::chain_store::ChainStore;
+    use crate::shares::share_block::ShareBlock;
+    use crate::store::Store;
+    use crate::stratum::emission::EmissionReceiver;
+    use crate::stratum::sv2::noise::tests::setup_connection;
+    use crate::stratum::work::notify::NotifyCmd;
+    use crate::stratum::work::tracker::start_tracker_actor;
+    use crate::test_utils::{create_test_commitment, load_valid_stratum_work_components};
+    use bitcoindrpc::BitcoinRpcConfig;
+    use bitcoindrpc::test_utils::{mock_submit_block_with_any_body, setup_mock_bitcoin_rpc};
+    use mining_sv2::SubmitSharesExtended;
+    use std::sync::Arc;
+    use tempfile::{TempDir, tempdir};
+    use tokio::sync::mpsc;
+
+    const FIXTURES: &str = "../tests/test_data/validation/stratum/b/";
+    const USER: &str = "tb1q3udk7r26qs32ltf9nmqrjaaa7tr55qmkk30q5d";
+
+    /// Stratum context on signet with the receivers and directories
+    /// that have to outlive it
+    pub(crate) async fn test_context(
+        bitcoinrpc_config: BitcoinRpcConfig,
+    ) -> (
+        StratumContext,
+        mpsc::Receiver<NotifyCmd>,
+        EmissionReceiver,
+        Vec<TempDir>,
+    ) {
+        let (notify_tx, notify_rx) = mpsc::channel(10);
+        let (emissions_tx, emissions_rx) = mpsc::channel(10);
+        let stats_dir = tempdir().unwrap();
+        let metrics = metrics::start_metrics(stats_dir.path().to_str().unwrap().to_string())
+            .await
+            .unwrap();
+        let store_dir = tempdir().unwrap();
+        let store = Arc::new(ChainStore::new(
+            Arc::new(Store::new(store_dir.path().to_str().unwrap().to_string(), false).unwrap()),
+            ShareBlock::build_genesis_for_network(bitcoin::Network::Signet),
+            bitcoin::Network::Signet,
+        ));
+        let ctx = StratumContext {
+            notify_tx,
+            tracker_handle: start_tracker_actor(),
+            bitcoinrpc_config,
+            start_difficulty: 1,
+            minimum_difficulty: 1,
+            maximum_difficulty: None,
+            emissions_tx,
+            network: bitcoin::Network::Signet,
+            metrics,
+            store,
+        };
+        (ctx, notify_rx, emissions_rx, vec![stats_dir, store_dir])
+    }
+
+    async fn setup(ctx: StratumContext) -> MiningConnection {
+        let mut connection =
+            MiningConnection::new(SocketAddr::from(([127, 0, 0, 1], 3334)), 0x1fffe000, ctx);
+        let replies = connection
+            .handle_message(setup_connection().into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            replies[..],
+            [Sv2Message::SetupConnectionSuccess(_)]
+        ));
+        connection
+    }
+
+    /// Use the fixture's extranonce1 for the channel and send it the
+    /// fixture's job, returning the SV2 job messages
+    async fn send_fixture_job(
+        connection: &mut MiningConnection,
+        channel_id: u32,
+    ) -> Vec<Sv2Message<'static>> {
+        let (template, notify, _submit, authorize_response) =
+            load_valid_stratum_work_components(FIXTURES);
+        let enonce1 = authorize_response.result.unwrap()[1].clone();
+        let channel = connection.channels.get_mut(&channel_id).unwrap();
+        channel.session.enonce1_hex = enonce1.as_str().unwrap().to_string();
+
+        connection
+            .ctx
+            .tracker_handle
+            .insert_job(
+                Arc::new(template),
+                notify.params.coinbase1.clone(),
+                notify.params.coinbase2.clone(),
+                Some(create_test_commitment()),
+                JobId(u64::from_str_radix(&notify.params.job_id, 16).unwrap()),
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+        connection
+            .handle_notify(&serde_json::to_string(&notify).unwrap())
+            .await
+    }
+
+    #[test]
+    fn test_target_for_difficulty() {
+        let target = target_for_difficulty(1);
+        assert_eq!(target.inner_as_ref()[26..28], [0xff, 0xff]);
+        assert!(target.inner_as_ref()[..26].iter().all(|byte| *byte == 0));
+        assert_eq!(target.inner_as_ref()[28..], [0, 0, 0, 0]);
+
+        // Difficulty 0xffff leaves 1 << 208
+        let target = target_for_difficulty(0xffff);
+        let mut expected = [0u8; 32];
+        expected[26] = 1;
+        assert_eq!(target.inner_as_ref(), expected);
+
+        // Difficulty zero is treated as one
+        assert_eq!(
+            target_for_difficulty(0).inner_as_ref(),
+            target_for_difficulty(1).inner_as_ref()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_setup_connection_required_and_version_checked() {
+        let (_mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        let (ctx, _notify_rx, _emissions_rx, _dirs) = test_context(bitcoinrpc_config).await;
+        let mut connection =
+            MiningConnection::new(SocketAddr::from(([127, 0, 0, 1], 3334)), 0x1fffe000, ctx);
+
+        let close = mining_sv2::CloseChannel {
+            channel_id: 1,
+            reason_code: str0255(""),
+        };
+        assert!(connection.handle_message(close.into()).await.is_err());
+
+        let mut setup = setup_connection();
+        setup.min_version = 3;
+        setup.max_version = 3;
+        let replies = connection.handle_message(setup.into()).await.unwrap();
+        match &replies[..] {
+            [Sv2Message::SetupConnectionError(error)] => {
+                assert_eq!(
+                    error.error_code.as_utf8_or_hex(),
+                    "protocol-version-mismatch"
+                )
+            }
+            other => panic!("Expected SetupConnectionError, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extended_channel_share_accepted() {
+        let (mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        mock_submit_block_with_any_body(&mock_server).await;
+        let (ctx, mut notify_rx, mut emissions_rx, _dirs) = test_context(bitcoinrpc_config).await;
+        let mut connection = setup(ctx).await;
+
+        let open = OpenExtendedMiningChannel {
+            request_id: 7,
+            user_identity: str0255(USER),
+            nominal_hash_rate: 1.0e12,
+            max_target: [0xff; 32].into(),
+            min_extranonce_size: EXTRANONCE2_SIZE as u16,
+        };
+        let channel_id = match &connection.handle_message(open.into()).await.unwrap()[..] {
+            [Sv2Message::OpenExtendedMiningChannelSuccess(success)] => {
+                assert_eq!(success.request_id, 7);
+                assert_eq!(success.extranonce_size, EXTRANONCE2_SIZE as u16);
+                assert_eq!(success.extranonce_prefix.inner_as_ref().len(), 4);
+                success.channel_id
+            }
+            other => panic!("Expected OpenExtendedMiningChannelSuccess, got {other:?}"),
+        };
+        // Opening the channel asks the notifier for a job
+        assert!(matches!(
+            notify_rx.try_recv(),
+            Ok(NotifyCmd::SendToClient { .. })
+        ));
+
+        let job_id = match &send_fixture_job(&mut connection, channel_id).await[..] {
+            [
+                Sv2Message::NewExtendedMiningJob(job),
+                Sv2Message::SetNewPrevHash(prev_hash),
+            ] => {
+                assert!(job.min_ntime.clone().into_inner().is_none());
+                assert_eq!(prev_hash.job_id, job.job_id);
+                assert_eq!(prev_hash.nbits, 0x1e0377ae);
+                job.job_id
+            }
+            other => panic!("Expected a future job and SetNewPrevHash, got {other:?}"),
+        };
+
+        // Extranonces must be the size given when opening the channel
+        let short_submit = SubmitSharesExtended {
+            channel_id,
+            sequence_number: 1,
+            job_id,
+            nonce: 0xf15f1590,
+            ntime: 0x67b6f938,
+            version: 0x20000000,
+            extranonce: vec![0u8; EXTRANONCE2_SIZE - 1].try_into().unwrap(),
+        };
+        match &connection
+            .handle_message(short_submit.into())
+            .await
+            .unwrap()[..]
+        {
+            [Sv2Message::SubmitSharesError(error)] => {
+                assert_eq!(error.error_code.as_utf8_or_hex(), "invalid-extranonce-size")
+            }
+            other => panic!("Expected SubmitSharesError, got {other:?}"),
+        }
+
+        let submit = SubmitSharesExtended {
+            channel_id,
+            sequence_number: 1,
+            job_id,
+            nonce: 0xf15f1590,
+            ntime: 0x67b6f938,
+            version: 0x20000000,
+            extranonce: vec![0u8; EXTRANONCE2_SIZE].try_into().unwrap(),
+        };
+        match &connection
+            .handle_message(submit.clone().into())
+            .await
+            .unwrap()[..]
+        {
+            [Sv2Message::SubmitSharesSuccess(success)] => {
+                assert_eq!(success.last_sequence_number, 1);
+                assert_eq!(success.new_submits_accepted_count, 1);
+            }
+            other => panic!("Expected SubmitSharesSuccess, got {other:?}"),
+        }
+        let emission = emissions_rx.try_recv().unwrap();
+        assert_eq!(emission.pplns.btcaddress, Some(USER.to_string()));
+        mock_server.verify().await;
+
+        // Resubmitting the share is rejected as a duplicate
+        match &connection.handle_message(submit.into()).await.unwrap()[..] {
+            [Sv2Message::SubmitSharesError(error)] => {
+                assert_eq!(error.error_code.as_utf8_or_hex(), "duplicate-share")
+            }
+            other => panic!("Expected SubmitSharesError, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_standard_channel_job_merkle_root() {
+        let (mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        mock_submit_block_with_any_body(&mock_server).await;
+        let (ctx, _notify_rx, mut emissions_rx, _dirs) = test_context(bitcoinrpc_config).await;
+        let mut connection = setup(ctx).await;
+
+        let open = OpenStandardMiningChannel {
+            request_id: 3.into(),
+            user_identity: str0255(USER),
+            nominal_hash_rate: 1.0e12,
+            max_target: [0xff; 32].into(),
+        };
+        let channel_id = match &connection.handle_message(open.into()).await.unwrap()[..] {
+            [Sv2Message::OpenStandardMiningChannelSuccess(success)] => success.channel_id,
+            other => panic!("Expected OpenStandardMiningChannelSuccess, got {other:?}"),
+        };
+
+        let (job_id, merkle_root) = match &send_fixture_job(&mut connection, channel_id).await[..] {
+            [Sv2Message::NewMiningJob(job), Sv2Message::SetNewPrevHash(_)] => {
+                (job.job_id, job.merkle_root.to_vec())
+            }
+            other => panic!("Expected a future job and SetNewPrevHash, got {other:?}"),
+        };
+
+        let submit = mining_sv2::SubmitSharesStandard {
+            channel_id,
+            sequence_number: 1,
+            job_id,
+            nonce: 0xf15f1590,
+            ntime: 0x67b6f938,
+            version: 0x20000000,
+        };
+        let replies = connection.handle_message(submit.into()).await.unwrap();
+        assert!(matches!(replies[..], [Sv2Message::SubmitSharesSuccess(_)]));
+
+        // The share's block commits to the merkle root sent with the job
+        let emission = emissions_rx.try_recv().unwrap();
+        assert_eq!(
+            emission.block.header.merkle_root.to_byte_array().to_vec(),
+            merkle_root
+        );
+
+        // Unknown jobs and channels are rejected
+        let submit = mining_sv2::SubmitSharesStandard {
+            channel_id,
+            sequence_number: 2,
+            job_id: job_id + 1,
+            nonce: 0,
+            ntime: 0x67b6f938,
+            version: 0x20000000,
+        };
+        match &connection.handle_message(submit.into()).await.unwrap()[..] {
+            [Sv2Message::SubmitSharesError(error)] => {
+                assert_eq!(error.error_code.as_utf8_or_hex(), "invalid-job-id")
+            }
+            other => panic!("Expected SubmitSharesError, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_channels_per_connection_capped() {
+        let (_mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        let (ctx, mut notify_rx, _emissions_rx, _dirs) = test_context(bitcoinrpc_config).await;
+        let mut connection = setup(ctx).await;
+        connection.max_channels = 2;
+
+        let open = |request_id: u32| OpenStandardMiningChannel {
+            request_id: request_id.into(),
+            user_identity: str0255(USER),
+            nominal_hash_rate: 1.0e12,
+            max_target: [0xff; 32].into(),
+        };
+        for request_id in 1..=2 {
+            let replies = connection
+                .handle_message(open(request_id).into())
+                .await
+                .unwrap();
+            assert!(matches!(
+                replies[..],
+                [Sv2Message::OpenStandardMiningChannelSuccess(_)]
+            ));
+            assert!(notify_rx.try_recv().is_ok());
+        }
+
+        match &connection.handle_message(open(3).into()).await.unwrap()[..] {
+            [Sv2Message::OpenMiningChannelError(error)] => {
+                assert_eq!(error.request_id, 3);
+                assert_eq!(error.error_code.as_utf8_or_hex(), "too-many-channels");
+            }
+            other => panic!("Expected OpenMiningChannelError, got {other:?}"),
+        }
+        assert_eq!(connection.channels.len(), 2);
+        assert!(notify_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_sent_without_channels() {
+        let (_mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        let (ctx, _notify_rx, _emissions_rx, _dirs) = test_context(bitcoinrpc_config).await;
+        let mut connection = setup(ctx).await;
+
+        let reconnect = Message::Reconnect(ReconnectNotification::new(
+            Some("pool.example.com"),
+            Some(3334),
+            Some(5),
+        ));
+        match &connection
+            .handle_notify(&serde_json::to_string(&reconnect).unwrap())
+            .await[..]
+        {
+            [Sv2Message::Reconnect(reconnect)] => {
+                assert_eq!(reconnect.new_host.as_utf8_or_hex(), "pool.example.com");
+                assert_eq!(reconnect.new_port, 3334);
+            }
+            other => panic!("Expected Reconnect, got {other:?}"),
+        }
+
+        // Without a host the miner reconnects to the same server
+        let reconnect = Message::Reconnect(ReconnectNotification::new(None, None, None));
+        match &connection
+            .handle_notify(&serde_json::to_string(&reconnect).unwrap())
+            .await[..]
+        {
+            [Sv2Message::Reconnect(reconnect)] => {
+                assert_eq!(reconnect.new_host.as_utf8_or_hex(), "");
+                assert_eq!(reconnect.new_port, 0);
+            }
+            other => panic!("Expected Reconnect, got {other:?}"),
+        }
+    }
+}
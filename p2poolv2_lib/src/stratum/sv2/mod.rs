@@ -0,0 +1,28 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+//! Stratum V2 mining protocol endpoint.
+//!
+//! SV2 miners connect over a Noise encrypted connection and open
+//! standard or extended channels. Each channel is backed by a stratum
+//! [`Session`](crate::stratum::session::Session) and receives the jobs
+//! built for SV1 miners, so share validation, PPLNS accounting and
+//! emissions are shared by both protocols.
+
+pub mod messages;
+pub mod mining;
+pub mod noise;
+pub mod server;
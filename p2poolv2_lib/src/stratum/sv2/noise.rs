@@ -0,0 +1,358 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+use crate::stratum::sv2::messages::{Sv2Message, decode};
+use bitcoin::secp256k1::{Keypair, Secp256k1, SecretKey};
+use codec_sv2::framing_sv2::framing::Sv2Frame;
+use codec_sv2::framing_sv2::header::Header;
+use codec_sv2::framing_sv2::{
+    ENCRYPTED_SV2_FRAME_HEADER_SIZE, SV2_FRAME_CHUNK_SIZE, SV2_FRAME_HEADER_SIZE,
+};
+use codec_sv2::noise_sv2::{AEAD_MAC_LEN, ELLSWIFT_ENCODING_SIZE, NoiseCodec, Responder};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest message payload accepted from miners. Miners only send
+/// small control and submit messages.
+pub const MAX_PAYLOAD_LEN: usize = 64 * 1024;
+
+/// Errors on a Stratum V2 connection. All of them close the connection.
+#[derive(Debug, thiserror::Error)]
+pub enum Sv2Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Noise handshake failed: {0}")]
+    Handshake(String),
+    #[error("Codec error: {0}")]
+    Codec(String),
+    #[error("Invalid authority key: {0}")]
+    InvalidKey(String),
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+}
+
+/// Authority key pair signing the certificates presented to miners
+/// during the noise handshake. Miners pin the public key.
+#[derive(Clone)]
+pub struct AuthorityKeys {
+    public: [u8; 32],
+    secret: [u8; 32],
+    cert_validity: Duration,
+}
+
+impl AuthorityKeys {
+    /// Load the key pair from a hex encoded secp256k1 secret key
+    pub fn from_secret_hex(secret: &str, cert_validity: Duration) -> Result<Self, Sv2Error> {
+        let secret_key =
+            SecretKey::from_str(secret).map_err(|e| Sv2Error::InvalidKey(e.to_string()))?;
+        let keypair = Keypair::from_secret_key(&Secp256k1::new(), &secret_key);
+        Ok(Self {
+            public: keypair.x_only_public_key().0.serialize(),
+            secret: secret_key.secret_bytes(),
+            cert_validity,
+        })
+    }
+
+    /// The x-only public key, hex encoded, to hand out to miners
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.public)
+    }
+}
+
+/// Keep the secret key out of logs
+impl std::fmt::Debug for AuthorityKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthorityKeys")
+            .field("public", &self.public_key_hex())
+            .field("cert_validity", &self.cert_validity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An encrypted SV2 connection after the noise handshake.
+pub struct NoiseConnection<R, W> {
+    reader: R,
+    writer: W,
+    codec: NoiseCodec,
+    /// Bytes read from the socket and not yet decrypted
+    buffer: Vec<u8>,
+    /// Header of the frame being received, once it has been decrypted
+    header: Option<Header>,
+}
+
+impl<R, W> NoiseConnection<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Run the responder side of the noise NX handshake and return the
+    /// connection in transport mode.
+    pub async fn accept(
+        mut reader: R,
+        mut writer: W,
+        keys: &AuthorityKeys,
+    ) -> Result<Self, Sv2Error> {
+        let mut responder =
+            Responder::from_authority_kp(&keys.public, &keys.secret, keys.cert_validity)
+                .map_err(|e| Sv2Error::Handshake(format!("{e:?}")))?;
+
+        let mut initiator_ephemeral = [0u8; ELLSWIFT_ENCODING_SIZE];
+        reader.read_exact(&mut initiator_ephemeral).await?;
+        let (reply, codec) = responder
+            .step_1(initiator_ephemeral)
+            .map_err(|e| Sv2Error::Handshake(format!("{e:?}")))?;
+        writer.write_all(&reply).await?;
+        writer.flush().await?;
+
+        Ok(Self::with_codec(reader, writer, codec))
+    }
+
+    fn with_codec(reader: R, writer: W, codec: NoiseCodec) -> Self {
+        Self {
+            reader,
+            writer,
+            codec,
+            buffer: Vec::new(),
+            header: None,
+        }
+    }
+
+    /// Receive the next message, None when the peer closed the connection.
+    ///
+    /// Cancel safe, bytes read are buffered until a full frame arrives,
+    /// so it can be used in `tokio::select!`.
+    pub async fn recv(&mut self) -> Result<Option<Sv2Message<'static>>, Sv2Error> {
+        loop {
+            if let Some(message) = self.decode_buffered()? {
+                return Ok(Some(message));
+            }
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Decrypt the next frame if all of it has been received
+    fn decode_buffered(&mut self) -> Result<Option<Sv2Message<'static>>, Sv2Error> {
+        if self.header.is_none() {
+            if self.buffer.len() < ENCRYPTED_SV2_FRAME_HEADER_SIZE {
+                return Ok(None);
+            }
+            let mut header: Vec<u8> = self
+                .buffer
+                .drain(..ENCRYPTED_SV2_FRAME_HEADER_SIZE)
+                .collect();
+            self.codec
+                .decrypt(&mut header)
+                .map_err(|e| Sv2Error::Codec(format!("{e:?}")))?;
+            let header =
+                Header::from_bytes(&header).map_err(|e| Sv2Error::Codec(format!("{e:?}")))?;
+            if header.encrypted_len() > MAX_PAYLOAD_LEN + AEAD_MAC_LEN {
+                return Err(Sv2Error::Protocol(format!(
+                    "Message of {} bytes exceeds limit of {MAX_PAYLOAD_LEN}",
+                    header.encrypted_len()
+                )));
+            }
+            self.header = Some(header);
+        }
+
+        let Some(header) = self.header else {
+            return Ok(None);
+        };
+        if self.buffer.len() < header.encrypted_len() {
+            return Ok(None);
+        }
+        self.header = None;
+
+        let mut payload = Vec::with_capacity(header.encrypted_len());
+        let encrypted: Vec<u8> = self.buffer.drain(..header.encrypted_len()).collect();
+        for chunk in encrypted.chunks(SV2_FRAME_CHUNK_SIZE) {
+            let mut chunk = chunk.to_vec();
+            self.codec
+                .decrypt(&mut chunk)
+                .map_err(|e| Sv2Error::Codec(format!("{e:?}")))?;
+            payload.extend_from_slice(&chunk);
+        }
+        decode(header.msg_type(), &mut payload).map(Some)
+    }
+
+    /// Encrypt and send a message
+    pub async fn send(&mut self, message: Sv2Message<'_>) -> Result<(), Sv2Error> {
+        let message_type = message.message_type();
+        let channel_bit = message.channel_bit();
+        let frame: Sv2Frame<Sv2Message<'_>, Vec<u8>> =
+            Sv2Frame::from_message(message, message_type, 0, channel_bit)
+                .ok_or_else(|| Sv2Error::Codec("Message too large".into()))?;
+        let mut plaintext = vec![0u8; frame.encoded_length()];
+        frame
+            .serialize(&mut plaintext)
+            .map_err(|e| Sv2Error::Codec(format!("{e:?}")))?;
+
+        let mut bytes = plaintext[..SV2_FRAME_HEADER_SIZE].to_vec();
+        self.codec
+            .encrypt(&mut bytes)
+            .map_err(|e| Sv2Error::Codec(format!("{e:?}")))?;
+        for chunk in plaintext[SV2_FRAME_HEADER_SIZE..].chunks(SV2_FRAME_CHUNK_SIZE - AEAD_MAC_LEN)
+        {
+            let mut chunk = chunk.to_vec();
+            self.codec
+                .encrypt(&mut chunk)
+                .map_err(|e| Sv2Error::Codec(format!("{e:?}")))?;
+            bytes.extend_from_slice(&chunk);
+        }
+
+        self.writer.write_all(&bytes).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use codec_sv2::noise_sv2::{INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE, Initiator};
+    use common_messages_sv2::{Protocol, SetupConnection, SetupConnectionSuccess};
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    pub(crate) const SECRET: &str =
+        "0101010101010101010101010101010101010101010101010101010101010101";
+
+    pub(crate) type DuplexConnection =
+        NoiseConnection<ReadHalf<DuplexStream>, WriteHalf<DuplexStream>>;
+
+    /// Connect as a miner, verifying the pool's authority key
+    pub(crate) async fn connect<R, W>(
+        mut reader: R,
+        mut writer: W,
+        authority: &AuthorityKeys,
+    ) -> NoiseConnection<R, W>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut initiator = Initiator::from_raw_k(authority.public).unwrap();
+        writer
+            .write_all(&initiator.step_0().unwrap())
+            .await
+            .unwrap();
+        let mut reply = [0u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE];
+        reader.read_exact(&mut reply).await.unwrap();
+        let codec = initiator.step_2(reply).unwrap();
+        NoiseConnection::with_codec(reader, writer, codec)
+    }
+
+    /// A pool and a miner connection over an in memory stream
+    pub(crate) async fn connected_pair() -> (DuplexConnection, DuplexConnection) {
+        let keys = AuthorityKeys::from_secret_hex(SECRET, Duration::from_secs(3600)).unwrap();
+        let (pool, miner) = tokio::io::duplex(256 * 1024);
+        let (pool_reader, pool_writer) = tokio::io::split(pool);
+        let (miner_reader, miner_writer) = tokio::io::split(miner);
+        let pool_keys = keys.clone();
+        let pool = tokio::spawn(async move {
+            NoiseConnection::accept(pool_reader, pool_writer, &pool_keys)
+                .await
+                .unwrap()
+        });
+        let miner = connect(miner_reader, miner_writer, &keys).await;
+        (pool.await.unwrap(), miner)
+    }
+
+    pub(crate) fn setup_connection() -> SetupConnection<'static> {
+        SetupConnection {
+            protocol: Protocol::MiningProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0,
+            endpoint_host: "127.0.0.1".to_string().try_into().unwrap(),
+            endpoint_port: 3334,
+            vendor: "test".to_string().try_into().unwrap(),
+            hardware_version: "".to_string().try_into().unwrap(),
+            firmware: "".to_string().try_into().unwrap(),
+            device_id: "".to_string().try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_authority_keys_from_secret_hex() {
+        let keys = AuthorityKeys::from_secret_hex(SECRET, Duration::from_secs(60)).unwrap();
+        assert_eq!(keys.public_key_hex().len(), 64);
+        assert!(AuthorityKeys::from_secret_hex("not hex", Duration::from_secs(60)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_exchange_messages() {
+        let (mut pool, mut miner) = connected_pair().await;
+
+        miner.send(setup_connection().into()).await.unwrap();
+        match pool.recv().await.unwrap() {
+            Some(Sv2Message::SetupConnection(setup)) => {
+                assert_eq!(setup.endpoint_port, 3334);
+                assert_eq!(setup.vendor.as_utf8_or_hex(), "test");
+            }
+            other => panic!("Expected SetupConnection, got {other:?}"),
+        }
+
+        // Several frames in flight are decoded one at a time
+        for flags in 0..3 {
+            pool.send(
+                SetupConnectionSuccess {
+                    used_version: 2,
+                    flags,
+                }
+                .into(),
+            )
+            .await
+            .unwrap();
+        }
+        for expected in 0..3 {
+            match miner.recv().await.unwrap() {
+                Some(Sv2Message::SetupConnectionSuccess(success)) => {
+                    assert_eq!(success.flags, expected)
+                }
+                other => panic!("Expected SetupConnectionSuccess, got {other:?}"),
+            }
+        }
+
+        drop(miner);
+        assert!(pool.recv().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_fails_with_wrong_authority() {
+        let keys = AuthorityKeys::from_secret_hex(SECRET, Duration::from_secs(3600)).unwrap();
+        let other = AuthorityKeys::from_secret_hex(
+            "0202020202020202020202020202020202020202020202020202020202020202",
+            Duration::from_secs(3600),
+        )
+        .unwrap();
+        let (pool, miner) = tokio::io::duplex(64 * 1024);
+        let (pool_reader, pool_writer) = tokio::io::split(pool);
+        let (mut miner_reader, mut miner_writer) = tokio::io::split(miner);
+        tokio::spawn(async move {
+            let _ = NoiseConnection::accept(pool_reader, pool_writer, &keys).await;
+        });
+
+        let mut initiator = Initiator::from_raw_k(other.public).unwrap();
+        miner_writer
+            .write_all(&initiator.step_0().unwrap())
+            .await
+            .unwrap();
+        let mut reply = [0u8; INITIATOR_EXPECTED_HANDSHAKE_MESSAGE_SIZE];
+        miner_reader.read_exact(&mut reply).await.unwrap();
+        assert!(initiator.step_2(reply).is_err());
+    }
+}
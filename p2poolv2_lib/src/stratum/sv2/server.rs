@@ -0,0 +1,426 @@
+// Copyright (C) 2024, 2025 P2Poolv2 Developers (see AUTHORS)
+//
+// This file is part of P2Poolv2
+//
+// P2Poolv2 is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License as published by the Free
+// Software Foundation, either version 3 of the License, or (at your option)
+// any later version.
+//
+// P2Poolv2 is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+// FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
+
+#[cfg(not(test))]
+use crate::stratum::client_connections::ClientConnectionsHandle;
+#[cfg(test)]
+#[mockall_double::double]
+use crate::stratum::client_connections::ClientConnectionsHandle;
+
+use crate::accounting::stats::metrics;
+use crate::shares::chain::chain_store::ChainStore;
+use crate::stratum::emission::EmissionSender;
+use crate::stratum::server::StratumContext;
+use crate::stratum::session_timeout::FIRST_SHARE_TIMEOUT;
+use crate::stratum::sv2::messages::Sv2Message;
+use crate::stratum::sv2::mining::MiningConnection;
+use crate::stratum::sv2::noise::{AuthorityKeys, NoiseConnection, Sv2Error};
+use crate::stratum::work::notify::NotifyCmd;
+use crate::stratum::work::tracker::TrackerHandle;
+use bitcoindrpc::BitcoinRpcConfig;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::{debug, error, info};
+
+/// Time allowed for a miner to complete the noise handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a miner can stay connected without an open channel, the same
+/// time stratum v1 miners have to authorize
+const NO_CHANNEL_TIMEOUT: Duration = Duration::from_secs(FIRST_SHARE_TIMEOUT);
+
+/// Stratum V2 mining server. Shares the job pipeline with the stratum
+/// v1 server: it receives the same notifies from the notifier and
+/// validates shares with the same handlers.
+pub struct Sv2Server {
+    pub hostname: String,
+    pub port: u16,
+    pub start_difficulty: u64,
+    pub minimum_difficulty: u64,
+    pub maximum_difficulty: Option<u64>,
+    pub network: bitcoin::Network,
+    pub version_mask: i32,
+    authority_keys: AuthorityKeys,
+    shutdown_rx: oneshot::Receiver<()>,
+    connections_handle: ClientConnectionsHandle,
+    emissions_tx: EmissionSender,
+    store: Arc<ChainStore>,
+}
+
+/// Builder for Sv2Server to avoid dependency on StratumConfig
+#[derive(Default)]
+pub struct Sv2ServerBuilder {
+    hostname: Option<String>,
+    port: Option<u16>,
+    start_difficulty: Option<u64>,
+    minimum_difficulty: Option<u64>,
+    maximum_difficulty: Option<Option<u64>>,
+    network: Option<bitcoin::Network>,
+    version_mask: Option<i32>,
+    authority_keys: Option<AuthorityKeys>,
+    shutdown_rx: Option<oneshot::Receiver<()>>,
+    connections_handle: Option<ClientConnectionsHandle>,
+    emissions_tx: Option<EmissionSender>,
+    store: Option<Arc<ChainStore>>,
+}
+
+impl Sv2ServerBuilder {
+    pub fn hostname(mut self, hostname: String) -> Self {
+        self.hostname = Some(hostname);
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn start_difficulty(mut self, start_difficulty: u64) -> Self {
+        self.start_difficulty = Some(start_difficulty);
+        self
+    }
+
+    pub fn minimum_difficulty(mut self, minimum_difficulty: u64) -> Self {
+        self.minimum_difficulty = Some(minimum_difficulty);
+        self
+    }
+
+    pub fn maximum_difficulty(mut self, maximum_difficulty: Option<u64>) -> Self {
+        self.maximum_difficulty = Some(maximum_difficulty);
+        self
+    }
+
+    pub fn network(mut self, network: bitcoin::Network) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    pub fn version_mask(mut self, version_mask: i32) -> Self {
+        self.version_mask = Some(version_mask);
+        self
+    }
+
+    pub fn authority_keys(mut self, authority_keys: AuthorityKeys) -> Self {
+        self.authority_keys = Some(authority_keys);
+        self
+    }
+
+    pub fn shutdown_rx(mut self, shutdown_rx: oneshot::Receiver<()>) -> Self {
+        self.shutdown_rx = Some(shutdown_rx);
+        self
+    }
+
+    pub fn connections_handle(mut self, connections_handle: ClientConnectionsHandle) -> Self {
+        self.connections_handle = Some(connections_handle);
+        self
+    }
+
+    pub fn emissions_tx(mut self, emissions_tx: EmissionSender) -> Self {
+        self.emissions_tx = Some(emissions_tx);
+        self
+    }
+
+    pub fn store(mut self, store: Arc<ChainStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub async fn build(self) -> Result<Sv2Server, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Sv2Server {
+            hostname: self.hostname.ok_or("hostname is required")?,
+            port: self.port.ok_or("port is required")?,
+            start_difficulty: self
+                .start_difficulty
+                .ok_or("start_difficulty is required")?,
+            minimum_difficulty: self
+                .minimum_difficulty
+                .ok_or("minimum_difficulty is required")?,
+            maximum_difficulty: self
+                .maximum_difficulty
+                .ok_or("maximum_difficulty is required")?,
+            network: self.network.ok_or("network is required")?,
+            version_mask: self.version_mask.ok_or("version_mask is required")?,
+            authority_keys: self.authority_keys.ok_or("authority_keys is required")?,
+            shutdown_rx: self.shutdown_rx.ok_or("shutdown_rx is required")?,
+            connections_handle: self
+                .connections_handle
+                .ok_or("connections_handle is required")?,
+            emissions_tx: self.emissions_tx.ok_or("emissions_tx is required")?,
+            store: self.store.ok_or("store is required")?,
+        })
+    }
+}
+
+impl Sv2Server {
+    /// Accept SV2 miners until shutdown
+    pub async fn start(
+        &mut self,
+        ready_tx: Option<oneshot::Sender<()>>,
+        notify_tx: mpsc::Sender<NotifyCmd>,
+        tracker_handle: TrackerHandle,
+        bitcoinrpc_config: BitcoinRpcConfig,
+        metrics: metrics::MetricsHandle,
+    ) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let bind_address = format!("{}:{}", self.hostname, self.port);
+        info!("Starting Stratum V2 server at {}", bind_address);
+        let listener = match TcpListener::bind(&bind_address).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind to {}: {}", bind_address, e);
+                return Err(Box::new(e));
+            }
+        };
+
+        if let Some(ready_tx) = ready_tx {
+            ready_tx.send(()).ok();
+        }
+        loop {
+            tokio::select! {
+                _ = &mut self.shutdown_rx => {
+                    info!("Shutdown signal received");
+                    break;
+                }
+                connection = listener.accept() => {
+                    let (stream, addr) = match connection {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            info!("Connection failed: {}", e);
+                            continue;
+                        }
+                    };
+                    info!("New SV2 connection from: {}", addr);
+                    let (message_rx, shutdown_rx) = self.connections_handle.add(addr).await;
+                    let (reader, writer) = stream.into_split();
+                    let ctx = StratumContext {
+                        notify_tx: notify_tx.clone(),
+                        tracker_handle: tracker_handle.clone(),
+                        bitcoinrpc_config: bitcoinrpc_config.clone(),
+                        start_difficulty: self.start_difficulty,
+                        minimum_difficulty: self.minimum_difficulty,
+                        maximum_difficulty: self.maximum_difficulty,
+                        emissions_tx: self.emissions_tx.clone(),
+                        network: self.network,
+                        metrics: metrics.clone(),
+                        store: self.store.clone(),
+                    };
+                    let keys = self.authority_keys.clone();
+                    let version_mask = self.version_mask;
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(reader, writer, addr, &keys, message_rx, shutdown_rx, version_mask, ctx).await {
+                            error!("Error occurred while handling SV2 connection {addr}: {e}. Closing connection.");
+                        }
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the noise handshake and then the mining protocol on a connection
+/// until either side closes it. Connections without an open channel
+/// for [`NO_CHANNEL_TIMEOUT`] are closed.
+#[allow(clippy::too_many_arguments)]
+async fn handle_connection<R, W>(
+    reader: R,
+    writer: W,
+    addr: SocketAddr,
+    keys: &AuthorityKeys,
+    mut message_rx: mpsc::Receiver<Arc<String>>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+    version_mask: i32,
+    ctx: StratumContext,
+) -> Result<(), Sv2Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut connection = match tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        NoiseConnection::accept(reader, writer, keys),
+    )
+    .await
+    {
+        Ok(connection) => connection?,
+        Err(_) => return Err(Sv2Error::Handshake("Timed out".into())),
+    };
+    let mut mining = MiningConnection::new(addr, version_mask, ctx);
+    let mut idle_deadline = Some(Instant::now() + NO_CHANNEL_TIMEOUT);
+
+    let result = loop {
+        tokio::select! {
+            _ = &mut shutdown_rx => {
+                info!("Shutdown signal received, closing SV2 connection from {}", addr);
+                break Ok(());
+            }
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                info!("SV2 connection from {} opened no channel, disconnecting", addr);
+                break Err(Sv2Error::Protocol("No channel opened".into()));
+            }
+            Some(notify) = message_rx.recv() => {
+                if let Err(e) = send_all(&mut connection, mining.handle_notify(&notify).await).await {
+                    break Err(e);
+                }
+            }
+            message = connection.recv() => {
+                match message {
+                    Ok(Some(message)) => {
+                        debug!("Rx {} {:?}", addr, message);
+                        let replies = match mining.handle_message(message).await {
+                            Ok(replies) => replies,
+                            Err(e) => break Err(e),
+                        };
+                        if let Err(e) = send_all(&mut connection, replies).await {
+                            break Err(e);
+                        }
+                        idle_deadline = match (mining.has_channels(), idle_deadline) {
+                            (true, _) => None,
+                            (false, None) => Some(Instant::now() + NO_CHANNEL_TIMEOUT),
+                            (false, deadline) => deadline,
+                        };
+                    }
+                    Ok(None) => {
+                        info!("SV2 connection closed by client: {}", addr);
+                        break Ok(());
+                    }
+                    Err(e) => break Err(e),
+                }
+            }
+        }
+    };
+    mining.close().await;
+    result
+}
+
+async fn send_all<R, W>(
+    connection: &mut NoiseConnection<R, W>,
+    messages: Vec<Sv2Message<'static>>,
+) -> Result<(), Sv2Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    for message in messages {
+        connection.send(message).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stratum::sv2::mining::tests::test_context;
+    use crate::stratum::sv2::noise::tests::{SECRET, connect, setup_connection};
+    use bitcoindrpc::test_utils::setup_mock_bitcoin_rpc;
+    use mining_sv2::OpenStandardMiningChannel;
+
+    #[tokio::test]
+    async fn test_handle_connection_rejects_unknown_user() {
+        let (_mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        let (ctx, _notify_rx, _emissions_rx, _dirs) = test_context(bitcoinrpc_config).await;
+        let keys = AuthorityKeys::from_secret_hex(SECRET, Duration::from_secs(3600)).unwrap();
+        let (pool, miner) = tokio::io::duplex(64 * 1024);
+        let (pool_reader, pool_writer) = tokio::io::split(pool);
+        let (miner_reader, miner_writer) = tokio::io::split(miner);
+        let (_message_tx, message_rx) = mpsc::channel(10);
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+
+        let pool_keys = keys.clone();
+        let pool = tokio::spawn(async move {
+            handle_connection(
+                pool_reader,
+                pool_writer,
+                SocketAddr::from(([127, 0, 0, 1], 3334)),
+                &pool_keys,
+                message_rx,
+                shutdown_rx,
+                0x1fffe000,
+                ctx,
+            )
+            .await
+        });
+
+        let mut miner = connect(miner_reader, miner_writer, &keys).await;
+        miner.send(setup_connection().into()).await.unwrap();
+        assert!(matches!(
+            miner.recv().await.unwrap(),
+            Some(Sv2Message::SetupConnectionSuccess(_))
+        ));
+
+        let open = OpenStandardMiningChannel {
+            request_id: 1.into(),
+            user_identity: "not an address".to_string().try_into().unwrap(),
+            nominal_hash_rate: 1.0,
+            max_target: [0xff; 32].into(),
+        };
+        miner.send(open.into()).await.unwrap();
+        match miner.recv().await.unwrap() {
+            Some(Sv2Message::OpenMiningChannelError(error)) => {
+                assert_eq!(error.request_id, 1);
+                assert_eq!(error.error_code.as_utf8_or_hex(), "unknown-user");
+            }
+            other => panic!("Expected OpenMiningChannelError, got {other:?}"),
+        }
+
+        drop(miner);
+        assert!(pool.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_closes_without_channel() {
+        let (_mock_server, bitcoinrpc_config) = setup_mock_bitcoin_rpc().await;
+        let (ctx, _notify_rx, _emissions_rx, _dirs) = test_context(bitcoinrpc_config).await;
+        let keys = AuthorityKeys::from_secret_hex(SECRET, Duration::from_secs(3600)).unwrap();
+        let (pool, miner) = tokio::io::duplex(64 * 1024);
+        let (pool_reader, pool_writer) = tokio::io::split(pool);
+        let (miner_reader, miner_writer) = tokio::io::split(miner);
+        let (_message_tx, message_rx) = mpsc::channel(10);
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+
+        let pool_keys = keys.clone();
+        let pool = tokio::spawn(async move {
+            handle_connection(
+                pool_reader,
+                pool_writer,
+                SocketAddr::from(([127, 0, 0, 1], 3334)),
+                &pool_keys,
+                message_rx,
+                shutdown_rx,
+                0x1fffe000,
+                ctx,
+            )
+            .await
+        });
+
+        let mut miner = connect(miner_reader, miner_writer, &keys).await;
+        miner.send(setup_connection().into()).await.unwrap();
+        assert!(matches!(
+            miner.recv().await.unwrap(),
+            Some(Sv2Message::SetupConnectionSuccess(_))
+        ));
+
+        // The miner never opens a channel
+        tokio::time::pause();
+        tokio::time::advance(NO_CHANNEL_TIMEOUT).await;
+        assert!(matches!(pool.await.unwrap(), Err(Sv2Error::Protocol(_))));
+        assert!(matches!(miner.recv().await, Ok(None) | Err(_)));
+    }
+}
@@ -500,6 +500,8 @@ mod tests {
             merkle_root: template.get_merkle_root_without_coinbase(),
             bits: CompactTarget::from_consensus(0x207fffff),
             time: 1700000000u32,
+            donation: None,
+            fee: None,
         };
 
         let share_commitment_cloned = share_commitment.clone();
@@ -19,8 +19,9 @@ use super::coinbase::{build_coinbase_transaction, split_coinbase};
 use super::error::WorkError;
 use super::gbt::build_merkle_branches_for_template;
 use super::tracker::{JobId, TrackerHandle};
-use crate::accounting::OutputPair;
 use crate::accounting::simple_pplns::payout::Payout;
+use crate::accounting::simple_pplns::share_chain_payout;
+use crate::accounting::{LedgerUpdate, OutputPair};
 use crate::config::StratumConfig;
 #[cfg(test)]
 #[mockall_double::double]
@@ -60,29 +61,52 @@ fn parse_flags(flags: Option<String>) -> PushBytesBuf {
 ///
 /// difficulty_multiplier is used to get the total difficulty we need
 /// to match to collect all the shares to use to compute output distribution.
+///
+/// When the node mines on the share chain, i.e. there is a share
+/// commitment, the distribution is computed from the share chain at
+/// the commitment's parent share with the commitment's share bits and
+/// coinbase cuts, so that every node agrees on it. Otherwise the local
+/// stratum shares are used, and entitlements that do not make it into
+/// the coinbase are returned as ledger updates. The share chain
+/// distribution has no ledger updates, as it must be reproducible by
+/// every node.
 async fn build_output_distribution(
     template: &BlockTemplate,
     store: &Arc<ChainStore>,
     config: &StratumConfig<crate::config::Parsed>,
-) -> Vec<OutputPair> {
+    share_commitment: Option<&ShareCommitment>,
+) -> (Vec<OutputPair>, Vec<LedgerUpdate>) {
     const DEFAULT_STEP_SIZE_SECONDS: u64 = 24 * 60 * 60; // 1 day
-    let payout = Payout::new(DEFAULT_STEP_SIZE_SECONDS);
     let total_amount = bitcoin::Amount::from_sat(template.coinbasevalue);
 
-    let compact_target = bitcoin::pow::CompactTarget::from_unprefixed_hex(&template.bits).unwrap();
-    let required_target = bitcoin::Target::from_compact(compact_target);
-
-    let total_difficulty = required_target.difficulty_float() * config.difficulty_multiplier;
+    let distribution = if let Some(commitment) = share_commitment {
+        share_chain_payout::get_output_distribution(
+            store,
+            &commitment.prev_share_blockhash,
+            &commitment.miner_pubkey,
+            share_chain_payout::window_work(commitment.bits),
+            total_amount,
+            &commitment.coinbase_cuts(),
+            config.network,
+        )
+        .map(|distribution| (distribution, Vec::new()))
+    } else {
+        let payout = Payout::new(DEFAULT_STEP_SIZE_SECONDS);
+        let compact_target =
+            bitcoin::pow::CompactTarget::from_unprefixed_hex(&template.bits).unwrap();
+        let required_target = bitcoin::Target::from_compact(compact_target);
+        let total_difficulty = required_target.difficulty_float() * config.difficulty_multiplier;
+        payout
+            .get_output_distribution(store, total_difficulty, total_amount, config)
+            .await
+    };
 
-    match payout
-        .get_output_distribution(&store, total_difficulty, total_amount, config)
-        .await
-    {
+    match distribution {
         Ok(distribution) => distribution,
         Err(e) => {
             // Log error and return empty distribution
             debug!("PPLNS accounting failed: {}", e);
-            Vec::new()
+            (Vec::new(), Vec::new())
         }
     }
 }
@@ -148,12 +172,12 @@ async fn build_notify_and_commitment(
     tracker_handle: &TrackerHandle,
 ) -> Result<(String, Option<ShareCommitment>), WorkError> {
     let job_id = tracker_handle.get_next_job_id().await.unwrap();
-    let output_distribution = build_output_distribution(template, chain_store, config).await;
-
-    let share_commitment =
-        build_share_commitment(chain_store, template, miner_pubkey).map_err(|_| WorkError {
+    let share_commitment = build_share_commitment(chain_store, template, miner_pubkey, config)
+        .map_err(|_| WorkError {
             message: "Failed to build share commitment".to_string(),
         })?;
+    let (output_distribution, ledger_updates) =
+        build_output_distribution(template, chain_store, config, share_commitment.as_ref()).await;
     let commitment_hash = share_commitment
         .as_ref()
         .map(|commitment| commitment.hash());
@@ -177,6 +201,7 @@ async fn build_notify_and_commitment(
             notify.params.coinbase2.to_string(),
             share_commitment.clone(),
             job_id,
+            ledger_updates,
         )
         .await
         .unwrap();
@@ -219,6 +244,14 @@ pub async fn start_notify(
                 let clean_jobs = latest_template.is_none()
                     || latest_template.unwrap().previousblockhash != template.previousblockhash;
                 latest_template = Some(Arc::clone(&template));
+                // Keep the template transactions to reconstruct compact share blocks from peers
+                chain_store.add_template_transactions(
+                    template
+                        .transactions
+                        .iter()
+                        .map(bitcoin::Transaction::from)
+                        .collect(),
+                );
 
                 let (notify_str, _share_commitment) = match build_notify_and_commitment(
                     &template,
@@ -346,11 +379,14 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let stratum_config = StratumConfig::new_for_test_default().parse().unwrap();
 
-        let output_distribution =
-            build_output_distribution(&template, &Arc::new(store), &stratum_config).await;
+        let (output_distribution, _) =
+            build_output_distribution(&template, &Arc::new(store), &stratum_config, None).await;
         // Build Notify
         let notify = build_notify(&template, output_distribution, job_id, false, &[], None)
             .expect("Failed to build notify");
@@ -433,17 +469,35 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         store.expect_add_job().returning(|_| Ok(()));
 
+        // Template transactions are added to the mempool view
+        store
+            .expect_add_template_transactions()
+            .withf(|transactions| transactions.len() == 1)
+            .times(1)
+            .return_const(());
+
         store
             .expect_get_current_target()
             .returning(|| Ok(503543726));
+        store.expect_get_share_balances().returning(|_| Ok(None));
 
-        let genesis = genesis_for_tests().block_hash();
+        let genesis = genesis_for_tests();
+        let genesis_hash = genesis.block_hash();
         store
             .expect_get_chain_tip_and_uncles()
-            .returning(move || (genesis, std::collections::HashSet::new()));
+            .returning(move || (genesis_hash, std::collections::HashSet::new()));
+
+        // Payout is computed from the share chain as a miner pubkey is configured
+        store
+            .expect_get_share_header()
+            .with(mockall::predicate::eq(genesis_hash))
+            .returning(move |_| Some(genesis.header.clone()));
 
         let stratum_config = StratumConfig::new_for_test_default().parse().unwrap();
         let miner_pubkey: CompressedPublicKey =
@@ -564,11 +618,14 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
         let stratum_config = StratumConfig::new_for_test_default().parse().unwrap();
 
-        let output_distribution =
-            build_output_distribution(&template, &Arc::new(store), &stratum_config).await;
+        let (output_distribution, _) =
+            build_output_distribution(&template, &Arc::new(store), &stratum_config, None).await;
 
         let result = build_notify(
             &template,
@@ -616,15 +673,24 @@ mod tests {
         store
             .expect_get_pplns_shares_filtered()
             .return_const(shares);
+        store
+            .expect_get_ledger_balances()
+            .returning(|| Ok(HashMap::new()));
 
-        let genesis = genesis_for_tests().block_hash();
+        let genesis = genesis_for_tests();
+        let genesis_hash = genesis.block_hash();
         store
             .expect_get_chain_tip_and_uncles()
-            .returning(move || (genesis, std::collections::HashSet::new()));
+            .returning(move || (genesis_hash, std::collections::HashSet::new()));
+        store
+            .expect_get_share_header()
+            .with(mockall::predicate::eq(genesis_hash))
+            .returning(move |_| Some(genesis.header.clone()));
 
         store
             .expect_get_current_target()
             .returning(|| Ok(503543726));
+        store.expect_get_share_balances().returning(|_| Ok(None));
 
         // Setup config and tracker
         let stratum_config = StratumConfig::new_for_test_default().parse().unwrap();
@@ -672,7 +738,7 @@ mod tests {
         assert!(details.share_commitment.is_some());
         let stored_commitment = details.share_commitment.unwrap();
         assert_eq!(stored_commitment.miner_pubkey, miner_pubkey);
-        assert_eq!(stored_commitment.prev_share_blockhash, genesis);
+        assert_eq!(stored_commitment.prev_share_blockhash, genesis_hash);
     }
 
     /// This test build_notify, parse then verify
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU General Public License along with
 // P2Poolv2. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::accounting::LedgerUpdate;
 use crate::shares::share_commitment::ShareCommitment;
 
 use super::block_template::BlockTemplate;
@@ -22,7 +23,7 @@ use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, warn};
 
-const MAX_JOB_AGE_SECS: u64 = 15 * 60; // 15 minutes
+pub const MAX_JOB_AGE_SECS: u64 = 15 * 60; // 15 minutes
 
 /// The job id sent to miners.
 /// A job id matches a block template.
@@ -53,6 +54,8 @@ pub struct JobDetails {
     pub coinbase2: String,
     pub generation_timestamp: u64,
     pub share_commitment: Option<ShareCommitment>,
+    /// Ledger changes to apply when the job finds a bitcoin block
+    pub ledger_updates: Vec<LedgerUpdate>,
 }
 
 /// A map that associates templates with job id
@@ -73,6 +76,7 @@ impl Tracker {
         coinbase2: String,
         share_commitment: Option<ShareCommitment>,
         job_id: JobId,
+        ledger_updates: Vec<LedgerUpdate>,
     ) -> JobId {
         self.job_details.insert(
             job_id,
@@ -85,6 +89,7 @@ impl Tracker {
                     .unwrap_or_default()
                     .as_secs(),
                 share_commitment,
+                ledger_updates,
             },
         );
         job_id
@@ -139,6 +144,7 @@ pub enum Command {
         coinbase2: String,
         job_id: JobId,
         share_commitment: Option<ShareCommitment>,
+        ledger_updates: Vec<LedgerUpdate>,
         resp: oneshot::Sender<JobId>,
     },
     /// Get job details by job id
@@ -172,6 +178,7 @@ impl TrackerHandle {
         coinbase2: String,
         share_commitment: Option<ShareCommitment>,
         job_id: JobId,
+        ledger_updates: Vec<LedgerUpdate>,
     ) -> Result<JobId, String> {
         let (resp_tx, resp_rx) = oneshot::channel();
 
@@ -182,6 +189,7 @@ impl TrackerHandle {
                 coinbase2,
                 job_id,
                 share_commitment,
+                ledger_updates,
                 resp: resp_tx,
             })
             .await
@@ -287,6 +295,7 @@ impl TrackerActor {
                     coinbase2,
                     job_id,
                     share_commitment,
+                    ledger_updates,
                     resp,
                 } => {
                     let job_id = self.tracker.insert_job(
@@ -295,6 +304,7 @@ impl TrackerActor {
                         coinbase2,
                         share_commitment,
                         job_id,
+                        ledger_updates,
                     );
                     let _ = resp.send(job_id);
                 }
@@ -414,6 +424,7 @@ mod tests {
                 "cb2".to_string(),
                 Some(create_test_commitment()),
                 JobId(1),
+                Vec::new(),
             )
             .await;
         // Test inserting a block template
@@ -460,6 +471,7 @@ mod tests {
             "old_cb2".to_string(),
             Some(create_test_commitment()),
             old_job_id,
+            Vec::new(),
         );
 
         // Manually set an old timestamp (20 minutes ago)
@@ -479,6 +491,7 @@ mod tests {
             "new_cb2".to_string(),
             None,
             new_job_id,
+            Vec::new(),
         );
 
         // Verify both jobs exist
@@ -504,6 +517,7 @@ mod tests {
                 "old_actor_cb2".to_string(),
                 None,
                 JobId(3),
+                Vec::new(),
             )
             .await
             .unwrap();
@@ -52,6 +52,8 @@ pub fn create_test_commitment() -> ShareCommitment {
         merkle_root: Some(TxMerkleNode::all_zeros()),
         bits: CompactTarget::from_consensus(0x207fffff),
         time: 1700000000,
+        donation: None,
+        fee: None,
     }
 }
 
@@ -80,6 +82,23 @@ pub fn test_coinbase_transaction() -> bitcoin::Transaction {
     create_coinbase_transaction(&pubkey, bitcoin::Network::Signet)
 }
 
+/// A transaction spending a made up output, paying value to an empty script
+#[cfg(test)]
+pub fn test_spending_transaction(value: u64) -> bitcoin::Transaction {
+    bitcoin::Transaction {
+        version: bitcoin::transaction::Version::TWO,
+        lock_time: bitcoin::absolute::LockTime::ZERO,
+        input: vec![bitcoin::TxIn {
+            previous_output: bitcoin::OutPoint::new(bitcoin::Txid::from_byte_array([1; 32]), 0),
+            ..Default::default()
+        }],
+        output: vec![bitcoin::TxOut {
+            value: bitcoin::Amount::from_sat(value),
+            script_pubkey: bitcoin::ScriptBuf::new(),
+        }],
+    }
+}
+
 #[cfg(test)]
 pub fn load_valid_stratum_work_components(
     path: &str,
@@ -124,8 +143,14 @@ pub fn load_valid_stratum_work_components(
 }
 
 #[cfg(test)]
+/// Build a share block that passes consensus validation from the
+/// stratum work components in path.
+///
+/// The bitcoin coinbase commits to the share header and the nonce is
+/// ground until the bitcoin header meets the share target.
 pub fn build_block_from_work_components(path: &str) -> ShareBlock {
     use bitcoin::TxMerkleNode;
+    use bitcoin::blockdata::script::Builder;
 
     let (template, _notify, submit, _authorize) = load_valid_stratum_work_components(path);
 
@@ -136,14 +161,34 @@ pub fn build_block_from_work_components(path: &str) -> ShareBlock {
             .unwrap()
             .into();
 
+    let commitment = ShareCommitment {
+        prev_share_blockhash: BlockHash::all_zeros(),
+        uncles: vec![],
+        miner_pubkey: CompressedPublicKey::from_str(
+            "020202020202020202020202020202020202020202020202020202020202020202",
+        )
+        .unwrap(),
+        merkle_root: Some(share_merkle_root),
+        bits: CompactTarget::from_consensus(0x207fffff),
+        time: 1700000000u32,
+        donation: None,
+        fee: None,
+    };
+
     let mut bitcoin_transactions: Vec<Transaction> = template
         .transactions
         .iter()
         .map(bitcoin::Transaction::from)
         .collect();
 
-    // For the tests use the same coinbase as share block, i.e. using the same pubkey. This is so we don't have empty transactions and end up with a None merkle root.
-    bitcoin_transactions.insert(0, coinbase.clone());
+    // For the tests use the same coinbase outputs as share block, i.e. using the same pubkey. This is so we don't have empty transactions and end up with a None merkle root.
+    // The scriptsig commits to the share header, as done by stratum.
+    let mut bitcoin_coinbase = coinbase.clone();
+    bitcoin_coinbase.input[0].script_sig = Builder::new()
+        .push_int(template.height as i64)
+        .push_slice(commitment.hash().as_byte_array())
+        .into_script();
+    bitcoin_transactions.insert(0, bitcoin_coinbase);
 
     let bitcoin_merkle_root = bitcoin::merkle_tree::calculate_root(
         bitcoin_transactions.iter().map(|tx| tx.compute_txid()),
@@ -151,7 +196,7 @@ pub fn build_block_from_work_components(path: &str) -> ShareBlock {
     .unwrap()
     .into();
 
-    let bitcoin_header = Header {
+    let mut bitcoin_header = Header {
         version: bitcoin::block::Version::from_consensus(template.version),
         prev_blockhash: BlockHash::from_str(&template.previousblockhash).unwrap(),
         merkle_root: bitcoin_merkle_root,
@@ -160,21 +205,13 @@ pub fn build_block_from_work_components(path: &str) -> ShareBlock {
         nonce: u32::from_str_radix(submit.params[4].as_ref().unwrap(), 16).unwrap(),
     };
 
-    let share_header = ShareHeader {
-        prev_share_blockhash: BlockHash::all_zeros(),
-        uncles: vec![],
-        miner_pubkey: CompressedPublicKey::from_str(
-            "020202020202020202020202020202020202020202020202020202020202020202",
-        )
-        .unwrap(),
-        merkle_root: Some(share_merkle_root),
-        bitcoin_header,
-        time: 1700000000u32,
-        bits: CompactTarget::from_consensus(0x207fffff),
-    };
+    let share_target = bitcoin::Target::from_compact(commitment.bits);
+    while !share_target.is_met_by(bitcoin_header.block_hash()) {
+        bitcoin_header.nonce = bitcoin_header.nonce.wrapping_add(1);
+    }
 
     ShareBlock {
-        header: share_header,
+        header: ShareHeader::from_commitment_and_header(commitment, bitcoin_header),
         transactions: vec![coinbase],
         bitcoin_transactions,
     }
@@ -309,6 +346,8 @@ fn test_share_block(
         bitcoin_header,
         time: 1700000000u32,
         bits: CompactTarget::from_consensus(0x01e0377ae * work.unwrap_or(1)),
+        donation: None,
+        fee: None,
     };
 
     ShareBlock {
@@ -403,6 +442,8 @@ impl TestShareHeaderBuilder {
             },
             time: 1700000000u32,
             bits: CompactTarget::from_consensus(0x207fffff),
+            donation: None,
+            fee: None,
         }
     }
 }
@@ -24,8 +24,11 @@ use p2poolv2_lib::shares::chain::chain_store::ChainStore;
 use p2poolv2_lib::shares::share_block::ShareBlock;
 use p2poolv2_lib::store::Store;
 use p2poolv2_lib::stratum::client_connections::start_connections_handler;
+use p2poolv2_lib::stratum::drain::drain_connections;
 use p2poolv2_lib::stratum::emission::Emission;
 use p2poolv2_lib::stratum::server::StratumServerBuilder;
+use p2poolv2_lib::stratum::sv2::server::Sv2ServerBuilder;
+use p2poolv2_lib::stratum::tls::load_tls_acceptor;
 use p2poolv2_lib::stratum::work::gbt::start_gbt;
 use p2poolv2_lib::stratum::work::notify::start_notify;
 use p2poolv2_lib::stratum::work::tracker::start_tracker_actor;
@@ -103,7 +106,6 @@ async fn main() -> Result<(), String> {
         .map(|miner_config| miner_config.pubkey);
     let bitcoinrpc_config = config.bitcoinrpc.clone();
 
-    let (stratum_shutdown_tx, stratum_shutdown_rx) = tokio::sync::oneshot::channel();
     let (notify_tx, notify_rx) = tokio::sync::mpsc::channel(1);
     let tracker_handle = start_tracker_actor();
 
@@ -166,44 +168,127 @@ async fn main() -> Result<(), String> {
     let metrics_cloned = metrics_handle.clone();
     let store_for_stratum = chain_store.clone();
 
-    tokio::spawn(async move {
-        let mut stratum_server = StratumServerBuilder::default()
-            .shutdown_rx(stratum_shutdown_rx)
+    // Load all TLS certificates before spawning any server, so a bad
+    // certificate stops startup without leaving other listeners running.
+    let mut listeners = Vec::new();
+    for listener in stratum_config.listeners() {
+        let tls_acceptor = match (&listener.tls_cert, &listener.tls_key) {
+            (Some(tls_cert), Some(tls_key)) => match load_tls_acceptor(tls_cert, tls_key) {
+                Ok(tls_acceptor) => Some(tls_acceptor),
+                Err(e) => {
+                    let port = listener.port;
+                    error!("Failed to load TLS certificate for port {port}: {e}");
+                    return Err(format!("Failed to load TLS certificate for port {port}"));
+                }
+            },
+            _ => None,
+        };
+        listeners.push((listener, tls_acceptor));
+    }
+
+    let (sv2_shutdown_tx, sv2_shutdown_rx) = tokio::sync::oneshot::channel();
+    let mut sv2_shutdown_tx = Some(sv2_shutdown_tx);
+    if let (Some(sv2_port), Some(authority_keys)) = (
+        stratum_config.sv2_port,
+        stratum_config.sv2_authority_keys().cloned(),
+    ) {
+        info!(
+            "Stratum V2 authority public key: {}",
+            authority_keys.public_key_hex()
+        );
+        let sv2_builder = Sv2ServerBuilder::default()
+            .shutdown_rx(sv2_shutdown_rx)
             .connections_handle(connections_handle.clone())
-            .emissions_tx(emissions_tx)
-            .hostname(stratum_config.hostname)
-            .port(stratum_config.port)
+            .emissions_tx(emissions_tx.clone())
+            .hostname(stratum_config.hostname.clone())
+            .port(sv2_port)
             .start_difficulty(stratum_config.start_difficulty)
             .minimum_difficulty(stratum_config.minimum_difficulty)
             .maximum_difficulty(stratum_config.maximum_difficulty)
             .network(stratum_config.network)
             .version_mask(stratum_config.version_mask)
-            .store(store_for_stratum)
-            .build()
-            .await
-            .unwrap();
-        info!("Starting Stratum server...");
-        let result = stratum_server
-            .start(
-                None,
-                notify_tx,
-                tracker_handle,
-                bitcoinrpc_config,
-                metrics_cloned,
-            )
-            .await;
-        if result.is_err() {
-            error!("Failed to start Stratum server: {}", result.unwrap_err());
+            .authority_keys(authority_keys)
+            .store(chain_store.clone());
+        let notify_tx = notify_tx.clone();
+        let tracker_handle = tracker_handle.clone();
+        let bitcoinrpc_config = bitcoinrpc_config.clone();
+        let metrics = metrics_handle.clone();
+        tokio::spawn(async move {
+            let mut sv2_server = sv2_builder.build().await.unwrap();
+            if let Err(e) = sv2_server
+                .start(None, notify_tx, tracker_handle, bitcoinrpc_config, metrics)
+                .await
+            {
+                error!("Failed to start Stratum V2 server: {}", e);
+            }
+            info!("Stratum V2 server stopped");
+        });
+    }
+
+    let mut stratum_shutdown_txs = Vec::new();
+    for (listener, tls_acceptor) in listeners {
+        let port = listener.port;
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        stratum_shutdown_txs.push(shutdown_tx);
+        let mut builder = StratumServerBuilder::default()
+            .shutdown_rx(shutdown_rx)
+            .connections_handle(connections_handle.clone())
+            .emissions_tx(emissions_tx.clone())
+            .hostname(listener.hostname)
+            .port(port)
+            .start_difficulty(listener.start_difficulty)
+            .minimum_difficulty(listener.minimum_difficulty)
+            .maximum_difficulty(listener.maximum_difficulty)
+            .network(stratum_config.network)
+            .version_mask(listener.version_mask)
+            .store(store_for_stratum.clone());
+        if let Some(tls_acceptor) = tls_acceptor {
+            builder = builder.tls_acceptor(tls_acceptor);
         }
-        info!("Stratum server stopped");
-    });
+        let notify_tx = notify_tx.clone();
+        let tracker_handle = tracker_handle.clone();
+        let bitcoinrpc_config = bitcoinrpc_config.clone();
+        let metrics = metrics_cloned.clone();
+        tokio::spawn(async move {
+            let mut stratum_server = builder.build().await.unwrap();
+            info!("Starting Stratum server on port {port}...");
+            if let Err(e) = stratum_server
+                .start(None, notify_tx, tracker_handle, bitcoinrpc_config, metrics)
+                .await
+            {
+                error!("Failed to start Stratum server on port {port}: {}", e);
+            }
+            info!("Stratum server on port {port} stopped");
+        });
+    }
 
-    let api_shutdown_tx = match start_api_server(
-        config.api.clone(),
+    let (drain_tx, mut drain_rx) = tokio::sync::mpsc::channel(1);
+    let api_config = config.api.clone();
+    let (node_handle, mut stopping_rx) = match NodeHandle::new(
+        config,
         chain_store.clone(),
+        emissions_rx,
         metrics_handle.clone(),
     )
     .await
+    {
+        Ok(started) => started,
+        Err(e) => {
+            error!("Failed to start node: {e}");
+            return Err(format!("Failed to start node: {e}"));
+        }
+    };
+    info!("Node started");
+
+    let api_shutdown_tx = match start_api_server(
+        api_config.clone(),
+        chain_store,
+        metrics_handle,
+        node_handle.sync_status(),
+        node_handle.reachability(),
+        drain_tx,
+    )
+    .await
     {
         Ok(shutdown_tx) => shutdown_tx,
         Err(e) => {
@@ -213,28 +298,43 @@ async fn main() -> Result<(), String> {
     };
     info!(
         "API server started on host {} port {}",
-        config.api.hostname, config.api.port
+        api_config.hostname, api_config.port
     );
 
-    match NodeHandle::new(config, chain_store, emissions_rx, metrics_handle).await {
-        Ok((_node_handle, stopping_rx)) => {
-            info!("Node started");
-            if (stopping_rx.await).is_ok() {
-                info!("Node shutting down ...");
+    tokio::select! {
+        Some(request) = drain_rx.recv() => {
+            info!("Draining stratum connections ...");
+            // Further drain requests are rejected by the API
+            drain_rx.close();
 
-                stratum_shutdown_tx
-                    .send(())
-                    .expect("Failed to send shutdown signal to Stratum server");
-
-                let _ = api_shutdown_tx.send(());
+            // Stop accepting new connections, then move the existing ones away
+            for stratum_shutdown_tx in stratum_shutdown_txs.drain(..) {
+                let _ = stratum_shutdown_tx.send(());
+            }
+            if let Some(sv2_shutdown_tx) = sv2_shutdown_tx.take() {
+                let _ = sv2_shutdown_tx.send(());
+            }
+            let closed = drain_connections(&connections_handle, &request).await;
+            info!("Stratum connections drained, {closed} closed without reconnecting");
 
-                info!("Node stopped");
+            if let Err(e) = node_handle.shutdown().await {
+                error!("Failed to shut down node after drain: {e}");
             }
+            let _ = stopping_rx.await;
         }
-        Err(e) => {
-            error!("Failed to start node: {e}");
-            return Err(format!("Failed to start node: {e}"));
-        }
+        _ = &mut stopping_rx => {}
+    }
+    info!("Node shutting down ...");
+
+    for stratum_shutdown_tx in stratum_shutdown_txs {
+        let _ = stratum_shutdown_tx.send(());
+    }
+    if let Some(sv2_shutdown_tx) = sv2_shutdown_tx {
+        let _ = sv2_shutdown_tx.send(());
     }
+
+    let _ = api_shutdown_tx.send(());
+
+    info!("Node stopped");
     Ok(())
 }
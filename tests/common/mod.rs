@@ -42,6 +42,20 @@ pub fn default_test_config() -> Config {
             max_requests_per_second: 1,
             peer_inactivity_timeout_secs: 60,
             dial_timeout_secs: 30,
+            node_key_path: None,
+            max_redial_peers: 8,
+            ban_threshold: 100,
+            ban_duration_secs: 86400,
+            bootstrap_peers: vec![],
+            dns_seeds: vec![],
+            target_outbound_peers: 8,
+            discovery_interval_secs: 60,
+            max_peers_per_subnet: 2,
+            enable_quic: false,
+            enable_autonat: false,
+            enable_relay_client: false,
+            relay_servers: vec![],
+            enable_relay_server: false,
         },
         bitcoinrpc: BitcoinRpcConfig {
             url: "http://localhost:8332".to_string(),
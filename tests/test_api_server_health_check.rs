@@ -20,6 +20,8 @@ use p2poolv2_api::api::error::ApiError;
 use p2poolv2_api::start_api_server;
 use p2poolv2_lib::accounting::{simple_pplns::SimplePplnsShare, stats::metrics::start_metrics};
 use p2poolv2_lib::config::ApiConfig;
+use p2poolv2_lib::node::reachability::ReachabilityHandle;
+use p2poolv2_lib::node::sync::SyncStatusHandle;
 use p2poolv2_lib::shares::chain::chain_store::ChainStore;
 use p2poolv2_lib::shares::share_block::ShareBlock;
 use p2poolv2_lib::store::Store;
@@ -54,10 +56,19 @@ async fn test_api_server_without_authentication() -> Result<(), ApiError> {
         auth_token: None,
     };
 
+    let (drain_tx, mut drain_rx) = tokio::sync::mpsc::channel(1);
+
     // Start API server with the new signature
-    let shutdown_tx = start_api_server(api_config.clone(), chain_store.clone(), metrics_handle)
-        .await
-        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    let shutdown_tx = start_api_server(
+        api_config.clone(),
+        chain_store.clone(),
+        metrics_handle,
+        SyncStatusHandle::default(),
+        ReachabilityHandle::default(),
+        drain_tx,
+    )
+    .await
+    .map_err(|e| ApiError::ServerError(e.to_string()))?;
 
     // Give server a moment to start
     sleep(Duration::from_millis(500)).await;
@@ -81,6 +92,32 @@ async fn test_api_server_without_authentication() -> Result<(), ApiError> {
         .map_err(|e| ApiError::ServerError(e.to_string()))?;
     assert_eq!(body, "OK", "Health endpoint returned unexpected body");
 
+    // Check /sync_status endpoint
+    let response = client
+        .get(format!("http://127.0.0.1:{}/sync_status", api_config.port))
+        .send()
+        .await
+        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    assert!(response.status().is_success());
+    let body = response
+        .text()
+        .await
+        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    assert!(
+        body.contains(r#""state":"Idle""#),
+        "Unexpected sync status: {body}"
+    );
+
+    // Draining shuts the node down, so it needs authentication
+    let response = client
+        .post(format!("http://127.0.0.1:{}/drain", api_config.port))
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+    assert!(drain_rx.try_recv().is_err());
+
     // Send shutdown signal
     let _ = shutdown_tx.send(());
 
@@ -136,10 +173,19 @@ async fn test_api_server_with_authentication() -> Result<(), ApiError> {
         auth_token: Some(test_token),
     };
 
+    let (drain_tx, mut drain_rx) = tokio::sync::mpsc::channel(1);
+
     // Start API server with authentication
-    let shutdown_tx = start_api_server(api_config.clone(), chain_store.clone(), metrics_handle)
-        .await
-        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    let shutdown_tx = start_api_server(
+        api_config.clone(),
+        chain_store.clone(),
+        metrics_handle,
+        SyncStatusHandle::default(),
+        ReachabilityHandle::default(),
+        drain_tx,
+    )
+    .await
+    .map_err(|e| ApiError::ServerError(e.to_string()))?;
 
     // Give server a moment to start
     sleep(Duration::from_millis(500)).await;
@@ -202,7 +248,7 @@ async fn test_api_server_with_authentication() -> Result<(), ApiError> {
     // Test 4: Metrics endpoint should also require auth
     let response = client
         .get(format!("http://127.0.0.1:{}/metrics", api_config.port))
-        .header(header::AUTHORIZATION, valid_auth)
+        .header(header::AUTHORIZATION, valid_auth.clone())
         .send()
         .await
         .map_err(|e| ApiError::ServerError(e.to_string()))?;
@@ -212,6 +258,41 @@ async fn test_api_server_with_authentication() -> Result<(), ApiError> {
         "Metrics endpoint with valid auth should return 200 OK"
     );
 
+    // Test 5: /drain rejects reconnect params out of order
+    let response = client
+        .post(format!("http://127.0.0.1:{}/drain", api_config.port))
+        .header(header::AUTHORIZATION, valid_auth.clone())
+        .json(&serde_json::json!({ "port": 3333 }))
+        .send()
+        .await
+        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    // Test 6: /drain hands the request to the node
+    let response = client
+        .post(format!("http://127.0.0.1:{}/drain", api_config.port))
+        .header(header::AUTHORIZATION, valid_auth.clone())
+        .json(&serde_json::json!({ "host": "pool.example.com", "port": 3333, "wait": 5 }))
+        .send()
+        .await
+        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    let request = drain_rx.recv().await.unwrap();
+    assert_eq!(request.host.as_deref(), Some("pool.example.com"));
+    assert_eq!(request.port, Some(3333));
+    assert_eq!(request.wait, Some(5));
+
+    // Test 7: once the node is draining, further requests are rejected
+    drain_rx.close();
+    let response = client
+        .post(format!("http://127.0.0.1:{}/drain", api_config.port))
+        .header(header::AUTHORIZATION, valid_auth)
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+
     // Send shutdown signal
     let _ = shutdown_tx.send(());
 
@@ -252,10 +333,16 @@ async fn test_pplns_shares_endpoint_get_all() -> Result<(), ApiError> {
     };
 
     // Start API server
-    let shutdown_tx =
-        p2poolv2_api::start_api_server(api_config.clone(), chain_store.clone(), metrics_handle)
-            .await
-            .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    let shutdown_tx = p2poolv2_api::start_api_server(
+        api_config.clone(),
+        chain_store.clone(),
+        metrics_handle,
+        SyncStatusHandle::default(),
+        ReachabilityHandle::default(),
+        tokio::sync::mpsc::channel(1).0,
+    )
+    .await
+    .map_err(|e| ApiError::ServerError(e.to_string()))?;
 
     sleep(Duration::from_millis(500)).await;
 
@@ -363,10 +450,16 @@ async fn test_pplns_shares_endpoint_limit() -> Result<(), ApiError> {
     };
 
     // Start API server
-    let shutdown_tx =
-        p2poolv2_api::start_api_server(api_config.clone(), chain_store.clone(), metrics_handle)
-            .await
-            .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    let shutdown_tx = p2poolv2_api::start_api_server(
+        api_config.clone(),
+        chain_store.clone(),
+        metrics_handle,
+        SyncStatusHandle::default(),
+        ReachabilityHandle::default(),
+        tokio::sync::mpsc::channel(1).0,
+    )
+    .await
+    .map_err(|e| ApiError::ServerError(e.to_string()))?;
 
     sleep(Duration::from_millis(500)).await;
 
@@ -470,10 +563,16 @@ async fn test_pplns_shares_endpoint_time_filter() -> Result<(), ApiError> {
     };
 
     // Start API server
-    let shutdown_tx =
-        p2poolv2_api::start_api_server(api_config.clone(), chain_store.clone(), metrics_handle)
-            .await
-            .map_err(|e| ApiError::ServerError(e.to_string()))?;
+    let shutdown_tx = p2poolv2_api::start_api_server(
+        api_config.clone(),
+        chain_store.clone(),
+        metrics_handle,
+        SyncStatusHandle::default(),
+        ReachabilityHandle::default(),
+        tokio::sync::mpsc::channel(1).0,
+    )
+    .await
+    .map_err(|e| ApiError::ServerError(e.to_string()))?;
 
     sleep(Duration::from_millis(500)).await;
 